@@ -31,6 +31,25 @@ impl KeyPair {
             self.public.algorithm()
         }
     }
+
+    // A fresh Ed25519 key, for nodes started without one
+    pub fn random() -> KeyPair {
+        let private = PrivateKey::random(&mut rand_core::OsRng, Algorithm::Ed25519).unwrap();
+        KeyPair::try_from(private).unwrap()
+    }
+
+    pub fn can_sign(&self) -> bool {
+        self.private.is_some()
+    }
+
+    // The public part in SSH wire format, as sent to peers
+    pub fn public_bytes(&self) -> Option<Vec<u8>> {
+        PublicKey::from( self.public.clone() ).to_bytes().ok()
+    }
+
+    pub fn from_public_bytes(bytes: &[u8]) -> Option<KeyPair> {
+        PublicKey::from_bytes(bytes).ok().map( KeyPair::from )
+    }
 }
 
 // Compatible ssh_key crate /*
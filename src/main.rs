@@ -6,124 +6,36 @@ mod network;
 mod message;
 mod workers;
 mod crypto;
+mod node;
 
 use clap::{Arg, App};
 
-use crate::network::host::Host;
-use crate::network::udp::Datagram;
-use crate::network::network::Network;
+use crate::node::{Node,NodeBuilder,Worker,JOIN_TIMEOUT};
+use crate::workers::config::Config;
 
-use crate::message::header::Header;
-use crate::message::signal::{Signal,SignalType};
-
-use crate::memory::sqlite::SqliteCore;
-use crate::memory::shared_fifo::SharedFifo;
-
-use crate::workers::{heartbeat::heartbeater,receive::receiver,emit::emitter,dispatch::dispatcher,config::Config};
-// crate::workers::trace::tracer;
-
-// for dev/test only
-async fn server_and_client(dg: Datagram, i_max: usize, stade: u8) -> std::io::Result<(Network,Network,SharedFifo<Datagram,()>, SharedFifo<Datagram,()> )>{
-    //////////////////////////// 
-    let income: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
-    let outcome: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
-    ////////////////////////////
-    let mut backbone: Signal<()> = Signal::new(SignalType::broadcast);
-    //let tracing: Signal<Datagram> = Signal::new(SignalType::mpsc);
-    ////////////////////////////
-    let mut client = Config::from_file("client.config").unwrap();
-    let mut server = Config::from_file("server.config").unwrap();
-    let co = match SqliteCore::init("toktok.db") {
-        None => { return Err( std::io::Error::new(std::io::ErrorKind::Other,"No db file found !")); },
-        Some(co) => co,
+// Look `name` up among the nodes reachable with `config_file`, once one of them answered our HELLO.
+// Only our own services are known if none did
+async fn query_service(config_file: &str, name: &str) -> std::io::Result<Vec<network::service::Service>> {
+    let config = match Config::from_file(config_file) {
+        Err(err) => { return Err( std::io::Error::other( format!("{:?}", err) ) ); },
+        Ok(config) => config,
     };
+    query_with( Node::builder().config(config), name ).await
+}
 
-    if let Ok(key) = crypto::openssh::from(
-        "toktok".to_string(),
-        Some(
-            "lama".to_string()
-        )
-    ) {
-        client.verify(&key).unwrap();
-        server.verify(&key).unwrap();
-    }
-
-    let client = client.into_network().await.unwrap();
-    let server = server.into_network().await.unwrap();
-    ////////////////////////////
-    // 
-    let mut tasks: Vec<tokio::task::JoinHandle<Result<(), std::io::Error>>> = Vec::new();
-    tasks.push(tokio::task::spawn( 
-        receiver(
-            server.clone(),
-            income.clone(),
-            backbone.subscribe()
-        )
-    ));
-
-    if stade > 0 {
-        tasks.push(tokio::task::spawn( 
-            dispatcher(
-                server.clone(),
-                income.clone(),
-                outcome.clone(),
-                /*tracing.subscribe(),*/
-                backbone.subscribe()
-            )
-        ));
-    }
-    
-    if stade > 1 {
-        tasks.push(tokio::task::spawn( 
-            emitter(
-                server.clone(),
-                outcome.clone(),
-                backbone.subscribe()
-            )
-        ));
-    }
-
-    if stade > 2 {
-        tasks.push(tokio::task::spawn(
-            heartbeater(
-                server.clone(),
-                backbone.subscribe()
-            )
-        ));
-    }
-
-    /*
-    if stade > 3 {
-        tasks.push(tokio::task::spawn( 
-            tracer(
-                tracing,
-                co,
-                outcome.clone(),
-                5
-            )
-        ));
-    }*/
-    //
-    ////////////////////////////
-    ////////////////////////////
-    //
-    let dst = Host::new( "127.0.0.1:3333" );
-
-    for _ in 1..i_max {
-        client.send_to(dg.clone(), Some(dst.clone()) ).await;
-    }
-    //
-    ////////////////////////////
-    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-    backbone.send(()).await.ok();
-    client.send_to(dg, Some(dst) ).await;
-
-    while !tasks.is_empty() { tasks.pop().unwrap().await.ok(); }
+// The same with the node `builder` makes, e.g. on a simulated network
+async fn query_with(builder: NodeBuilder, name: &str) -> std::io::Result<Vec<network::service::Service>> {
+    let node = match builder.workers( &[Worker::Receiver, Worker::Dispatcher, Worker::Emitter] ).start().await {
+        Err(err) => { return Err( std::io::Error::other( format!("{:?}", err) ) ); },
+        Ok(node) => node,
+    };
 
-    Ok((client, server, income, outcome))
+    node.established(JOIN_TIMEOUT).await;
+    let services = node.network().query_service(name).await;
+    node.shutdown().await?;
+    Ok(services)
 }
 
-
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     let matches = App::new("Toktok")
@@ -141,35 +53,79 @@ async fn main() -> std::io::Result<()> {
                  .takes_value(true)
                  .help("OpenSSH format keyfile. Default: toktok"))
         // for private keyfile with password, should ask the password ... in an env file ? :c
-        .arg(Arg::with_name("execution_mode")
-                 .short('e')
-                 .long("execution-mode")
+        .arg(Arg::with_name("query_service")
+                 .short('s')
+                 .long("query-service")
+                 .takes_value(true)
+                 .help("Print the nodes providing this service, then exit"))
+        .arg(Arg::with_name("storage")
+                 .long("storage")
                  .takes_value(true)
-                 .help("execution mod: [C/s]"))
+                 .help("SQLite database the tracer writes to. Default: toktok.db"))
         .get_matches();
     
     let config_file = matches.value_of("config_file").unwrap_or("toktok.config");
     let key_file = matches.value_of("keyfile").unwrap_or("toktok");
-    let execution_mode = matches.value_of("execution_mode");
-    let force_as_server = match execution_mode {
-        None => false,
-        Some(s) => {
-            match s.parse::<String>() {
-                Err(_) => false,
-                Ok(str) => (str.to_lowercase().get(0..1) == Some("s") ),
+    let storage = matches.value_of("storage").unwrap_or("toktok.db");
+
+    if let Some(name) = matches.value_of("query_service") {
+        for service in query_service(config_file, name).await? {
+            match service.server() {
+                None => println!("{} {}", service.name(), service.port()),
+                Some(server) => println!("{} {}", service.name(), server.local_addr()),
             }
         }
-    };
-
-    let dg = Datagram::from(Header::HELLO);
-    let i_max = 2;
+        return Ok(());
+    }
 
-    let (client, server, income, outcome) = server_and_client(dg, i_max,255).await.unwrap();
+    let config = match Config::from_file(config_file) {
+        Err(err) => { return Err( std::io::Error::other( format!("{:?}", err) ) ); },
+        Ok(config) => config,
+    };
+    let mut builder = Node::builder().config(config).storage(storage);
+    // for private keyfile with password, should ask the password ... in an env file ? :c
+    if let Ok(key) = crypto::openssh::from( key_file.to_string(), None ) {
+        builder = builder.key(key);
+    }
+    let node = match builder.start().await {
+        Err(err) => { return Err( std::io::Error::other( format!("{:?}", err) ) ); },
+        Ok(node) => node,
+    };
 
-    println!("\n\nClient:\n {:#?}",client);
-    println!("\n\nServeur:\n {:#?}",server);
-    println!("\n\nIncome:\n {:#?}",income);
-    println!("\n\nOutcome:\n {:#?}",outcome);
+    // Runs until interrupted
+    tokio::signal::ctrl_c().await.ok();
+    println!("\n\nWorkers:\n {:#?}",node.status());
+    node.shutdown().await?;
     
     Ok(())
-}
\ No newline at end of file
+}
+#[tokio::test(start_paused = true)]
+async fn test_query_service_cli() -> std::io::Result<()> {
+    use std::sync::Arc;
+    use crate::network::host::Host;
+    use crate::network::network::Network;
+    use crate::network::service::Service;
+
+    // a second node providing "lama", the CLI only knows it from its configuration
+    let sim = crate::network::simulated::SimNet::new(0);
+    let provider: Config = serde_json::from_str(r#"{"server":null,"gateway":"127.255.255.255:4723","rx":"127.0.0.2:4723","tx":null,"clients":null,"services":null,"signature":null}"#).unwrap();
+    let net = Network::new( Arc::new( sim.bind( Host::new("127.0.0.2:4723") ) ), None, Host::new("127.255.255.255:4723"), None )
+        .with_services( vec![ Service::new( "lama".to_string(), 1234, None ) ] );
+    let provider = match Node::builder().config(provider).network(net).workers( &[Worker::Receiver, Worker::Dispatcher, Worker::Emitter] ).start().await {
+        Err(err) => { return Err( std::io::Error::other( format!("{:?}", err) ) ); },
+        Ok(node) => node,
+    };
+    let dir = std::env::temp_dir().join( format!("toktok-query-{}", std::process::id()) );
+    std::fs::create_dir_all(&dir)?;
+    let config_file = dir.join("client.config");
+    std::fs::write( &config_file, r#"{"server":null,"gateway":"127.255.255.255:4724","rx":"127.0.0.1:4724","tx":null,"clients":{"127.0.0.2":"127.0.0.2:4723"},"services":null,"signature":null}"# )?;
+
+    let config = Config::from_file( config_file.to_str().unwrap() ).unwrap();
+    let net = Network::new( Arc::new( sim.bind( Host::new("127.0.0.1:4724") ) ), None, Host::new("127.255.255.255:4724"), None );
+    let services = query_with( Node::builder().config(config).network(net), "lama" ).await;
+    provider.shutdown().await?;
+    std::fs::remove_dir_all(&dir)?;
+    let services = services?;
+    assert_eq!(services.iter().map( |service| (service.name(), service.port(), service.server()) ).collect::<Vec<_>>(),vec![ ("lama", 1234, Some( Host::new("127.0.0.2:1234") )) ]);
+    Ok(())
+}
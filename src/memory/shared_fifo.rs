@@ -1,14 +1,67 @@
 
 use std::sync::{Arc, Mutex};
-use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64,Ordering};
+use std::net::IpAddr;
+use std::collections::{HashMap,VecDeque};
 use tokio::sync::futures::Notified;
+use serde::{Deserialize, Serialize};
 
 use crate::message::signal::{Signal,SignalType,SignalErr};
 
+// What a full queue gives up to make room
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Serialize,Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropPolicy {
+    // The item being pushed
+    DropNewest,
+    // The item waiting for the longest
+    DropOldest,
+    // The oldest item of the source holding the most, a flooding source only loses its own
+    FairShare,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Serialize,Deserialize)]
+pub struct QueueLimit {
+    capacity: usize,
+    policy: DropPolicy,
+}
+
+impl QueueLimit {
+    pub const fn new(capacity: usize, policy: DropPolicy) -> QueueLimit {
+        QueueLimit { capacity, policy }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn policy(&self) -> DropPolicy {
+        self.policy
+    }
+}
+
+// A limit and who pushed an item, for the fair share
+#[derive(Debug)]
+struct Bound<T> {
+    limit: QueueLimit,
+    source: fn(&T) -> Option<IpAddr>,
+}
+
+impl<T> Clone for Bound<T> {
+    fn clone(&self) -> Self {
+        Bound { limit: self.limit, source: self.source }
+    }
+}
+
 #[derive(Debug)]
 pub struct SharedFifo<T,V>  {
     fifo: Arc<Mutex<VecDeque<T>>>,
     signal: Signal<V>,
+    bound: Option<Bound<T>>,
+    dropped: Arc<AtomicU64>,
+    dropped_from: Arc<Mutex<HashMap<Option<IpAddr>,u64>>>,
+    // Items queued per source, kept along pushes and pops for the fair share. Locked after `fifo`
+    held: Arc<Mutex<HashMap<Option<IpAddr>,usize>>>,
 }
 
 impl <T,V: std::clone::Clone> SharedFifo<T,V> {
@@ -17,15 +70,88 @@ impl <T,V: std::clone::Clone> SharedFifo<T,V> {
         let data: VecDeque<T> = VecDeque::new();
         let fifo = Arc::new(Mutex::new(data));
         let signal: Signal<V> = Signal::new(signal_mod);
-        SharedFifo { fifo, signal }        
+        SharedFifo { fifo, signal, bound: None, dropped: Arc::new( AtomicU64::new(0) ), dropped_from: Arc::new( Mutex::new( HashMap::new() ) ), held: Arc::new( Mutex::new( HashMap::new() ) ) }        
+    }
+
+    // At most `limit.capacity` items, `source` tells who pushed one
+    pub fn bounded(signal_mod: SignalType, limit: QueueLimit, source: fn(&T) -> Option<IpAddr>) -> SharedFifo<T,V> {
+        let mut sfifo: SharedFifo<T,V> = SharedFifo::new(signal_mod);
+        sfifo.bound = Some( Bound { limit, source } );
+        sfifo
     }
 
     // assuming it's a fifo => push_front
-    pub fn push(&mut self, data: T) -> () {
-        {
-            let mut fifo = self.fifo.lock().unwrap();
-            fifo.push_front(data);
-        }
+    // false when `data` itself was dropped to respect the limit
+    pub fn push(&mut self, data: T) -> bool {
+        let mut fifo = self.fifo.lock().unwrap();
+        let mut held = self.held.lock().unwrap();
+        let bound: &Bound<T> = match &self.bound {
+            Some(bound) if fifo.len() >= bound.limit.capacity => bound,
+            Some(bound) => {
+                *held.entry( (bound.source)(&data) ).or_insert(0) += 1;
+                fifo.push_front(data);
+                return true;
+            },
+            None => {
+                fifo.push_front(data);
+                return true;
+            },
+        };
+
+        // pushed at the front: the oldest items are at the back
+        let victim: Option<usize> = match bound.limit.policy {
+            _ if fifo.is_empty() => None,
+            DropPolicy::DropNewest => None,
+            DropPolicy::DropOldest => Some( fifo.len() - 1 ),
+            DropPolicy::FairShare => {
+                let source: Option<IpAddr> = (bound.source)(&data);
+                let own: usize = held.get(&source).copied().unwrap_or(0) + 1;
+                let (heaviest, most) = held.iter().max_by_key( |(_, count)| **count ).map( |(source, count)| (*source, *count) ).unwrap_or( (None, 0) );
+                match own >= most {
+                    true => None,
+                    false => fifo.iter().rposition( |item| (bound.source)(item) == heaviest ),
+                }
+            },
+        };
+
+        let (dropped, kept): (Option<IpAddr>, bool) = match victim {
+            None => ( (bound.source)(&data), false ),
+            Some(position) => {
+                let old: T = fifo.remove(position).unwrap();
+                let dropped: Option<IpAddr> = (bound.source)(&old);
+                release(&mut held, dropped);
+                *held.entry( (bound.source)(&data) ).or_insert(0) += 1;
+                fifo.push_front(data);
+                ( dropped, true )
+            },
+        };
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        *self.dropped_from.lock().unwrap().entry(dropped).or_insert(0) += 1;
+        kept
+    }
+
+    pub fn len(&self) -> usize {
+        self.fifo.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Items given up since the queue was created
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_from(&self, source: Option<IpAddr>) -> u64 {
+        self.dropped_from.lock().unwrap().get(&source).copied().unwrap_or(0)
+    }
+
+    // An item popped and then given up, e.g. nowhere to take it: counted like the ones the limit drops
+    pub fn discard(&self, item: &T) {
+        let source: Option<IpAddr> = self.bound.as_ref().and_then( |bound| (bound.source)(item) );
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        *self.dropped_from.lock().unwrap().entry(source).or_insert(0) += 1;
     }
     pub async fn push_notice(&mut self, data: T, value: V) -> Result< (),SignalErr >{
         self.push(data);
@@ -34,7 +160,11 @@ impl <T,V: std::clone::Clone> SharedFifo<T,V> {
     // assuming it's a fifo => pop_back
     pub fn pop(&self) -> Option<T> {
         let mut fifo = self.fifo.lock().unwrap();
-        fifo.pop_front()
+        let item: Option<T> = fifo.pop_front();
+        if let (Some(item), Some(bound)) = (item.as_ref(), self.bound.as_ref()) {
+            release( &mut self.held.lock().unwrap(), (bound.source)(item) );
+        }
+        item
     }
 
     pub fn try_recv(&mut self) -> Result< Option<V>,SignalErr > {
@@ -62,7 +192,20 @@ impl<T,V: std::clone::Clone>  Clone for SharedFifo<T,V> {
     fn clone(&self) -> Self {
         SharedFifo { 
             fifo: Arc::clone(&self.fifo), 
-            signal: self.signal.subscribe() 
+            signal: self.signal.subscribe(),
+            bound: self.bound.clone(),
+            dropped: Arc::clone(&self.dropped),
+            dropped_from: Arc::clone(&self.dropped_from),
+            held: Arc::clone(&self.held),
+        }
+    }
+}
+
+fn release(held: &mut HashMap<Option<IpAddr>,usize>, source: Option<IpAddr>) {
+    if let Some(count) = held.get_mut(&source) {
+        *count -= 1;
+        if *count == 0 {
+            held.remove(&source);
         }
     }
 }
@@ -133,3 +276,48 @@ async fn test_shared_fifo_broadcast() {
     assert_eq!(i_max,i);
     assert_eq!(None,debug.pop());
 }
+
+#[test]
+fn test_shared_fifo_drop_policies() {
+    let source = |item: &(u8,usize)| Some( IpAddr::from([10, 0, 0, item.0]) );
+    let push_all = |policy: DropPolicy, items: &[(u8,usize)]| {
+        let mut sfifo: SharedFifo<(u8,usize),()> = SharedFifo::bounded(SignalType::notify, QueueLimit::new(3, policy), source);
+        let kept: Vec<bool> = items.iter().map( |item| sfifo.push(*item) ).collect();
+        let mut left: Vec<(u8,usize)> = Vec::new();
+        while let Some(item) = sfifo.pop() {
+            left.push(item);
+        }
+        left.sort();
+        (kept, left, sfifo)
+    };
+
+    let (kept, left, sfifo) = push_all(DropPolicy::DropNewest, &[(1,0), (1,1), (1,2), (1,3)]);
+    assert_eq!(kept,vec![true, true, true, false]);
+    assert_eq!(left,vec![(1,0), (1,1), (1,2)]);
+    assert_eq!(sfifo.dropped(),1);
+
+    let (kept, left, _) = push_all(DropPolicy::DropOldest, &[(1,0), (1,1), (1,2), (1,3)]);
+    assert_eq!(kept,vec![true; 4]);
+    assert_eq!(left,vec![(1,1), (1,2), (1,3)]);
+
+    // the flooder loses its own datagrams, the others still get in at its expense
+    let (kept, left, mut sfifo) = push_all(DropPolicy::FairShare, &[(1,0), (1,1), (1,2), (1,3), (2,0), (3,0)]);
+    assert_eq!(kept,vec![true, true, true, false, true, true]);
+    assert_eq!(left,vec![(1,2), (2,0), (3,0)]);
+    assert_eq!(sfifo.dropped(),3);
+    assert_eq!(sfifo.dropped_from( Some( IpAddr::from([10, 0, 0, 1]) ) ),3);
+    assert!(sfifo.is_empty());
+
+    // what was popped no longer counts against its source
+    for item in [(2,1), (2,2), (2,3)] {
+        assert!(sfifo.push(item));
+    }
+    sfifo.pop();
+    sfifo.pop();
+    for item in [(1,4), (1,5), (3,1)] {
+        assert!(sfifo.push(item));
+    }
+    let mut left: Vec<(u8,usize)> = std::iter::from_fn( || sfifo.pop() ).collect();
+    left.sort();
+    assert_eq!(left,vec![(1,5), (2,1), (3,1)]);
+}
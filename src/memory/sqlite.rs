@@ -2,8 +2,12 @@ use crate::network::host::Host;
 use metrohash::MetroHash64;
 
 use std::hash::Hasher;
+use std::sync::{Arc,Mutex};
 
-use sqlite::Connection;
+use sqlite::{Connection,State};
+
+use crate::network::peer::PeerId;
+use crate::network::lease::Lease;
 
 /*
 
@@ -56,5 +60,95 @@ impl SqliteCore {
 
 #[test]
 pub fn test_sqlite_init() {
-    let co = SqliteCore::init("test.db").unwrap();
-}
\ No newline at end of file
+    let file = std::env::temp_dir().join( format!("toktok-test-{}.db", std::process::id()) );
+    let co = SqliteCore::init( file.to_str().unwrap() ).unwrap();
+    std::fs::remove_file(&file).ok();
+}
+
+// Address leases granted by the server, over one connection shared by the clones. Calls block: the
+// network makes them from a blocking task
+#[derive(Clone)]
+pub struct LeaseStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl std::fmt::Debug for LeaseStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LeaseStore").finish()
+    }
+}
+
+impl LeaseStore {
+    pub fn open<P: AsRef<std::path::Path>>(filename: P) -> Option<LeaseStore> {
+        let co = Connection::open(filename).ok()?;
+        co.execute("
+            CREATE TABLE IF NOT EXISTS Leases (
+                Peer BIG INT PRIMARY KEY NOT NULL,
+                Ip TEXT NOT NULL,
+                Expires BIG INT NOT NULL
+            );
+        ").ok()?;
+        Some( LeaseStore { connection: Arc::new( Mutex::new(co) ) } )
+    }
+
+    pub fn save(&self, lease: &Lease) -> bool {
+        let ip: String = lease.ip().to_string();
+        let saved = || -> sqlite::Result<()> {
+            let co = self.connection.lock().unwrap();
+            let mut stmt = co.prepare("INSERT OR REPLACE INTO Leases VALUES (?, ?, ?)")?
+                .bind(1, lease.id().value() as i64)?
+                .bind(2, ip.as_str())?
+                .bind(3, lease.expires() as i64)?;
+            stmt.next()?;
+            Ok(())
+        };
+        saved().is_ok()
+    }
+
+    pub fn remove(&self, id: &PeerId) -> bool {
+        let removed = || -> sqlite::Result<()> {
+            let co = self.connection.lock().unwrap();
+            let mut stmt = co.prepare("DELETE FROM Leases WHERE Peer = ?")?.bind(1, id.value() as i64)?;
+            stmt.next()?;
+            Ok(())
+        };
+        removed().is_ok()
+    }
+
+    pub fn load(&self) -> Vec<Lease> {
+        let mut leases: Vec<Lease> = Vec::new();
+        let co = self.connection.lock().unwrap();
+        if let Ok(mut stmt) = co.prepare("SELECT Peer, Ip, Expires FROM Leases") {
+            while let Ok(State::Row) = stmt.next() {
+                let peer = stmt.read::<i64>(0);
+                let ip = stmt.read::<String>(1).ok().and_then( |ip| ip.parse().ok() );
+                let expires = stmt.read::<i64>(2);
+                if let (Ok(peer), Some(ip), Ok(expires)) = (peer, ip, expires) {
+                    leases.push( Lease::new( PeerId::new(peer as u64), ip, expires as u64 ) );
+                }
+            }
+        }
+        leases
+    }
+}
+
+#[test]
+pub fn test_lease_store() {
+    let dir = std::env::temp_dir().join( format!("toktok-leases-{}", std::process::id()) );
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("leases.db");
+    let _ = std::fs::remove_file(&file);
+    let store = LeaseStore::open(&file).unwrap();
+    let lease = Lease::new( PeerId::new(u64::MAX), "10.8.0.2".parse().unwrap(), 1234 );
+
+    assert!(store.save(&lease));
+    assert!(store.save( &Lease::new( PeerId::new(7), "fd00::7".parse().unwrap(), 99 ) ));
+    assert!(store.save( &Lease::new( PeerId::new(7), "fd00::8".parse().unwrap(), 100 ) ));
+    let mut loaded = LeaseStore::open(&file).unwrap().load();
+    loaded.sort_by_key( |l| l.expires() );
+    assert_eq!(loaded,vec![ Lease::new( PeerId::new(7), "fd00::8".parse().unwrap(), 100 ), lease ]);
+
+    assert!(store.remove( &PeerId::new(7) ));
+    assert_eq!(store.load(),vec![lease]);
+    std::fs::remove_dir_all(&dir).ok();
+}
@@ -1,3 +1,13 @@
 pub mod header;
 pub mod tlv;
 pub mod signal;
+pub mod hello;
+pub mod relay;
+pub mod route;
+pub mod dht;
+pub mod gossip;
+pub mod packet;
+pub mod lease;
+pub mod service;
+pub mod stream;
+pub mod pmtu;
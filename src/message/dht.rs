@@ -0,0 +1,153 @@
+use crate::message::tlv::TLV;
+use crate::message::header::Header;
+use crate::network::host::Host;
+use crate::network::peer::PeerId;
+use crate::network::dht::Contact;
+
+// Every DHT message starts with [nonce][sender], the sender ends up in the receiver's buckets
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum DhtMessage {
+    FindNode { nonce: u32, sender: PeerId, target: PeerId },
+    FindValue { nonce: u32, sender: PeerId, key: PeerId },
+    Nodes { nonce: u32, sender: PeerId, contacts: Vec<Contact> },
+    Value { nonce: u32, sender: PeerId, key: PeerId, hosts: Vec<Host> },
+    Store { nonce: u32, sender: PeerId, key: PeerId, hosts: Vec<Host> },
+}
+
+impl DhtMessage {
+    pub fn header(&self) -> Header {
+        match self {
+            DhtMessage::FindNode { .. } => Header::FIND_NODE,
+            DhtMessage::FindValue { .. } => Header::FIND_VALUE,
+            DhtMessage::Nodes { .. } => Header::NODES,
+            DhtMessage::Value { .. } => Header::VALUE,
+            DhtMessage::Store { .. } => Header::STORE,
+        }
+    }
+
+    pub fn nonce(&self) -> u32 {
+        match self {
+            DhtMessage::FindNode { nonce, .. } => *nonce,
+            DhtMessage::FindValue { nonce, .. } => *nonce,
+            DhtMessage::Nodes { nonce, .. } => *nonce,
+            DhtMessage::Value { nonce, .. } => *nonce,
+            DhtMessage::Store { nonce, .. } => *nonce,
+        }
+    }
+
+    pub fn sender(&self) -> PeerId {
+        match self {
+            DhtMessage::FindNode { sender, .. } => *sender,
+            DhtMessage::FindValue { sender, .. } => *sender,
+            DhtMessage::Nodes { sender, .. } => *sender,
+            DhtMessage::Value { sender, .. } => *sender,
+            DhtMessage::Store { sender, .. } => *sender,
+        }
+    }
+
+    pub fn to_tlv(&self) -> Option<TLV> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend_from_slice(&self.nonce().to_be_bytes());
+        bytes.extend_from_slice(&self.sender().to_bytes());
+
+        match self {
+            DhtMessage::FindNode { target, .. } => {
+                bytes.extend_from_slice(&target.to_bytes());
+            },
+            DhtMessage::FindValue { key, .. } => {
+                bytes.extend_from_slice(&key.to_bytes());
+            },
+            DhtMessage::Nodes { contacts, .. } => {
+                bytes.push(contacts.len() as u8);
+                for contact in contacts.iter() {
+                    bytes.extend_from_slice(&contact.id().to_bytes());
+                    bytes.append(&mut contact.host().to_bytes());
+                }
+            },
+            DhtMessage::Value { key, hosts, .. } | DhtMessage::Store { key, hosts, .. } => {
+                bytes.extend_from_slice(&key.to_bytes());
+                bytes.push(hosts.len() as u8);
+                for host in hosts.iter() {
+                    bytes.append(&mut host.to_bytes());
+                }
+            },
+        }
+
+        TLV::new( self.header(), Some(bytes) )
+    }
+
+    pub fn from_tlv(tlv: &TLV) -> Option<DhtMessage> {
+        let bytes: Vec<u8> = tlv.payload();
+        if bytes.len() < 12 {
+            return None;
+        }
+        let nonce: u32 = u32::from_be_bytes([bytes[0],bytes[1],bytes[2],bytes[3]]);
+        let sender: PeerId = PeerId::from_bytes(&bytes[4..12])?;
+        let body: &[u8] = &bytes[12..];
+
+        match tlv.header() {
+            Header::FIND_NODE => Some( DhtMessage::FindNode { nonce, sender, target: PeerId::from_bytes(body)? } ),
+            Header::FIND_VALUE => Some( DhtMessage::FindValue { nonce, sender, key: PeerId::from_bytes(body)? } ),
+            Header::NODES => {
+                let count: usize = *body.first()? as usize;
+                let mut cursor: usize = 1;
+                let mut contacts: Vec<Contact> = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let id = PeerId::from_bytes( body.get(cursor..)? )?;
+                    let (host, used) = Host::from_bytes( body.get((cursor+8)..)? )?;
+                    contacts.push( Contact::new(id, host) );
+                    cursor += 8 + used;
+                }
+                Some( DhtMessage::Nodes { nonce, sender, contacts } )
+            },
+            Header::VALUE | Header::STORE => {
+                let key: PeerId = PeerId::from_bytes(body)?;
+                let count: usize = *body.get(8)? as usize;
+                let mut cursor: usize = 9;
+                let mut hosts: Vec<Host> = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let (host, used) = Host::from_bytes( body.get(cursor..)? )?;
+                    hosts.push(host);
+                    cursor += used;
+                }
+                match tlv.header() {
+                    Header::VALUE => Some( DhtMessage::Value { nonce, sender, key, hosts } ),
+                    _ => Some( DhtMessage::Store { nonce, sender, key, hosts } ),
+                }
+            },
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_dht_message_from_to() {
+    let contacts = vec![
+        Contact::new( PeerId::new(1), Host::new("127.0.0.1:1111") ),
+        Contact::new( PeerId::new(2), Host::new("[::1]:2222") ),
+    ];
+    let hosts = vec![ Host::new("127.0.0.1:1111"), Host::new("10.0.0.1:1") ];
+
+    let messages = [
+        DhtMessage::FindNode { nonce: 1, sender: PeerId::new(9), target: PeerId::new(3) },
+        DhtMessage::FindValue { nonce: 2, sender: PeerId::new(9), key: PeerId::new(3) },
+        DhtMessage::Nodes { nonce: 3, sender: PeerId::new(9), contacts },
+        DhtMessage::Value { nonce: 4, sender: PeerId::new(9), key: PeerId::new(3), hosts: hosts.clone() },
+        DhtMessage::Store { nonce: 5, sender: PeerId::new(9), key: PeerId::new(3), hosts },
+    ];
+
+    for message in messages.iter() {
+        let tlv = message.to_tlv().unwrap();
+        assert_eq!(tlv.header(),message.header());
+        assert_eq!(Some(message.clone()),DhtMessage::from_tlv(&tlv));
+    }
+}
+
+#[test]
+fn test_dht_message_truncated() {
+    let message = DhtMessage::Nodes { nonce: 3, sender: PeerId::new(9), contacts: vec![ Contact::new( PeerId::new(1), Host::new("127.0.0.1:1111") ) ] };
+    let mut payload = message.to_tlv().unwrap().payload();
+    payload.pop();
+    assert_eq!(None,DhtMessage::from_tlv( &TLV::new(Header::NODES, Some(payload)).unwrap() ));
+    assert_eq!(None,DhtMessage::from_tlv( &TLV::new(Header::PING, Some(vec![0; 20])).unwrap() ));
+}
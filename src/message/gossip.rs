@@ -0,0 +1,147 @@
+use crate::message::tlv::TLV;
+use crate::message::header::Header;
+use crate::network::host::Host;
+use crate::network::peer::PeerId;
+
+// Enough room left to ride along a small datagram inside a MULTIPLE
+pub const GOSSIP_MAX_UPDATES: usize = 8;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+impl MemberState {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            MemberState::Alive => 0,
+            MemberState::Suspect => 1,
+            MemberState::Dead => 2,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<MemberState> {
+        match byte {
+            0 => Some(MemberState::Alive),
+            1 => Some(MemberState::Suspect),
+            2 => Some(MemberState::Dead),
+            _ => None,
+        }
+    }
+}
+
+// [id][state][incarnation][host]
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct MemberUpdate {
+    id: PeerId,
+    state: MemberState,
+    incarnation: u32,
+    host: Host,
+}
+
+impl MemberUpdate {
+    pub fn new(id: PeerId, state: MemberState, incarnation: u32, host: Host) -> MemberUpdate {
+        MemberUpdate { id, state, incarnation, host }
+    }
+
+    pub fn id(&self) -> PeerId {
+        self.id
+    }
+
+    pub fn state(&self) -> MemberState {
+        self.state
+    }
+
+    pub fn incarnation(&self) -> u32 {
+        self.incarnation
+    }
+
+    pub fn host(&self) -> Host {
+        self.host
+    }
+}
+
+pub fn gossip_to_tlv(updates: &[MemberUpdate]) -> Option<TLV> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for update in updates.iter() {
+        bytes.extend_from_slice(&update.id.to_bytes());
+        bytes.push(update.state.to_byte());
+        bytes.extend_from_slice(&update.incarnation.to_be_bytes());
+        bytes.append(&mut update.host.to_bytes());
+    }
+    TLV::new( Header::GOSSIP, Some(bytes) )
+}
+
+pub fn gossip_from_bytes(bytes: &[u8]) -> Option<Vec<MemberUpdate>> {
+    let mut updates: Vec<MemberUpdate> = Vec::new();
+    let mut cursor: usize = 0;
+
+    while cursor < bytes.len() {
+        let id = PeerId::from_bytes( bytes.get(cursor..)? )?;
+        let state = MemberState::from_byte( *bytes.get(cursor+8)? )?;
+        let raw = bytes.get((cursor+9)..(cursor+13))?;
+        let incarnation = u32::from_be_bytes([raw[0],raw[1],raw[2],raw[3]]);
+        let (host, used) = Host::from_bytes( bytes.get((cursor+13)..)? )?;
+        updates.push( MemberUpdate { id, state, incarnation, host } );
+        cursor += 13 + used;
+    }
+    Some(updates)
+}
+
+// PING_REQ and PING_ACK name the member being probed on behalf of someone else
+pub fn probe_to_bytes(target: PeerId, host: Host) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::from( target.to_bytes() );
+    bytes.append(&mut host.to_bytes());
+    bytes
+}
+
+pub fn probe_from_bytes(bytes: &[u8]) -> Option<(PeerId, Host)> {
+    let target = PeerId::from_bytes(bytes)?;
+    let (host, _) = Host::from_bytes( bytes.get(8..)? )?;
+    Some( (target, host) )
+}
+
+// BYE: the leaving member and the incarnation its death is gossiped with
+pub fn bye_to_tlv(id: PeerId, incarnation: u32) -> Option<TLV> {
+    let mut bytes: Vec<u8> = Vec::from( id.to_bytes() );
+    bytes.extend_from_slice(&incarnation.to_be_bytes());
+    TLV::new( Header::BYE, Some(bytes) )
+}
+
+pub fn bye_from_bytes(bytes: &[u8]) -> Option<(PeerId, u32)> {
+    let id = PeerId::from_bytes(bytes)?;
+    let raw = bytes.get(8..12)?;
+    Some( (id, u32::from_be_bytes([raw[0],raw[1],raw[2],raw[3]])) )
+}
+
+#[test]
+fn test_gossip_from_to() {
+    let updates = vec![
+        MemberUpdate::new( PeerId::new(1), MemberState::Alive, 3, Host::new("127.0.0.1:1111") ),
+        MemberUpdate::new( PeerId::new(2), MemberState::Dead, 0, Host::new("[::1]:2222") ),
+    ];
+    let tlv = gossip_to_tlv(&updates).unwrap();
+    assert_eq!(tlv.header(),Header::GOSSIP);
+    assert_eq!(Some(updates),gossip_from_bytes( &tlv.payload() ));
+
+    let mut truncated = tlv.payload();
+    truncated.pop();
+    assert_eq!(None,gossip_from_bytes(&truncated));
+}
+
+#[test]
+fn test_probe_from_to() {
+    let bytes = probe_to_bytes( PeerId::new(5), Host::new("127.0.0.1:5555") );
+    assert_eq!(Some( (PeerId::new(5), Host::new("127.0.0.1:5555")) ),probe_from_bytes(&bytes));
+    assert_eq!(None,probe_from_bytes(&bytes[0..9]));
+}
+
+#[test]
+fn test_bye_from_to() {
+    let tlv = bye_to_tlv( PeerId::new(7), 4 ).unwrap();
+    assert_eq!(tlv.header(),Header::BYE);
+    assert_eq!(Some( (PeerId::new(7), 4) ),bye_from_bytes( &tlv.payload() ));
+    assert_eq!(None,bye_from_bytes( &tlv.payload()[0..11] ));
+}
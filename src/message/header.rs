@@ -1,13 +1,43 @@
 #![allow(dead_code)]
 #![allow(unused_imports)]
 #![allow(unused_variables)]
+#![allow(non_camel_case_types)]
 
-#[derive(Debug,PartialEq,Eq,Copy,Clone)]
+// Named the way they are written on the wire
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug,PartialEq,Eq,Copy,Clone,Hash)]
 pub enum Header {
     HELLO,
     MULTIPLE,
     PING,
     PONG,
+    RELAY,
+    ROUTE,
+    FIND_NODE,
+    FIND_VALUE,
+    NODES,
+    VALUE,
+    STORE,
+    GOSSIP,
+    PING_REQ,
+    PING_ACK,
+    IPPACKET,
+    LEASE_REQUEST,
+    LEASE_OFFER,
+    LEASE_ACK,
+    LEASE_CONFIRM,
+    SERVICE_ANNOUNCE,
+    SERVICE_QUERY,
+    STREAM_OPEN,
+    STREAM_DATA,
+    STREAM_ACK,
+    STREAM_CLOSE,
+    BYE,
+    PROBE,
+    PROBE_ACK,
+    FRAGMENT,
+    // Left to applications, see MessageHandler: 32 to 62
+    APPLICATION(u8),
     UNKNOWN,
 }
 
@@ -18,6 +48,32 @@ impl Header {
             Header::HELLO => 1,
             Header::PING => 2,
             Header::PONG => 4,
+            Header::LEASE_CONFIRM => 5,
+            Header::RELAY => 8,
+            Header::ROUTE => 9,
+            Header::FIND_NODE => 10,
+            Header::FIND_VALUE => 11,
+            Header::NODES => 12,
+            Header::VALUE => 13,
+            Header::STORE => 14,
+            Header::GOSSIP => 15,
+            Header::PING_REQ => 16,
+            Header::PING_ACK => 17,
+            Header::IPPACKET => 18,
+            Header::LEASE_REQUEST => 19,
+            Header::LEASE_OFFER => 20,
+            Header::LEASE_ACK => 21,
+            Header::SERVICE_ANNOUNCE => 22,
+            Header::SERVICE_QUERY => 23,
+            Header::STREAM_OPEN => 24,
+            Header::STREAM_DATA => 25,
+            Header::STREAM_ACK => 26,
+            Header::STREAM_CLOSE => 27,
+            Header::BYE => 28,
+            Header::PROBE => 29,
+            Header::PROBE_ACK => 30,
+            Header::FRAGMENT => 31,
+            Header::APPLICATION(id) => *id,
             Header::MULTIPLE => 63,
         }
     }
@@ -25,6 +81,32 @@ impl Header {
     pub fn from_byte(header_byte: u8) -> Header {
         return match header_byte {
             63 => Header::MULTIPLE,
+            32..=62 => Header::APPLICATION(header_byte),
+            31 => Header::FRAGMENT,
+            30 => Header::PROBE_ACK,
+            29 => Header::PROBE,
+            28 => Header::BYE,
+            27 => Header::STREAM_CLOSE,
+            26 => Header::STREAM_ACK,
+            25 => Header::STREAM_DATA,
+            24 => Header::STREAM_OPEN,
+            23 => Header::SERVICE_QUERY,
+            22 => Header::SERVICE_ANNOUNCE,
+            21 => Header::LEASE_ACK,
+            20 => Header::LEASE_OFFER,
+            19 => Header::LEASE_REQUEST,
+            18 => Header::IPPACKET,
+            17 => Header::PING_ACK,
+            16 => Header::PING_REQ,
+            15 => Header::GOSSIP,
+            14 => Header::STORE,
+            13 => Header::VALUE,
+            12 => Header::NODES,
+            11 => Header::FIND_VALUE,
+            10 => Header::FIND_NODE,
+            9 => Header::ROUTE,
+            8 => Header::RELAY,
+            5 => Header::LEASE_CONFIRM,
             4 => Header::PONG,
             2 => Header::PING,
             1 => Header::HELLO,
@@ -0,0 +1,194 @@
+use std::net::{IpAddr,Ipv4Addr,Ipv6Addr};
+
+use crate::network::peer::PeerId;
+
+// HELLO payload, a list of [tag][len][value] fields so it can grow without breaking old peers
+const TAG_ID: u8 = 1;
+const TAG_OVERLAY: u8 = 2;
+const TAG_NAME: u8 = 3;
+const TAG_KEY: u8 = 4;
+const TAG_NONCE: u8 = 5;
+const TAG_ECHO: u8 = 6;
+const TAG_PROOF: u8 = 7;
+
+// Handshake: each side sends a nonce along with its public key, the other side echoes it with its
+// signature of `proof_message`. A session is only established on a valid proof
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Hello {
+    id: PeerId,
+    overlay: Option<IpAddr>,
+    name: Option<String>,
+    key: Option<Vec<u8>>,
+    nonce: Option<u64>,
+    // The nonce of the peer we answer, and our signature
+    proof: Option<(u64,Vec<u8>)>,
+}
+
+// What is signed: the peer's nonce, bound to our id
+pub fn proof_message(nonce: u64, id: PeerId) -> Vec<u8> {
+    let mut message: Vec<u8> = nonce.to_be_bytes().to_vec();
+    message.extend_from_slice(&id.to_bytes());
+    message
+}
+
+impl Hello {
+    pub fn new(id: PeerId) -> Hello {
+        Hello { id, overlay: None, name: None, key: None, nonce: None, proof: None }
+    }
+
+    // Our public key in SSH wire format, and the nonce the peer has to sign
+    pub fn with_challenge(mut self, key: Vec<u8>, nonce: u64) -> Self {
+        self.key = Some(key);
+        self.nonce = Some(nonce);
+        self
+    }
+
+    pub fn with_proof(mut self, nonce: u64, signature: Vec<u8>) -> Self {
+        self.proof = Some( (nonce, signature) );
+        self
+    }
+
+    pub fn key(&self) -> Option<&[u8]> {
+        self.key.as_deref()
+    }
+
+    pub fn nonce(&self) -> Option<u64> {
+        self.nonce
+    }
+
+    pub fn proof(&self) -> Option<(u64, &[u8])> {
+        self.proof.as_ref().map( |(nonce, signature)| (*nonce, signature.as_slice()) )
+    }
+
+    // The node name, as resolved by the DNS responder
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some( name.to_string() );
+        self
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    // The overlay address leased to the sender
+    pub fn with_overlay(mut self, ip: IpAddr) -> Self {
+        self.overlay = Some(ip);
+        self
+    }
+
+    pub fn id(&self) -> PeerId {
+        self.id
+    }
+
+    pub fn overlay(&self) -> Option<IpAddr> {
+        self.overlay
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        push_field(&mut bytes, TAG_ID, &self.id.to_bytes());
+        match self.overlay {
+            None => {},
+            Some(IpAddr::V4(ip)) => { push_field(&mut bytes, TAG_OVERLAY, &ip.octets()); },
+            Some(IpAddr::V6(ip)) => { push_field(&mut bytes, TAG_OVERLAY, &ip.octets()); },
+        }
+        if let Some(name) = self.name.as_ref() {
+            push_field(&mut bytes, TAG_NAME, name.as_bytes());
+        }
+        if let (Some(key), Some(nonce)) = (self.key.as_ref(), self.nonce) {
+            push_field(&mut bytes, TAG_KEY, key);
+            push_field(&mut bytes, TAG_NONCE, &nonce.to_be_bytes());
+        }
+        if let Some((nonce, signature)) = self.proof.as_ref() {
+            push_field(&mut bytes, TAG_ECHO, &nonce.to_be_bytes());
+            push_field(&mut bytes, TAG_PROOF, signature);
+        }
+        bytes
+    }
+
+    // Unknown tags are skipped, a HELLO without id is not a Hello
+    pub fn from_bytes(bytes: &[u8]) -> Option<Hello> {
+        let mut id: Option<PeerId> = None;
+        let mut overlay: Option<IpAddr> = None;
+        let mut name: Option<String> = None;
+        let mut key: Option<Vec<u8>> = None;
+        let mut nonce: Option<u64> = None;
+        let mut echo: Option<u64> = None;
+        let mut signature: Option<Vec<u8>> = None;
+
+        let mut cursor: usize = 0;
+        while cursor + 2 <= bytes.len() {
+            let tag = bytes[cursor];
+            let len = bytes[cursor+1] as usize;
+            cursor += 2;
+
+            if cursor + len > bytes.len() {
+                return None;
+            }
+            let value = &bytes[cursor..(cursor+len)];
+            cursor += len;
+
+            match tag {
+                TAG_ID => { id = PeerId::from_bytes(value); },
+                TAG_OVERLAY => {
+                    overlay = match value.len() {
+                        4 => Some( IpAddr::V4( Ipv4Addr::new(value[0], value[1], value[2], value[3]) ) ),
+                        16 => {
+                            let mut raw: [u8; 16] = [0; 16];
+                            raw.copy_from_slice(value);
+                            Some( IpAddr::V6( Ipv6Addr::from(raw) ) )
+                        },
+                        _ => None,
+                    };
+                },
+                TAG_NAME => { name = String::from_utf8( Vec::from(value) ).ok(); },
+                TAG_KEY => { key = Some( Vec::from(value) ); },
+                TAG_NONCE => { nonce = value.try_into().ok().map( u64::from_be_bytes ); },
+                TAG_ECHO => { echo = value.try_into().ok().map( u64::from_be_bytes ); },
+                TAG_PROOF => { signature = Some( Vec::from(value) ); },
+                _ => {},
+            }
+        }
+
+        let (key, nonce) = match (key, nonce) {
+            (Some(key), Some(nonce)) => (Some(key), Some(nonce)),
+            _ => (None, None),
+        };
+        let proof: Option<(u64,Vec<u8>)> = echo.zip(signature);
+        id.map( |id| Hello { id, overlay, name, key, nonce, proof } )
+    }
+}
+
+fn push_field(bytes: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    bytes.push(tag);
+    bytes.push(value.len() as u8);
+    bytes.extend_from_slice(value);
+}
+
+#[test]
+fn test_hello_from_to() {
+    let hello = Hello::new( PeerId::new(42) );
+    let bytes = hello.to_bytes();
+    assert_eq!(bytes.len(),10);
+    assert_eq!(Some(hello),Hello::from_bytes(&bytes));
+
+    let hello = Hello::new( PeerId::new(42) ).with_overlay( "10.8.0.2".parse().unwrap() );
+    assert_eq!(Some(hello.clone()),Hello::from_bytes( &hello.to_bytes() ));
+    let hello = Hello::new( PeerId::new(42) ).with_overlay( "fd00::2".parse().unwrap() );
+    assert_eq!(Some(hello.clone()),Hello::from_bytes( &hello.to_bytes() ));
+    let hello = Hello::new( PeerId::new(42) ).with_name("alpha");
+    assert_eq!(Some("alpha"),Hello::from_bytes( &hello.to_bytes() ).as_ref().and_then( |h| h.name() ));
+    let hello = Hello::new( PeerId::new(42) ).with_challenge( vec![1; 51], 7 ).with_proof( 9, vec![2; 64] );
+    assert_eq!(Some(hello.clone()),Hello::from_bytes( &hello.to_bytes() ));
+}
+
+#[test]
+fn test_hello_unknown_tag_and_truncated() {
+    let mut bytes = vec![200, 2, 9, 9];
+    bytes.append( &mut Hello::new( PeerId::new(7) ).to_bytes() );
+    assert_eq!(Some(PeerId::new(7)),Hello::from_bytes(&bytes).map(|h| h.id()));
+
+    bytes.pop();
+    assert_eq!(None,Hello::from_bytes(&bytes));
+    assert_eq!(None,Hello::from_bytes(&[]));
+}
@@ -0,0 +1,122 @@
+use std::net::{IpAddr,Ipv4Addr,Ipv6Addr};
+
+use crate::message::tlv::TLV;
+use crate::message::header::Header;
+use crate::network::peer::PeerId;
+
+// DHCP-like: REQUEST without address -> OFFER -> REQUEST with the offered address -> ACK.
+// Renewing is a REQUEST with the address in use. CONFIRM asks the server whether a peer holds the
+// address it claims, the server sends it back when it does
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum LeaseMessage {
+    Request { id: PeerId, requested: Option<IpAddr> },
+    Offer { ip: IpAddr, prefix: u8, duration: u32 },
+    Ack { ip: IpAddr, prefix: u8, duration: u32 },
+    Confirm { id: PeerId, ip: IpAddr },
+}
+
+impl LeaseMessage {
+    pub fn header(&self) -> Header {
+        match self {
+            LeaseMessage::Request { .. } => Header::LEASE_REQUEST,
+            LeaseMessage::Offer { .. } => Header::LEASE_OFFER,
+            LeaseMessage::Ack { .. } => Header::LEASE_ACK,
+            LeaseMessage::Confirm { .. } => Header::LEASE_CONFIRM,
+        }
+    }
+
+    pub fn to_tlv(self) -> Option<TLV> {
+        let mut bytes: Vec<u8> = Vec::new();
+        match self {
+            LeaseMessage::Request { id, requested } => {
+                bytes.extend_from_slice(&id.to_bytes());
+                if let Some(ip) = requested {
+                    bytes.append(&mut ip_to_bytes(&ip));
+                }
+            },
+            LeaseMessage::Offer { ip, prefix, duration } | LeaseMessage::Ack { ip, prefix, duration } => {
+                bytes.append(&mut ip_to_bytes(&ip));
+                bytes.push(prefix);
+                bytes.extend_from_slice(&duration.to_be_bytes());
+            },
+            LeaseMessage::Confirm { id, ip } => {
+                bytes.extend_from_slice(&id.to_bytes());
+                bytes.append(&mut ip_to_bytes(&ip));
+            },
+        }
+        TLV::new( self.header(), Some(bytes) )
+    }
+
+    pub fn from_tlv(tlv: &TLV) -> Option<LeaseMessage> {
+        let bytes: Vec<u8> = tlv.payload();
+        match tlv.header() {
+            Header::LEASE_REQUEST => {
+                let id = PeerId::from_bytes(&bytes)?;
+                let requested = match bytes.len() {
+                    8 => None,
+                    _ => Some( ip_from_bytes(&bytes[8..])?.0 ),
+                };
+                Some( LeaseMessage::Request { id, requested } )
+            },
+            Header::LEASE_OFFER | Header::LEASE_ACK => {
+                let (ip, used) = ip_from_bytes(&bytes)?;
+                let prefix = *bytes.get(used)?;
+                let raw = bytes.get((used+1)..(used+5))?;
+                let duration = u32::from_be_bytes([raw[0],raw[1],raw[2],raw[3]]);
+                match tlv.header() {
+                    Header::LEASE_OFFER => Some( LeaseMessage::Offer { ip, prefix, duration } ),
+                    _ => Some( LeaseMessage::Ack { ip, prefix, duration } ),
+                }
+            },
+            Header::LEASE_CONFIRM => {
+                let id = PeerId::from_bytes(&bytes)?;
+                Some( LeaseMessage::Confirm { id, ip: ip_from_bytes(&bytes[8..])?.0 } )
+            },
+            _ => None,
+        }
+    }
+}
+
+// [4|6][address]
+pub fn ip_to_bytes(ip: &IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => { let mut bytes = vec![4]; bytes.extend_from_slice(&ip.octets()); bytes },
+        IpAddr::V6(ip) => { let mut bytes = vec![6]; bytes.extend_from_slice(&ip.octets()); bytes },
+    }
+}
+
+pub fn ip_from_bytes(bytes: &[u8]) -> Option<(IpAddr, usize)> {
+    match bytes.first()? {
+        4 => {
+            let raw = bytes.get(1..5)?;
+            Some( (IpAddr::V4( Ipv4Addr::new(raw[0], raw[1], raw[2], raw[3]) ), 5) )
+        },
+        6 => {
+            let mut raw: [u8; 16] = [0; 16];
+            raw.copy_from_slice( bytes.get(1..17)? );
+            Some( (IpAddr::V6( Ipv6Addr::from(raw) ), 17) )
+        },
+        _ => None,
+    }
+}
+
+#[test]
+fn test_lease_message_from_to() {
+    let ip: IpAddr = "10.8.0.2".parse().unwrap();
+    let messages = [
+        LeaseMessage::Request { id: PeerId::new(1), requested: None },
+        LeaseMessage::Request { id: PeerId::new(1), requested: Some( "fd00::2".parse().unwrap() ) },
+        LeaseMessage::Offer { ip, prefix: 24, duration: 3600 },
+        LeaseMessage::Ack { ip, prefix: 24, duration: 3600 },
+        LeaseMessage::Confirm { id: PeerId::new(1), ip },
+    ];
+    for message in messages.iter() {
+        let tlv = message.to_tlv().unwrap();
+        assert_eq!(tlv.header(),message.header());
+        assert_eq!(Some(*message),LeaseMessage::from_tlv(&tlv));
+    }
+
+    let mut payload = messages[3].to_tlv().unwrap().payload();
+    payload.pop();
+    assert_eq!(None,LeaseMessage::from_tlv( &TLV::new(Header::LEASE_ACK, Some(payload)).unwrap() ));
+}
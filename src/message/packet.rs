@@ -0,0 +1,73 @@
+use std::net::{IpAddr,Ipv4Addr,Ipv6Addr};
+
+use crate::message::tlv::TLV;
+use crate::message::header::Header;
+
+// IPPACKET carries one raw IPv4/IPv6 packet, nothing else: addresses are read from the packet
+pub fn packet_to_tlv(packet: &[u8]) -> Option<TLV> {
+    ip_destination(packet)?;
+    TLV::new( Header::IPPACKET, Some( Vec::from(packet) ) )
+}
+
+pub fn ip_version(packet: &[u8]) -> Option<u8> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => Some(4),
+        6 if packet.len() >= 40 => Some(6),
+        _ => None,
+    }
+}
+
+pub fn ip_source(packet: &[u8]) -> Option<IpAddr> {
+    match ip_version(packet)? {
+        4 => Some( IpAddr::V4( ipv4(&packet[12..16]) ) ),
+        _ => Some( IpAddr::V6( ipv6(&packet[8..24]) ) ),
+    }
+}
+
+pub fn ip_destination(packet: &[u8]) -> Option<IpAddr> {
+    match ip_version(packet)? {
+        4 => Some( IpAddr::V4( ipv4(&packet[16..20]) ) ),
+        _ => Some( IpAddr::V6( ipv6(&packet[24..40]) ) ),
+    }
+}
+
+fn ipv4(bytes: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])
+}
+
+fn ipv6(bytes: &[u8]) -> Ipv6Addr {
+    let mut raw: [u8; 16] = [0; 16];
+    raw.copy_from_slice(bytes);
+    Ipv6Addr::from(raw)
+}
+
+// For test: a minimal IPv4/UDP packet, checksums left empty
+pub fn ipv4_packet(src: Ipv4Addr, dst: Ipv4Addr, payload: &[u8]) -> Vec<u8> {
+    let len: u16 = (20 + payload.len()) as u16;
+    let mut packet: Vec<u8> = vec![0x45, 0];
+    packet.extend_from_slice(&len.to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0, 64, 17, 0, 0]);
+    packet.extend_from_slice(&src.octets());
+    packet.extend_from_slice(&dst.octets());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+#[test]
+fn test_ip_addresses() {
+    let packet = ipv4_packet( Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2), &[1, 2, 3] );
+    assert_eq!(Some(4),ip_version(&packet));
+    assert_eq!(Some( IpAddr::V4( Ipv4Addr::new(10, 0, 0, 1) ) ),ip_source(&packet));
+    assert_eq!(Some( IpAddr::V4( Ipv4Addr::new(10, 0, 0, 2) ) ),ip_destination(&packet));
+
+    let mut packet6: Vec<u8> = vec![0x60, 0, 0, 0, 0, 0, 17, 64];
+    packet6.extend_from_slice( &Ipv6Addr::LOCALHOST.octets() );
+    packet6.extend_from_slice( &"fd00::2".parse::<Ipv6Addr>().unwrap().octets() );
+    assert_eq!(Some( IpAddr::V6( "fd00::2".parse().unwrap() ) ),ip_destination(&packet6));
+
+    // truncated or not IP at all
+    assert_eq!(None,ip_destination(&packet[0..19]));
+    assert_eq!(None,ip_destination(&[0x15; 40]));
+    assert_eq!(None,packet_to_tlv(&[]));
+    assert_eq!(Header::IPPACKET,packet_to_tlv(&packet).unwrap().header());
+}
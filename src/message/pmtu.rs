@@ -0,0 +1,109 @@
+use crate::message::tlv::TLV;
+use crate::message::header::Header;
+
+// PROBE is padded to the datagram size being tried, PROBE_ACK only tells the size back.
+// FRAGMENT carries a slice of an encoded TLV too large for the path
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum PmtuMessage {
+    Probe { size: u16 },
+    Ack { size: u16 },
+    Fragment { id: u16, index: u8, count: u8, bytes: Vec<u8> },
+}
+
+// [id][index][count]
+pub const FRAGMENT_OVERHEAD: usize = 4;
+
+impl PmtuMessage {
+    pub fn header(&self) -> Header {
+        match self {
+            PmtuMessage::Probe { .. } => Header::PROBE,
+            PmtuMessage::Ack { .. } => Header::PROBE_ACK,
+            PmtuMessage::Fragment { .. } => Header::FRAGMENT,
+        }
+    }
+
+    pub fn to_tlv(&self) -> Option<TLV> {
+        let mut bytes: Vec<u8> = Vec::new();
+        match self {
+            PmtuMessage::Probe { size } => {
+                // the whole datagram, TLV header included, is `size` bytes long
+                if (*size as usize) < 4 {
+                    return None;
+                }
+                bytes.extend_from_slice(&size.to_be_bytes());
+                bytes.resize(*size as usize - 2, 0);
+            },
+            PmtuMessage::Ack { size } => {
+                bytes.extend_from_slice(&size.to_be_bytes());
+            },
+            PmtuMessage::Fragment { id, index, count, bytes: part } => {
+                bytes.extend_from_slice(&id.to_be_bytes());
+                bytes.push(*index);
+                bytes.push(*count);
+                bytes.extend_from_slice(part);
+            },
+        }
+        TLV::new( self.header(), Some(bytes) )
+    }
+
+    pub fn from_tlv(tlv: &TLV) -> Option<PmtuMessage> {
+        let bytes: Vec<u8> = tlv.payload();
+        let raw = bytes.get(0..2)?;
+        let first: u16 = u16::from_be_bytes([raw[0],raw[1]]);
+        match tlv.header() {
+            // a probe cut on its way is no evidence for its size
+            Header::PROBE if bytes.len() + 2 == first as usize => Some( PmtuMessage::Probe { size: first } ),
+            Header::PROBE_ACK => Some( PmtuMessage::Ack { size: first } ),
+            Header::FRAGMENT => {
+                let index = *bytes.get(2)?;
+                let count = *bytes.get(3)?;
+                if index >= count {
+                    return None;
+                }
+                Some( PmtuMessage::Fragment { id: first, index, count, bytes: Vec::from( &bytes[FRAGMENT_OVERHEAD..] ) } )
+            },
+            _ => None,
+        }
+    }
+}
+
+// The FRAGMENTs carrying `tlv` within datagrams of `mtu` bytes, None when it already fits
+pub fn fragment(tlv: &TLV, id: u16, mtu: usize) -> Option<Vec<TLV>> {
+    let bytes: Vec<u8> = Vec::from( tlv.to_bytes() );
+    let room: usize = mtu.checked_sub(2 + FRAGMENT_OVERHEAD)?;
+    if bytes.len() <= mtu || room == 0 || bytes.len().div_ceil(room) > u8::MAX as usize {
+        return None;
+    }
+
+    let count: u8 = bytes.len().div_ceil(room) as u8;
+    bytes.chunks(room).enumerate()
+        .map( |(index, part)| PmtuMessage::Fragment { id, index: index as u8, count, bytes: Vec::from(part) }.to_tlv() )
+        .collect()
+}
+
+#[test]
+fn test_pmtu_from_to() {
+    let probe = PmtuMessage::Probe { size: 1026 }.to_tlv().unwrap();
+    assert_eq!(probe.to_bytes().len(),1026);
+    assert_eq!(Some( PmtuMessage::Probe { size: 1026 } ),PmtuMessage::from_tlv(&probe));
+    let small = PmtuMessage::Probe { size: 600 }.to_tlv().unwrap();
+    assert_eq!(small.to_bytes().len(),600);
+
+    // truncated on the way
+    let mut cut = small.payload();
+    cut.pop();
+    assert_eq!(None,PmtuMessage::from_tlv( &TLV::new( Header::PROBE, Some(cut) ).unwrap() ));
+
+    let ack = PmtuMessage::Ack { size: 600 };
+    assert_eq!(Some( ack.clone() ),PmtuMessage::from_tlv( &ack.to_tlv().unwrap() ));
+
+    let big = TLV::new( Header::UNKNOWN, Some( vec![7; 1000] ) ).unwrap();
+    assert_eq!(None,fragment(&big, 1, 1026));
+    let parts = fragment(&big, 9, 508).unwrap();
+    assert_eq!(parts.len(),2);
+    assert!(parts.iter().all( |part| part.to_bytes().len() <= 508 ));
+    match PmtuMessage::from_tlv(&parts[1]) {
+        Some( PmtuMessage::Fragment { id, index, count, bytes } ) => { assert_eq!((id, index, count, bytes.len()),(9, 1, 2, 1002 - 502)); },
+        _ => { panic!("not a fragment"); },
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::VecDeque;
+
+use crate::message::tlv::TLV;
+use crate::message::header::Header;
+use crate::network::peer::PeerId;
+
+pub const RELAY_HOP_LIMIT: u8 = 8;
+
+// [hops][path len][src][dst][path ...][inner TLV bytes]
+const RELAY_OVERHEAD: usize = 2 + 8 + 8;
+
+// A relay only reads the envelope: the inner TLV is carried as opaque bytes and is
+// never parsed on the way, whatever protects it end to end stays out of reach
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Relay {
+    hops: u8,
+    src: PeerId,
+    dst: PeerId,
+    path: Vec<PeerId>,
+    inner: Vec<u8>,
+}
+
+impl Relay {
+    pub fn new(src: PeerId, dst: PeerId, inner: &TLV) -> Relay {
+        Relay { hops: RELAY_HOP_LIMIT, src, dst, path: Vec::new(), inner: Vec::from( inner.to_bytes() ) }
+    }
+
+    pub fn hops(&self) -> u8 {
+        self.hops
+    }
+
+    pub fn src(&self) -> PeerId {
+        self.src
+    }
+
+    pub fn dst(&self) -> PeerId {
+        self.dst
+    }
+
+    pub fn path(&self) -> Vec<PeerId> {
+        self.path.clone()
+    }
+
+    // Only meaningful for the final destination
+    pub fn inner(&self) -> Option<TLV> {
+        TLV::from_bytes( VecDeque::from( self.inner.clone() ) )
+    }
+
+    pub fn inner_len(&self) -> usize {
+        self.inner.len()
+    }
+
+    // Record `via` on the path, refuse if the hop limit is reached or if we already went through it
+    pub fn forward(&mut self, via: PeerId) -> bool {
+        if self.hops == 0 || via == self.src || self.path.contains(&via) {
+            return false;
+        }
+        self.hops -= 1;
+        self.path.push(via);
+        true
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(RELAY_OVERHEAD + 8*self.path.len() + self.inner.len());
+        bytes.push(self.hops);
+        bytes.push(self.path.len() as u8);
+        bytes.extend_from_slice(&self.src.to_bytes());
+        bytes.extend_from_slice(&self.dst.to_bytes());
+        for via in self.path.iter() {
+            bytes.extend_from_slice(&via.to_bytes());
+        }
+        bytes.extend_from_slice(&self.inner);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Relay> {
+        if bytes.len() < RELAY_OVERHEAD {
+            return None;
+        }
+
+        let hops = bytes[0];
+        let path_len = bytes[1] as usize;
+        let src = PeerId::from_bytes(&bytes[2..10])?;
+        let dst = PeerId::from_bytes(&bytes[10..18])?;
+
+        let mut cursor: usize = RELAY_OVERHEAD;
+        if cursor + 8*path_len > bytes.len() {
+            return None;
+        }
+
+        let mut path: Vec<PeerId> = Vec::with_capacity(path_len);
+        for _ in 0..path_len {
+            path.push( PeerId::from_bytes(&bytes[cursor..(cursor+8)])? );
+            cursor += 8;
+        }
+
+        Some( Relay { hops, src, dst, path, inner: bytes[cursor..].to_vec() } )
+    }
+
+    pub fn to_tlv(&self) -> Option<TLV> {
+        TLV::new( Header::RELAY, Some( self.to_bytes() ) )
+    }
+}
+
+#[test]
+fn test_relay_from_to() {
+    let inner = TLV::new(Header::PING, Some(vec![1,2,3])).unwrap();
+    let mut relay = Relay::new( PeerId::new(1), PeerId::new(3), &inner );
+    assert!( relay.forward( PeerId::new(2) ) );
+
+    let tlv = relay.to_tlv().unwrap();
+    assert_eq!(tlv.header(),Header::RELAY);
+
+    let decoded = Relay::from_bytes( &tlv.payload() ).unwrap();
+    assert_eq!(decoded,relay);
+    assert_eq!(decoded.hops(),RELAY_HOP_LIMIT - 1);
+    assert_eq!(decoded.path(),vec![PeerId::new(2)]);
+    assert_eq!(decoded.inner(),Some(inner));
+}
+
+#[test]
+fn test_relay_truncated() {
+    let inner = TLV::new(Header::PING, None).unwrap();
+    let mut relay = Relay::new( PeerId::new(1), PeerId::new(3), &inner );
+    relay.forward( PeerId::new(2) );
+    let bytes = relay.to_bytes();
+
+    assert_eq!(None,Relay::from_bytes(&bytes[0..17]));
+    // path announced but missing
+    assert_eq!(None,Relay::from_bytes(&bytes[0..20]));
+}
+
+#[test]
+fn test_relay_loop_and_hop_limit() {
+    let inner = TLV::new(Header::PING, None).unwrap();
+    let mut relay = Relay::new( PeerId::new(1), PeerId::new(100), &inner );
+
+    // back to the source or twice through the same relay
+    assert!( !relay.forward( PeerId::new(1) ) );
+    assert!( relay.forward( PeerId::new(2) ) );
+    assert!( !relay.forward( PeerId::new(2) ) );
+
+    for i in 3..(RELAY_HOP_LIMIT as u64 + 2) {
+        assert!( relay.forward( PeerId::new(i) ) );
+    }
+    assert_eq!(relay.hops(),0);
+    assert!( !relay.forward( PeerId::new(50) ) );
+}
@@ -0,0 +1,130 @@
+use crate::message::tlv::TLV;
+use crate::message::header::Header;
+use crate::network::peer::PeerId;
+
+// [dst][metric][seqno]
+const ENTRY_LEN: usize = 8 + 1 + 4;
+const MAX_ENTRIES: usize = 1024 / ENTRY_LEN;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct RouteEntry {
+    dst: PeerId,
+    metric: u8,
+    seqno: u32,
+}
+
+impl RouteEntry {
+    pub fn new(dst: PeerId, metric: u8, seqno: u32) -> RouteEntry {
+        RouteEntry { dst, metric, seqno }
+    }
+
+    pub fn dst(&self) -> PeerId {
+        self.dst
+    }
+
+    pub fn metric(&self) -> u8 {
+        self.metric
+    }
+
+    pub fn seqno(&self) -> u32 {
+        self.seqno
+    }
+}
+
+// ROUTE payload: the sender first (metric 0), then every destination it can reach
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Advertisement {
+    entries: Vec<RouteEntry>,
+}
+
+impl Advertisement {
+    pub fn new(entries: Vec<RouteEntry>) -> Advertisement {
+        Advertisement { entries }
+    }
+
+    pub fn entries(&self) -> Vec<RouteEntry> {
+        self.entries.clone()
+    }
+
+    pub fn origin(&self) -> Option<PeerId> {
+        match self.entries.first() {
+            Some(entry) if entry.metric() == 0 => Some( entry.dst() ),
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(ENTRY_LEN * self.entries.len());
+        for entry in self.entries.iter() {
+            bytes.extend_from_slice(&entry.dst.to_bytes());
+            bytes.push(entry.metric);
+            bytes.extend_from_slice(&entry.seqno.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Advertisement> {
+        if !bytes.len().is_multiple_of(ENTRY_LEN) {
+            return None;
+        }
+
+        let mut entries: Vec<RouteEntry> = Vec::with_capacity(bytes.len() / ENTRY_LEN);
+        for chunk in bytes.chunks(ENTRY_LEN) {
+            let dst = PeerId::from_bytes(&chunk[0..8])?;
+            let metric = chunk[8];
+            let seqno = u32::from_be_bytes([chunk[9],chunk[10],chunk[11],chunk[12]]);
+            entries.push( RouteEntry { dst, metric, seqno } );
+        }
+        Some( Advertisement { entries } )
+    }
+
+    // A big table does not fit in one TLV, every chunk starts with the origin again
+    pub fn to_tlvs(&self) -> Vec<TLV> {
+        let mut tlvs: Vec<TLV> = Vec::new();
+        let (origin, others) = match self.entries.split_first() {
+            None => { return tlvs; },
+            Some((origin, others)) => (origin, others),
+        };
+
+        let mut chunks = others.chunks(MAX_ENTRIES - 1).peekable();
+        if chunks.peek().is_none() {
+            tlvs.push( TLV::new( Header::ROUTE, Some( Advertisement::new( vec![*origin] ).to_bytes() ) ).unwrap() );
+        }
+        for chunk in chunks {
+            let mut entries: Vec<RouteEntry> = vec![*origin];
+            entries.extend_from_slice(chunk);
+            tlvs.push( TLV::new( Header::ROUTE, Some( Advertisement::new(entries).to_bytes() ) ).unwrap() );
+        }
+        tlvs
+    }
+}
+
+#[test]
+fn test_advertisement_from_to() {
+    let adv = Advertisement::new( vec![
+        RouteEntry::new( PeerId::new(1), 0, 10 ),
+        RouteEntry::new( PeerId::new(2), 3, 7 ),
+    ] );
+
+    let bytes = adv.to_bytes();
+    assert_eq!(bytes.len(),2*ENTRY_LEN);
+    assert_eq!(Some(adv.clone()),Advertisement::from_bytes(&bytes));
+    assert_eq!(Some(PeerId::new(1)),adv.origin());
+    assert_eq!(None,Advertisement::from_bytes(&bytes[1..]));
+}
+
+#[test]
+fn test_advertisement_to_tlvs() {
+    let mut entries = vec![ RouteEntry::new( PeerId::new(0), 0, 2 ) ];
+    for i in 1..200 {
+        entries.push( RouteEntry::new( PeerId::new(i), 1, 2 ) );
+    }
+
+    let tlvs = Advertisement::new(entries).to_tlvs();
+    assert_eq!(tlvs.len(),3);
+    for tlv in tlvs.iter() {
+        let adv = Advertisement::from_bytes( &tlv.payload() ).unwrap();
+        assert_eq!(Some(PeerId::new(0)),adv.origin());
+    }
+    assert_eq!(1,Advertisement::new( vec![ RouteEntry::new( PeerId::new(0), 0, 2 ) ] ).to_tlvs().len());
+}
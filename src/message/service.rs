@@ -0,0 +1,97 @@
+use crate::message::tlv::TLV;
+use crate::message::header::Header;
+use crate::network::host::Host;
+use crate::network::service::Service;
+
+// ANNOUNCE: [ttl][name_len][name][port][0 | server host], a service without server runs on the announcer
+// QUERY: [name_len][name]
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum ServiceMessage {
+    Announce { ttl: u32, service: Service },
+    Query { name: String },
+}
+
+impl ServiceMessage {
+    pub fn header(&self) -> Header {
+        match self {
+            ServiceMessage::Announce { .. } => Header::SERVICE_ANNOUNCE,
+            ServiceMessage::Query { .. } => Header::SERVICE_QUERY,
+        }
+    }
+
+    pub fn to_tlv(&self) -> Option<TLV> {
+        let mut bytes: Vec<u8> = Vec::new();
+        match self {
+            ServiceMessage::Announce { ttl, service } => {
+                bytes.extend_from_slice(&ttl.to_be_bytes());
+                bytes.append(&mut name_to_bytes( service.name() )?);
+                bytes.extend_from_slice(&service.port().to_be_bytes());
+                match service.server() {
+                    None => bytes.push(0),
+                    Some(server) => bytes.append(&mut server.to_bytes()),
+                }
+            },
+            ServiceMessage::Query { name } => {
+                bytes.append(&mut name_to_bytes(name)?);
+            },
+        }
+        TLV::new( self.header(), Some(bytes) )
+    }
+
+    pub fn from_tlv(tlv: &TLV) -> Option<ServiceMessage> {
+        let bytes: Vec<u8> = tlv.payload();
+        match tlv.header() {
+            Header::SERVICE_ANNOUNCE => {
+                let raw = bytes.get(0..4)?;
+                let ttl = u32::from_be_bytes([raw[0],raw[1],raw[2],raw[3]]);
+                let (name, used) = name_from_bytes(&bytes[4..])?;
+                let cursor: usize = 4 + used;
+                let raw = bytes.get(cursor..(cursor+2))?;
+                let port = u16::from_be_bytes([raw[0],raw[1]]);
+                let server: Option<Host> = match bytes.get(cursor+2)? {
+                    0 => None,
+                    _ => Some( Host::from_bytes(&bytes[(cursor+2)..])?.0 ),
+                };
+                Some( ServiceMessage::Announce { ttl, service: Service::new(name, port, server) } )
+            },
+            Header::SERVICE_QUERY => {
+                let (name, _) = name_from_bytes(&bytes)?;
+                Some( ServiceMessage::Query { name } )
+            },
+            _ => None,
+        }
+    }
+}
+
+// Names are at most 255 bytes of UTF-8
+fn name_to_bytes(name: &str) -> Option<Vec<u8>> {
+    let len: u8 = u8::try_from( name.len() ).ok()?;
+    let mut bytes: Vec<u8> = vec![len];
+    bytes.extend_from_slice( name.as_bytes() );
+    Some(bytes)
+}
+
+fn name_from_bytes(bytes: &[u8]) -> Option<(String, usize)> {
+    let len: usize = *bytes.first()? as usize;
+    let name = String::from_utf8( Vec::from( bytes.get(1..(1+len))? ) ).ok()?;
+    Some( (name, 1 + len) )
+}
+
+#[test]
+fn test_service_message_from_to() {
+    let messages = [
+        ServiceMessage::Announce { ttl: 60, service: Service::new("web".to_string(), 80, None) },
+        ServiceMessage::Announce { ttl: 0, service: Service::new("db".to_string(), 5432, Some( Host::new("[fd00::1]:5432") )) },
+        ServiceMessage::Query { name: "web".to_string() },
+    ];
+    for message in messages.iter() {
+        let tlv = message.to_tlv().unwrap();
+        assert_eq!(tlv.header(),message.header());
+        assert_eq!(Some(message.clone()),ServiceMessage::from_tlv(&tlv));
+    }
+
+    assert_eq!(None,ServiceMessage::Query { name: "x".repeat(256) }.to_tlv());
+    let mut payload = messages[1].to_tlv().unwrap().payload();
+    payload.pop();
+    assert_eq!(None,ServiceMessage::from_tlv( &TLV::new(Header::SERVICE_ANNOUNCE, Some(payload)).unwrap() ));
+}
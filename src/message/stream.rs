@@ -0,0 +1,100 @@
+use crate::message::tlv::TLV;
+use crate::message::header::Header;
+
+// Every message starts with the stream ID, OPEN and CLOSE take a sequence number like DATA
+// so they are retransmitted and delivered in order with it.
+// OPEN: [stream][seq][port], DATA: [stream][seq][bytes], ACK: [stream][next][window], CLOSE: [stream][seq]
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum StreamMessage {
+    Open { stream: u32, seq: u32, port: u16 },
+    Data { stream: u32, seq: u32, bytes: Vec<u8> },
+    Ack { stream: u32, next: u32, window: u16 },
+    Close { stream: u32, seq: u32 },
+}
+
+impl StreamMessage {
+    pub fn header(&self) -> Header {
+        match self {
+            StreamMessage::Open { .. } => Header::STREAM_OPEN,
+            StreamMessage::Data { .. } => Header::STREAM_DATA,
+            StreamMessage::Ack { .. } => Header::STREAM_ACK,
+            StreamMessage::Close { .. } => Header::STREAM_CLOSE,
+        }
+    }
+
+    pub fn stream(&self) -> u32 {
+        match self {
+            StreamMessage::Open { stream, .. } | StreamMessage::Data { stream, .. } | StreamMessage::Ack { stream, .. } | StreamMessage::Close { stream, .. } => *stream,
+        }
+    }
+
+    // None for ACKs, they are never retransmitted
+    pub fn seq(&self) -> Option<u32> {
+        match self {
+            StreamMessage::Open { seq, .. } | StreamMessage::Data { seq, .. } | StreamMessage::Close { seq, .. } => Some(*seq),
+            StreamMessage::Ack { .. } => None,
+        }
+    }
+
+    pub fn to_tlv(&self) -> Option<TLV> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend_from_slice(&self.stream().to_be_bytes());
+        match self {
+            StreamMessage::Open { seq, port, .. } => {
+                bytes.extend_from_slice(&seq.to_be_bytes());
+                bytes.extend_from_slice(&port.to_be_bytes());
+            },
+            StreamMessage::Data { seq, bytes: data, .. } => {
+                bytes.extend_from_slice(&seq.to_be_bytes());
+                bytes.extend_from_slice(data);
+            },
+            StreamMessage::Ack { next, window, .. } => {
+                bytes.extend_from_slice(&next.to_be_bytes());
+                bytes.extend_from_slice(&window.to_be_bytes());
+            },
+            StreamMessage::Close { seq, .. } => {
+                bytes.extend_from_slice(&seq.to_be_bytes());
+            },
+        }
+        TLV::new( self.header(), Some(bytes) )
+    }
+
+    pub fn from_tlv(tlv: &TLV) -> Option<StreamMessage> {
+        let bytes: Vec<u8> = tlv.payload();
+        let stream: u32 = read_u32(&bytes, 0)?;
+        let value: u32 = read_u32(&bytes, 4)?;
+        let rest: &[u8] = &bytes[8..];
+        match tlv.header() {
+            Header::STREAM_OPEN if rest.len() == 2 => Some( StreamMessage::Open { stream, seq: value, port: u16::from_be_bytes([rest[0],rest[1]]) } ),
+            Header::STREAM_DATA => Some( StreamMessage::Data { stream, seq: value, bytes: Vec::from(rest) } ),
+            Header::STREAM_ACK if rest.len() == 2 => Some( StreamMessage::Ack { stream, next: value, window: u16::from_be_bytes([rest[0],rest[1]]) } ),
+            Header::STREAM_CLOSE if rest.is_empty() => Some( StreamMessage::Close { stream, seq: value } ),
+            _ => None,
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    let raw = bytes.get(at..(at+4))?;
+    Some( u32::from_be_bytes([raw[0],raw[1],raw[2],raw[3]]) )
+}
+
+#[test]
+fn test_stream_message_from_to() {
+    let messages = [
+        StreamMessage::Open { stream: 1, seq: 0, port: 22 },
+        StreamMessage::Data { stream: 1, seq: 1, bytes: vec![1, 2, 3] },
+        StreamMessage::Data { stream: 1, seq: 2, bytes: Vec::new() },
+        StreamMessage::Ack { stream: 0x8000_0001, next: 3, window: 16 },
+        StreamMessage::Close { stream: 1, seq: 3 },
+    ];
+    for message in messages.iter() {
+        let tlv = message.to_tlv().unwrap();
+        assert_eq!(tlv.header(),message.header());
+        assert_eq!(Some(message.clone()),StreamMessage::from_tlv(&tlv));
+    }
+
+    assert_eq!(None,messages[3].seq());
+    assert_eq!(None,StreamMessage::from_tlv( &TLV::new(Header::STREAM_ACK, Some( vec![0; 9] )).unwrap() ));
+    assert_eq!(None,StreamMessage::from_tlv( &TLV::new(Header::STREAM_CLOSE, Some( vec![0; 7] )).unwrap() ));
+}
@@ -2,3 +2,20 @@ pub mod udp;
 pub mod host;
 pub mod network;
 pub mod service;
+pub mod peer;
+pub mod relay;
+pub mod routing;
+pub mod dht;
+pub mod membership;
+pub mod link;
+pub mod pacing;
+pub mod transport;
+pub mod simulated;
+pub mod packet_io;
+pub mod lease;
+pub mod stream;
+pub mod dns;
+pub mod pmtu;
+pub mod batch;
+pub mod session;
+pub mod middleware;
@@ -0,0 +1,413 @@
+use std::sync::Mutex;
+
+#[cfg(target_os = "linux")]
+use std::net::{SocketAddr,SocketAddrV6,Ipv4Addr,Ipv6Addr};
+#[cfg(target_os = "linux")]
+use std::os::fd::RawFd;
+#[cfg(target_os = "linux")]
+use std::sync::OnceLock;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool,Ordering};
+
+use crate::network::host::Host;
+
+// Messages moved by one system call at most. Sent with GSO, one message carries several datagrams
+// of the same size to the same host, the kernel cuts them apart. GRO is not used: the kernel would
+// hand over up to 64 KiB at once, and every receive buffer would have to be that large for
+// datagrams of BUFFER_SIZE at most
+pub const BATCH_SIZE: usize = 32;
+// What one GSO message carries at most: the kernel's own limits on segments and UDP payload
+#[cfg(target_os = "linux")]
+const GSO_SEGMENTS: usize = 64;
+#[cfg(target_os = "linux")]
+const GSO_BYTES: usize = 65507;
+// The largest datagram a TLV makes
+pub const BUFFER_SIZE: usize = 1026;
+// Buffers kept for later, the others are freed
+const POOL_SIZE: usize = 4 * BATCH_SIZE;
+
+// Receive buffers are given back after use instead of allocated for every datagram
+#[derive(Debug,Default)]
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    pub fn new() -> BufferPool {
+        BufferPool { free: Mutex::new( Vec::new() ) }
+    }
+
+    pub fn take(&self) -> Vec<u8> {
+        match self.free.lock().unwrap().pop() {
+            None => vec![0; BUFFER_SIZE],
+            Some(buf) => buf,
+        }
+    }
+
+    pub fn give(&self, mut buf: Vec<u8>) {
+        let mut free = self.free.lock().unwrap();
+        if free.len() < POOL_SIZE {
+            buf.resize(BUFFER_SIZE, 0);
+            free.push(buf);
+        }
+    }
+
+    pub fn available(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // SAFETY: all-zero is a valid sockaddr_storage, and it is large enough for both families
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len: usize = match addr {
+        SocketAddr::V4(v4) => {
+            let sin = unsafe { &mut *( &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in ) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = v4.port().to_be();
+            sin.sin_addr = libc::in_addr { s_addr: u32::from_ne_bytes( v4.ip().octets() ) };
+            std::mem::size_of::<libc::sockaddr_in>()
+        },
+        SocketAddr::V6(v6) => {
+            let sin6 = unsafe { &mut *( &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6 ) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = v6.port().to_be();
+            sin6.sin6_flowinfo = v6.flowinfo();
+            sin6.sin6_addr = libc::in6_addr { s6_addr: v6.ip().octets() };
+            sin6.sin6_scope_id = v6.scope_id();
+            std::mem::size_of::<libc::sockaddr_in6>()
+        },
+    };
+    (storage, len as libc::socklen_t)
+}
+
+#[cfg(target_os = "linux")]
+fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    // SAFETY: the family tells which structure the kernel wrote
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*( storage as *const libc::sockaddr_storage as *const libc::sockaddr_in ) };
+            Some( SocketAddr::from( (Ipv4Addr::from( sin.sin_addr.s_addr.to_ne_bytes() ), u16::from_be(sin.sin_port)) ) )
+        },
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*( storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6 ) };
+            Some( SocketAddr::V6( SocketAddrV6::new( Ipv6Addr::from(sin6.sin6_addr.s6_addr), u16::from_be(sin6.sin6_port), sin6.sin6_flowinfo, sin6.sin6_scope_id ) ) )
+        },
+        _ => None,
+    }
+}
+
+// Whether the kernel takes UDP_SEGMENT: asked once, given up for good if a GSO send is refused
+#[cfg(target_os = "linux")]
+static GSO: OnceLock<bool> = OnceLock::new();
+#[cfg(target_os = "linux")]
+static GSO_REFUSED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(target_os = "linux")]
+fn gso(fd: RawFd) -> bool {
+    let supported: bool = *GSO.get_or_init( || {
+        let mut size: libc::c_int = 0;
+        let mut len: libc::socklen_t = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: `size` and `len` outlive the call and match what the option returns
+        unsafe { libc::getsockopt( fd, libc::SOL_UDP, libc::UDP_SEGMENT, &mut size as *mut libc::c_int as *mut libc::c_void, &mut len ) == 0 }
+    } );
+    supported && !GSO_REFUSED.load(Ordering::Relaxed)
+}
+
+// How many of `packets` each message carries, for BATCH_SIZE messages at most: consecutive
+// datagrams to the same host and of the same size go together, the last one may be shorter
+#[cfg(target_os = "linux")]
+fn segments(packets: &[(Vec<u8>,Host)], gso: bool) -> Vec<usize> {
+    let mut groups: Vec<usize> = Vec::new();
+    let mut next: usize = 0;
+    while next < packets.len() && groups.len() < BATCH_SIZE {
+        let (first, dst) = (&packets[next].0, packets[next].1);
+        let most: usize = match gso && !first.is_empty() {
+            true => GSO_SEGMENTS.min( GSO_BYTES / first.len() ),
+            false => 1,
+        };
+        let mut count: usize = 1;
+        while count < most && next + count < packets.len() {
+            let (bytes, to) = &packets[next + count];
+            if *to != dst || bytes.len() > first.len() {
+                break;
+            }
+            count += 1;
+            if bytes.len() < first.len() {
+                break;
+            }
+        }
+        groups.push(count);
+        next += count;
+    }
+    groups
+}
+
+// How many of `packets` left, from the first one: one system call for up to BATCH_SIZE messages
+#[cfg(target_os = "linux")]
+pub fn sendmmsg(fd: RawFd, packets: &[(Vec<u8>,Host)]) -> std::io::Result<usize> {
+    let gso: bool = gso(fd);
+    match send_segments( fd, packets, &segments(packets, gso) ) {
+        // the socket's device can't segment after all: from now on one datagram per message
+        Err(err) if gso && matches!( err.raw_os_error(), Some(libc::EIO) | Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) ) => {
+            GSO_REFUSED.store(true, Ordering::Relaxed);
+            send_segments( fd, packets, &segments(packets, false) )
+        },
+        sent => sent,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_segments(fd: RawFd, packets: &[(Vec<u8>,Host)], groups: &[usize]) -> std::io::Result<usize> {
+    let count: usize = groups.len();
+    if count == 0 {
+        return Ok(0);
+    }
+    let carried: usize = groups.iter().sum();
+    // where the datagrams of each message start in `packets`
+    let starts: Vec<usize> = groups.iter().scan( 0, |next, group| { let start: usize = *next; *next += group; Some(start) } ).collect();
+
+    let mut addrs: Vec<(libc::sockaddr_storage, libc::socklen_t)> = starts.iter().map( |start| to_sockaddr( &packets[*start].1.sock() ) ).collect();
+    let mut iovs: Vec<libc::iovec> = packets[..carried].iter()
+        .map( |(bytes, _)| libc::iovec { iov_base: bytes.as_ptr() as *mut libc::c_void, iov_len: bytes.len() } )
+        .collect();
+    // room for one UDP_SEGMENT control message each, u64 keeps it aligned for cmsghdr
+    let space: usize = unsafe { libc::CMSG_SPACE( std::mem::size_of::<u16>() as libc::c_uint ) } as usize;
+    let mut controls: Vec<Vec<u64>> = groups.iter().map( |_| vec![0u64; space.div_ceil(8)] ).collect();
+    let mut msgs: Vec<libc::mmsghdr> = Vec::with_capacity(count);
+    for (i, (group, first)) in groups.iter().zip( starts.iter().copied() ).enumerate() {
+        // SAFETY: all-zero is a valid mmsghdr, the pointers set below outlive the call
+        let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+        msg.msg_hdr.msg_name = &mut addrs[i].0 as *mut libc::sockaddr_storage as *mut libc::c_void;
+        msg.msg_hdr.msg_namelen = addrs[i].1;
+        msg.msg_hdr.msg_iov = &mut iovs[first];
+        msg.msg_hdr.msg_iovlen = *group as _;
+        if *group > 1 {
+            msg.msg_hdr.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_controllen = space as _;
+            // SAFETY: the control buffer holds one cmsghdr and its u16
+            unsafe {
+                let cmsg: *mut libc::cmsghdr = libc::CMSG_FIRSTHDR(&msg.msg_hdr);
+                (*cmsg).cmsg_level = libc::SOL_UDP;
+                (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                (*cmsg).cmsg_len = libc::CMSG_LEN( std::mem::size_of::<u16>() as libc::c_uint ) as _;
+                std::ptr::write_unaligned( libc::CMSG_DATA(cmsg) as *mut u16, packets[first].0.len() as u16 );
+            }
+        }
+        msgs.push(msg);
+    }
+
+    let sent = unsafe { libc::sendmmsg( fd, msgs.as_mut_ptr(), count as libc::c_uint, 0 ) };
+    match sent < 0 {
+        true => Err( std::io::Error::last_os_error() ),
+        false => Ok( groups[..sent as usize].iter().sum() ),
+    }
+}
+
+// The datagrams already waiting, up to BATCH_SIZE: `meta` gets the length and source of each.
+// Datagrams larger than their buffer are dropped, cut short they would be taken for other ones
+#[cfg(target_os = "linux")]
+pub fn recvmmsg(fd: RawFd, bufs: &mut [Vec<u8>], meta: &mut [(usize,Host)]) -> std::io::Result<usize> {
+    loop {
+        // only truncated ones: the next ones waiting, until there are none
+        match recv_whole(fd, bufs, meta)? {
+            0 if bufs.len().min( meta.len() ) > 0 => {},
+            kept => { return Ok(kept); },
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn recv_whole(fd: RawFd, bufs: &mut [Vec<u8>], meta: &mut [(usize,Host)]) -> std::io::Result<usize> {
+    let count: usize = bufs.len().min( meta.len() ).min(BATCH_SIZE);
+    if count == 0 {
+        return Ok(0);
+    }
+
+    // SAFETY: all-zero is a valid sockaddr_storage
+    let mut addrs: Vec<libc::sockaddr_storage> = vec![ unsafe { std::mem::zeroed() }; count ];
+    let mut iovs: Vec<libc::iovec> = bufs[..count].iter_mut()
+        .map( |buf| libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() } )
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = Vec::with_capacity(count);
+    for i in 0..count {
+        // SAFETY: all-zero is a valid mmsghdr, the pointers set below outlive the call
+        let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+        msg.msg_hdr.msg_name = &mut addrs[i] as *mut libc::sockaddr_storage as *mut libc::c_void;
+        msg.msg_hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_hdr.msg_iov = &mut iovs[i];
+        msg.msg_hdr.msg_iovlen = 1;
+        msgs.push(msg);
+    }
+
+    let received = unsafe { libc::recvmmsg( fd, msgs.as_mut_ptr(), count as libc::c_uint, libc::MSG_DONTWAIT, std::ptr::null_mut() ) };
+    if received < 0 {
+        return Err( std::io::Error::last_os_error() );
+    }
+    let unspecified: SocketAddr = SocketAddr::from( ([0, 0, 0, 0], 0) );
+    let mut kept: usize = 0;
+    for i in 0..(received as usize) {
+        if msgs[i].msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+            continue;
+        }
+        bufs.swap(kept, i);
+        meta[kept] = ( msgs[i].msg_len as usize, Host::from( from_sockaddr(&addrs[i]).unwrap_or(unspecified) ) );
+        kept += 1;
+    }
+    Ok(kept)
+}
+
+#[test]
+fn test_buffer_pool() {
+    let pool = BufferPool::new();
+    let mut buf = pool.take();
+    assert_eq!(buf.len(),BUFFER_SIZE);
+    buf.truncate(3);
+    pool.give(buf);
+    assert_eq!(pool.available(),1);
+    assert_eq!(pool.take().len(),BUFFER_SIZE);
+
+    for _ in 0..(POOL_SIZE + 1) {
+        pool.give( vec![0; BUFFER_SIZE] );
+    }
+    assert_eq!(pool.available(),POOL_SIZE);
+}
+
+#[tokio::test]
+async fn test_batch_loopback() -> std::io::Result<()> {
+    use crate::network::transport::Transport;
+
+    let a = tokio::net::UdpSocket::bind("127.0.0.1:4700").await?;
+    let b = tokio::net::UdpSocket::bind("[::1]:4701").await.ok();
+    let c = tokio::net::UdpSocket::bind("127.0.0.1:4702").await?;
+    let dst = Host::new("127.0.0.1:4702");
+
+    let packets: Vec<(Vec<u8>,Host)> = (0..(BATCH_SIZE as u8 + 8)).map( |i| (vec![i; 100 + i as usize], dst) ).collect();
+    let mut sent: usize = 0;
+    while sent < packets.len() {
+        sent += a.send_batch( &packets[sent..] ).await?;
+    }
+
+    let mut bufs: Vec<Vec<u8>> = vec![ vec![0; BUFFER_SIZE]; BATCH_SIZE ];
+    let mut meta: Vec<(usize,Host)> = vec![ (0, dst); BATCH_SIZE ];
+    let mut received: Vec<Vec<u8>> = Vec::new();
+    while received.len() < packets.len() {
+        let count = tokio::time::timeout( std::time::Duration::from_secs(1), c.recv_batch(&mut bufs, &mut meta) ).await??;
+        for i in 0..count {
+            assert_eq!(meta[i].1,Host::new("127.0.0.1:4700"));
+            received.push( bufs[i][..meta[i].0].to_vec() );
+        }
+    }
+    assert_eq!(received,packets.into_iter().map( |(bytes, _)| bytes ).collect::<Vec<Vec<u8>>>());
+
+    // IPv6 sources come back as they are
+    if let Some(b) = b {
+        b.send_batch( &[ (vec![7; 10], Host::new("[::1]:4701")) ] ).await?;
+        let count = b.recv_batch(&mut bufs, &mut meta).await?;
+        assert_eq!((count, meta[0]),(1, (10, Host::new("[::1]:4701"))));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_batch_segments() {
+    let (a, b) = (Host::new("127.0.0.1:1111"), Host::new("127.0.0.2:2222"));
+    let packets: Vec<(Vec<u8>,Host)> = vec![
+        (vec![0; 100], a), (vec![0; 100], a), (vec![0; 60], a), (vec![0; 100], a),
+        (vec![0; 100], b), (vec![0; 200], b),
+        (vec![], a), (vec![], a),
+    ];
+    // same host and size together, a shorter one closes the message
+    assert_eq!(segments(&packets, true),vec![3, 1, 1, 1, 1, 1]);
+    assert_eq!(segments(&packets, false),vec![1; 8]);
+
+    // no more than the kernel takes in one message, no more messages than BATCH_SIZE
+    let many: Vec<(Vec<u8>,Host)> = vec![ (vec![0; BUFFER_SIZE], a); 4 * BATCH_SIZE * GSO_SEGMENTS ];
+    let groups = segments(&many, true);
+    assert_eq!(groups.len(),BATCH_SIZE);
+    assert!(groups.iter().all( |group| *group == GSO_BYTES / BUFFER_SIZE ));
+}
+
+#[tokio::test]
+async fn test_batch_gso_and_truncated() -> std::io::Result<()> {
+    use crate::network::transport::Transport;
+
+    let a = tokio::net::UdpSocket::bind("127.0.0.1:4741").await?;
+    let c = tokio::net::UdpSocket::bind("127.0.0.1:4742").await?;
+    let dst = Host::new("127.0.0.1:4742");
+
+    // equal sizes to one host: one message each time GSO is there, still apart on arrival
+    let packets: Vec<(Vec<u8>,Host)> = (0..20u8).map( |i| (vec![i; 500], dst) ).collect();
+    let mut sent: usize = 0;
+    while sent < packets.len() {
+        sent += a.send_batch( &packets[sent..] ).await?;
+    }
+    // too large for a receive buffer: dropped, not taken cut short
+    a.send_to( &vec![9; BUFFER_SIZE + 100], dst.sock() ).await?;
+    a.send_to( &[7; 10], dst.sock() ).await?;
+
+    let mut bufs: Vec<Vec<u8>> = vec![ vec![0; BUFFER_SIZE]; BATCH_SIZE ];
+    let mut meta: Vec<(usize,Host)> = vec![ (0, dst); BATCH_SIZE ];
+    let mut received: Vec<Vec<u8>> = Vec::new();
+    while received.len() < packets.len() + 1 {
+        let count = tokio::time::timeout( std::time::Duration::from_secs(1), c.recv_batch(&mut bufs, &mut meta) ).await??;
+        for i in 0..count {
+            received.push( bufs[i][..meta[i].0].to_vec() );
+        }
+    }
+    let mut expected: Vec<Vec<u8>> = packets.into_iter().map( |(bytes, _)| bytes ).collect();
+    expected.push( vec![7; 10] );
+    assert_eq!(received,expected);
+    Ok(())
+}
+
+// cargo test bench_loopback_throughput -- --ignored --nocapture
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn bench_loopback_throughput() -> std::io::Result<()> {
+    use std::sync::Arc;
+    use crate::network::network::Network;
+    use crate::network::udp::Datagram;
+    use crate::message::tlv::TLV;
+    use crate::message::header::Header;
+
+    const DATAGRAMS: usize = 200_000;
+    for (batched, port) in [(false, 4703), (true, 4705)] {
+        let tx = Network::new( Arc::new( tokio::net::UdpSocket::bind( format!("127.0.0.1:{}", port) ).await? ), None, Host::new("127.255.255.255:1"), None );
+        let rx = Network::new( Arc::new( tokio::net::UdpSocket::bind( format!("127.0.0.1:{}", port + 1) ).await? ), None, Host::new("127.255.255.255:1"), None );
+        let dst = rx.local_addr();
+
+        let counter = tokio::task::spawn( async move {
+            let mut received: usize = 0;
+            let mut last = std::time::Instant::now();
+            loop {
+                let got = match batched {
+                    false => tokio::time::timeout( std::time::Duration::from_millis(200), rx.recv_from() ).await.map( |dg| dg.map( |_| 1 ) ),
+                    true => tokio::time::timeout( std::time::Duration::from_millis(200), rx.recv_batch() ).await.map( |dgs| dgs.map( |dgs| dgs.len() ) ),
+                };
+                match got {
+                    Ok(Ok(count)) => { received += count; last = std::time::Instant::now(); },
+                    _ => { return (received, last); },
+                }
+            }
+        });
+
+        let dg = Datagram::new( None, TLV::new( Header::UNKNOWN, Some( vec![0; 1000] ) ).unwrap(), Some(dst) );
+        let start = std::time::Instant::now();
+        let mut sent: usize = 0;
+        while sent < DATAGRAMS {
+            match batched {
+                false => { tx.send_to(dg.clone(), None).await.ok(); sent += 1; },
+                true => { tx.send_many( vec![dg.clone(); BATCH_SIZE] ).await; sent += BATCH_SIZE; },
+            }
+        }
+        let elapsed = start.elapsed();
+        let (received, last) = counter.await.unwrap();
+        // what the socket buffer could not hold is lost, the receive rate is the one to compare
+        println!("batched: {:5} sent {} datagrams/s, received {} of {} at {} datagrams/s", batched,
+            (sent as f64 / elapsed.as_secs_f64()) as u64, received, sent, (received as f64 / (last - start).as_secs_f64()) as u64);
+    }
+    Ok(())
+}
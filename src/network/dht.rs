@@ -0,0 +1,306 @@
+use std::collections::{HashMap,VecDeque};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::network::host::Host;
+use crate::network::peer::PeerId;
+use crate::message::dht::DhtMessage;
+
+// Kademlia parameters: bucket size and lookup parallelism
+pub const K: usize = 8;
+pub const ALPHA: usize = 3;
+// A contact leaves its bucket after that many lookups in a row it did not answer
+pub const STALE_LIMIT: u32 = 3;
+// Endpoint records kept for others, the oldest one makes room when full
+pub const MAX_VALUES: usize = 1024;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Contact {
+    id: PeerId,
+    host: Host,
+}
+
+impl Contact {
+    pub fn new(id: PeerId, host: Host) -> Contact {
+        Contact { id, host }
+    }
+
+    pub fn id(&self) -> PeerId {
+        self.id
+    }
+
+    pub fn host(&self) -> Host {
+        self.host
+    }
+}
+
+pub fn distance(a: &PeerId, b: &PeerId) -> u64 {
+    a.value() ^ b.value()
+}
+
+// One bucket per bit of distance, least recently seen contacts at the front
+#[derive(Debug,Clone)]
+pub struct KBuckets {
+    own: PeerId,
+    buckets: Vec<VecDeque<Contact>>,
+}
+
+impl KBuckets {
+    pub fn new(own: PeerId) -> KBuckets {
+        KBuckets { own, buckets: vec![VecDeque::new(); 64] }
+    }
+
+    fn index(&self, id: &PeerId) -> Option<usize> {
+        match distance(&self.own, id) {
+            0 => None,
+            d => Some( 63 - d.leading_zeros() as usize ),
+        }
+    }
+
+    // A full bucket keeps its old contacts, long lived peers are the most likely to stay
+    pub fn insert(&mut self, contact: Contact) -> bool {
+        let index = match self.index(&contact.id()) {
+            None => { return false; },
+            Some(index) => index,
+        };
+        let bucket = &mut self.buckets[index];
+
+        if let Some(position) = bucket.iter().position( |c| c.id() == contact.id() ) {
+            bucket.remove(position);
+            bucket.push_back(contact);
+            true
+        }
+        else if bucket.len() < K {
+            bucket.push_back(contact);
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    pub fn remove(&mut self, id: &PeerId) -> bool {
+        match self.index(id) {
+            None => false,
+            Some(index) => {
+                let bucket = &mut self.buckets[index];
+                let len = bucket.len();
+                bucket.retain( |c| c.id() != *id );
+                len != bucket.len()
+            },
+        }
+    }
+
+    pub fn get(&self, id: &PeerId) -> Option<Contact> {
+        let index = self.index(id)?;
+        self.buckets[index].iter().find( |c| c.id() == *id ).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map( |b| b.len() ).sum()
+    }
+
+    pub fn closest(&self, target: &PeerId, count: usize) -> Vec<Contact> {
+        let mut contacts: Vec<Contact> = self.buckets.iter().flat_map( |b| b.iter().copied() ).collect();
+        contacts.sort_by_key( |c| distance(&c.id(), target) );
+        contacts.truncate(count);
+        contacts
+    }
+}
+
+// Everything the node keeps for the DHT: routing buckets, stored endpoint records, and
+// the lookups waiting for an answer
+#[derive(Debug)]
+pub struct Dht {
+    table: KBuckets,
+    // With when they were stored
+    values: HashMap<PeerId,(Vec<Host>,Instant)>,
+    pending: HashMap<u32,mpsc::Sender<DhtMessage>>,
+    nonce: u32,
+    // Unanswered lookups in a row, per contact
+    failures: HashMap<PeerId,u32>,
+}
+
+impl Dht {
+    pub fn new(own: PeerId) -> Dht {
+        Dht { table: KBuckets::new(own), values: HashMap::new(), pending: HashMap::new(), nonce: 0, failures: HashMap::new() }
+    }
+
+    pub fn table(&self) -> &KBuckets {
+        &self.table
+    }
+
+    pub fn table_mut(&mut self) -> &mut KBuckets {
+        &mut self.table
+    }
+
+    pub fn value(&self, key: &PeerId) -> Option<Vec<Host>> {
+        self.values.get(key).map( |(hosts, _)| hosts.clone() )
+    }
+
+    pub fn values(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn store(&mut self, key: PeerId, hosts: Vec<Host>, now: Instant) {
+        if !self.values.contains_key(&key) && self.values.len() >= MAX_VALUES {
+            let oldest: Option<PeerId> = self.values.iter().min_by_key( |(_, (_, stored))| *stored ).map( |(key, _)| *key );
+            if let Some(oldest) = oldest {
+                self.values.remove(&oldest);
+            }
+        }
+        self.values.insert(key, (hosts, now));
+    }
+
+    // The contact answered, or sent us anything
+    pub fn seen(&mut self, contact: Contact) {
+        self.failures.remove( &contact.id() );
+        self.table.insert(contact);
+    }
+
+    // The contact let a lookup down, true once it got dropped from its bucket
+    pub fn failed(&mut self, id: &PeerId) -> bool {
+        let failures = self.failures.entry(*id).or_insert(0);
+        *failures += 1;
+        if *failures < STALE_LIMIT {
+            return false;
+        }
+        self.failures.remove(id);
+        self.table.remove(id)
+    }
+
+    // Register a query, its answer will be handed to `reply`
+    pub fn register(&mut self, reply: mpsc::Sender<DhtMessage>) -> u32 {
+        self.nonce = self.nonce.wrapping_add(1);
+        self.pending.insert(self.nonce, reply);
+        self.nonce
+    }
+
+    pub fn unregister(&mut self, nonce: &u32) {
+        self.pending.remove(nonce);
+    }
+
+    pub fn waiting(&self, nonce: &u32) -> Option<mpsc::Sender<DhtMessage>> {
+        self.pending.get(nonce).cloned()
+    }
+}
+
+#[test]
+fn test_kbuckets_insert_closest() {
+    let own = PeerId::new(0);
+    let mut table = KBuckets::new(own);
+
+    assert!( !table.insert( Contact::new( own, Host::new("127.0.0.1:1") ) ) );
+    for i in 1..=20 {
+        assert!( table.insert( Contact::new( PeerId::new(i), Host::new("127.0.0.1:1") ) ) );
+    }
+    assert_eq!(table.len(),20);
+
+    let closest = table.closest( &PeerId::new(5), 3 );
+    assert_eq!(closest[0].id(),PeerId::new(5));
+    assert_eq!(closest[1].id(),PeerId::new(4));
+    assert_eq!(closest[2].id(),PeerId::new(7));
+
+    assert!( table.remove( &PeerId::new(5) ) );
+    assert!( table.get( &PeerId::new(5) ).is_none() );
+    assert!( table.get( &PeerId::new(6) ).is_some() );
+}
+
+#[test]
+fn test_kbuckets_full_bucket() {
+    let mut table = KBuckets::new( PeerId::new(0) );
+
+    // distance in [2^63, 2^64) all share the last bucket
+    for i in 0..(K as u64) {
+        assert!( table.insert( Contact::new( PeerId::new( (1 << 63) + i ), Host::new("127.0.0.1:1") ) ) );
+    }
+    assert!( !table.insert( Contact::new( PeerId::new( (1 << 63) + 100 ), Host::new("127.0.0.1:1") ) ) );
+
+    // refreshing a known contact is always possible, and takes the new endpoint
+    assert!( table.insert( Contact::new( PeerId::new( 1 << 63 ), Host::new("127.0.0.1:2") ) ) );
+    assert_eq!(table.get( &PeerId::new( 1 << 63 ) ).unwrap().host().port(),2);
+}
+
+// For test: a node running the receive/dispatch/emit workers on a simulated link
+fn spawn_node(sim: &crate::network::simulated::SimNet, host: Host, backbone: &crate::message::signal::Signal<()>) -> crate::network::network::Network {
+    use crate::memory::shared_fifo::SharedFifo;
+    use crate::message::signal::SignalType;
+    use crate::network::udp::Datagram;
+
+    let sock = std::sync::Arc::new( sim.bind(host) );
+    let net = crate::network::network::Network::new(sock, None, Host::new("127.255.255.255:9"), None);
+    let income: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
+    let outcome: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
+
+    tokio::task::spawn( crate::workers::receive::receiver(net.clone(), income.clone(), backbone.subscribe()) );
+    tokio::task::spawn( crate::workers::dispatch::dispatcher(net.clone(), income, outcome.clone(), backbone.subscribe()) );
+    tokio::task::spawn( crate::workers::emit::emitter(net.clone(), outcome, backbone.subscribe()) );
+    net
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_dht_hundreds_of_nodes() -> std::io::Result<()> {
+    let backbone: crate::message::signal::Signal<()> = crate::message::signal::Signal::new(crate::message::signal::SignalType::broadcast);
+    let sim = crate::network::simulated::SimNet::new(0);
+    let n: usize = 200;
+
+    let nodes: Vec<crate::network::network::Network> = (0..n)
+        .map( |i| spawn_node( &sim, Host::new( &format!("10.0.{}.{}:4725", i / 250, i % 250 + 1) ), &backbone ) )
+        .collect();
+
+    // Everybody only knows a couple of bootstrap nodes
+    let bootstrap: Vec<Host> = vec![ nodes[0].local_addr(), nodes[1].local_addr() ];
+    for node in nodes.iter().skip(2) {
+        assert!( node.bootstrap(&bootstrap).await > 0 );
+    }
+    nodes[0].bootstrap( &[ nodes[1].local_addr() ] ).await;
+    nodes[1].bootstrap( &[ nodes[0].local_addr() ] ).await;
+
+    for node in nodes.iter() {
+        assert!( node.publish().await > 0 );
+    }
+
+    // Lookups from far away nodes, without relying on direct knowledge
+    for i in 0..20 {
+        let seeker = &nodes[ (i * 37) % n ];
+        let target = &nodes[ (i * 53 + 11) % n ];
+        if seeker.id() == target.id() {
+            continue;
+        }
+
+        let contacts = seeker.find_node( target.id() ).await;
+        assert_eq!(Some( target.local_addr() ),contacts.iter().find( |c| c.id() == target.id() ).map( |c| c.host() ));
+
+        assert_eq!(Some( vec![ target.local_addr() ] ),seeker.find_value( target.id() ).await);
+        assert_eq!(Some( vec![ target.local_addr() ] ),seeker.locate( target.id() ).await);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_dht_stale_contacts_and_values() {
+    let mut dht = Dht::new( PeerId::new(0) );
+    let contact = Contact::new( PeerId::new(1), Host::new("127.0.0.1:1") );
+
+    // a contact answering in between starts over
+    dht.seen(contact);
+    for _ in 1..STALE_LIMIT {
+        assert!( !dht.failed( &contact.id() ) );
+    }
+    dht.seen(contact);
+    for _ in 1..STALE_LIMIT {
+        assert!( !dht.failed( &contact.id() ) );
+    }
+    assert!( dht.failed( &contact.id() ) );
+    assert!( dht.table().get( &contact.id() ).is_none() );
+
+    // the oldest record makes room
+    let start = Instant::now();
+    for i in 0..=(MAX_VALUES as u64) {
+        dht.store( PeerId::new(i + 1), vec![ Host::new("127.0.0.1:1") ], start + tokio::time::Duration::from_secs(i) );
+    }
+    assert_eq!(dht.values(),MAX_VALUES);
+    assert!( dht.value( &PeerId::new(1) ).is_none() );
+    assert!( dht.value( &PeerId::new(2) ).is_some() );
+}
@@ -0,0 +1,185 @@
+use std::net::IpAddr;
+
+// Peers are resolved as <name>.toktok
+pub const DNS_DOMAIN: &str = "toktok";
+// Addresses move as leases and endpoints change, resolvers should not keep them long
+const DNS_TTL: u32 = 30;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Rcode {
+    NoError,
+    FormErr,
+    NxDomain,
+    NotImp,
+    Refused,
+}
+
+impl Rcode {
+    fn to_byte(self) -> u8 {
+        match self {
+            Rcode::NoError => 0,
+            Rcode::FormErr => 1,
+            Rcode::NxDomain => 3,
+            Rcode::NotImp => 4,
+            Rcode::Refused => 5,
+        }
+    }
+}
+
+// Node names are one DNS label: letters, digits and dashes, compared in lowercase
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 63 && !name.starts_with('-') && !name.ends_with('-')
+        && name.bytes().all( |b| b.is_ascii_alphanumeric() || b == b'-' )
+}
+
+// The node name a query is about: None outside of our domain
+pub fn node_name(qname: &str) -> Option<String> {
+    let qname: String = qname.trim_end_matches('.').to_lowercase();
+    let name = qname.strip_suffix(DNS_DOMAIN)?.strip_suffix('.')?;
+    match valid_name(name) {
+        true => Some( name.to_string() ),
+        false => None,
+    }
+}
+
+// Only what we answer: a standard query with a single question
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct DnsQuery {
+    id: u16,
+    opcode: u8,
+    recursion: bool,
+    qname: String,
+    qtype: u16,
+    qclass: u16,
+    // The question as received, copied in the response
+    question: Vec<u8>,
+}
+
+impl DnsQuery {
+    pub fn from_bytes(bytes: &[u8]) -> Option<DnsQuery> {
+        let header = bytes.get(0..12)?;
+        // responses and multiple questions are not for us
+        if header[2] & 0x80 != 0 || u16::from_be_bytes([header[4], header[5]]) != 1 {
+            return None;
+        }
+
+        let mut labels: Vec<String> = Vec::new();
+        let mut cursor: usize = 12;
+        loop {
+            let len: usize = *bytes.get(cursor)? as usize;
+            cursor += 1;
+            if len == 0 {
+                break;
+            }
+            // no compression in a lone question
+            if len > 63 {
+                return None;
+            }
+            labels.push( String::from_utf8( Vec::from( bytes.get(cursor..(cursor+len))? ) ).ok()? );
+            cursor += len;
+        }
+        let fixed = bytes.get(cursor..(cursor+4))?;
+
+        Some( DnsQuery {
+            id: u16::from_be_bytes([header[0], header[1]]),
+            opcode: (header[2] >> 3) & 0x0f,
+            recursion: header[2] & 0x01 != 0,
+            qname: labels.join("."),
+            qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+            qclass: u16::from_be_bytes([fixed[2], fixed[3]]),
+            question: Vec::from( &bytes[12..(cursor+4)] ),
+        } )
+    }
+
+    pub fn qname(&self) -> &str {
+        &self.qname
+    }
+
+    pub fn standard(&self) -> bool {
+        self.opcode == 0
+    }
+
+    // The addresses wanted among `ips`
+    pub fn wanted(&self, ips: &[IpAddr]) -> Vec<IpAddr> {
+        if self.qclass != CLASS_IN {
+            return Vec::new();
+        }
+        ips.iter().filter( |ip| matches!( (self.qtype, ip), (TYPE_A, IpAddr::V4(_)) | (TYPE_AAAA, IpAddr::V6(_)) | (TYPE_ANY, _) ) ).copied().collect()
+    }
+
+    // Authoritative answer, the question pointed at by every record
+    pub fn response(&self, rcode: Rcode, ips: &[IpAddr]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity( 12 + self.question.len() + 28 * ips.len() );
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        bytes.push( 0x80 | (self.opcode << 3) | 0x04 | self.recursion as u8 );
+        bytes.push( rcode.to_byte() );
+        bytes.extend_from_slice(&1u16.to_be_bytes());
+        bytes.extend_from_slice(&(ips.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&[0, 0, 0, 0]);
+        bytes.extend_from_slice(&self.question);
+
+        for ip in ips.iter() {
+            bytes.extend_from_slice(&[0xc0, 12]);
+            let (rtype, rdata): (u16, Vec<u8>) = match ip {
+                IpAddr::V4(ip) => (TYPE_A, Vec::from( ip.octets() )),
+                IpAddr::V6(ip) => (TYPE_AAAA, Vec::from( ip.octets() )),
+            };
+            bytes.extend_from_slice(&rtype.to_be_bytes());
+            bytes.extend_from_slice(&CLASS_IN.to_be_bytes());
+            bytes.extend_from_slice(&DNS_TTL.to_be_bytes());
+            bytes.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(&rdata);
+        }
+        bytes
+    }
+}
+
+// For test: a query like the ones sent by a stub resolver
+pub fn dns_query(id: u16, qname: &str, qtype: u16) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+    bytes.extend_from_slice(&id.to_be_bytes());
+    bytes.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in qname.split('.') {
+        bytes.push(label.len() as u8);
+        bytes.extend_from_slice(label.as_bytes());
+    }
+    bytes.push(0);
+    bytes.extend_from_slice(&qtype.to_be_bytes());
+    bytes.extend_from_slice(&CLASS_IN.to_be_bytes());
+    bytes
+}
+
+#[test]
+fn test_dns_query_response() {
+    assert_eq!(node_name("Alpha.TokTok."),Some( "alpha".to_string() ));
+    assert_eq!(node_name("alpha.example"),None);
+    assert_eq!(node_name("a.b.toktok"),None);
+    assert_eq!(node_name("toktok"),None);
+    assert!(!valid_name("-alpha"));
+
+    let query = DnsQuery::from_bytes( &dns_query(0x1234, "alpha.toktok", TYPE_A) ).unwrap();
+    assert_eq!(query.qname(),"alpha.toktok");
+    assert!(query.standard());
+    let ips: Vec<IpAddr> = vec![ "10.8.0.2".parse().unwrap(), "fd00::2".parse().unwrap() ];
+    assert_eq!(query.wanted(&ips),vec![ ips[0] ]);
+
+    let response = query.response( Rcode::NoError, &query.wanted(&ips) );
+    assert_eq!(&response[0..2],&[0x12, 0x34]);
+    assert_eq!(response[2] & 0x84,0x84);
+    assert_eq!(response[3],0);
+    assert_eq!(&response[6..8],&[0, 1]);
+    assert_eq!(&response[(response.len()-4)..],&[10, 8, 0, 2]);
+
+    let nx = query.response( Rcode::NxDomain, &[] );
+    assert_eq!((nx[3], nx.len()),(3, 12 + query.question.len()));
+
+    // a response is not a query, and a truncated question neither
+    assert_eq!(None,DnsQuery::from_bytes(&response));
+    let bytes = dns_query(1, "alpha.toktok", TYPE_AAAA);
+    assert_eq!(None,DnsQuery::from_bytes( &bytes[..(bytes.len()-1)] ));
+}
@@ -1,18 +1,41 @@
 use std::net::{ToSocketAddrs, SocketAddr, IpAddr};
 use serde::{Deserialize, Serialize, Deserializer, Serializer};
 
-#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
 pub struct Host {
     sock: SocketAddr,
     ip: IpAddr,
     port: u16,
 }
 
+// How often names given in the configuration are resolved again
+pub const RESOLVE_PERIOD: std::time::Duration = std::time::Duration::from_secs(60);
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum HostErr {
+    // Not a `host:port`, or the port is not a u16
+    InvalidFormat,
+    Unresolved,
+}
+
 impl Host {
+    // Only for literals known to be valid, panics otherwise: use `parse` or `resolve`
     pub fn new(sock: &str) -> Host {
         Host::try_from(sock).unwrap()
     }
 
+    // `IpAddr:port` only, nothing is resolved
+    pub fn parse(sock: &str) -> Result<Host,HostErr> {
+        Host::try_from(sock).map_err( |_| HostErr::InvalidFormat )
+    }
+
+    // `IpAddr:port` or `hostname:port`, the first address found is used
+    pub async fn resolve(sock: &str) -> Result<Host,HostErr> {
+        let mut name = HostName::parse(sock)?;
+        name.resolve().await?;
+        name.host().ok_or(HostErr::Unresolved)
+    }
+
     pub fn sock(&self) -> SocketAddr {
         self.sock.clone()
     }
@@ -29,6 +52,51 @@ impl Host {
         format!("{}:{}",self.ip(),self.port())
     }
 
+    // [4|6][ip][port], as carried inside messages
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(19);
+        match self.ip {
+            IpAddr::V4(ip) => {
+                bytes.push(4);
+                bytes.extend_from_slice(&ip.octets());
+            },
+            IpAddr::V6(ip) => {
+                bytes.push(6);
+                bytes.extend_from_slice(&ip.octets());
+            },
+        }
+        bytes.extend_from_slice(&self.port.to_be_bytes());
+        bytes
+    }
+
+    // Returns the host and how many bytes it used
+    pub fn from_bytes(bytes: &[u8]) -> Option<(Host, usize)> {
+        let ip_len: usize = match bytes.first() {
+            Some(4) => 4,
+            Some(6) => 16,
+            _ => { return None; },
+        };
+        let len: usize = 1 + ip_len + 2;
+        if bytes.len() < len {
+            return None;
+        }
+
+        let ip: IpAddr = match ip_len {
+            4 => {
+                let mut octets: [u8; 4] = [0; 4];
+                octets.copy_from_slice(&bytes[1..5]);
+                IpAddr::from(octets)
+            },
+            _ => {
+                let mut octets: [u8; 16] = [0; 16];
+                octets.copy_from_slice(&bytes[1..17]);
+                IpAddr::from(octets)
+            },
+        };
+        let port: u16 = u16::from_be_bytes([bytes[len-2], bytes[len-1]]);
+        Some( (Host::from( SocketAddr::new(ip, port) ), len) )
+    }
+
     /*pub fn to_socket_addrs(&self) ->  {
         std::iter::once(self.sock())
     }*/
@@ -79,4 +147,136 @@ impl<'de> Deserialize<'de> for Host {
             Err(err) => Err(serde::de::Error::custom(err)),
         }
     }
-}
\ No newline at end of file
+}
+
+// A host given by name, remembered so it can be resolved again when its address changes
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct HostName {
+    name: String,
+    port: u16,
+    resolved: Option<Host>,
+}
+
+impl HostName {
+    // Literal addresses are resolved right away, and never again
+    pub fn parse(sock: &str) -> Result<HostName,HostErr> {
+        if let Ok(host) = Host::try_from(sock) {
+            return Ok( HostName::from(host) );
+        }
+        let (name, port) = sock.rsplit_once(':').ok_or(HostErr::InvalidFormat)?;
+        let port: u16 = port.parse().map_err( |_| HostErr::InvalidFormat )?;
+        let name: &str = name.trim_start_matches('[').trim_end_matches(']');
+        if name.is_empty() || name.contains( |c: char| c.is_whitespace() || c == '/' ) {
+            return Err(HostErr::InvalidFormat);
+        }
+        Ok( HostName { name: name.to_string(), port, resolved: None } )
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    // None until resolved once
+    pub fn host(&self) -> Option<Host> {
+        self.resolved
+    }
+
+    pub fn literal(&self) -> bool {
+        self.name.parse::<IpAddr>().is_ok()
+    }
+
+    // True if the address changed. A failed resolution keeps the previous address
+    pub async fn resolve(&mut self) -> Result<bool,HostErr> {
+        if self.literal() && self.resolved.is_some() {
+            return Ok(false);
+        }
+        let mut found = match tokio::net::lookup_host( (self.name.as_str(), self.port) ).await {
+            Err(_) => { return Err(HostErr::Unresolved); },
+            Ok(found) => found,
+        };
+        let host: Host = Host::from( found.next().ok_or(HostErr::Unresolved)? );
+        let changed: bool = self.resolved != Some(host);
+        self.resolved = Some(host);
+        Ok(changed)
+    }
+}
+
+impl From<Host> for HostName {
+    fn from(host: Host) -> Self {
+        HostName { name: host.ip().to_string(), port: host.port(), resolved: Some(host) }
+    }
+}
+
+impl std::fmt::Display for HostName {
+    // Literals are written the way Host is, so signed configurations stay the same
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.literal(), self.resolved) {
+            (true, Some(host)) => write!(f, "{}", host.local_addr()),
+            _ => write!(f, "{}:{}", self.name, self.port),
+        }
+    }
+}
+
+impl Serialize for HostName {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for HostName {
+    fn deserialize<D>(deserializer: D) -> Result<HostName, D::Error>
+        where D: Deserializer<'de>
+    {
+        let s: &str = Deserialize::deserialize(deserializer)?;
+        HostName::parse(s).map_err( |_| serde::de::Error::custom("Incorrect format, should be host:u16") )
+    }
+}
+
+#[test]
+fn test_host_from_to_bytes() {
+    let v4 = Host::new("127.0.0.1:3333");
+    let v6 = Host::new("[::1]:4444");
+
+    let mut bytes = v4.to_bytes();
+    assert_eq!(bytes.len(),7);
+    bytes.append( &mut v6.to_bytes() );
+
+    let (first, used) = Host::from_bytes(&bytes).unwrap();
+    assert_eq!(first,v4);
+    let (second, used_v6) = Host::from_bytes(&bytes[used..]).unwrap();
+    assert_eq!(second,v6);
+    assert_eq!(used_v6,19);
+
+    assert_eq!(None,Host::from_bytes(&bytes[0..6]));
+    assert_eq!(None,Host::from_bytes(&[5,1,2,3]));
+}
+
+#[tokio::test]
+async fn test_host_name_resolve() {
+    assert_eq!(Host::parse("127.0.0.1"),Err(HostErr::InvalidFormat));
+    assert_eq!(Host::parse("localhost:3333"),Err(HostErr::InvalidFormat));
+    assert_eq!(HostName::parse("localhost:99999"),Err(HostErr::InvalidFormat));
+    assert_eq!(HostName::parse(":3333"),Err(HostErr::InvalidFormat));
+
+    let literal = HostName::parse("127.0.0.1:3333").unwrap();
+    assert!(literal.literal());
+    assert_eq!(literal.host(),Some( Host::new("127.0.0.1:3333") ));
+    assert_eq!("\"127.0.0.1:3333\"",serde_json::to_string(&literal).unwrap());
+
+    let mut name: HostName = serde_json::from_str("\"localhost:3333\"").unwrap();
+    assert_eq!((name.name(), name.port(), name.host()),("localhost", 3333, None));
+    assert_eq!(Ok(true),name.resolve().await);
+    assert!(name.host().unwrap().ip().is_loopback());
+    assert_eq!(Ok(false),name.resolve().await);
+    assert_eq!("\"localhost:3333\"",serde_json::to_string(&name).unwrap());
+
+    assert!(Host::resolve("localhost:3333").await.unwrap().ip().is_loopback());
+    assert_eq!(Host::resolve("no-such-host.invalid:3333").await,Err(HostErr::Unresolved));
+}
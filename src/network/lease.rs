@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::net::{IpAddr,Ipv4Addr,Ipv6Addr};
+use serde::{Deserialize, Serialize, Deserializer, Serializer};
+use tokio::time::{Duration,Instant};
+
+use crate::network::peer::PeerId;
+use crate::memory::sqlite::LeaseStore;
+
+// Leases are kept across restarts, so their expiry is wall clock time
+pub fn unix_now() -> u64 {
+    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Err(_) => 0,
+        Ok(elapsed) => elapsed.as_secs(),
+    }
+}
+
+// Default lease, and how often a node without one asks again
+pub const LEASE_DURATION: u32 = 3600;
+pub const LEASE_RETRY: Duration = Duration::from_secs(1);
+
+// Larger IPv6 pools are only scanned this far for a free address
+const MAX_SCAN: u128 = 1 << 16;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(network: IpAddr, prefix: u8) -> Option<Cidr> {
+        match network {
+            IpAddr::V4(ip) if prefix <= 32 => {
+                let mask: u32 = if prefix == 0 { 0 } else { u32::MAX << (32 - prefix) };
+                Some( Cidr { network: IpAddr::V4( Ipv4Addr::from( u32::from(ip) & mask ) ), prefix } )
+            },
+            IpAddr::V6(ip) if prefix <= 128 => {
+                let mask: u128 = if prefix == 0 { 0 } else { u128::MAX << (128 - prefix) };
+                Some( Cidr { network: IpAddr::V6( Ipv6Addr::from( u128::from(ip) & mask ) ), prefix } )
+            },
+            _ => None,
+        }
+    }
+
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    fn bits(&self) -> u8 {
+        match self.network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+
+    fn offset(&self, ip: &IpAddr) -> Option<u128> {
+        let offset: u128 = match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => u32::from(*ip).wrapping_sub( u32::from(network) ) as u128,
+            (IpAddr::V6(network), IpAddr::V6(ip)) => u128::from(*ip).wrapping_sub( u128::from(network) ),
+            _ => { return None; },
+        };
+        match self.bits() - self.prefix {
+            host_bits if host_bits < 128 && offset >> host_bits != 0 => None,
+            _ => Some(offset),
+        }
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.offset(ip).is_some()
+    }
+
+    // The network address, and the IPv4 broadcast one, are never handed out
+    fn usable(&self, offset: u128) -> bool {
+        let host_bits: u8 = self.bits() - self.prefix;
+        if host_bits < 2 {
+            return true;
+        }
+        let last: u128 = if host_bits == 128 { u128::MAX } else { (1 << host_bits) - 1 };
+        offset != 0 && !( self.bits() == 32 && offset == last ) && offset <= last
+    }
+
+    fn host(&self, offset: u128) -> Option<IpAddr> {
+        if !self.usable(offset) {
+            return None;
+        }
+        match self.network {
+            IpAddr::V4(network) => Some( IpAddr::V4( Ipv4Addr::from( u32::from(network).wrapping_add(offset as u32) ) ) ),
+            IpAddr::V6(network) => Some( IpAddr::V6( Ipv6Addr::from( u128::from(network).wrapping_add(offset) ) ) ),
+        }
+    }
+}
+
+impl TryFrom<&str> for Cidr {
+    type Error = String;
+
+    fn try_from(cidr: &str) -> Result<Self, Self::Error> {
+        let (network, prefix) = cidr.split_once('/').ok_or( format!("{} is not a CIDR", cidr) )?;
+        let network: IpAddr = network.parse().map_err( |_| format!("{} is not a CIDR", cidr) )?;
+        let prefix: u8 = prefix.parse().map_err( |_| format!("{} is not a CIDR", cidr) )?;
+        Cidr::new(network, prefix).ok_or( format!("{} is not a CIDR", cidr) )
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D>(deserializer: D) -> Result<Cidr, D::Error>
+        where D: Deserializer<'de>
+    {
+        let s: &str = Deserialize::deserialize(deserializer)?;
+        Cidr::try_from(s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Lease {
+    id: PeerId,
+    ip: IpAddr,
+    expires: u64,
+}
+
+impl Lease {
+    pub fn new(id: PeerId, ip: IpAddr, expires: u64) -> Lease {
+        Lease { id, ip, expires }
+    }
+
+    pub fn id(&self) -> PeerId {
+        self.id
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    pub fn expires(&self) -> u64 {
+        self.expires
+    }
+}
+
+// Server side: who holds which address of the pool, and until when
+#[derive(Debug)]
+pub struct AddressPool {
+    cidr: Cidr,
+    duration: u32,
+    leases: HashMap<PeerId,Lease>,
+    store: Option<LeaseStore>,
+    // Expired here and not removed from the store yet
+    stale: Vec<PeerId>,
+}
+
+impl AddressPool {
+    pub fn new(cidr: Cidr, duration: u32) -> AddressPool {
+        AddressPool { cidr, duration, leases: HashMap::new(), store: None, stale: Vec::new() }
+    }
+
+    // Leases saved by a previous run are taken back, as long as they fit the pool
+    pub fn with_store(mut self, store: LeaseStore) -> Self {
+        for lease in store.load() {
+            if self.cidr.contains( &lease.ip() ) {
+                self.leases.insert( lease.id(), lease );
+            }
+        }
+        self.store = Some(store);
+        self
+    }
+
+    pub fn cidr(&self) -> Cidr {
+        self.cidr
+    }
+
+    pub fn duration(&self) -> u32 {
+        self.duration
+    }
+
+    // Changes are not written by the pool: it is used under a lock, the disk is left to the caller
+    pub fn store(&self) -> Option<LeaseStore> {
+        self.store.clone()
+    }
+
+    pub fn take_stale(&mut self) -> Vec<PeerId> {
+        std::mem::take(&mut self.stale)
+    }
+
+    pub fn lease(&self, id: &PeerId) -> Option<Lease> {
+        self.leases.get(id).copied()
+    }
+
+    pub fn leases(&self) -> Vec<Lease> {
+        self.leases.values().copied().collect()
+    }
+
+    fn holder(&self, ip: &IpAddr) -> Option<PeerId> {
+        self.leases.values().find( |lease| lease.ip() == *ip ).map( |lease| lease.id() )
+    }
+
+    fn free(&self, id: &PeerId, ip: &IpAddr) -> bool {
+        match self.cidr.offset(ip) {
+            None => false,
+            Some(offset) => self.cidr.usable(offset) && self.holder(ip).is_none_or( |holder| holder == *id ),
+        }
+    }
+
+    // Same address as before if possible, then the requested one, then the first free one
+    pub fn offer(&mut self, id: PeerId, requested: Option<IpAddr>, now: u64) -> Option<IpAddr> {
+        self.expire(now);
+        if let Some(lease) = self.lease(&id) {
+            return Some( lease.ip() );
+        }
+        if let Some(ip) = requested {
+            if self.free(&id, &ip) {
+                return Some(ip);
+            }
+        }
+        (1..MAX_SCAN).map_while( |offset| self.cidr.host(offset) ).find( |ip| self.free(&id, ip) )
+    }
+
+    // Grant or renew, a peer holds one address at a time
+    pub fn commit(&mut self, id: PeerId, ip: IpAddr, now: u64) -> Option<Lease> {
+        self.expire(now);
+        if !self.free(&id, &ip) {
+            return None;
+        }
+        let lease = Lease::new( id, ip, now + self.duration as u64 );
+        self.leases.insert(id, lease);
+        Some(lease)
+    }
+
+    pub fn expire(&mut self, now: u64) -> Vec<Lease> {
+        let expired: Vec<Lease> = self.leases.values().filter( |lease| lease.expires() <= now ).copied().collect();
+        for lease in expired.iter() {
+            self.leases.remove( &lease.id() );
+            if self.store.is_some() {
+                self.stale.push( lease.id() );
+            }
+        }
+        expired
+    }
+}
+
+// Client side: the address we were granted, renewed halfway through
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct ClientLease {
+    ip: IpAddr,
+    prefix: u8,
+    duration: u32,
+    acquired: Instant,
+}
+
+impl ClientLease {
+    pub fn new(ip: IpAddr, prefix: u8, duration: u32, acquired: Instant) -> ClientLease {
+        ClientLease { ip, prefix, duration, acquired }
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn renew_at(&self) -> Instant {
+        self.acquired + Duration::from_secs( self.duration as u64 / 2 )
+    }
+
+    pub fn expired(&self, now: Instant) -> bool {
+        now >= self.acquired + Duration::from_secs( self.duration as u64 )
+    }
+}
+
+#[test]
+fn test_cidr() {
+    let cidr = Cidr::try_from("10.8.0.77/24").unwrap();
+    assert_eq!(cidr.to_string(),"10.8.0.0/24");
+    assert!(cidr.contains( &"10.8.0.255".parse().unwrap() ));
+    assert!(!cidr.contains( &"10.8.1.1".parse().unwrap() ));
+    assert!(!cidr.contains( &"fd00::1".parse().unwrap() ));
+    assert_eq!(cidr.host(0),None);
+    assert_eq!(cidr.host(1),Some( "10.8.0.1".parse().unwrap() ));
+    assert_eq!(cidr.host(255),None);
+
+    assert!(Cidr::try_from("10.8.0.0/33").is_err());
+    assert!(Cidr::try_from("10.8.0.0").is_err());
+    let cidr6 = Cidr::try_from("fd00::/64").unwrap();
+    assert_eq!(cidr6.host(2),Some( "fd00::2".parse().unwrap() ));
+    assert_eq!("\"fd00::/64\"",serde_json::to_string(&cidr6).unwrap());
+}
+
+#[test]
+fn test_address_pool() {
+    let mut pool = AddressPool::new( Cidr::try_from("10.8.0.0/30").unwrap(), 60 );
+    let (a, b, c) = ( PeerId::new(1), PeerId::new(2), PeerId::new(3) );
+
+    // only .1 and .2 are usable in a /30
+    let ip = pool.offer(a, None, 0).unwrap();
+    assert_eq!(ip,"10.8.0.1".parse::<IpAddr>().unwrap());
+    assert!(pool.commit(a, ip, 0).is_some());
+    assert_eq!(Some(ip),pool.offer(a, Some( "10.8.0.2".parse().unwrap() ), 10));
+
+    assert!(pool.commit(b, ip, 0).is_none());
+    let other = pool.offer(b, Some(ip), 0).unwrap();
+    assert_eq!(other,"10.8.0.2".parse::<IpAddr>().unwrap());
+    pool.commit(b, other, 0);
+    assert_eq!(None,pool.offer(c, None, 0));
+
+    // renewing pushes the expiry, the others run out
+    assert_eq!(pool.commit(a, ip, 50).unwrap().expires(),110);
+    assert_eq!(pool.expire(60),vec![ Lease::new(b, other, 60) ]);
+    assert_eq!(Some(other),pool.offer(c, None, 60));
+}
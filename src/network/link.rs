@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use tokio::time::{Duration,Instant};
+
+use crate::network::host::Host;
+
+// A PING not answered within this delay counts as lost
+pub const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+// [nonce][timestamp in µs since the monitor started], echoed back in the PONG
+pub const PING_PAYLOAD_LEN: usize = 4 + 8;
+
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub struct LinkStats {
+    srtt: Option<Duration>,
+    jitter: Duration,
+    sent: u64,
+    received: u64,
+    lost: u64,
+    loss: f64,
+}
+
+impl LinkStats {
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    // Moving average, recent losses weigh more than old ones
+    pub fn loss_rate(&self) -> f64 {
+        self.loss
+    }
+
+    // RFC 6298 smoothing, the RTT variation is our jitter
+    fn sample(&mut self, rtt: Duration) {
+        self.received += 1;
+        self.loss *= 0.9;
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.jitter = rtt / 2;
+            },
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.jitter = (self.jitter * 3 + delta) / 4;
+                self.srtt = Some( (srtt * 7 + rtt) / 8 );
+            },
+        }
+    }
+
+    fn lose(&mut self) {
+        self.lost += 1;
+        self.loss = self.loss * 0.9 + 0.1;
+    }
+}
+
+#[derive(Debug)]
+pub struct LinkMonitor {
+    epoch: Instant,
+    nonce: u32,
+    outstanding: HashMap<u32,(Host,Instant)>,
+    stats: HashMap<Host,LinkStats>,
+}
+
+impl LinkMonitor {
+    pub fn new() -> LinkMonitor {
+        LinkMonitor { epoch: Instant::now(), nonce: 0, outstanding: HashMap::new(), stats: HashMap::new() }
+    }
+
+    pub fn stats(&self, host: &Host) -> Option<LinkStats> {
+        self.stats.get(host).copied()
+    }
+
+    pub fn links(&self) -> HashMap<Host,LinkStats> {
+        self.stats.clone()
+    }
+
+    // Payload of the next PING to `host`
+    pub fn ping(&mut self, host: Host, now: Instant) -> Vec<u8> {
+        self.nonce = self.nonce.wrapping_add(1);
+        self.outstanding.insert( self.nonce, (host, now) );
+        self.stats.entry(host).or_default().sent += 1;
+
+        let timestamp: u64 = now.duration_since(self.epoch).as_micros() as u64;
+        let mut payload: Vec<u8> = Vec::with_capacity(PING_PAYLOAD_LEN);
+        payload.extend_from_slice(&self.nonce.to_be_bytes());
+        payload.extend_from_slice(&timestamp.to_be_bytes());
+        payload
+    }
+
+    // Match a PONG to its PING, returns the RTT sample
+    pub fn pong(&mut self, host: &Host, payload: &[u8], now: Instant) -> Option<Duration> {
+        if payload.len() != PING_PAYLOAD_LEN {
+            return None;
+        }
+        let nonce: u32 = u32::from_be_bytes([payload[0],payload[1],payload[2],payload[3]]);
+        let mut raw: [u8; 8] = [0; 8];
+        raw.copy_from_slice(&payload[4..12]);
+        let timestamp: Duration = Duration::from_micros( u64::from_be_bytes(raw) );
+
+        match self.outstanding.get(&nonce) {
+            Some((pinged, _)) if pinged == host => {},
+            _ => { return None; },
+        }
+        self.outstanding.remove(&nonce);
+
+        let rtt: Duration = now.checked_duration_since( self.epoch + timestamp ).unwrap_or_default();
+        self.stats.entry(*host).or_default().sample(rtt);
+        Some(rtt)
+    }
+
+    // Forget the unanswered PINGs, each one is a loss
+    pub fn expire(&mut self, now: Instant) {
+        let lost: Vec<u32> = self.outstanding.iter()
+            .filter( |(_, (_, sent))| now.duration_since(*sent) >= PING_TIMEOUT )
+            .map( |(nonce, _)| *nonce )
+            .collect();
+
+        for nonce in lost {
+            if let Some((host, _)) = self.outstanding.remove(&nonce) {
+                self.stats.entry(host).or_default().lose();
+            }
+        }
+    }
+}
+
+impl Default for LinkMonitor {
+    fn default() -> Self {
+        LinkMonitor::new()
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_link_rtt_and_jitter() {
+    let mut monitor = LinkMonitor::new();
+    let host = Host::new("127.0.0.1:1111");
+
+    let payload = monitor.ping( host, Instant::now() );
+    tokio::time::advance( Duration::from_millis(100) ).await;
+    assert_eq!(Some( Duration::from_millis(100) ),monitor.pong( &host, &payload, Instant::now() ));
+
+    let stats = monitor.stats(&host).unwrap();
+    assert_eq!(Some( Duration::from_millis(100) ),stats.srtt());
+    assert_eq!(Duration::from_millis(50),stats.jitter());
+
+    let payload = monitor.ping( host, Instant::now() );
+    tokio::time::advance( Duration::from_millis(20) ).await;
+    monitor.pong( &host, &payload, Instant::now() );
+
+    let stats = monitor.stats(&host).unwrap();
+    assert_eq!(Some( Duration::from_millis(90) ),stats.srtt());
+    assert_eq!(Duration::from_micros(57500),stats.jitter());
+    assert_eq!(2,stats.received());
+
+    // replayed or foreign PONGs are ignored
+    assert_eq!(None,monitor.pong( &host, &payload, Instant::now() ));
+    let other = monitor.ping( host, Instant::now() );
+    assert_eq!(None,monitor.pong( &Host::new("127.0.0.1:2222"), &other, Instant::now() ));
+    assert_eq!(None,monitor.pong( &host, &[], Instant::now() ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_link_loss() {
+    let mut monitor = LinkMonitor::new();
+    let host = Host::new("127.0.0.1:1111");
+
+    monitor.ping( host, Instant::now() );
+    let answered = monitor.ping( host, Instant::now() );
+    monitor.pong( &host, &answered, Instant::now() );
+
+    tokio::time::advance(PING_TIMEOUT).await;
+    monitor.expire( Instant::now() );
+
+    let stats = monitor.stats(&host).unwrap();
+    assert_eq!(2,stats.sent());
+    assert_eq!(1,stats.lost());
+    assert!(stats.loss_rate() > 0.0);
+}
@@ -0,0 +1,423 @@
+use std::collections::{HashMap,VecDeque};
+use tokio::time::{Duration,Instant};
+use rand_core::{OsRng,RngCore};
+
+use crate::network::host::Host;
+use crate::network::peer::PeerId;
+use crate::message::gossip::{MemberState,MemberUpdate};
+
+// SWIM timings: one probe per period, indirect probes once the direct one timed out,
+// suspects are declared dead if they don't refute in time
+pub const PROBE_PERIOD: Duration = Duration::from_secs(2);
+pub const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+pub const SUSPECT_TIMEOUT: Duration = Duration::from_secs(6);
+pub const INDIRECT_PROBES: usize = 3;
+// Dead members are kept that long, so that late gossip about them is still recognised as stale,
+// then forgotten
+pub const DEAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Member {
+    host: Host,
+    state: MemberState,
+    incarnation: u32,
+    since: Instant,
+}
+
+impl Member {
+    pub fn host(&self) -> Host {
+        self.host
+    }
+
+    pub fn state(&self) -> MemberState {
+        self.state
+    }
+
+    pub fn incarnation(&self) -> u32 {
+        self.incarnation
+    }
+}
+
+// What the heartbeater has to do after a tick or a gossip
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum SwimAction {
+    Probe(PeerId, Host),
+    IndirectProbe { target: PeerId, host: Host, via: Vec<Host> },
+    Join(PeerId, Host),
+    Evict(PeerId, Host),
+}
+
+#[derive(Debug,Clone,Copy)]
+struct Probe {
+    target: PeerId,
+    sent: Instant,
+    indirect: bool,
+}
+
+#[derive(Debug)]
+pub struct Membership {
+    own: PeerId,
+    host: Host,
+    incarnation: u32,
+    members: HashMap<PeerId,Member>,
+    updates: VecDeque<(MemberUpdate,u32)>,
+    probe: Option<Probe>,
+    last_probe: Option<Instant>,
+    order: Vec<PeerId>,
+    // target -> who asked us to probe it
+    indirect: HashMap<PeerId,Vec<Host>>,
+}
+
+impl Membership {
+    pub fn new(own: PeerId, host: Host) -> Membership {
+        Membership {
+            own, host, incarnation: 0,
+            members: HashMap::new(), updates: VecDeque::new(),
+            probe: None, last_probe: None, order: Vec::new(),
+            indirect: HashMap::new(),
+        }
+    }
+
+    pub fn incarnation(&self) -> u32 {
+        self.incarnation
+    }
+
+    pub fn member(&self, id: &PeerId) -> Option<Member> {
+        self.members.get(id).copied()
+    }
+
+    pub fn members(&self) -> HashMap<PeerId,Member> {
+        self.members.clone()
+    }
+
+    fn alive_members(&self) -> usize {
+        self.members.values().filter( |m| m.state != MemberState::Dead ).count()
+    }
+
+    // Every update is gossiped about 3*log2(n) times
+    fn gossip(&mut self, update: MemberUpdate) {
+        let transmissions: u32 = 3 * (usize::BITS - (self.alive_members() + 1).leading_zeros());
+        self.updates.retain( |(u, _)| u.id() != update.id() );
+        self.updates.push_back( (update, transmissions) );
+    }
+
+    // Direct evidence: the peer talked to us (HELLO)
+    pub fn alive(&mut self, id: PeerId, host: Host, now: Instant) -> bool {
+        if id == self.own {
+            return false;
+        }
+        let incarnation: u32 = match self.members.get(&id) {
+            Some(member) if member.state == MemberState::Alive && member.host == host => { return false; },
+            Some(member) => member.incarnation,
+            None => 0,
+        };
+        self.members.insert( id, Member { host, state: MemberState::Alive, incarnation, since: now } );
+        self.gossip( MemberUpdate::new( id, MemberState::Alive, incarnation, host ) );
+        true
+    }
+
+    // Apply what somebody else told us, SWIM precedence rules on incarnations
+    pub fn apply(&mut self, update: MemberUpdate, now: Instant) -> Option<SwimAction> {
+        if update.id() == self.own {
+            // Refute any suspicion about ourselves with a fresh incarnation
+            if update.state() != MemberState::Alive && update.incarnation() >= self.incarnation {
+                self.incarnation = update.incarnation() + 1;
+                let refute = MemberUpdate::new( self.own, MemberState::Alive, self.incarnation, self.host );
+                self.gossip(refute);
+            }
+            return None;
+        }
+
+        let accept: bool = match self.members.get( &update.id() ) {
+            None => update.state() != MemberState::Dead,
+            Some(member) => match (update.state(), member.state) {
+                (MemberState::Alive, _) => update.incarnation() > member.incarnation,
+                (MemberState::Suspect, MemberState::Alive) => update.incarnation() >= member.incarnation,
+                (MemberState::Suspect, MemberState::Suspect) => update.incarnation() > member.incarnation,
+                (MemberState::Suspect, MemberState::Dead) => false,
+                (MemberState::Dead, state) => state != MemberState::Dead,
+            },
+        };
+        if !accept {
+            return None;
+        }
+        // Where a known member is, is ours to find out: only its own HELLO moves it, see `alive`
+        let host: Host = self.members.get( &update.id() ).map( |m| m.host ).unwrap_or( update.host() );
+        let update = MemberUpdate::new( update.id(), update.state(), update.incarnation(), host );
+
+        let previous: Option<MemberState> = self.members.get( &update.id() ).map( |m| m.state );
+        self.members.insert( update.id(), Member { host: update.host(), state: update.state(), incarnation: update.incarnation(), since: now } );
+        self.gossip(update);
+
+        match (update.state(), previous) {
+            (MemberState::Dead, _) => Some( SwimAction::Evict( update.id(), update.host() ) ),
+            (_, None) | (_, Some(MemberState::Dead)) => Some( SwimAction::Join( update.id(), update.host() ) ),
+            _ => None,
+        }
+    }
+
+    // An answer from `id`: the running probe succeeded, returns the members waiting for it
+    pub fn ack(&mut self, id: &PeerId, now: Instant) -> Vec<Host> {
+        if let Some(probe) = self.probe {
+            if probe.target == *id {
+                self.probe = None;
+            }
+        }
+        if let Some(member) = self.members.get_mut(id) {
+            if member.state == MemberState::Suspect {
+                member.state = MemberState::Alive;
+                member.since = now;
+            }
+        }
+        self.indirect.remove(id).unwrap_or_default()
+    }
+
+    pub fn indirect_probe(&mut self, target: PeerId, requester: Host) {
+        let requesters = self.indirect.entry(target).or_default();
+        if !requesters.contains(&requester) {
+            requesters.push(requester);
+        }
+    }
+
+    fn suspect(&mut self, id: &PeerId, now: Instant) {
+        let update: Option<MemberUpdate> = match self.members.get_mut(id) {
+            Some(member) if member.state == MemberState::Alive => {
+                member.state = MemberState::Suspect;
+                member.since = now;
+                Some( MemberUpdate::new( *id, MemberState::Suspect, member.incarnation, member.host ) )
+            },
+            _ => None,
+        };
+        if let Some(update) = update {
+            self.gossip(update);
+        }
+    }
+
+    // k random members able to probe `target` for us
+    fn helpers(&self, target: &PeerId) -> Vec<Host> {
+        let mut candidates: Vec<Host> = self.members.iter()
+            .filter( |(id, m)| *id != target && m.state == MemberState::Alive )
+            .map( |(_, m)| m.host )
+            .collect();
+
+        let mut helpers: Vec<Host> = Vec::with_capacity(INDIRECT_PROBES);
+        while !candidates.is_empty() && helpers.len() < INDIRECT_PROBES {
+            let pick = (OsRng.next_u32() as usize) % candidates.len();
+            helpers.push( candidates.swap_remove(pick) );
+        }
+        helpers
+    }
+
+    // Round robin over a shuffled list, like SWIM, so every member is probed in bounded time
+    fn next_target(&mut self) -> Option<PeerId> {
+        loop {
+            match self.order.pop() {
+                None => {
+                    self.order = self.members.iter().filter( |(_, m)| m.state != MemberState::Dead ).map( |(id, _)| *id ).collect();
+                    if self.order.is_empty() {
+                        return None;
+                    }
+                    for i in (1..self.order.len()).rev() {
+                        let j = (OsRng.next_u32() as usize) % (i + 1);
+                        self.order.swap(i, j);
+                    }
+                },
+                Some(id) => {
+                    if self.members.get(&id).map( |m| m.state != MemberState::Dead ).unwrap_or(false) {
+                        return Some(id);
+                    }
+                },
+            }
+        }
+    }
+
+    pub fn tick(&mut self, now: Instant) -> Vec<SwimAction> {
+        let mut actions: Vec<SwimAction> = Vec::new();
+
+        if let Some(mut probe) = self.probe {
+            let elapsed: Duration = now.duration_since(probe.sent);
+            if elapsed >= PROBE_PERIOD {
+                self.suspect(&probe.target, now);
+                self.probe = None;
+            }
+            else if !probe.indirect && elapsed >= PROBE_TIMEOUT {
+                if let Some(member) = self.members.get(&probe.target) {
+                    let via: Vec<Host> = self.helpers(&probe.target);
+                    if !via.is_empty() {
+                        actions.push( SwimAction::IndirectProbe { target: probe.target, host: member.host, via } );
+                    }
+                }
+                probe.indirect = true;
+                self.probe = Some(probe);
+            }
+        }
+
+        let expired: Vec<PeerId> = self.members.iter()
+            .filter( |(_, m)| m.state == MemberState::Suspect && now.duration_since(m.since) >= SUSPECT_TIMEOUT )
+            .map( |(id, _)| *id )
+            .collect();
+        for id in expired {
+            if let Some(member) = self.members.get_mut(&id) {
+                member.state = MemberState::Dead;
+                member.since = now;
+                let update = MemberUpdate::new( id, MemberState::Dead, member.incarnation, member.host );
+                actions.push( SwimAction::Evict( id, member.host ) );
+                self.gossip(update);
+            }
+        }
+        self.members.retain( |_, m| m.state != MemberState::Dead || now.duration_since(m.since) < DEAD_TIMEOUT );
+        let members = &self.members;
+        self.indirect.retain( |id, _| members.contains_key(id) );
+
+        let due: bool = match self.last_probe {
+            None => true,
+            Some(last) => now.duration_since(last) >= PROBE_PERIOD,
+        };
+        if self.probe.is_none() && due {
+            if let Some(target) = self.next_target() {
+                let host: Host = self.members[&target].host;
+                self.probe = Some( Probe { target, sent: now, indirect: false } );
+                self.last_probe = Some(now);
+                actions.push( SwimAction::Probe( target, host ) );
+            }
+        }
+
+        actions
+    }
+
+    // Updates to piggyback on the next datagram
+    pub fn pending_updates(&mut self, max: usize) -> Vec<MemberUpdate> {
+        let mut picked: Vec<MemberUpdate> = Vec::new();
+        for _ in 0..self.updates.len().min(max) {
+            if let Some((update, remaining)) = self.updates.pop_front() {
+                picked.push(update);
+                if remaining > 1 {
+                    self.updates.push_back( (update, remaining - 1) );
+                }
+            }
+        }
+        picked
+    }
+}
+
+#[test]
+fn test_membership_join_and_gossip() {
+    let now = Instant::now();
+    let mut members = Membership::new( PeerId::new(0), Host::new("127.0.0.1:1000") );
+
+    assert!( members.alive( PeerId::new(1), Host::new("127.0.0.1:1001"), now ) );
+    assert!( !members.alive( PeerId::new(1), Host::new("127.0.0.1:1001"), now ) );
+    assert!( !members.alive( PeerId::new(0), Host::new("127.0.0.1:1000"), now ) );
+
+    let updates = members.pending_updates(8);
+    assert_eq!(updates.len(),1);
+    assert_eq!(updates[0].state(),MemberState::Alive);
+
+    // gossip about an unknown member is a join, about a dead one is ignored
+    let join = MemberUpdate::new( PeerId::new(2), MemberState::Alive, 0, Host::new("127.0.0.1:1002") );
+    assert_eq!(Some( SwimAction::Join( PeerId::new(2), Host::new("127.0.0.1:1002") ) ),members.apply(join, now));
+    let ghost = MemberUpdate::new( PeerId::new(3), MemberState::Dead, 0, Host::new("127.0.0.1:1003") );
+    assert_eq!(None,members.apply(ghost, now));
+    assert!(members.member( &PeerId::new(3) ).is_none());
+}
+
+#[test]
+fn test_membership_incarnation_rules() {
+    let now = Instant::now();
+    let mut members = Membership::new( PeerId::new(0), Host::new("127.0.0.1:1000") );
+    let id = PeerId::new(1);
+    let host = Host::new("127.0.0.1:1001");
+    members.alive(id, host, now);
+
+    // suspect at the same incarnation wins over alive, stale alive does not clear it
+    assert_eq!(None,members.apply( MemberUpdate::new(id, MemberState::Suspect, 0, host), now ));
+    assert_eq!(MemberState::Suspect,members.member(&id).unwrap().state());
+    members.apply( MemberUpdate::new(id, MemberState::Alive, 0, host), now );
+    assert_eq!(MemberState::Suspect,members.member(&id).unwrap().state());
+
+    // the member refuted with a higher incarnation, the gossiped host is not taken
+    members.apply( MemberUpdate::new(id, MemberState::Alive, 1, Host::new("127.0.0.1:1009")), now );
+    assert_eq!(MemberState::Alive,members.member(&id).unwrap().state());
+    assert_eq!(host,members.member(&id).unwrap().host());
+
+    // dead is final
+    assert_eq!(Some( SwimAction::Evict(id, host) ),members.apply( MemberUpdate::new(id, MemberState::Dead, 0, host), now ));
+    assert_eq!(None,members.apply( MemberUpdate::new(id, MemberState::Suspect, 5, host), now ));
+
+    // but a rejoin with a newer incarnation is accepted
+    assert_eq!(Some( SwimAction::Join(id, host) ),members.apply( MemberUpdate::new(id, MemberState::Alive, 6, host), now ));
+}
+
+#[test]
+fn test_membership_refute() {
+    let now = Instant::now();
+    let mut members = Membership::new( PeerId::new(0), Host::new("127.0.0.1:1000") );
+    members.apply( MemberUpdate::new( PeerId::new(0), MemberState::Suspect, 0, Host::new("127.0.0.1:1000") ), now );
+    assert_eq!(members.incarnation(),1);
+
+    let updates = members.pending_updates(8);
+    assert_eq!(updates[0].id(),PeerId::new(0));
+    assert_eq!(updates[0].state(),MemberState::Alive);
+    assert_eq!(updates[0].incarnation(),1);
+}
+
+#[test]
+fn test_membership_probe_suspect_dead() {
+    let start = Instant::now();
+    let mut members = Membership::new( PeerId::new(0), Host::new("127.0.0.1:1000") );
+    let target = PeerId::new(1);
+    let host = Host::new("127.0.0.1:1001");
+    members.alive(target, host, start);
+    members.alive( PeerId::new(2), Host::new("127.0.0.1:1002"), start );
+
+    // only two members: probe whoever comes first, then make sure it is `target`
+    let mut now = start;
+    let mut probed: Option<PeerId> = None;
+    while probed != Some(target) {
+        for action in members.tick(now) {
+            if let SwimAction::Probe(id, _) = action {
+                probed = Some(id);
+                if id != target {
+                    members.ack(&id, now);
+                }
+            }
+        }
+        now += PROBE_PERIOD;
+    }
+    now -= PROBE_PERIOD;
+
+    // no answer: indirect probe through the other member, then suspicion
+    let actions = members.tick(now + PROBE_TIMEOUT);
+    assert_eq!(actions,vec![ SwimAction::IndirectProbe { target, host, via: vec![ Host::new("127.0.0.1:1002") ] } ]);
+    members.indirect_probe( PeerId::new(2), host );
+    members.tick(now + PROBE_PERIOD);
+    assert_eq!(MemberState::Suspect,members.member(&target).unwrap().state());
+
+    // an ack clears the suspicion, silence makes it dead
+    members.ack(&target, now + PROBE_PERIOD);
+    assert_eq!(MemberState::Alive,members.member(&target).unwrap().state());
+    members.suspect(&target, now + PROBE_PERIOD);
+    let actions = members.tick(now + PROBE_PERIOD + SUSPECT_TIMEOUT);
+    assert!(actions.contains( &SwimAction::Evict(target, host) ));
+    assert_eq!(MemberState::Dead,members.member(&target).unwrap().state());
+
+    // the tombstone outlives the gossip about it, then goes
+    let dead = now + PROBE_PERIOD + SUSPECT_TIMEOUT;
+    assert_eq!(None,members.apply( MemberUpdate::new(target, MemberState::Suspect, 0, host), dead ));
+    members.tick(dead + DEAD_TIMEOUT - PROBE_PERIOD);
+    assert!(members.member(&target).is_some());
+    members.tick(dead + DEAD_TIMEOUT);
+    assert!(members.member(&target).is_none());
+}
+
+#[test]
+fn test_membership_indirect_ack() {
+    let now = Instant::now();
+    let mut members = Membership::new( PeerId::new(0), Host::new("127.0.0.1:1000") );
+    let requester = Host::new("127.0.0.1:1005");
+    members.indirect_probe( PeerId::new(1), requester );
+    members.indirect_probe( PeerId::new(1), requester );
+
+    assert_eq!(vec![requester],members.ack( &PeerId::new(1), now ));
+    assert!(members.ack( &PeerId::new(1), now ).is_empty());
+}
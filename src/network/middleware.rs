@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::sync::{Arc,Mutex};
+use tokio::time::Instant;
+
+use crate::network::host::Host;
+use crate::network::udp::Datagram;
+use crate::network::network::Network;
+use crate::network::pacing::RateLimit;
+use crate::message::header::Header;
+
+// A step on the way in, between the socket and the handlers, and on the way out, between the
+// handlers and the socket. Returning None drops the datagram there
+pub trait Layer: Send + Sync {
+    fn inbound(&self, _net: &Network, dg: Datagram) -> Option<Datagram> {
+        Some(dg)
+    }
+
+    fn outbound(&self, _net: &Network, dg: Datagram) -> Option<Datagram> {
+        Some(dg)
+    }
+}
+
+// Layers wrap each other: the first one added sees datagrams first on their way in and last on
+// their way out, e.g. decryption before the signature check, and signing before encryption
+#[derive(Clone,Default)]
+pub struct Pipeline {
+    layers: Vec<Arc<dyn Layer>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline { layers: Vec::new() }
+    }
+
+    pub fn layer(mut self, layer: Arc<dyn Layer>) -> Self {
+        self.layers.push(layer);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    pub fn inbound(&self, net: &Network, dg: Datagram) -> Option<Datagram> {
+        self.layers.iter().try_fold( dg, |dg, layer| layer.inbound(net, dg) )
+    }
+
+    pub fn outbound(&self, net: &Network, dg: Datagram) -> Option<Datagram> {
+        self.layers.iter().rev().try_fold( dg, |dg, layer| layer.outbound(net, dg) )
+    }
+}
+
+impl std::fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pipeline").field("layers", &self.layers.len()).finish()
+    }
+}
+
+// Datagrams seen per header, whatever the layers after it do with them
+#[derive(Debug,Default)]
+pub struct MetricsLayer {
+    inbound: Mutex<HashMap<Header,u64>>,
+    outbound: Mutex<HashMap<Header,u64>>,
+}
+
+impl MetricsLayer {
+    pub fn new() -> MetricsLayer {
+        MetricsLayer::default()
+    }
+
+    pub fn inbound_counts(&self) -> HashMap<Header,u64> {
+        self.inbound.lock().unwrap().clone()
+    }
+
+    pub fn outbound_counts(&self) -> HashMap<Header,u64> {
+        self.outbound.lock().unwrap().clone()
+    }
+}
+
+impl Layer for MetricsLayer {
+    fn inbound(&self, _net: &Network, dg: Datagram) -> Option<Datagram> {
+        *self.inbound.lock().unwrap().entry( dg.header() ).or_insert(0) += 1;
+        Some(dg)
+    }
+
+    fn outbound(&self, _net: &Network, dg: Datagram) -> Option<Datagram> {
+        *self.outbound.lock().unwrap().entry( dg.header() ).or_insert(0) += 1;
+        Some(dg)
+    }
+}
+
+// Inbound bytes per source, over the limit is dropped rather than delayed: unlike the pacer on the
+// way out, there is nobody to make wait
+#[derive(Debug)]
+pub struct RateLimitLayer {
+    limit: RateLimit,
+    // Tokens left per source, and when they were last counted
+    buckets: Mutex<HashMap<Host,(f64,Instant)>>,
+    dropped: Mutex<u64>,
+}
+
+impl RateLimitLayer {
+    pub fn new(limit: RateLimit) -> RateLimitLayer {
+        RateLimitLayer { limit, buckets: Mutex::new( HashMap::new() ), dropped: Mutex::new(0) }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        *self.dropped.lock().unwrap()
+    }
+}
+
+impl Layer for RateLimitLayer {
+    fn inbound(&self, _net: &Network, dg: Datagram) -> Option<Datagram> {
+        let src: Host = dg.src()?;
+        let now = Instant::now();
+        let size: f64 = ( dg.data().length() as usize + 2 ) as f64;
+        let burst: f64 = self.limit.burst() as f64;
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let (tokens, last) = buckets.entry(src).or_insert( (burst, now) );
+        *tokens = ( *tokens + now.duration_since(*last).as_secs_f64() * self.limit.rate() as f64 ).min(burst);
+        *last = now;
+        if *tokens < size {
+            *self.dropped.lock().unwrap() += 1;
+            return None;
+        }
+        *tokens -= size;
+        Some(dg)
+    }
+}
+
+#[tokio::test]
+async fn test_pipeline_order_and_drop() {
+    use crate::message::tlv::TLV;
+
+    // renames one header into another, both ways, dropping what it doesn't know
+    struct Rename(Header, Header);
+    impl Layer for Rename {
+        fn inbound(&self, _net: &Network, mut dg: Datagram) -> Option<Datagram> {
+            if dg.header() != self.0 {
+                return None;
+            }
+            dg.set_header(self.1);
+            Some(dg)
+        }
+
+        fn outbound(&self, _net: &Network, mut dg: Datagram) -> Option<Datagram> {
+            if dg.header() != self.1 {
+                return None;
+            }
+            dg.set_header(self.0);
+            Some(dg)
+        }
+    }
+
+    let sock = Arc::new( crate::network::simulated::SimNet::new(0).bind( Host::new("127.0.0.1:4715") ) );
+    let net = Network::new(sock, None, Host::new("127.255.255.255:4715"), None);
+    let metrics = Arc::new( MetricsLayer::new() );
+    let pipeline = Pipeline::new()
+        .layer( metrics.clone() )
+        .layer( Arc::new( Rename(Header::APPLICATION(32), Header::APPLICATION(33)) ) )
+        .layer( Arc::new( Rename(Header::APPLICATION(33), Header::PING) ) );
+    assert_eq!(pipeline.len(),3);
+    let peer = Host::new("127.0.0.2:2222");
+
+    // in: 32 -> 33 -> PING, out: PING -> 33 -> 32
+    let dg = Datagram::new( Some(peer), TLV::new( Header::APPLICATION(32), None ).unwrap(), None );
+    let dg = pipeline.inbound(&net, dg).unwrap();
+    assert_eq!(dg.header(),Header::PING);
+    assert_eq!(pipeline.outbound(&net, dg).unwrap().header(),Header::APPLICATION(32));
+
+    // dropped past the metrics, still counted
+    let dg = Datagram::new( Some(peer), TLV::new( Header::HELLO, None ).unwrap(), None );
+    assert!(pipeline.inbound(&net, dg).is_none());
+    assert_eq!(metrics.inbound_counts(),HashMap::from([ (Header::APPLICATION(32), 1), (Header::HELLO, 1) ]));
+    assert_eq!(metrics.outbound_counts(),HashMap::from([ (Header::APPLICATION(32), 1) ]));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_rate_limit_layer() {
+    use crate::message::tlv::TLV;
+
+    let sock = Arc::new( crate::network::simulated::SimNet::new(0).bind( Host::new("127.0.0.1:4716") ) );
+    let net = Network::new(sock, None, Host::new("127.255.255.255:4716"), None);
+    // 100 bytes a second, 3 datagrams of 100 bytes at once
+    let limit = RateLimitLayer::new( RateLimit::new(100, 300) );
+    let peer = Host::new("127.0.0.2:2222");
+    let other = Host::new("127.0.0.3:3333");
+    let dg = |src: Host| Datagram::new( Some(src), TLV::new( Header::PING, Some( vec![0; 98] ) ).unwrap(), None );
+
+    for _ in 0..3 {
+        assert!(limit.inbound( &net, dg(peer) ).is_some());
+    }
+    assert!(limit.inbound( &net, dg(peer) ).is_none());
+    // every source has its own budget
+    assert!(limit.inbound( &net, dg(other) ).is_some());
+    tokio::time::advance( tokio::time::Duration::from_secs(1) ).await;
+    assert!(limit.inbound( &net, dg(peer) ).is_some());
+    assert!(limit.inbound( &net, dg(peer) ).is_none());
+    assert_eq!(limit.dropped(),2);
+}
@@ -1,86 +1,620 @@
 use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
 
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize,Ordering};
 use std::net::IpAddr;
-use std::collections::HashMap;
+use std::collections::{HashMap,HashSet};
+use signature::{Signer,Verifier};
+use ssh_key::Signature;
 
-use crate::network::host::Host;
+use crate::network::host::{Host,HostName,HostErr};
 use crate::network::udp::Datagram;
+use crate::network::peer::PeerId;
+use crate::network::relay::RelayStats;
+use crate::network::routing::RoutingTable;
+use crate::network::dht::{Dht,Contact,distance,K,ALPHA};
+use crate::network::membership::{Membership,Member,SwimAction};
+use crate::network::link::{LinkMonitor,LinkStats};
+use crate::network::pacing::{Pacer,RateLimit};
+use crate::network::transport::Transport;
+use crate::network::lease::{AddressPool,ClientLease,Lease,unix_now};
+use crate::memory::sqlite::LeaseStore;
+use crate::network::service::{Service,ServiceRegistry,SERVICE_TTL};
+use crate::network::stream::{StreamTable,StreamKey,StreamSend,STREAM_WINDOW};
+use crate::network::dns::{DnsQuery,Rcode,node_name,valid_name};
+use crate::network::pmtu::{PathMtu,Fragments};
+use crate::network::batch::{BufferPool,BATCH_SIZE};
+use crate::network::session::{Session,Sessions};
+use crate::network::middleware::Pipeline;
+use crate::workers::registry::{HandlerRegistry,MessageHandler};
+use crate::crypto::asymetric::KeyPair;
 use crate::message::tlv::TLV;
 use crate::message::header::Header;
+use crate::message::hello::{Hello,proof_message};
+use crate::message::relay::Relay;
+use crate::message::route::Advertisement;
+use crate::message::dht::DhtMessage;
+use crate::message::gossip::{MemberUpdate,MemberState,gossip_to_tlv,bye_to_tlv,GOSSIP_MAX_UPDATES};
+use crate::message::packet::{packet_to_tlv,ip_destination};
+use crate::message::lease::LeaseMessage;
+use crate::message::service::ServiceMessage;
+use crate::message::stream::StreamMessage;
+use crate::message::pmtu::{PmtuMessage,fragment};
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum SendErr {
+    // No endpoint given and no route to the peer ID
+    NoDestination,
+    // The socket buffer is full, the kernel holds us back
+    RateLimited,
+    // Too large for a datagram, even wrapped for a relay
+    TooLarge,
+    Socket(std::io::ErrorKind),
+}
+
+impl SendErr {
+    // Worth trying again a bit later
+    pub fn transient(&self) -> bool {
+        matches!( self, SendErr::RateLimited | SendErr::Socket( std::io::ErrorKind::Interrupted | std::io::ErrorKind::TimedOut ) )
+    }
+}
+
+impl From<std::io::Error> for SendErr {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::WouldBlock => SendErr::RateLimited,
+            kind => SendErr::Socket(kind),
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Endpoint {
+    Gateway,
+    Server,
+}
+
+// How long a DHT lookup waits for a round of answers
+const DHT_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_millis(500);
+// How long a service query waits for announces
+const SERVICE_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_millis(500);
 
 #[derive(Debug)]
 pub struct Network {
-    server: Arc<Option<Host>>,
-    gateway: Arc<Host>,
-    rx: Arc<UdpSocket>,
-    tx: Arc<UdpSocket>,
+    server: Arc<Mutex<Option<Host>>>,
+    gateway: Arc<Mutex<Host>>,
+    // Names given for the gateway and the server, resolved again now and then
+    hostnames: Arc<Mutex<Vec<(Endpoint,HostName)>>>,
+    rx: Arc<dyn Transport>,
+    tx: Arc<dyn Transport>,
     clients: Arc<Mutex<HashMap<IpAddr,Host>>>,
     broadcastable: Arc<bool>,
+    id: Arc<PeerId>,
+    peers: Arc<Mutex<HashMap<PeerId,Host>>>,
+    relayed: Arc<Mutex<HashMap<PeerId,RelayStats>>>,
+    routes: Arc<Mutex<RoutingTable>>,
+    dht: Arc<Mutex<Dht>>,
+    membership: Arc<Mutex<Membership>>,
+    links: Arc<Mutex<LinkMonitor>>,
+    pacer: Arc<Mutex<Pacer>>,
+    overlay: Arc<Mutex<HashMap<IpAddr,PeerId>>>,
+    tunnel: Arc<Mutex<Option<mpsc::Sender<Vec<u8>>>>>,
+    pool: Arc<Mutex<Option<AddressPool>>>,
+    lease: Arc<Mutex<Option<ClientLease>>>,
+    services: Arc<Mutex<ServiceRegistry>>,
+    local_services: Arc<Vec<Service>>,
+    streams: Arc<Mutex<StreamTable>>,
+    name: Arc<Option<String>>,
+    names: Arc<Mutex<HashMap<PeerId,String>>>,
+    dns: Arc<Option<Host>>,
+    pmtu: Arc<Mutex<PathMtu>>,
+    fragments: Arc<Mutex<Fragments>>,
+    send_failures: Arc<Mutex<HashMap<Host,u64>>>,
+    buffers: Arc<BufferPool>,
+    // Receive sockets sharing `rx`'s address, see `receivers`
+    shards: Arc<Vec<Arc<dyn Transport>>>,
+    sessions: Arc<Mutex<Sessions>>,
+    // Signs our side of the handshakes, see `greet`
+    key: Arc<KeyPair>,
+    handlers: Arc<HandlerRegistry>,
+    middleware: Arc<Pipeline>,
+    // Datagrams given to the dispatchers' workers and not handled yet, see WorkerPool
+    dispatch_depth: Arc<AtomicUsize>,
 }
 
 impl Network {
-    pub fn new(sock: Arc<UdpSocket>, sock_tx: Option<Arc<UdpSocket>>,gateway: Host, server: Option<Host>) -> Network {
+    pub fn new<T: Transport + 'static>(sock: Arc<T>, sock_tx: Option<Arc<T>>,gateway: Host, server: Option<Host>) -> Network {
+        let sock_tx: Option<Arc<dyn Transport>> = match sock_tx {
+            None => None,
+            Some(sock_tx) => Some(sock_tx),
+        };
+        Network::with_transport(sock, sock_tx, gateway, server)
+    }
+
+    // Listeners may use different transports, e.g. receive over UDP and send over TCP
+    pub fn with_transport(sock: Arc<dyn Transport>, sock_tx: Option<Arc<dyn Transport>>,gateway: Host, server: Option<Host>) -> Network {
         let clients: Arc<Mutex<HashMap<IpAddr,Host>>> = Arc::new( Mutex::new( HashMap::new() ) );
         let broadcastable: bool = match sock.set_broadcast(true){
             Err(_) => false,
             Ok(_) => true,
         };
+        let local: Host = match sock.local_addr() {
+            Err(_) => Host::from( std::net::SocketAddr::from( ([0, 0, 0, 0], 0) ) ),
+            Ok(addr) => addr,
+        };
+        let id: PeerId = PeerId::from(&local);
+        let peers: Arc<Mutex<HashMap<PeerId,Host>>> = Arc::new( Mutex::new( HashMap::new() ) );
+        let relayed: Arc<Mutex<HashMap<PeerId,RelayStats>>> = Arc::new( Mutex::new( HashMap::new() ) );
+        let routes: Arc<Mutex<RoutingTable>> = Arc::new( Mutex::new( RoutingTable::new(id) ) );
+        let dht: Arc<Mutex<Dht>> = Arc::new( Mutex::new( Dht::new(id) ) );
+        let membership: Arc<Mutex<Membership>> = Arc::new( Mutex::new( Membership::new(id, local) ) );
+        let links: Arc<Mutex<LinkMonitor>> = Arc::new( Mutex::new( LinkMonitor::new() ) );
+        let pacer: Arc<Mutex<Pacer>> = Arc::new( Mutex::new( Pacer::default() ) );
+        let overlay: Arc<Mutex<HashMap<IpAddr,PeerId>>> = Arc::new( Mutex::new( HashMap::new() ) );
+        let tunnel: Arc<Mutex<Option<mpsc::Sender<Vec<u8>>>>> = Arc::new( Mutex::new( None ) );
+        let pool: Arc<Mutex<Option<AddressPool>>> = Arc::new( Mutex::new( None ) );
+        let lease: Arc<Mutex<Option<ClientLease>>> = Arc::new( Mutex::new( None ) );
+        let services: Arc<Mutex<ServiceRegistry>> = Arc::new( Mutex::new( ServiceRegistry::new() ) );
+        let local_services: Arc<Vec<Service>> = Arc::new( Vec::new() );
+        let streams: Arc<Mutex<StreamTable>> = Arc::new( Mutex::new( StreamTable::new() ) );
+        let names: Arc<Mutex<HashMap<PeerId,String>>> = Arc::new( Mutex::new( HashMap::new() ) );
+        let pmtu: Arc<Mutex<PathMtu>> = Arc::new( Mutex::new( PathMtu::new() ) );
+        let fragments: Arc<Mutex<Fragments>> = Arc::new( Mutex::new( Fragments::new() ) );
+        let send_failures: Arc<Mutex<HashMap<Host,u64>>> = Arc::new( Mutex::new( HashMap::new() ) );
+        let buffers: Arc<BufferPool> = Arc::new( BufferPool::new() );
+        let shards: Arc<Vec<Arc<dyn Transport>>> = Arc::new( Vec::new() );
+        let sessions: Arc<Mutex<Sessions>> = Arc::new( Mutex::new( Sessions::new() ) );
+        let key: Arc<KeyPair> = Arc::new( KeyPair::random() );
+        let handlers: Arc<HandlerRegistry> = Arc::new( HandlerRegistry::with_defaults() );
+        let middleware: Arc<Pipeline> = Arc::new( Pipeline::new() );
+        let dispatch_depth: Arc<AtomicUsize> = Arc::new( AtomicUsize::new(0) );
 
         match sock_tx {
             None => 
-                Network { server: Arc::new(server), gateway: Arc::new(gateway), rx: Arc::clone(&sock), tx: sock , clients: clients, broadcastable: Arc::new(broadcastable)},
+                Network { server: Arc::new( Mutex::new(server) ), gateway: Arc::new( Mutex::new(gateway) ), hostnames: Arc::new( Mutex::new( Vec::new() ) ), rx: Arc::clone(&sock), tx: sock , clients, broadcastable: Arc::new(broadcastable), id: Arc::new(id), peers, relayed, routes, dht, membership, links, pacer, overlay, tunnel, pool, lease, services, local_services, streams, name: Arc::new(None), names, dns: Arc::new(None), pmtu, fragments, send_failures, buffers, shards, sessions, key, handlers, middleware, dispatch_depth },
             Some(sock_tx) => 
-                Network { server: Arc::new(server), gateway: Arc::new(gateway), rx: sock, tx: sock_tx , clients: clients, broadcastable: Arc::new(broadcastable)},
+                Network { server: Arc::new( Mutex::new(server) ), gateway: Arc::new( Mutex::new(gateway) ), hostnames: Arc::new( Mutex::new( Vec::new() ) ), rx: sock, tx: sock_tx , clients, broadcastable: Arc::new(broadcastable), id: Arc::new(id), peers, relayed, routes, dht, membership, links, pacer, overlay, tunnel, pool, lease, services, local_services, streams, name: Arc::new(None), names, dns: Arc::new(None), pmtu, fragments, send_failures, buffers, shards, sessions, key, handlers, middleware, dispatch_depth },
+        }
+    }
+
+    // Must be called before the network is cloned into the workers
+    pub fn with_id(mut self, id: PeerId) -> Self {
+        self.id = Arc::new(id);
+        self.routes = Arc::new( Mutex::new( RoutingTable::new(id) ) );
+        self.dht = Arc::new( Mutex::new( Dht::new(id) ) );
+        self.membership = Arc::new( Mutex::new( Membership::new(id, self.local_addr()) ) );
+        self
+    }
+
+    // Must be called before the network is cloned into the workers
+    pub fn with_limits(mut self, global: Option<RateLimit>, peers: HashMap<Host,RateLimit>) -> Self {
+        self.pacer = Arc::new( Mutex::new( Pacer::new(global, peers) ) );
+        self
+    }
+
+    // The node hands out overlay addresses from `pool`
+    pub fn with_pool(self, pool: AddressPool) -> Self {
+        *self.pool.lock().unwrap() = Some(pool);
+        self
+    }
+
+    // Must be called before the network is cloned into the workers
+    pub fn with_services(mut self, services: Vec<Service>) -> Self {
+        self.local_services = Arc::new(services);
+        self
+    }
+
+    // Must be called before the network is cloned into the workers, invalid names are ignored
+    pub fn with_name(mut self, name: &str) -> Self {
+        if valid_name(name) {
+            self.name = Arc::new( Some( name.to_lowercase() ) );
+        }
+        self
+    }
+
+    // Must be called before the network is cloned into the workers
+    pub fn with_shards(mut self, shards: Vec<Arc<dyn Transport>>) -> Self {
+        self.shards = Arc::new(shards);
+        self
+    }
+
+    // Must be called before the network is cloned into the workers. A key that can't sign, or too
+    // large for a HELLO field, leaves the random one in place
+    pub fn with_key(mut self, key: KeyPair) -> Self {
+        if key.can_sign() && key.public_bytes().is_some_and( |bytes| bytes.len() <= u8::MAX as usize ) {
+            self.key = Arc::new(key);
+        }
+        self
+    }
+
+    // Must be called before the network is cloned into the workers
+    pub fn with_handlers(mut self, handlers: HandlerRegistry) -> Self {
+        self.handlers = Arc::new(handlers);
+        self
+    }
+
+    // Registered for this header, see `with_handlers`
+    pub fn message_handler(&self, header: Header) -> Option<Arc<dyn MessageHandler>> {
+        self.handlers.get(header)
+    }
+
+    // Must be called before the network is cloned into the workers
+    pub fn with_middleware(mut self, middleware: Pipeline) -> Self {
+        self.middleware = Arc::new(middleware);
+        self
+    }
+
+    // Received datagrams go through the middleware before being queued for the handlers
+    pub fn inbound(&self, dg: Datagram) -> Option<Datagram> {
+        self.middleware.inbound(self, dg)
+    }
+
+    // Datagrams to send go through the middleware before being batched
+    pub fn outbound(&self, dg: Datagram) -> Option<Datagram> {
+        self.middleware.outbound(self, dg)
+    }
+
+    // One network per receive socket, everything else shared: each one for its own receiver
+    pub fn receivers(&self) -> Vec<Network> {
+        let mut receivers: Vec<Network> = vec![ self.clone() ];
+        for shard in self.shards.iter() {
+            let mut net = self.clone();
+            net.rx = Arc::clone(shard);
+            receivers.push(net);
+        }
+        receivers
+    }
+
+    // Must be called before the network is cloned into the workers
+    pub fn with_dns(mut self, listen: Host) -> Self {
+        self.dns = Arc::new( Some(listen) );
+        self
+    }
+
+    pub fn name(&self) -> Option<String> {
+        self.name.as_ref().clone()
+    }
+
+    pub fn dns(&self) -> Option<Host> {
+        *self.dns
+    }
+
+    // Keep resolving these names, see `re_resolve`
+    pub fn with_hostnames(self, gateway: Option<HostName>, server: Option<HostName>) -> Self {
+        {
+            let mut hostnames = self.hostnames.lock().unwrap();
+            for (endpoint, name) in [ (Endpoint::Gateway, gateway), (Endpoint::Server, server) ] {
+                if let Some(name) = name.filter( |name| !name.literal() ) {
+                    hostnames.push( (endpoint, name) );
+                }
+            }
+        }
+        self
+    }
+
+    pub fn gateway(&self) -> Host {
+        *self.gateway.lock().unwrap()
+    }
+
+    // Resolve the gateway and server names again, true if one of them moved.
+    // A name which can't be resolved keeps its last address
+    pub async fn re_resolve(&self) -> Result<bool,HostErr> {
+        let mut hostnames: Vec<(Endpoint,HostName)> = self.hostnames.lock().unwrap().clone();
+        let mut changed: bool = false;
+        let mut result: Result<(),HostErr> = Ok(());
+
+        for (endpoint, name) in hostnames.iter_mut() {
+            match name.resolve().await {
+                Err(err) => { result = Err(err); },
+                Ok(false) => {},
+                Ok(true) => {
+                    changed = true;
+                    let host: Host = name.host().unwrap();
+                    match endpoint {
+                        Endpoint::Gateway => { *self.gateway.lock().unwrap() = host; },
+                        Endpoint::Server => { *self.server.lock().unwrap() = Some(host); },
+                    }
+                },
+            }
+        }
+        *self.hostnames.lock().unwrap() = hostnames;
+        result.map( |_| changed )
+    }
+
+    pub fn id(&self) -> PeerId {
+        *self.id
+    }
+
+    pub fn server(&self) -> Option<Host> {
+        *self.server.lock().unwrap()
+    }
+
+    fn introduction(&self) -> Hello {
+        let mut hello: Hello = Hello::new( self.id() );
+        if let Some(ip) = self.overlay_ip() {
+            hello = hello.with_overlay(ip);
+        }
+        if let Some(name) = self.name.as_ref() {
+            hello = hello.with_name(name);
+        }
+        hello
+    }
+
+    // Without nonce: whoever hears it greets us back, see `greet`
+    pub fn hello(&self) -> Datagram {
+        Datagram::from( TLV::new( Header::HELLO, Some( self.introduction().to_bytes() ) ).unwrap() )
+    }
+
+    // Leaving: one BYE per client, queued before the workers are stopped so the emitter drains them
+    pub fn farewell(&self) -> Vec<Datagram> {
+        let incarnation: u32 = self.membership.lock().unwrap().incarnation();
+        let bye: TLV = bye_to_tlv( self.id(), incarnation ).unwrap();
+        let clients: Vec<Host> = self.clients.lock().unwrap().values().copied().collect();
+        let mut sessions = self.sessions.lock().unwrap();
+        clients.into_iter()
+            .map( |client| {
+                sessions.close( &client, tokio::time::Instant::now() );
+                Datagram::new( None, bye.clone(), Some(client) )
+            } )
+            .collect()
+    }
+
+    pub fn session(&self, peer: &Host) -> Option<Session> {
+        self.sessions.lock().unwrap().get(peer)
+    }
+
+    pub fn sessions(&self) -> HashMap<Host,Session> {
+        self.sessions.lock().unwrap().sessions()
+    }
+
+    // Whether `header` may arrive from `peer` in the state of their session, see `session::admits`
+    pub fn admit(&self, peer: &Host, header: Header) -> bool {
+        self.sessions.lock().unwrap().admit( peer, header, tokio::time::Instant::now() )
+    }
+
+    // Our HELLO for `peer`, starting the handshake unless a session is already established: our
+    // nonce for the peer to sign, and our signature of its own
+    pub fn greet(&self, peer: Host) -> Datagram {
+        let (nonce, challenge) = self.sessions.lock().unwrap().greet( peer, tokio::time::Instant::now() );
+        let mut hello: Hello = self.introduction();
+        if let Some(key) = self.key.public_bytes() {
+            hello = hello.with_challenge(key, nonce);
+        }
+        if let Some(challenge) = challenge {
+            if let Ok(signature) = self.key.try_sign( &proof_message( challenge, self.id() ) ) {
+                hello = hello.with_proof( challenge, signature.as_bytes().to_vec() );
+            }
+        }
+        Datagram::new( None, TLV::new( Header::HELLO, Some( hello.to_bytes() ) ).unwrap(), Some(peer) )
+    }
+
+    // The peer signed the nonce we sent it with the key it sent along: the session is established
+    pub fn verify_hello(&self, peer: &Host, hello: &Hello) -> bool {
+        let (echo, signature) = match hello.proof() {
+            None => { return false; },
+            Some(proof) => proof,
+        };
+        let key: KeyPair = match hello.key().and_then(KeyPair::from_public_bytes) {
+            None => { return false; },
+            Some(key) => key,
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.get(peer).map( |session| session.nonce() ) != Some(echo) {
+            return false;
+        }
+        let signed: bool = Signature::new( key.algorithm(), signature.to_vec() )
+            .is_ok_and( |signature| key.verify( &proof_message( echo, hello.id() ), &signature ).is_ok() );
+        signed && sessions.verified( *peer, hello.id(), key.into(), tokio::time::Instant::now() )
+    }
+
+    // HELLOs for the clients without a session, or whose handshake went unanswered
+    pub fn greetings(&self) -> Vec<Datagram> {
+        let now = tokio::time::Instant::now();
+        let clients: Vec<Host> = self.clients.lock().unwrap().values().copied().collect();
+        let due: Vec<Host> = {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.expire(now);
+            sessions.due(&clients, now)
+        };
+        due.into_iter().map( |client| self.greet(client) ).collect()
+    }
+
+    // The peer said HELLO, true when we owe it ours. `signed` once `verify_hello` accepted it
+    pub fn hello_received(&self, peer: &Host, hello: Option<&Hello>, signed: bool) -> bool {
+        let challenge: Option<u64> = hello.and_then( |hello| hello.nonce() );
+        self.sessions.lock().unwrap().hello( *peer, challenge, signed, tokio::time::Instant::now() )
+    }
+
+    // The failure detector had to ask others about `peer`
+    pub fn suspect(&self, peer: &Host) {
+        self.sessions.lock().unwrap().suspect( peer, tokio::time::Instant::now() );
+    }
+
+    // The peer said BYE: declared dead to the membership right away instead of after the probe timeouts
+    pub fn leave(&mut self, id: PeerId, incarnation: u32, client: &Host) {
+        self.apply_gossip( vec![ MemberUpdate::new( id, MemberState::Dead, incarnation, *client ) ] );
+        self.evict(client);
+    }
+
+    // Through the middleware first, a datagram it drops counts as sent with nothing on the wire
+    pub async fn send_to(&self, dg: Datagram, override_dst: Option<Host> ) -> Result<usize,SendErr> {
+        let dg: Datagram = match self.outbound(dg) {
+            None => { return Ok(0); },
+            Some(dg) => dg,
+        };
+        let (dst, packets) = self.prepare(dg, override_dst)?;
+        let mut sent: usize = 0;
+        for bytes in packets {
+            sent += self.transmit( &bytes, dst ).await?;
         }
+        Ok(sent)
     }
 
-    pub async fn send_to(&self, dg: Datagram, override_dst: Option<Host> ) -> usize {
+    // Each datagram's own result, like `send_to` for each of them
+    pub async fn send_many(&self, dgs: Vec<Datagram>) -> Vec<Result<usize,SendErr>> {
+        let passed: Vec<Option<Datagram>> = dgs.into_iter().map( |dg| self.outbound(dg) ).collect();
+        let kept: Vec<bool> = passed.iter().map( |dg| dg.is_some() ).collect();
+        let mut results = self.send_outbound( passed.into_iter().flatten().collect() ).await.into_iter();
+        kept.into_iter().map( |kept| match kept {
+            true => results.next().unwrap_or( Ok(0) ),
+            false => Ok(0),
+        } ).collect()
+    }
+
+    // Datagrams that already went through the middleware, e.g. the emitter's batches. Sent BATCH_SIZE
+    // at a time where the transport allows it
+    pub async fn send_outbound(&self, dgs: Vec<Datagram>) -> Vec<Result<usize,SendErr>> {
+        let mut results: Vec<Result<usize,SendErr>> = Vec::with_capacity( dgs.len() );
+        let mut packets: Vec<(Vec<u8>,Host)> = Vec::new();
+        // which datagram each packet comes from
+        let mut owners: Vec<usize> = Vec::new();
+        for (index, dg) in dgs.into_iter().enumerate() {
+            match self.prepare(dg, None) {
+                Err(err) => { results.push( Err(err) ); },
+                Ok((dst, parts)) => {
+                    results.push( Ok(0) );
+                    for bytes in parts {
+                        owners.push(index);
+                        packets.push( (bytes, dst) );
+                    }
+                },
+            }
+        }
 
-        let dst: Host = match override_dst {
+        let mut next: usize = 0;
+        while next < packets.len() {
+            let failed: Option<std::io::Error> = match self.tx.send_batch( &packets[next..] ).await {
+                Ok(sent) if sent > 0 => {
+                    for i in next..(next + sent) {
+                        if let Ok(total) = &mut results[owners[i]] {
+                            *total += packets[i].0.len();
+                        }
+                    }
+                    next += sent;
+                    None
+                },
+                Ok(_) => Some( std::io::Error::from( std::io::ErrorKind::WriteZero ) ),
+                Err(err) => Some(err),
+            };
+            // the first packet left is the one that failed, the others get their own try
+            if let Some(err) = failed {
+                *self.send_failures.lock().unwrap().entry( packets[next].1 ).or_insert(0) += 1;
+                results[owners[next]] = Err( SendErr::from(err) );
+                next += 1;
+            }
+        }
+        results
+    }
+
+    // Routed, piggybacked and fragmented: the next hop and the bytes to send it
+    fn prepare(&self, dg: Datagram, override_dst: Option<Host>) -> Result<(Host, Vec<Vec<u8>>),SendErr> {
+        let (dst, dg): (Host, Datagram) = match override_dst {
             None => { match dg.dst() {
-                None => { return 0; },
-                Some(dst) => dst,
+                None => self.resolve(dg)?,
+                Some(dst) => (dst, dg),
             } },
-            Some(dst) => dst,
+            Some(dst) => (dst, dg),
         };
-        
-        match self.tx.send_to( &dg.to_bytes(), dst.sock() ).await {
-            Err(_) => { return 0; },
-            Ok(len) => { return len; },
+        // A probe is sent as is: its size is what gets measured
+        if dg.header() == Header::PROBE {
+            return Ok( (dst, vec![ dg.to_bytes() ]) );
+        }
+
+        // Gossip rides along only when it doesn't make the datagram fragment
+        let mtu: usize = self.mtu(&dst);
+        let piggybacked: Datagram = self.piggyback( dg.clone() );
+        let dg: Datagram = match piggybacked.data().length() as usize + 2 <= mtu {
+            true => piggybacked,
+            false => dg,
+        };
+
+        let id: u16 = self.fragments.lock().unwrap().next_id();
+        match fragment( &dg.data(), id, mtu ) {
+            None => Ok( (dst, vec![ dg.to_bytes() ]) ),
+            Some(parts) => Ok( (dst, parts.iter().map( |part| Vec::from( part.to_bytes() ) ).collect()) ),
+        }
+    }
+
+    // Socket errors are counted against the destination
+    async fn transmit(&self, bytes: &[u8], dst: Host) -> Result<usize,SendErr> {
+        match self.tx.send_to( bytes, dst ).await {
+            Ok(len) => Ok(len),
+            Err(err) => {
+                *self.send_failures.lock().unwrap().entry(dst).or_insert(0) += 1;
+                Err( SendErr::from(err) )
+            },
         }
     }
+
+    pub fn send_failures(&self, dst: &Host) -> u64 {
+        self.send_failures.lock().unwrap().get(dst).copied().unwrap_or(0)
+    }
     
+    pub fn dispatch_depth(&self) -> usize {
+        self.dispatch_depth.load(Ordering::SeqCst)
+    }
+
+    pub fn dispatch_queued(&self, queued: usize) {
+        self.dispatch_depth.fetch_add(queued, Ordering::SeqCst);
+    }
+
+    pub fn dispatch_handled(&self, handled: usize) {
+        self.dispatch_depth.fetch_sub(handled, Ordering::SeqCst);
+    }
+
     pub async fn recv_from(&self) -> Result<Datagram, std::io::Error> {
-        let mut buf: [u8; 1026] = [0; 1026]; 
-        
-        let (len, addr) = self.rx.recv_from(&mut buf).await?;
+        let mut buf: Vec<u8> = self.buffers.take();
+        let received = self.rx.recv_from(&mut buf).await;
+        let dg = received.map( |(len, addr)| Network::decode( &buf[..len], addr ) );
+        self.buffers.give(buf);
+        dg
+    }
 
-        let mut tmp_vec: Vec<u8> = Vec::from(buf);
-        tmp_vec.truncate(len);
-        
-        match Datagram::from_bytes( Some( Host::from(addr) ), tmp_vec, None ) {
-            None => Ok( Datagram::new( Some( Host::from(addr) ), TLV::new(Header::UNKNOWN,None).unwrap(), None ) ),
-            Some(dg) => Ok(dg),
+    // At least one datagram, and those already waiting behind it
+    pub async fn recv_batch(&self) -> Result<Vec<Datagram>, std::io::Error> {
+        let mut bufs: Vec<Vec<u8>> = (0..BATCH_SIZE).map( |_| self.buffers.take() ).collect();
+        let mut meta: Vec<(usize,Host)> = vec![ (0, Host::from( std::net::SocketAddr::from( ([0, 0, 0, 0], 0) ) )); BATCH_SIZE ];
+        let received = self.rx.recv_batch(&mut bufs, &mut meta).await;
+        let dgs = received.map( |count| (0..count).map( |i| Network::decode( &bufs[i][..meta[i].0], meta[i].1 ) ).collect() );
+        for buf in bufs {
+            self.buffers.give(buf);
         }
+        dgs
     }
 
-    pub async fn multicast(&self,dg: Datagram) {
+    fn decode(bytes: &[u8], addr: Host) -> Datagram {
+        match Datagram::from_bytes( Some(addr), bytes.to_vec(), None ) {
+            None => Datagram::new( Some(addr), TLV::new(Header::UNKNOWN,None).unwrap(), None ),
+            Some(dg) => dg,
+        }
+    }
+
+    // The clients it could not be sent to, with why
+    pub async fn multicast(&self,dg: Datagram) -> Vec<(Host,SendErr)> {
         let clients: HashMap<IpAddr,Host>;
         
         {
             clients = self.clients.lock().unwrap().clone();
         }
 
+        let mut failed: Vec<(Host,SendErr)> = Vec::new();
         for (_,client) in clients.iter() {
-            self.send_to( dg.clone(), Some(*client) ).await;
+            if let Err(err) = self.send_to( dg.clone(), Some(*client) ).await {
+                failed.push( (*client, err) );
+            }
         }
+        failed
     }
 
     // Use the gateway to JOIN the network, in decentralized networks server != gateway
-    pub async fn broadcast(&self,dg: Datagram) {
-        if *self.broadcastable {
-            self.send_to( dg, Some(*self.gateway) ).await;
+    pub async fn broadcast(&self,dg: Datagram) -> Result<usize,SendErr> {
+        match *self.broadcastable {
+            false => Err(SendErr::NoDestination),
+            true => {
+                let gateway: Host = self.gateway();
+                self.send_to( dg, Some(gateway) ).await
+            },
         }
     }
 
@@ -89,7 +623,7 @@ impl Network {
     }
 
     pub fn insert(&mut self, client: &Host) -> bool {
-        if self.contains(&client) {
+        if self.contains(client) {
             false
         }
         else {
@@ -99,28 +633,875 @@ impl Network {
     }
 
     pub fn remove(&mut self, client: &Host) -> bool {
+        let lost: Vec<PeerId> = self.peers.lock().unwrap().iter().filter( |(_, host)| host.ip() == client.ip() ).map( |(id, _)| *id ).collect();
+        for id in lost.iter() {
+            self.routes.lock().unwrap().neighbour_lost(id);
+            self.sessions.lock().unwrap().unpin(id);
+        }
+        self.names.lock().unwrap().retain( |id, _| !lost.contains(id) );
+        self.peers.lock().unwrap().retain( |_, host| host.ip() != client.ip() );
+        self.pmtu.lock().unwrap().forget(client);
+        self.sessions.lock().unwrap().forget(client);
         match  self.clients.lock().unwrap().remove( &client.ip() ) {
             None => false,
             Some(_) => true,
         }
     }
 
+    // Bind a peer ID to the endpoint it was last seen on, returns true for a newcomer. An ID already
+    // bound only moves to an endpoint whose session proved it
+    pub fn insert_peer(&mut self, id: PeerId, client: &Host) -> bool {
+        if !self.may_bind(id, client) {
+            return false;
+        }
+        self.insert(client);
+        self.peers.lock().unwrap().insert( id, *client ).is_none()
+    }
+
+    fn may_bind(&self, id: PeerId, client: &Host) -> bool {
+        match self.peer(&id) {
+            None => true,
+            Some(bound) => bound == *client || self.session(client).and_then( |session| session.id() ) == Some(id),
+        }
+    }
+
+    pub fn peer(&self, id: &PeerId) -> Option<Host> {
+        self.peers.lock().unwrap().get(id).copied()
+    }
+
+    // Names announced in HELLO, a name stays with the first peer announcing it until that peer is
+    // removed, other claims are refused
+    pub fn name_peer(&self, id: PeerId, name: &str) -> bool {
+        if !valid_name(name) {
+            return false;
+        }
+        let name: String = name.to_lowercase();
+        let mut names = self.names.lock().unwrap();
+        if names.iter().any( |(other, taken)| *other != id && *taken == name ) {
+            return false;
+        }
+        names.insert(id, name);
+        true
+    }
+
+    pub fn peer_name(&self, id: &PeerId) -> Option<String> {
+        self.names.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn names(&self) -> HashMap<PeerId,String> {
+        self.names.lock().unwrap().clone()
+    }
+
+    // Overlay addresses first, then the endpoint. None for a name nobody has
+    pub fn resolve_name(&self, name: &str) -> Option<Vec<IpAddr>> {
+        let name: String = name.to_lowercase();
+        let id: PeerId = match self.name.as_ref() {
+            Some(own) if *own == name => self.id(),
+            _ => *self.names.lock().unwrap().iter().find( |(_, taken)| **taken == name )?.0,
+        };
+
+        let mut ips: Vec<IpAddr> = self.overlay.lock().unwrap().iter().filter( |(_, owner)| **owner == id ).map( |(ip, _)| *ip ).collect();
+        ips.sort();
+        let endpoint: Option<Host> = match id == self.id() {
+            true => Some( self.local_addr() ),
+            false => self.peer(&id).or_else( || self.members().get(&id).map( |member| member.host() ) ),
+        };
+        if let Some(host) = endpoint {
+            if !ips.contains( &host.ip() ) && !host.ip().is_unspecified() {
+                ips.push( host.ip() );
+            }
+        }
+        Some(ips)
+    }
+
+    // Answer a DNS query for <name>.toktok, anything else is refused
+    pub fn dns_answer(&self, bytes: &[u8]) -> Option<Vec<u8>> {
+        let query: DnsQuery = DnsQuery::from_bytes(bytes)?;
+        if !query.standard() {
+            return Some( query.response( Rcode::NotImp, &[] ) );
+        }
+        let name: String = match node_name( query.qname() ) {
+            None => { return Some( query.response( Rcode::Refused, &[] ) ); },
+            Some(name) => name,
+        };
+        match self.resolve_name(&name) {
+            None => Some( query.response( Rcode::NxDomain, &[] ) ),
+            Some(ips) => Some( query.response( Rcode::NoError, &query.wanted(&ips) ) ),
+        }
+    }
+
+    pub fn peer_id(&self, client: &Host) -> Option<PeerId> {
+        self.peers.lock().unwrap().iter().find( |(_, host)| *host == client ).map( |(id, _)| *id )
+    }
+
+    // Whether `client` already showed it is `id`, by its HELLO or on its session
+    pub fn bound(&self, id: &PeerId, client: &Host) -> bool {
+        self.peer(id) == Some(*client) || self.session(client).and_then( |session| session.id() ) == Some(*id)
+    }
+
+    // Wrap the datagram into a RELAY envelope and hand it to `via`
+    pub async fn relay_to(&self, dg: Datagram, via: Host, dst: PeerId) -> Result<usize,SendErr> {
+        match Relay::new( self.id(), dst, &dg.data() ).to_tlv() {
+            None => Err(SendErr::TooLarge),
+            Some(tlv) => self.send_to( Datagram::from(tlv), Some(via) ).await,
+        }
+    }
+
+    // Direct neighbour first, otherwise the endpoint of the next hop given by the routing table
+    pub fn next_hop(&self, dst: &PeerId) -> Option<Host> {
+        if let Some(host) = self.peer(dst) {
+            return Some(host);
+        }
+        let via: Option<PeerId> = self.routes.lock().unwrap().next_hop(dst);
+        match via {
+            None => None,
+            Some(via) => self.peer(&via),
+        }
+    }
+
+    // Datagram addressed by peer ID: sent as is to a neighbour, wrapped into a RELAY otherwise
+    fn resolve(&self, dg: Datagram) -> Result<(Host, Datagram),SendErr> {
+        let dst: PeerId = dg.peer().ok_or(SendErr::NoDestination)?;
+        let next: Host = self.next_hop(&dst).ok_or(SendErr::NoDestination)?;
+
+        if self.peer(&dst).is_some() {
+            Ok( (next, dg) )
+        }
+        else {
+            let tlv = Relay::new( self.id(), dst, &dg.data() ).to_tlv().ok_or(SendErr::TooLarge)?;
+            Ok( (next, Datagram::new( None, tlv, Some(next) )) )
+        }
+    }
+
+    pub fn advertisement(&self) -> Advertisement {
+        self.routes.lock().unwrap().advertisement()
+    }
+
+    pub fn update_routes(&self, from: PeerId, adv: &Advertisement) -> bool {
+        self.routes.lock().unwrap().update(from, adv)
+    }
+
+    pub fn expire_routes(&self, max_age: tokio::time::Duration) {
+        self.routes.lock().unwrap().expire(max_age)
+    }
+
+    pub fn routing_table(&self) -> RoutingTable {
+        self.routes.lock().unwrap().clone()
+    }
+
+    // A peer introduced itself, it joins the membership and our client table
+    pub fn member_alive(&mut self, id: PeerId, client: &Host) -> bool {
+        if !self.may_bind(id, client) {
+            return false;
+        }
+        self.insert_peer(id, client);
+        self.membership.lock().unwrap().alive(id, *client, tokio::time::Instant::now())
+    }
+
+    pub fn members(&self) -> HashMap<PeerId,Member> {
+        self.membership.lock().unwrap().members()
+    }
+
+    pub fn member(&self, id: &PeerId) -> Option<Member> {
+        self.membership.lock().unwrap().member(id)
+    }
+
+    pub fn swim_tick(&self) -> Vec<SwimAction> {
+        self.membership.lock().unwrap().tick( tokio::time::Instant::now() )
+    }
+
+    // Apply gossip, joins and evictions are carried out right away. Gossip proves nothing about a
+    // host: a joining member is greeted and only bound once its session proved the id, and only the
+    // host a member is bound to is evicted
+    pub fn apply_gossip(&mut self, updates: Vec<MemberUpdate>) {
+        let now = tokio::time::Instant::now();
+        for update in updates {
+            let action: Option<SwimAction> = self.membership.lock().unwrap().apply(update, now);
+            match action {
+                Some(SwimAction::Join(id, host)) => {
+                    match self.session(&host).and_then( |session| session.id() ) == Some(id) {
+                        true => { self.insert_peer(id, &host); },
+                        false => { self.insert(&host); },
+                    }
+                },
+                Some(SwimAction::Evict(id, host)) if self.peer(&id) == Some(host) => { self.evict(&host); },
+                _ => {},
+            }
+        }
+    }
+
+    // `client` answered, returns the members that asked us to probe it
+    pub fn ack(&self, client: &Host) -> Vec<Host> {
+        match self.peer_id(client) {
+            None => Vec::new(),
+            Some(id) => self.ack_id(&id),
+        }
+    }
+
+    pub fn ack_id(&self, id: &PeerId) -> Vec<Host> {
+        self.membership.lock().unwrap().ack( id, tokio::time::Instant::now() )
+    }
+
+    pub fn indirect_probe(&self, target: PeerId, requester: Host) {
+        self.membership.lock().unwrap().indirect_probe(target, requester)
+    }
+
+    // PING carrying a fresh nonce, its PONG feeds the link statistics
+    pub fn ping(&self, client: Host) -> Datagram {
+        let payload: Vec<u8> = self.links.lock().unwrap().ping( client, tokio::time::Instant::now() );
+        Datagram::new( None, TLV::new( Header::PING, Some(payload) ).unwrap(), Some(client) )
+    }
+
+    pub fn pong(&self, client: &Host, payload: &[u8]) -> Option<tokio::time::Duration> {
+        self.links.lock().unwrap().pong( client, payload, tokio::time::Instant::now() )
+    }
+
+    pub fn expire_pings(&self) {
+        self.links.lock().unwrap().expire( tokio::time::Instant::now() )
+    }
+
+    pub fn link_stats(&self, client: &Host) -> Option<LinkStats> {
+        self.links.lock().unwrap().stats(client)
+    }
+
+    pub fn peer_link_stats(&self, id: &PeerId) -> Option<LinkStats> {
+        self.link_stats( &self.peer(id)? )
+    }
+
+    pub fn links(&self) -> HashMap<Host,LinkStats> {
+        self.links.lock().unwrap().links()
+    }
+
+    // How long the emitter must hold `dg` back, the bandwidth is reserved right away
+    pub fn pace(&self, dg: &Datagram) -> tokio::time::Duration {
+        let dst: Host = match dg.dst() {
+            Some(dst) => dst,
+            None => match self.resolve( dg.clone() ) {
+                Err(_) => { return tokio::time::Duration::ZERO; },
+                Ok((dst, _)) => dst,
+            },
+        };
+        let stats: Option<LinkStats> = self.link_stats(&dst);
+        let bytes: usize = dg.data().length() as usize + 2;
+        self.pacer.lock().unwrap().reserve( dst, bytes, stats, tokio::time::Instant::now() )
+    }
+
+    // The largest datagram the path to `dst` carries, as far as probing found
+    pub fn mtu(&self, dst: &Host) -> usize {
+        self.pmtu.lock().unwrap().mtu(dst)
+    }
+
+    // One padded PROBE per client whose path MTU search goes on
+    pub fn pmtu_probes(&self) -> Vec<Datagram> {
+        let now = tokio::time::Instant::now();
+        let clients: Vec<Host> = self.clients.lock().unwrap().values().copied().collect();
+        let mut pmtu = self.pmtu.lock().unwrap();
+        clients.into_iter()
+            .filter_map( |client| Some( (client, pmtu.probe(client, now)?) ) )
+            .filter_map( |(client, size)| Some( Datagram::new( None, PmtuMessage::Probe { size: size as u16 }.to_tlv()?, Some(client) ) ) )
+            .collect()
+    }
+
+    pub fn pmtu_acked(&self, client: &Host, size: u16) {
+        self.pmtu.lock().unwrap().acked( client, size as usize )
+    }
+
+    // The TLV a FRAGMENT completes, if any
+    pub fn reassemble(&self, client: Host, fragment: PmtuMessage) -> Option<TLV> {
+        self.fragments.lock().unwrap().reassemble( client, fragment, tokio::time::Instant::now() )
+    }
+
+    // Datagrams for the same endpoint are merged into MULTIPLEs as long as they fit its path MTU,
+    // the ones routed by peer ID and the probes are left alone
+    pub fn batch(&self, dgs: Vec<Datagram>) -> Vec<Datagram> {
+        let mut batched: Vec<Datagram> = Vec::new();
+        for dg in dgs {
+            let (dst, alone): (Option<Host>, bool) = (dg.dst(), dg.peer().is_some() || dg.header() == Header::PROBE);
+            let mtu: usize = match (dst, alone) {
+                (Some(dst), false) => self.mtu(&dst),
+                _ => { batched.push(dg); continue; },
+            };
+
+            let merged: Option<(usize,TLV)> = batched.iter().enumerate()
+                .filter( |(_, other)| other.dst() == dst && other.peer().is_none() && other.header() != Header::PROBE )
+                .find_map( |(position, other)| {
+                    let merged: TLV = TLV::merge( other.data(), dg.data() )?;
+                    (merged.length() as usize + 2 <= mtu).then_some( (position, merged) )
+                });
+            match merged {
+                None => { batched.push(dg); },
+                Some((position, merged)) => { batched[position] = Datagram::new( dg.src(), merged, dst ); },
+            }
+        }
+        batched
+    }
+
+    pub fn congestion_window(&self, dst: &Host) -> Option<f64> {
+        self.pacer.lock().unwrap().window(dst)
+    }
+
+    // Lowest RTT once inflated by the loss rate, unmeasured links come last
+    pub fn fastest(&self, candidates: &[Host]) -> Option<Host> {
+        let links = self.links.lock().unwrap();
+        candidates.iter().copied().min_by_key( |host| {
+            match links.stats(host).and_then( |stats| Some( (stats.srtt()?, stats.loss_rate()) ) ) {
+                None => tokio::time::Duration::MAX,
+                Some((srtt, loss)) => srtt.mul_f64( 1.0 / (1.0 - loss.min(0.99)) ),
+            }
+        })
+    }
+
+    // Dead peers leave every table
+    pub fn evict(&mut self, client: &Host) -> bool {
+        if let Some(id) = self.peer_id(client) {
+            self.dht.lock().unwrap().table_mut().remove(&id);
+        }
+        self.remove(client)
+    }
+
+    // Membership updates ride along whatever we send, as long as it still fits
+    pub fn piggyback(&self, dg: Datagram) -> Datagram {
+        let updates: Vec<MemberUpdate> = self.membership.lock().unwrap().pending_updates(GOSSIP_MAX_UPDATES);
+        if updates.is_empty() {
+            return dg;
+        }
+
+        match gossip_to_tlv(&updates) {
+            None => dg,
+            Some(gossip) => match TLV::merge( dg.data(), gossip ) {
+                None => dg,
+                Some(merged) => {
+                    let mut piggybacked = Datagram::new( dg.src(), merged, dg.dst() );
+                    if let Some(peer) = dg.peer() {
+                        piggybacked = piggybacked.with_peer(peer);
+                    }
+                    piggybacked
+                },
+            },
+        }
+    }
+
+    // Any DHT message tells us the sender is alive at this endpoint
+    pub fn dht_observe(&self, id: PeerId, host: &Host) {
+        self.dht.lock().unwrap().seen( Contact::new(id, *host) );
+    }
+
+    pub fn dht_contacts(&self) -> usize {
+        self.dht.lock().unwrap().table().len()
+    }
+
+    // Answer a query, or hand an answer to the lookup waiting for it
+    pub fn dht_answer(&self, message: DhtMessage, from: &Host) -> Option<DhtMessage> {
+        let mut dht = self.dht.lock().unwrap();
+        let sender: PeerId = self.id();
+
+        match message {
+            DhtMessage::FindNode { nonce, target, .. } => {
+                Some( DhtMessage::Nodes { nonce, sender, contacts: dht.table().closest(&target, K) } )
+            },
+            DhtMessage::FindValue { nonce, key, .. } => {
+                let value: Option<Vec<Host>> = match key == sender {
+                    true => Some( vec![ self.local_addr() ] ),
+                    false => dht.value(&key),
+                };
+                match value {
+                    Some(hosts) => Some( DhtMessage::Value { nonce, sender, key, hosts } ),
+                    None => Some( DhtMessage::Nodes { nonce, sender, contacts: dht.table().closest(&key, K) } ),
+                }
+            },
+            // An unspecified address means "where you see me from". Peers only store their own
+            // record, and it must lead back to where it came from: the port may differ when sending
+            // from another socket than the one we listen on
+            DhtMessage::Store { sender, key, hosts, .. } => {
+                let hosts: Vec<Host> = hosts.iter().map( |host| match host.ip().is_unspecified() {
+                    true => Host::from( std::net::SocketAddr::new( from.ip(), host.port() ) ),
+                    false => *host,
+                } ).collect();
+                if key == sender && hosts.iter().any( |host| host.ip() == from.ip() ) {
+                    dht.store(key, hosts, tokio::time::Instant::now());
+                }
+                None
+            },
+            answer => {
+                if let Some(waiting) = dht.waiting( &answer.nonce() ) {
+                    waiting.try_send(answer).ok();
+                }
+                None
+            },
+        }
+    }
+
+    // Iterative lookup: ask the ALPHA closest contacts we did not ask yet until nothing closer shows up
+    async fn lookup(&self, target: PeerId, value: bool) -> (Vec<Contact>, Option<Vec<Host>>) {
+        let (reply, mut answers) = tokio::sync::mpsc::channel::<DhtMessage>(K * ALPHA);
+        let mut shortlist: Vec<Contact> = self.dht.lock().unwrap().table().closest(&target, K);
+        let mut queried: HashSet<PeerId> = HashSet::new();
+        let mut found: Option<Vec<Host>> = None;
+
+        loop {
+            let batch: Vec<Contact> = shortlist.iter().filter( |c| !queried.contains(&c.id()) ).take(ALPHA).copied().collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut waiting: HashMap<u32,Contact> = HashMap::new();
+            for contact in batch.iter() {
+                queried.insert( contact.id() );
+                let nonce: u32 = self.dht.lock().unwrap().register( reply.clone() );
+                waiting.insert(nonce, *contact);
+
+                let query = match value {
+                    true => DhtMessage::FindValue { nonce, sender: self.id(), key: target },
+                    false => DhtMessage::FindNode { nonce, sender: self.id(), target },
+                };
+                if let Some(tlv) = query.to_tlv() {
+                    self.send_to( Datagram::from(tlv), Some( contact.host() ) ).await.ok();
+                }
+            }
+
+            let deadline = tokio::time::Instant::now() + DHT_TIMEOUT;
+            while !waiting.is_empty() {
+                let answer: DhtMessage = match tokio::time::timeout_at(deadline, answers.recv()).await {
+                    Ok(Some(answer)) => answer,
+                    _ => { break; },
+                };
+                if waiting.remove( &answer.nonce() ).is_none() {
+                    continue;
+                }
+                self.dht.lock().unwrap().unregister( &answer.nonce() );
+
+                match answer {
+                    DhtMessage::Nodes { contacts, .. } => {
+                        for contact in contacts {
+                            if contact.id() != self.id() && !shortlist.iter().any( |c| c.id() == contact.id() ) {
+                                shortlist.push(contact);
+                            }
+                        }
+                    },
+                    DhtMessage::Value { hosts, .. } => { found = Some(hosts); },
+                    _ => {},
+                }
+            }
+
+            // Silent contacts are dropped from the lookup, and from our buckets once they kept
+            // silent for STALE_LIMIT lookups in a row: a single lost datagram is not a dead peer
+            for (nonce, contact) in waiting.iter() {
+                let mut dht = self.dht.lock().unwrap();
+                dht.unregister(nonce);
+                dht.failed( &contact.id() );
+                shortlist.retain( |c| c.id() != contact.id() );
+            }
+
+            if found.is_some() {
+                break;
+            }
+            shortlist.sort_by_key( |c| distance(&c.id(), &target) );
+            shortlist.truncate(K);
+        }
+
+        (shortlist, found)
+    }
+
+    pub async fn find_node(&self, target: PeerId) -> Vec<Contact> {
+        self.lookup(target, false).await.0
+    }
+
+    pub async fn find_value(&self, key: PeerId) -> Option<Vec<Host>> {
+        self.lookup(key, true).await.1
+    }
+
+    // Join the DHT through a few known endpoints, returns how many contacts we ended up with
+    pub async fn bootstrap(&self, hosts: &[Host]) -> usize {
+        let (reply, mut answers) = tokio::sync::mpsc::channel::<DhtMessage>(hosts.len().max(1));
+        let mut nonces: Vec<u32> = Vec::new();
+
+        for host in hosts.iter() {
+            let nonce: u32 = self.dht.lock().unwrap().register( reply.clone() );
+            nonces.push(nonce);
+            if let Some(tlv) = ( DhtMessage::FindNode { nonce, sender: self.id(), target: self.id() } ).to_tlv() {
+                self.send_to( Datagram::from(tlv), Some(*host) ).await.ok();
+            }
+        }
+
+        // Answering hosts are put in our buckets by the handler, their contacts are added here
+        let deadline = tokio::time::Instant::now() + DHT_TIMEOUT;
+        for _ in 0..nonces.len() {
+            match tokio::time::timeout_at(deadline, answers.recv()).await {
+                Ok(Some(DhtMessage::Nodes { contacts, .. })) => {
+                    let mut dht = self.dht.lock().unwrap();
+                    for contact in contacts {
+                        dht.table_mut().insert(contact);
+                    }
+                },
+                Ok(Some(_)) => {},
+                _ => { break; },
+            }
+        }
+        for nonce in nonces.iter() {
+            self.dht.lock().unwrap().unregister(nonce);
+        }
+
+        self.find_node( self.id() ).await;
+        self.dht_contacts()
+    }
+
+    // Store our endpoints on the K nodes closest to our ID
+    pub async fn publish(&self) -> usize {
+        let closest: Vec<Contact> = self.find_node( self.id() ).await;
+        let store = DhtMessage::Store { nonce: 0, sender: self.id(), key: self.id(), hosts: vec![ self.local_addr() ] };
+
+        if let Some(tlv) = store.to_tlv() {
+            for contact in closest.iter() {
+                self.send_to( Datagram::from(tlv.clone()), Some( contact.host() ) ).await.ok();
+            }
+        }
+        closest.len()
+    }
+
+    // Current endpoints of any peer: known neighbour, DHT contact, or stored record
+    pub async fn locate(&self, id: PeerId) -> Option<Vec<Host>> {
+        if let Some(host) = self.peer(&id) {
+            return Some( vec![host] );
+        }
+        let contact: Option<Contact> = self.dht.lock().unwrap().table().get(&id);
+        if let Some(contact) = contact {
+            return Some( vec![ contact.host() ] );
+        }
+        if let Some(hosts) = self.find_value(id).await {
+            return Some(hosts);
+        }
+        self.find_node(id).await.iter().find( |c| c.id() == id ).map( |c| vec![ c.host() ] )
+    }
+
+    // Overlay addresses and the peers owning them
+    pub fn assign_overlay(&self, ip: IpAddr, id: PeerId) {
+        self.overlay.lock().unwrap().insert(ip, id);
+    }
+
+    pub fn overlay_peer(&self, ip: &IpAddr) -> Option<PeerId> {
+        self.overlay.lock().unwrap().get(ip).copied()
+    }
+
+    pub fn overlay_addresses(&self) -> HashMap<IpAddr,PeerId> {
+        self.overlay.lock().unwrap().clone()
+    }
+
+    // Packets for the local system, only one tunnel at a time
+    pub fn attach_tunnel(&self) -> mpsc::Receiver<Vec<u8>> {
+        let (sender, receiver) = mpsc::channel(1024);
+        *self.tunnel.lock().unwrap() = Some(sender);
+        receiver
+    }
+
+    // Dropped when no tunnel is attached or it can't keep up, like a full interface queue
+    pub fn deliver_packet(&self, packet: Vec<u8>) -> bool {
+        match self.tunnel.lock().unwrap().as_ref() {
+            None => false,
+            Some(sender) => sender.try_send(packet).is_ok(),
+        }
+    }
+
+    // Wrap a local packet for the peer owning its destination address
+    pub fn encapsulate(&self, packet: &[u8]) -> Option<Datagram> {
+        let id: PeerId = self.overlay_peer( &ip_destination(packet)? )?;
+        Some( Datagram::from( packet_to_tlv(packet)? ).with_peer(id) )
+    }
+
+    pub fn overlay_ip(&self) -> Option<IpAddr> {
+        self.lease.lock().unwrap().map( |lease| lease.ip() )
+    }
+
+    pub fn client_lease(&self) -> Option<ClientLease> {
+        *self.lease.lock().unwrap()
+    }
+
+    pub fn pool(&self) -> bool {
+        self.pool.lock().unwrap().is_some()
+    }
+
+    // Nothing to ask for before the lease is halfway through
+    pub fn lease_due(&self) -> bool {
+        match *self.lease.lock().unwrap() {
+            None => true,
+            Some(lease) => tokio::time::Instant::now() >= lease.renew_at(),
+        }
+    }
+
+    pub fn lease_request(&self) -> LeaseMessage {
+        LeaseMessage::Request { id: self.id(), requested: self.overlay_ip() }
+    }
+
+    // Server side: confirm the requested address, or offer one
+    pub fn lease_answer(&self, request: LeaseMessage) -> Option<LeaseMessage> {
+        let (id, requested) = match request {
+            LeaseMessage::Request { id, requested } => (id, requested),
+            _ => { return None; },
+        };
+        let now: u64 = unix_now();
+        let mut guard = self.pool.lock().unwrap();
+        let pool = guard.as_mut()?;
+        let (prefix, duration) = (pool.cidr().prefix(), pool.duration());
+
+        if let Some(ip) = requested {
+            if let Some(lease) = pool.commit(id, ip, now) {
+                let (store, stale) = (pool.store(), pool.take_stale());
+                drop(guard);
+                persist_leases(store, stale, Some(lease));
+                self.assign_overlay( lease.ip(), id );
+                return Some( LeaseMessage::Ack { ip, prefix, duration } );
+            }
+        }
+        let offered = pool.offer(id, requested, now);
+        let (store, stale) = (pool.store(), pool.take_stale());
+        drop(guard);
+        persist_leases(store, stale, None);
+        Some( LeaseMessage::Offer { ip: offered?, prefix, duration } )
+    }
+
+    // Server side: whether `ip` is leased to `id` out of our pool
+    pub fn lease_held(&self, id: &PeerId, ip: &IpAddr) -> bool {
+        match self.pool.lock().unwrap().as_ref() {
+            None => false,
+            Some(pool) => pool.lease(id).is_some_and( |lease| lease.ip() == *ip && lease.expires() > unix_now() ),
+        }
+    }
+
+    pub fn lease_acquired(&self, ip: IpAddr, prefix: u8, duration: u32) {
+        *self.lease.lock().unwrap() = Some( ClientLease::new( ip, prefix, duration, tokio::time::Instant::now() ) );
+        self.assign_overlay( ip, self.id() );
+    }
+
+    // The server leases its own address straight from the pool
+    pub fn lease_local(&self) -> bool {
+        let mut answer: Option<LeaseMessage> = self.lease_answer( self.lease_request() );
+        if let Some(LeaseMessage::Offer { ip, .. }) = answer {
+            answer = self.lease_answer( LeaseMessage::Request { id: self.id(), requested: Some(ip) } );
+        }
+        match answer {
+            Some(LeaseMessage::Ack { ip, prefix, duration }) => {
+                self.lease_acquired(ip, prefix, duration);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // Expired addresses stop being routed, ours included
+    pub fn expire_leases(&self) {
+        let (expired, store, stale): (Vec<Lease>, Option<LeaseStore>, Vec<PeerId>) = match self.pool.lock().unwrap().as_mut() {
+            None => (Vec::new(), None, Vec::new()),
+            Some(pool) => (pool.expire( unix_now() ), pool.store(), pool.take_stale()),
+        };
+        persist_leases(store, stale, None);
+        {
+            let mut overlay = self.overlay.lock().unwrap();
+            for lease in expired {
+                if overlay.get( &lease.ip() ) == Some( &lease.id() ) {
+                    overlay.remove( &lease.ip() );
+                }
+            }
+        }
+
+        let mut own = self.lease.lock().unwrap();
+        if own.is_some_and( |lease| lease.expired( tokio::time::Instant::now() ) ) {
+            *own = None;
+        }
+    }
+
+    pub fn local_services(&self) -> Vec<Service> {
+        self.local_services.to_vec()
+    }
+
+    // One ANNOUNCE per local service matching `name`, all of them without name
+    pub fn announcements(&self, name: Option<&str>) -> Vec<Datagram> {
+        self.local_services.iter()
+            .filter( |service| service.provided() && name.is_none_or( |name| service.name() == name ) )
+            .filter_map( |service| ServiceMessage::Announce { ttl: SERVICE_TTL.as_secs() as u32, service: service.clone() }.to_tlv() )
+            .map(Datagram::from)
+            .collect()
+    }
+
+    // A service announced without server runs on `from`
+    pub fn register_service(&self, service: Service, ttl: u32, from: &Host) {
+        let server: Host = Host::from( std::net::SocketAddr::new( from.ip(), service.port() ) );
+        let ttl = tokio::time::Duration::from_secs(ttl as u64).min(SERVICE_TTL);
+        self.services.lock().unwrap().register( service.with_server(server), ttl, tokio::time::Instant::now() );
+    }
+
+    pub fn expire_services(&self) {
+        self.services.lock().unwrap().expire( tokio::time::Instant::now() );
+    }
+
+    // Known providers of `name`, ours first
+    pub fn lookup_service(&self, name: &str) -> Vec<Service> {
+        let local: Host = self.local_addr();
+        let mut found: Vec<Service> = self.local_services.iter()
+            .filter( |service| service.provided() && service.name() == name )
+            .map( |service| service.clone().with_server( Host::from( std::net::SocketAddr::new( local.ip(), service.port() ) ) ) )
+            .collect();
+        for (service, _) in self.services.lock().unwrap().lookup( name, tokio::time::Instant::now() ) {
+            if !found.contains(&service) {
+                found.push(service);
+            }
+        }
+        found
+    }
+
+    pub fn known_services(&self) -> Vec<Service> {
+        self.services.lock().unwrap().services( tokio::time::Instant::now() )
+    }
+
+    // Ask every client, then give the answers some time to come back.
+    // Announces are only registered while the handler is running
+    pub async fn query_service(&self, name: &str) -> Vec<Service> {
+        if let Some(tlv) = (ServiceMessage::Query { name: name.to_string() }).to_tlv() {
+            self.multicast( Datagram::from(tlv) ).await;
+            tokio::time::sleep(SERVICE_TIMEOUT).await;
+        }
+        self.lookup_service(name)
+    }
+
+    // Only the ports of the services we provide can be reached through a stream
+    pub fn forwardable(&self, port: u16) -> bool {
+        self.local_services.iter().any( |service| service.provided() && service.server().is_none() && service.port() == port )
+    }
+
+    // Ask `peer` to connect to its local `port`, what it sends back comes out of the receiver
+    pub fn stream_open(&self, peer: Host, port: u16) -> (StreamKey, Datagram, mpsc::Receiver<Vec<u8>>) {
+        let (deliver, delivered) = mpsc::channel(STREAM_WINDOW);
+        let (key, open) = self.streams.lock().unwrap().open( peer, port, deliver, tokio::time::Instant::now() );
+        (key, stream_datagram(peer, &open), delivered)
+    }
+
+    // None if the OPEN was already accepted
+    pub fn stream_accept(&self, peer: Host, open: &StreamMessage) -> Option<(StreamKey, mpsc::Receiver<Vec<u8>>)> {
+        let key = StreamKey::received( peer, open.stream() );
+        let (deliver, delivered) = mpsc::channel(STREAM_WINDOW);
+        match self.streams.lock().unwrap().accept( key, open.seq()?, deliver ) {
+            false => None,
+            true => Some( (key, delivered) ),
+        }
+    }
+
+    pub fn stream_write(&self, key: &StreamKey, bytes: &[u8]) -> StreamSend<Datagram> {
+        match self.streams.lock().unwrap().write( key, bytes, tokio::time::Instant::now() ) {
+            StreamSend::Sent(segment) => StreamSend::Sent( stream_datagram( key.peer(), &segment ) ),
+            StreamSend::Blocked(space) => StreamSend::Blocked(space),
+            StreamSend::Gone => StreamSend::Gone,
+        }
+    }
+
+    pub fn stream_shutdown(&self, key: &StreamKey) -> Option<Datagram> {
+        let close: StreamMessage = self.streams.lock().unwrap().shutdown( key, tokio::time::Instant::now() )?;
+        Some( stream_datagram( key.peer(), &close ) )
+    }
+
+    // OPEN, DATA or CLOSE from `peer`, the answer is the ACK to send back
+    pub fn stream_receive(&self, peer: Host, segment: StreamMessage) -> Option<Datagram> {
+        let key = StreamKey::received( peer, segment.stream() );
+        let ack: StreamMessage = self.streams.lock().unwrap().receive( &key, segment )?;
+        Some( stream_datagram(peer, &ack) )
+    }
+
+    pub fn stream_acknowledge(&self, peer: Host, ack: &StreamMessage) {
+        if let StreamMessage::Ack { stream, next, window } = ack {
+            self.streams.lock().unwrap().acknowledge( &StreamKey::received(peer, *stream), *next, *window );
+        }
+    }
+
+    pub fn stream_retransmit(&self) -> Vec<Datagram> {
+        let segments = self.streams.lock().unwrap().retransmit( tokio::time::Instant::now() );
+        segments.iter().map( |(peer, segment)| stream_datagram(*peer, segment) ).collect()
+    }
+
+    pub fn stream_remove(&self, key: &StreamKey) {
+        self.streams.lock().unwrap().remove(key);
+    }
+
+    pub fn streams(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    pub fn account_relay(&self, src: PeerId, bytes: usize, forwarded: bool) {
+        self.relayed.lock().unwrap().entry(src).or_default().account(bytes, forwarded);
+    }
+
+    pub fn relay_stats(&self, src: &PeerId) -> Option<RelayStats> {
+        self.relayed.lock().unwrap().get(src).copied()
+    }
+
+    pub fn relay_usage(&self) -> HashMap<PeerId,RelayStats> {
+        self.relayed.lock().unwrap().clone()
+    }
+
     pub fn clone(&self) -> Self {
         Self { 
             server: Arc::clone(&self.server), 
             gateway: Arc::clone(&self.gateway),
+            hostnames: Arc::clone(&self.hostnames),
             rx: Arc::clone(&self.rx),
             tx: Arc::clone(&self.tx),
             clients: Arc::clone(&self.clients),
             broadcastable: Arc::clone(&self.broadcastable),
+            id: Arc::clone(&self.id),
+            peers: Arc::clone(&self.peers),
+            relayed: Arc::clone(&self.relayed),
+            routes: Arc::clone(&self.routes),
+            dht: Arc::clone(&self.dht),
+            membership: Arc::clone(&self.membership),
+            links: Arc::clone(&self.links),
+            pacer: Arc::clone(&self.pacer),
+            overlay: Arc::clone(&self.overlay),
+            tunnel: Arc::clone(&self.tunnel),
+            pool: Arc::clone(&self.pool),
+            lease: Arc::clone(&self.lease),
+            services: Arc::clone(&self.services),
+            local_services: Arc::clone(&self.local_services),
+            streams: Arc::clone(&self.streams),
+            name: Arc::clone(&self.name),
+            names: Arc::clone(&self.names),
+            dns: Arc::clone(&self.dns),
+            pmtu: Arc::clone(&self.pmtu),
+            fragments: Arc::clone(&self.fragments),
+            send_failures: Arc::clone(&self.send_failures),
+            buffers: Arc::clone(&self.buffers),
+            shards: Arc::clone(&self.shards),
+            sessions: Arc::clone(&self.sessions),
+            key: Arc::clone(&self.key),
+            handlers: Arc::clone(&self.handlers),
+            middleware: Arc::clone(&self.middleware),
+            dispatch_depth: Arc::clone(&self.dispatch_depth),
         }
     }
 
     pub fn local_addr(&self) -> Host {
-        Host::from( self.rx.local_addr().unwrap() )
+        self.rx.local_addr().unwrap()
+    }
+}
+
+
+// Pool changes reach the disk from a blocking task, once the pool lock was released: removals
+// first, a lease may have expired and been granted again at once
+fn persist_leases(store: Option<LeaseStore>, stale: Vec<PeerId>, saved: Option<Lease>) {
+    if let Some(store) = store {
+        if stale.is_empty() && saved.is_none() {
+            return;
+        }
+        tokio::task::spawn_blocking( move || {
+            for id in stale.iter() {
+                store.remove(id);
+            }
+            if let Some(lease) = saved {
+                store.save(&lease);
+            }
+        });
     }
 }
 
+// Segments are at most STREAM_SEGMENT bytes, they always fit in a TLV
+fn stream_datagram(peer: Host, message: &StreamMessage) -> Datagram {
+    Datagram::new( None, message.to_tlv().unwrap(), Some(peer) )
+}
 
 // For test
 async fn echo_server(port: u16) -> std::io::Result<()> {
@@ -132,7 +1513,7 @@ async fn echo_server(port: u16) -> std::io::Result<()> {
     println!("Received: {:#?}", dg);
 
     let dg = Datagram::new(None, dg.data(), dg.src() );
-    net.send_to( dg , None ).await;
+    net.send_to( dg , None ).await.ok();
 
     Ok(())
 }
@@ -148,7 +1529,7 @@ async fn echo_client(port: u16, srv: u16) -> std::io::Result<(TLV,TLV)> {
     let net: Network = Network::new(src, None, gateway, None);
        
     // Sending data
-    net.send_to( to_send.clone() , None ).await;
+    net.send_to( to_send.clone() , None ).await.ok();
 
     // Waiting for the echo
     let received: Datagram = net.recv_from().await?;
@@ -196,7 +1577,7 @@ async fn test_broadcast() -> std::io::Result<()>{
     let net: Network = Network::new(sock, None, Host::new("127.255.255.255:4040"),None);
     let dg: Datagram = Datagram::from( TLV::new(Header::UNKNOWN, Some(vec![1,8,1])).unwrap() );
 
-    net.broadcast(dg).await;
+    net.broadcast(dg).await.ok();
     Ok(())
 }
 
@@ -227,3 +1608,90 @@ async fn test_insert_contains_remove() -> std::io::Result<()>{
     Ok(())
 }
 
+#[tokio::test(start_paused = true)]
+async fn test_send_to_peer_routed() -> std::io::Result<()>{
+    let sim = crate::network::simulated::SimNet::new(0);
+    let sock = Arc::new( sim.bind( Host::new("10.0.0.1:4648") ) );
+    let mut net: Network = Network::new(sock, None, Host::new("127.255.255.255:4648"),None);
+    let hop_sock = Arc::new( sim.bind( Host::new("10.0.0.2:4649") ) );
+    let hop: Network = Network::new(hop_sock, None, Host::new("127.255.255.255:4649"),None);
+
+    let far = PeerId::new(42);
+    net.insert_peer( hop.id(), &hop.local_addr() );
+    net.update_routes( hop.id(), &Advertisement::new( vec![
+        crate::message::route::RouteEntry::new( hop.id(), 0, 2 ),
+        crate::message::route::RouteEntry::new( far, 1, 2 ),
+    ] ) );
+    assert_eq!(Some(hop.local_addr()),net.next_hop(&far));
+
+    // unknown destination
+    assert_eq!(Err(SendErr::NoDestination),net.send_to( Datagram::from(Header::PING).with_peer( PeerId::new(7) ), None ).await);
+
+    assert!(net.send_to( Datagram::from(Header::PING).with_peer(far), None ).await.is_ok_and( |len| len > 0 ));
+    let received: Datagram = hop.recv_from().await?;
+    assert_eq!(Header::RELAY,received.header());
+    let relay = Relay::from_bytes( &received.data().payload() ).unwrap();
+    assert_eq!(far,relay.dst());
+    assert_eq!(net.id(),relay.src());
+
+    // neighbours are reached directly
+    assert!(net.send_to( Datagram::from(Header::PING).with_peer( hop.id() ), None ).await.is_ok_and( |len| len > 0 ));
+    assert_eq!(Header::PING,hop.recv_from().await?.header());
+
+    // losing the neighbour breaks the route
+    net.remove( &hop.local_addr() );
+    assert_eq!(None,net.next_hop(&far));
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_piggyback_gossip() -> std::io::Result<()>{
+    let sim = crate::network::simulated::SimNet::new(0);
+    let sock = Arc::new( sim.bind( Host::new("10.0.0.1:4652") ) );
+    let mut net: Network = Network::new(sock, None, Host::new("127.255.255.255:4652"),None);
+    let peer_sock = Arc::new( sim.bind( Host::new("10.0.0.2:4653") ) );
+    let peer: Network = Network::new(peer_sock, None, Host::new("127.255.255.255:4653"),None);
+
+    net.member_alive( peer.id(), &peer.local_addr() );
+    net.send_to( Datagram::from(Header::PING), Some( peer.local_addr() ) ).await.ok();
+
+    let received: Datagram = peer.recv_from().await?;
+    assert_eq!(Header::MULTIPLE,received.header());
+    let parts: Vec<TLV> = received.data().split().unwrap();
+    assert_eq!(Header::PING,parts[0].header());
+    assert_eq!(Header::GOSSIP,parts[1].header());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_re_resolve_hostnames() -> std::io::Result<()> {
+    let sock = Arc::new( crate::network::simulated::SimNet::new(0).bind( Host::new("127.0.0.1:4694") ) );
+    let net = Network::new( sock, None, Host::new("10.0.0.1:3333"), Some( Host::new("10.0.0.2:3333") ) )
+        .with_hostnames( Some( HostName::parse("localhost:3333").unwrap() ), Some( HostName::parse("10.0.0.3:3333").unwrap() ) );
+
+    // literal addresses are left alone
+    assert_eq!(Ok(true),net.re_resolve().await);
+    assert!(net.gateway().ip().is_loopback());
+    assert_eq!(net.server(),Some( Host::new("10.0.0.2:3333") ));
+    assert_eq!(Ok(false),net.re_resolve().await);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dht_store_own_record_only() -> std::io::Result<()> {
+    let sock = Arc::new( crate::network::simulated::SimNet::new(0).bind( Host::new("127.0.0.1:4726") ) );
+    let net = Network::new(sock, None, Host::new("127.255.255.255:4726"), None);
+    let (owner, other) = (PeerId::new(1), PeerId::new(2));
+    let from = Host::new("10.0.0.1:2222");
+    let find = |key: PeerId| net.dht_answer( DhtMessage::FindValue { nonce: 0, sender: other, key }, &from );
+
+    // someone else's record, or one leading elsewhere, is not kept
+    assert!(net.dht_answer( DhtMessage::Store { nonce: 0, sender: other, key: owner, hosts: vec![from] }, &from ).is_none());
+    assert!(net.dht_answer( DhtMessage::Store { nonce: 0, sender: owner, key: owner, hosts: vec![ Host::new("10.0.0.9:2222") ] }, &from ).is_none());
+    assert!(matches!(find(owner), Some( DhtMessage::Nodes { .. } )));
+
+    // "where you see me from" does lead back
+    net.dht_answer( DhtMessage::Store { nonce: 0, sender: owner, key: owner, hosts: vec![ Host::new("0.0.0.0:2222") ] }, &from );
+    assert!(matches!(find(owner), Some( DhtMessage::Value { hosts, .. } ) if hosts == vec![from]));
+    Ok(())
+}
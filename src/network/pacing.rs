@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use tokio::time::{Duration,Instant};
+use serde::{Deserialize, Serialize};
+
+use crate::network::host::Host;
+use crate::network::link::LinkStats;
+
+// Largest datagram on the wire, the congestion window grows and shrinks by this much
+pub const SEGMENT: f64 = 1026.0;
+const INITIAL_WINDOW: f64 = 10.0 * SEGMENT;
+const MIN_WINDOW: f64 = 2.0 * SEGMENT;
+
+// Sustained rate and largest burst, both in bytes
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq,Eq)]
+pub struct RateLimit {
+    rate: u64,
+    burst: u64,
+}
+
+impl RateLimit {
+    pub fn new(rate: u64, burst: u64) -> RateLimit {
+        RateLimit { rate, burst }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn burst(&self) -> u64 {
+        self.burst
+    }
+}
+
+// Tokens may go negative: the debt is what the next sender has to wait for. It only grows with what
+// the emitter holds back, no more than PACED_LIMIT datagrams
+#[derive(Debug,Clone,Copy)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: f64, now: Instant) -> TokenBucket {
+        TokenBucket { rate, burst, tokens: burst, last: now }
+    }
+
+    fn reserve(&mut self, bytes: usize, now: Instant) -> Duration {
+        let elapsed: f64 = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.tokens -= bytes as f64;
+
+        if self.tokens >= 0.0 || self.rate <= 0.0 {
+            Duration::ZERO
+        }
+        else {
+            Duration::from_secs_f64( -self.tokens / self.rate )
+        }
+    }
+}
+
+// AIMD: one segment more per answered probe, half the window per lost one
+#[derive(Debug,Clone,Copy)]
+struct Window {
+    cwnd: f64,
+    received: u64,
+    lost: u64,
+    bucket: TokenBucket,
+}
+
+impl Window {
+    fn new(now: Instant) -> Window {
+        Window { cwnd: INITIAL_WINDOW, received: 0, lost: 0, bucket: TokenBucket::new(0.0, INITIAL_WINDOW, now) }
+    }
+
+    fn feedback(&mut self, stats: &LinkStats) {
+        if stats.lost() > self.lost {
+            for _ in self.lost..stats.lost() {
+                self.cwnd = (self.cwnd / 2.0).max(MIN_WINDOW);
+            }
+        }
+        else if stats.received() > self.received {
+            self.cwnd += SEGMENT * (stats.received() - self.received) as f64;
+        }
+        self.received = stats.received();
+        self.lost = stats.lost();
+    }
+
+    // A window's worth of bytes per round trip
+    fn reserve(&mut self, srtt: Duration, bytes: usize, now: Instant) -> Duration {
+        self.bucket.rate = self.cwnd / srtt.as_secs_f64().max(0.001);
+        self.bucket.burst = self.cwnd;
+        self.bucket.reserve(bytes, now)
+    }
+
+    fn cwnd(&self) -> f64 {
+        self.cwnd
+    }
+}
+
+#[derive(Debug)]
+pub struct Pacer {
+    global: Option<TokenBucket>,
+    limits: HashMap<Host,RateLimit>,
+    buckets: HashMap<Host,TokenBucket>,
+    windows: HashMap<Host,Window>,
+}
+
+impl Pacer {
+    pub fn new(global: Option<RateLimit>, limits: HashMap<Host,RateLimit>) -> Pacer {
+        let now = Instant::now();
+        Pacer {
+            global: global.map( |limit| TokenBucket::new(limit.rate as f64, limit.burst as f64, now) ),
+            limits,
+            buckets: HashMap::new(),
+            windows: HashMap::new(),
+        }
+    }
+
+    pub fn window(&self, dst: &Host) -> Option<f64> {
+        self.windows.get(dst).map( |w| w.cwnd() )
+    }
+
+    // How long `bytes` to `dst` must wait, the reservation is taken right away.
+    // The window only applies once the link has been measured
+    pub fn reserve(&mut self, dst: Host, bytes: usize, stats: Option<LinkStats>, now: Instant) -> Duration {
+        let mut wait: Duration = Duration::ZERO;
+
+        if let Some(global) = self.global.as_mut() {
+            wait = wait.max( global.reserve(bytes, now) );
+        }
+
+        if let Some(limit) = self.limits.get(&dst) {
+            let bucket = self.buckets.entry(dst).or_insert_with( || TokenBucket::new(limit.rate as f64, limit.burst as f64, now) );
+            wait = wait.max( bucket.reserve(bytes, now) );
+        }
+
+        if let Some(stats) = stats {
+            if let Some(srtt) = stats.srtt() {
+                let window = self.windows.entry(dst).or_insert_with( || Window::new(now) );
+                window.feedback(&stats);
+                wait = wait.max( window.reserve(srtt, bytes, now) );
+            }
+        }
+
+        wait
+    }
+}
+
+impl Default for Pacer {
+    fn default() -> Self {
+        Pacer::new(None, HashMap::new())
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_pacer_token_bucket() {
+    let dst = Host::new("127.0.0.1:1111");
+    let other = Host::new("127.0.0.1:2222");
+    let mut limits = HashMap::new();
+    limits.insert( dst, RateLimit::new(1000, 2000) );
+    let mut pacer = Pacer::new(None, limits);
+    let now = Instant::now();
+
+    // the burst goes out right away, then one second per kB
+    assert_eq!(Duration::ZERO,pacer.reserve(dst, 2000, None, now));
+    assert_eq!(Duration::from_millis(500),pacer.reserve(dst, 500, None, now));
+    assert_eq!(Duration::from_millis(1500),pacer.reserve(dst, 1000, None, now));
+    assert_eq!(Duration::ZERO,pacer.reserve(other, 100_000, None, now));
+
+    tokio::time::advance( Duration::from_secs(2) ).await;
+    assert_eq!(Duration::ZERO,pacer.reserve(dst, 500, None, Instant::now()));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_pacer_global_and_window() {
+    let dst = Host::new("127.0.0.1:1111");
+    let mut pacer = Pacer::new( Some( RateLimit::new(1000, 1000) ), HashMap::new() );
+    let now = Instant::now();
+
+    assert_eq!(Duration::ZERO,pacer.reserve(dst, 1000, None, now));
+    assert_eq!(Duration::from_secs(1),pacer.reserve(Host::new("127.0.0.1:2222"), 1000, None, now));
+
+    // measured link: the window halves on loss and grows back on answers
+    let mut pacer = Pacer::default();
+    let mut monitor = crate::network::link::LinkMonitor::new();
+    let payload = monitor.ping( dst, now );
+    tokio::time::advance( Duration::from_millis(10) ).await;
+    monitor.pong( &dst, &payload, Instant::now() );
+    pacer.reserve(dst, 0, monitor.stats(&dst), Instant::now());
+    assert_eq!(Some( INITIAL_WINDOW + SEGMENT ),pacer.window(&dst));
+
+    monitor.ping( dst, Instant::now() );
+    tokio::time::advance(crate::network::link::PING_TIMEOUT).await;
+    monitor.expire( Instant::now() );
+    pacer.reserve(dst, 0, monitor.stats(&dst), Instant::now());
+    assert_eq!(Some( (INITIAL_WINDOW + SEGMENT) / 2.0 ),pacer.window(&dst));
+}
@@ -0,0 +1,214 @@
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex};
+use tokio::sync::mpsc;
+
+use crate::network::transport::TransportFuture;
+
+// Where the overlay packets come from and go to: a TUN device, or anything pretending to be one
+pub trait PacketIo: std::fmt::Debug + Send + Sync {
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, usize>;
+    fn write<'a>(&'a self, packet: &'a [u8]) -> TransportFuture<'a, usize>;
+}
+
+// Packets are injected and collected by the caller, no privileges needed
+#[derive(Debug)]
+pub struct MemoryPacketIo {
+    inbound: tokio::sync::Mutex<mpsc::Receiver<Vec<u8>>>,
+    written: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+#[derive(Debug,Clone)]
+pub struct MemoryHandle {
+    inject: mpsc::Sender<Vec<u8>>,
+    written: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl MemoryPacketIo {
+    pub fn new() -> (MemoryPacketIo, MemoryHandle) {
+        let (inject, inbound) = mpsc::channel(1024);
+        let written: Arc<Mutex<Vec<Vec<u8>>>> = Arc::new( Mutex::new( Vec::new() ) );
+        (
+            MemoryPacketIo { inbound: tokio::sync::Mutex::new(inbound), written: Arc::clone(&written) },
+            MemoryHandle { inject, written },
+        )
+    }
+}
+
+impl MemoryHandle {
+    // As if the local system sent `packet` into the overlay
+    pub async fn inject(&self, packet: Vec<u8>) -> bool {
+        self.inject.send(packet).await.is_ok()
+    }
+
+    // Everything delivered to the local system so far
+    pub fn written(&self) -> Vec<Vec<u8>> {
+        self.written.lock().unwrap().clone()
+    }
+}
+
+impl PacketIo for MemoryPacketIo {
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, usize> {
+        Box::pin( async move {
+            match self.inbound.lock().await.recv().await {
+                None => Err( std::io::Error::from( std::io::ErrorKind::UnexpectedEof ) ),
+                Some(packet) => {
+                    let len = packet.len().min( buf.len() );
+                    buf[..len].copy_from_slice( &packet[..len] );
+                    Ok(len)
+                },
+            }
+        })
+    }
+
+    fn write<'a>(&'a self, packet: &'a [u8]) -> TransportFuture<'a, usize> {
+        Box::pin( async move {
+            self.written.lock().unwrap().push( Vec::from(packet) );
+            Ok( packet.len() )
+        })
+    }
+}
+
+// pcap link types we know how to strip down to the IP packet
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+
+// Replays the packets of a capture, and records what we deliver in another one
+#[derive(Debug)]
+pub struct PcapPacketIo {
+    packets: Mutex<std::vec::IntoIter<Vec<u8>>>,
+    output: Option<PathBuf>,
+}
+
+impl PcapPacketIo {
+    pub fn open(input: &Path, output: Option<&Path>) -> std::io::Result<PcapPacketIo> {
+        let packets: Vec<Vec<u8>> = pcap_read( &std::fs::read(input)? )?;
+        if let Some(output) = output {
+            std::fs::write( output, pcap_header() )?;
+        }
+        Ok( PcapPacketIo { packets: Mutex::new( packets.into_iter() ), output: output.map( |p| p.to_path_buf() ) } )
+    }
+}
+
+impl PacketIo for PcapPacketIo {
+    // Once the capture is replayed, reading fails with UnexpectedEof
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, usize> {
+        Box::pin( async move {
+            match self.packets.lock().unwrap().next() {
+                None => Err( std::io::Error::from( std::io::ErrorKind::UnexpectedEof ) ),
+                Some(packet) => {
+                    let len = packet.len().min( buf.len() );
+                    buf[..len].copy_from_slice( &packet[..len] );
+                    Ok(len)
+                },
+            }
+        })
+    }
+
+    fn write<'a>(&'a self, packet: &'a [u8]) -> TransportFuture<'a, usize> {
+        Box::pin( async move {
+            if let Some(output) = self.output.as_ref() {
+                use std::io::Write;
+                let mut file = std::fs::OpenOptions::new().append(true).open(output)?;
+                file.write_all( &pcap_record(packet) )?;
+            }
+            Ok( packet.len() )
+        })
+    }
+}
+
+pub fn pcap_header() -> Vec<u8> {
+    let mut header: Vec<u8> = Vec::with_capacity(24);
+    header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&65535u32.to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    header
+}
+
+pub fn pcap_record(packet: &[u8]) -> Vec<u8> {
+    let mut record: Vec<u8> = Vec::with_capacity( 16 + packet.len() );
+    record.extend_from_slice(&[0; 8]);
+    record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    record.extend_from_slice(packet);
+    record
+}
+
+pub fn pcap_read(bytes: &[u8]) -> std::io::Result<Vec<Vec<u8>>> {
+    let invalid = || std::io::Error::new( std::io::ErrorKind::InvalidData, "not a pcap capture" );
+    let word = |raw: &[u8], big: bool| -> u32 {
+        let raw = [raw[0], raw[1], raw[2], raw[3]];
+        if big { u32::from_be_bytes(raw) } else { u32::from_le_bytes(raw) }
+    };
+
+    if bytes.len() < 24 {
+        return Err( invalid() );
+    }
+    let big: bool = match u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) {
+        PCAP_MAGIC => false,
+        magic if magic.swap_bytes() == PCAP_MAGIC => true,
+        _ => { return Err( invalid() ); },
+    };
+    let linktype: u32 = word(&bytes[20..24], big);
+
+    let mut packets: Vec<Vec<u8>> = Vec::new();
+    let mut cursor: usize = 24;
+    while cursor + 16 <= bytes.len() {
+        let len: usize = word(&bytes[(cursor+8)..(cursor+12)], big) as usize;
+        let packet: &[u8] = bytes.get( (cursor+16)..(cursor+16+len) ).ok_or_else(invalid)?;
+        match linktype {
+            LINKTYPE_RAW => packets.push( Vec::from(packet) ),
+            // only IPv4 and IPv6 frames are kept
+            LINKTYPE_ETHERNET if packet.len() > 14 && matches!( (packet[12], packet[13]), (0x08, 0x00) | (0x86, 0xdd) ) => {
+                packets.push( Vec::from(&packet[14..]) );
+            },
+            LINKTYPE_ETHERNET => {},
+            _ => { return Err( invalid() ); },
+        }
+        cursor += 16 + len;
+    }
+    Ok(packets)
+}
+
+#[tokio::test]
+async fn test_pcap_replay_record() -> std::io::Result<()> {
+    use crate::message::packet::ipv4_packet;
+    use std::net::Ipv4Addr;
+
+    let first = ipv4_packet( Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2), &[1] );
+    let second = ipv4_packet( Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 3), &[2, 2] );
+
+    // an ethernet capture: the ARP frame is skipped, the link layer stripped
+    let mut capture: Vec<u8> = pcap_header();
+    capture[20] = LINKTYPE_ETHERNET as u8;
+    let mut frame: Vec<u8> = vec![0; 12];
+    frame.extend_from_slice(&[0x08, 0x00]);
+    frame.extend_from_slice(&first);
+    capture.append( &mut pcap_record(&frame) );
+    capture.append( &mut pcap_record(&[0xff; 42]) );
+    let mut frame: Vec<u8> = vec![0; 12];
+    frame.extend_from_slice(&[0x08, 0x00]);
+    frame.extend_from_slice(&second);
+    capture.append( &mut pcap_record(&frame) );
+
+    let dir = std::env::temp_dir();
+    let input = dir.join("toktok-test-input.pcap");
+    let output = dir.join("toktok-test-output.pcap");
+    std::fs::write(&input, &capture)?;
+
+    let io = PcapPacketIo::open( &input, Some(&output) )?;
+    let mut buf = [0; 1500];
+    let len = io.read(&mut buf).await?;
+    assert_eq!(&buf[..len],&first[..]);
+    let len = io.read(&mut buf).await?;
+    assert_eq!(&buf[..len],&second[..]);
+    assert!(io.read(&mut buf).await.is_err());
+
+    io.write(&second).await?;
+    assert_eq!(vec![second],pcap_read( &std::fs::read(&output)? )?);
+    assert!(pcap_read(&[0; 24]).is_err());
+    Ok(())
+}
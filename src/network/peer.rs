@@ -0,0 +1,67 @@
+use std::hash::Hasher;
+use metrohash::MetroHash64;
+use serde::{Deserialize, Serialize};
+
+use crate::network::host::Host;
+
+// Identity of a node inside the overlay, independent of the endpoint it uses
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,PartialOrd,Ord,Serialize,Deserialize)]
+pub struct PeerId(u64);
+
+impl PeerId {
+    pub fn new(id: u64) -> PeerId {
+        PeerId(id)
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+
+    pub fn to_bytes(self) -> [u8; 8] {
+        self.0.to_be_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<PeerId> {
+        if bytes.len() < 8 {
+            return None;
+        }
+
+        let mut raw: [u8; 8] = [0; 8];
+        raw.copy_from_slice(&bytes[0..8]);
+        Some( PeerId( u64::from_be_bytes(raw) ) )
+    }
+}
+
+// Same hashing as SqliteCore, a node without key is identified by its endpoint
+impl From<&Host> for PeerId {
+    fn from(host: &Host) -> Self {
+        let mut hasher = MetroHash64::new();
+        hasher.write(host.local_addr().as_bytes());
+        PeerId( hasher.finish() )
+    }
+}
+
+impl std::fmt::Display for PeerId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+#[test]
+fn test_peer_id_bytes() {
+    let id = PeerId::new(0x0102030405060708);
+    let bytes = id.to_bytes();
+    assert_eq!(bytes[0],1);
+    assert_eq!(bytes[7],8);
+    assert_eq!(Some(id),PeerId::from_bytes(&bytes));
+    assert_eq!(None,PeerId::from_bytes(&bytes[1..]));
+}
+
+#[test]
+fn test_peer_id_from_host() {
+    let one = PeerId::from( &Host::new("127.0.0.1:1111") );
+    let same = PeerId::from( &Host::new("127.0.0.1:1111") );
+    let other = PeerId::from( &Host::new("127.0.0.1:1112") );
+    assert_eq!(one,same);
+    assert_ne!(one,other);
+}
@@ -0,0 +1,286 @@
+use std::collections::{HashMap,VecDeque};
+use tokio::time::{Duration,Instant};
+
+use crate::network::host::Host;
+use crate::message::tlv::TLV;
+use crate::message::pmtu::PmtuMessage;
+
+// What every IPv4 path carries: 576 bytes minus the IP and UDP headers
+pub const PMTU_MIN: usize = 508;
+// The largest datagram a TLV makes
+pub const PMTU_MAX: usize = 1026;
+pub const PMTU_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+// A size is too large once that many probes of it went unanswered
+const PMTU_TRIES: u8 = 2;
+// Paths change, a settled search starts over after that
+pub const PMTU_REFRESH: Duration = Duration::from_secs(600);
+// Fragments of a TLV not all received by then are dropped
+pub const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(2);
+// TLVs put back together at once, per sender and overall: fragments starting more are dropped
+pub const FRAGMENT_PENDING_HOST: usize = 8;
+pub const FRAGMENT_PENDING: usize = 256;
+
+// Binary search between a size known to pass and one known to be too large
+#[derive(Debug,Clone,Copy)]
+struct Search {
+    low: usize,
+    high: usize,
+    // (size, sent at, tries)
+    pending: Option<(usize,Instant,u8)>,
+    settled: Option<Instant>,
+    // What is used meanwhile: the former limit until a probe fails
+    current: usize,
+}
+
+impl Search {
+    fn new(current: usize) -> Search {
+        Search { low: PMTU_MIN, high: PMTU_MAX, pending: None, settled: None, current }
+    }
+}
+
+#[derive(Debug,Default)]
+pub struct PathMtu {
+    paths: HashMap<Host,Search>,
+}
+
+impl PathMtu {
+    pub fn new() -> PathMtu {
+        PathMtu { paths: HashMap::new() }
+    }
+
+    // The largest datagram to send to `host`
+    pub fn mtu(&self, host: &Host) -> usize {
+        self.paths.get(host).map_or( PMTU_MAX, |search| search.current )
+    }
+
+    // The size to probe `host` with now, if any
+    pub fn probe(&mut self, host: Host, now: Instant) -> Option<usize> {
+        let search = self.paths.entry(host).or_insert( Search::new(PMTU_MAX) );
+        if let Some(settled) = search.settled {
+            if now < settled + PMTU_REFRESH {
+                return None;
+            }
+            *search = Search::new(search.current);
+        }
+
+        if let Some((size, sent, tries)) = search.pending {
+            if now < sent + PMTU_PROBE_TIMEOUT {
+                return None;
+            }
+            if tries < PMTU_TRIES {
+                search.pending = Some( (size, now, tries + 1) );
+                return Some(size);
+            }
+            search.pending = None;
+            search.high = size - 1;
+            if search.current > search.high {
+                search.current = search.low;
+            }
+        }
+
+        if search.low >= search.high {
+            search.settled = Some(now);
+            search.current = search.low;
+            return None;
+        }
+        let size: usize = (search.low + search.high).div_ceil(2);
+        search.pending = Some( (size, now, 1) );
+        Some(size)
+    }
+
+    // A probe of `size` bytes went through
+    pub fn acked(&mut self, host: &Host, size: usize) {
+        if let Some(search) = self.paths.get_mut(host) {
+            if search.pending.is_some_and( |(pending, _, _)| pending == size ) {
+                search.pending = None;
+            }
+            if size > search.low {
+                search.low = size.min(PMTU_MAX);
+                search.high = search.high.max(search.low);
+            }
+            search.current = search.current.max(search.low);
+        }
+    }
+
+    pub fn forget(&mut self, host: &Host) {
+        self.paths.remove(host);
+    }
+}
+
+#[derive(Debug)]
+struct Partial {
+    since: Instant,
+    parts: Vec<Option<Vec<u8>>>,
+}
+
+// TLVs being put back together, per sender and fragment ID
+#[derive(Debug,Default)]
+pub struct Fragments {
+    next_id: u16,
+    pending: HashMap<(Host,u16),Partial>,
+    // Reassemblies by start, the oldest expires first; completed ones are skipped when reached
+    order: VecDeque<(Instant,(Host,u16))>,
+    per_host: HashMap<Host,usize>,
+}
+
+impl Fragments {
+    pub fn new() -> Fragments {
+        Fragments { next_id: 0, pending: HashMap::new(), order: VecDeque::new(), per_host: HashMap::new() }
+    }
+
+    pub fn next_id(&mut self) -> u16 {
+        self.next_id = self.next_id.wrapping_add(1);
+        self.next_id
+    }
+
+    // The TLV once its last fragment arrived
+    pub fn reassemble(&mut self, peer: Host, fragment: PmtuMessage, now: Instant) -> Option<TLV> {
+        self.expire(now);
+        let (id, index, count, bytes) = match fragment {
+            PmtuMessage::Fragment { id, index, count, bytes } => (id, index, count, bytes),
+            _ => { return None; },
+        };
+
+        if !self.pending.contains_key( &(peer, id) ) {
+            let held: usize = self.per_host.get(&peer).copied().unwrap_or(0);
+            if held >= FRAGMENT_PENDING_HOST || self.pending.len() >= FRAGMENT_PENDING {
+                return None;
+            }
+            self.pending.insert( (peer, id), Partial { since: now, parts: vec![None; count as usize] } );
+            self.order.push_back( (now, (peer, id)) );
+            *self.per_host.entry(peer).or_insert(0) += 1;
+        }
+        let partial = self.pending.get_mut( &(peer, id) )?;
+        // the same ID reused with another count: keep the first one
+        *partial.parts.get_mut(index as usize)? = Some(bytes);
+        if partial.parts.iter().any( |part| part.is_none() ) {
+            return None;
+        }
+
+        let partial = self.remove( &(peer, id) )?;
+        let whole: Vec<u8> = partial.parts.into_iter().flatten().flatten().collect();
+        TLV::from_bytes( whole.into() )
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some( &(since, key) ) = self.order.front() {
+            if now < since + FRAGMENT_TIMEOUT {
+                break;
+            }
+            self.order.pop_front();
+            // the ID may have completed and started over since
+            if self.pending.get(&key).is_some_and( |partial| partial.since == since ) {
+                self.remove(&key);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &(Host,u16)) -> Option<Partial> {
+        let partial = self.pending.remove(key)?;
+        if let Some(held) = self.per_host.get_mut(&key.0) {
+            *held -= 1;
+            if *held == 0 {
+                self.per_host.remove(&key.0);
+            }
+        }
+        Some(partial)
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_path_mtu_search() {
+    let mut pmtu = PathMtu::new();
+    let host = Host::new("127.0.0.1:1111");
+    // the path carries up to 700 bytes, answers come back right away
+    let path: usize = 700;
+
+    assert_eq!(pmtu.mtu(&host),PMTU_MAX);
+    let mut probes: usize = 0;
+    for _ in 0..60 {
+        match pmtu.probe( host, Instant::now() ) {
+            None => { tokio::time::advance(PMTU_PROBE_TIMEOUT).await; },
+            Some(size) => {
+                probes += 1;
+                if size <= path {
+                    pmtu.acked(&host, size);
+                }
+            },
+        }
+    }
+    assert_eq!(pmtu.mtu(&host),path);
+    // binary search: about log2(1026 - 508) sizes, the failed ones tried twice
+    assert!(probes <= 2 * 10);
+
+    // settled until the refresh
+    tokio::time::advance(PMTU_PROBE_TIMEOUT).await;
+    assert_eq!(None,pmtu.probe( host, Instant::now() ));
+    tokio::time::advance(PMTU_REFRESH).await;
+    assert!(pmtu.probe( host, Instant::now() ).is_some());
+    assert_eq!(pmtu.mtu(&host),path);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_fragments_reassemble() {
+    use crate::message::header::Header;
+    use crate::message::pmtu::fragment;
+
+    let mut fragments = Fragments::new();
+    let peer = Host::new("127.0.0.1:1111");
+    let big = TLV::new( Header::UNKNOWN, Some( (0..1000).map( |i| i as u8 ).collect() ) ).unwrap();
+    let mut parts: Vec<PmtuMessage> = fragment(&big, 5, 300).unwrap().iter().map( |tlv| PmtuMessage::from_tlv(tlv).unwrap() ).collect();
+
+    // out of order, and a lost one expires
+    let last = parts.pop().unwrap();
+    assert_eq!(None,fragments.reassemble( peer, last.clone(), Instant::now() ));
+    assert_eq!(parts.len(),3);
+    assert_eq!(None,fragments.reassemble( peer, parts[2].clone(), Instant::now() ));
+    assert_eq!(None,fragments.reassemble( peer, parts[1].clone(), Instant::now() ));
+    assert_eq!(Some( big ),fragments.reassemble( peer, parts[0].clone(), Instant::now() ));
+    assert_eq!(fragments.len(),0);
+
+    fragments.reassemble( peer, last, Instant::now() );
+    tokio::time::advance(FRAGMENT_TIMEOUT).await;
+    assert_eq!(None,fragments.reassemble( peer, parts[0].clone(), Instant::now() ));
+    assert_eq!(fragments.len(),1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_fragments_pending_limits() {
+    use crate::message::header::Header;
+    use crate::message::pmtu::fragment;
+
+    let mut fragments = Fragments::new();
+    let big = TLV::new( Header::UNKNOWN, Some( vec![7; 1000] ) ).unwrap();
+    // the first fragment of a TLV with that ID, the others never come
+    let first = |id: u16| PmtuMessage::from_tlv( &fragment(&big, id, 300).unwrap()[0] ).unwrap();
+
+    // one sender holds a few reassemblies at most
+    let flood = Host::new("127.0.0.1:1111");
+    for id in 0..(FRAGMENT_PENDING_HOST as u16 + 5) {
+        fragments.reassemble( flood, first(id), Instant::now() );
+    }
+    assert_eq!(fragments.len(),FRAGMENT_PENDING_HOST);
+
+    // and all of them together a bounded number
+    for port in 2000..2100 {
+        let host = Host::new( &format!("127.0.0.1:{}", port) );
+        for id in 0..(FRAGMENT_PENDING_HOST as u16) {
+            fragments.reassemble( host, first(id), Instant::now() );
+        }
+    }
+    assert_eq!(fragments.len(),FRAGMENT_PENDING);
+
+    // once expired there is room again, a complete TLV still comes through
+    tokio::time::advance(FRAGMENT_TIMEOUT).await;
+    let parts: Vec<PmtuMessage> = fragment(&big, 1, 300).unwrap().iter().map( |tlv| PmtuMessage::from_tlv(tlv).unwrap() ).collect();
+    let mut whole = None;
+    for part in parts {
+        whole = fragments.reassemble( flood, part, Instant::now() );
+    }
+    assert_eq!(whole,Some( big ));
+    assert_eq!(fragments.len(),0);
+}
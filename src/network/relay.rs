@@ -0,0 +1,43 @@
+// What this node spent relaying traffic on behalf of a given source peer
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq)]
+pub struct RelayStats {
+    forwarded: u64,
+    bytes: u64,
+    dropped: u64,
+}
+
+impl RelayStats {
+    pub fn forwarded(&self) -> u64 {
+        self.forwarded
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn account(&mut self, bytes: usize, forwarded: bool) {
+        if forwarded {
+            self.forwarded += 1;
+            self.bytes += bytes as u64;
+        }
+        else {
+            self.dropped += 1;
+        }
+    }
+}
+
+#[test]
+fn test_relay_stats_account() {
+    let mut stats = RelayStats::default();
+    stats.account(100, true);
+    stats.account(20, true);
+    stats.account(999, false);
+
+    assert_eq!(stats.forwarded(),2);
+    assert_eq!(stats.bytes(),120);
+    assert_eq!(stats.dropped(),1);
+}
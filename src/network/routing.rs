@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use tokio::time::{Duration,Instant};
+
+use crate::network::peer::PeerId;
+use crate::message::route::{Advertisement,RouteEntry};
+
+// DSDV: metric 16 is unreachable, even seqnos come from the destination itself,
+// odd ones are issued by a neighbour that lost it, so a stale path can't come back
+pub const ROUTE_INFINITY: u8 = 16;
+pub const ROUTE_EXPIRY: Duration = Duration::from_secs(15);
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Route {
+    next_hop: PeerId,
+    metric: u8,
+    seqno: u32,
+    updated: Instant,
+}
+
+impl Route {
+    pub fn next_hop(&self) -> PeerId {
+        self.next_hop
+    }
+
+    pub fn metric(&self) -> u8 {
+        self.metric
+    }
+
+    pub fn seqno(&self) -> u32 {
+        self.seqno
+    }
+
+    pub fn reachable(&self) -> bool {
+        self.metric < ROUTE_INFINITY
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct RoutingTable {
+    own: PeerId,
+    seqno: u32,
+    routes: HashMap<PeerId,Route>,
+}
+
+impl RoutingTable {
+    pub fn new(own: PeerId) -> RoutingTable {
+        RoutingTable { own, seqno: 0, routes: HashMap::new() }
+    }
+
+    pub fn route(&self, dst: &PeerId) -> Option<Route> {
+        self.routes.get(dst).copied()
+    }
+
+    pub fn next_hop(&self, dst: &PeerId) -> Option<PeerId> {
+        match self.routes.get(dst) {
+            Some(route) if route.reachable() => Some( route.next_hop() ),
+            _ => None,
+        }
+    }
+
+    pub fn routes(&self) -> HashMap<PeerId,Route> {
+        self.routes.clone()
+    }
+
+    // Our own entry gets a fresh even seqno on every advertisement
+    pub fn advertisement(&mut self) -> Advertisement {
+        self.seqno = self.seqno.wrapping_add(2);
+
+        let mut entries: Vec<RouteEntry> = vec![ RouteEntry::new( self.own, 0, self.seqno ) ];
+        for (dst, route) in self.routes.iter() {
+            entries.push( RouteEntry::new( *dst, route.metric, route.seqno ) );
+        }
+        Advertisement::new(entries)
+    }
+
+    // Merge what `from` advertised, returns true if our table changed
+    pub fn update(&mut self, from: PeerId, adv: &Advertisement) -> bool {
+        let now = Instant::now();
+        let mut changed = false;
+
+        for entry in adv.entries().iter() {
+            if entry.dst() == self.own {
+                continue;
+            }
+
+            let metric = entry.metric().saturating_add(1).min(ROUTE_INFINITY);
+            let candidate = Route { next_hop: from, metric, seqno: entry.seqno(), updated: now };
+
+            let accept = match self.routes.get(&entry.dst()) {
+                None => candidate.reachable(),
+                Some(current) => {
+                    newer(candidate.seqno, current.seqno)
+                    || ( candidate.seqno == current.seqno && candidate.metric < current.metric )
+                    // same path, same news: only a refresh
+                    || ( candidate.seqno == current.seqno && current.next_hop == from && candidate.metric == current.metric )
+                },
+            };
+
+            if accept {
+                if self.routes.get(&entry.dst()).map( |r| (r.next_hop, r.metric, r.seqno) ) != Some( (from, metric, entry.seqno()) ) {
+                    changed = true;
+                }
+                self.routes.insert( entry.dst(), candidate );
+            }
+        }
+
+        changed
+    }
+
+    // Every route through a neighbour we lost becomes unreachable with a bumped (odd) seqno
+    pub fn neighbour_lost(&mut self, neighbour: &PeerId) {
+        for (_, route) in self.routes.iter_mut() {
+            if route.next_hop == *neighbour && route.reachable() {
+                route.metric = ROUTE_INFINITY;
+                route.seqno = route.seqno.wrapping_add(1);
+                route.updated = Instant::now();
+            }
+        }
+    }
+
+    // Silent routes are broken first, then forgotten once their breakage had time to spread
+    pub fn expire(&mut self, max_age: Duration) {
+        let now = Instant::now();
+
+        self.routes.retain( |_, route| route.reachable() || now.duration_since(route.updated) < max_age );
+        for (_, route) in self.routes.iter_mut() {
+            if route.reachable() && now.duration_since(route.updated) >= max_age {
+                route.metric = ROUTE_INFINITY;
+                route.seqno = route.seqno.wrapping_add(1);
+                route.updated = now;
+            }
+        }
+    }
+}
+
+// Serial number arithmetic, seqnos are allowed to wrap
+fn newer(candidate: u32, current: u32) -> bool {
+    candidate != current && candidate.wrapping_sub(current) < u32::MAX / 2
+}
+
+#[test]
+fn test_routing_learn_and_prefer() {
+    let (a, b, c, d) = ( PeerId::new(1), PeerId::new(2), PeerId::new(3), PeerId::new(4) );
+    let mut table = RoutingTable::new(a);
+
+    // b knows d at 2 hops
+    let from_b = Advertisement::new( vec![ RouteEntry::new(b, 0, 2), RouteEntry::new(d, 2, 6) ] );
+    assert!( table.update(b, &from_b) );
+    assert_eq!(Some(b),table.next_hop(&d));
+    assert_eq!(3,table.route(&d).unwrap().metric());
+    assert_eq!(Some(b),table.next_hop(&b));
+
+    // c is closer with the same seqno
+    let from_c = Advertisement::new( vec![ RouteEntry::new(c, 0, 2), RouteEntry::new(d, 0, 6) ] );
+    assert!( table.update(c, &from_c) );
+    assert_eq!(Some(c),table.next_hop(&d));
+
+    // b again: older information must not win
+    let from_b = Advertisement::new( vec![ RouteEntry::new(b, 0, 4), RouteEntry::new(d, 1, 4) ] );
+    table.update(b, &from_b);
+    assert_eq!(Some(c),table.next_hop(&d));
+
+    // ourselves are never a destination
+    let echo = Advertisement::new( vec![ RouteEntry::new(b, 0, 6), RouteEntry::new(a, 1, 100) ] );
+    table.update(b, &echo);
+    assert!(table.route(&a).is_none());
+}
+
+#[test]
+fn test_routing_no_count_to_infinity() {
+    let (a, b, d) = ( PeerId::new(1), PeerId::new(2), PeerId::new(4) );
+    let mut table = RoutingTable::new(a);
+
+    table.update(b, &Advertisement::new( vec![ RouteEntry::new(b, 0, 2), RouteEntry::new(d, 0, 8) ] ));
+    assert_eq!(Some(b),table.next_hop(&d));
+
+    // b is lost, d through b becomes unreachable with an odd seqno
+    table.neighbour_lost(&b);
+    assert_eq!(None,table.next_hop(&d));
+    assert_eq!(9,table.route(&d).unwrap().seqno());
+
+    // a third node still advertising the old seqno can't bring the stale path back
+    let c = PeerId::new(3);
+    table.update(c, &Advertisement::new( vec![ RouteEntry::new(c, 0, 2), RouteEntry::new(d, 1, 8) ] ));
+    assert_eq!(None,table.next_hop(&d));
+
+    // but fresh news from d does
+    table.update(c, &Advertisement::new( vec![ RouteEntry::new(c, 0, 4), RouteEntry::new(d, 1, 10) ] ));
+    assert_eq!(Some(c),table.next_hop(&d));
+}
+
+#[test]
+fn test_routing_advertisement_seqno() {
+    let mut table = RoutingTable::new( PeerId::new(1) );
+    let first = table.advertisement();
+    let second = table.advertisement();
+
+    assert_eq!(Some(PeerId::new(1)),first.origin());
+    assert_eq!(first.entries()[0].seqno() + 2,second.entries()[0].seqno());
+    assert_eq!(0,second.entries()[0].seqno() % 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_routing_expire() {
+    let (a, b) = ( PeerId::new(1), PeerId::new(2) );
+    let mut table = RoutingTable::new(a);
+    table.update(b, &Advertisement::new( vec![ RouteEntry::new(b, 0, 2) ] ));
+
+    tokio::time::advance(ROUTE_EXPIRY).await;
+    table.expire(ROUTE_EXPIRY);
+    assert!(!table.route(&b).unwrap().reachable());
+
+    tokio::time::advance(ROUTE_EXPIRY).await;
+    table.expire(ROUTE_EXPIRY);
+    assert!(table.route(&b).is_none());
+}
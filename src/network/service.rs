@@ -1,16 +1,89 @@
+use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
+use tokio::time::{Duration,Instant};
 use crate::network::host::Host;
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+// How long an announced service is remembered, announces are repeated well before that
+pub const SERVICE_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Service {
     name: String,
     port: u16,
     server: Option<Host>,
+    // Listen there and forward the connections to `port` on `server`, like `ssh -L`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    forward: Option<Host>,
 }
 
 impl Service {
     pub fn new(name: String, port: u16, server: Option<Host>) -> Self{
-        Service { name, port, server }
+        Service { name, port, server, forward: None }
+    }
+
+    pub fn with_forward(mut self, listen: Host) -> Self {
+        self.forward = Some(listen);
+        self
+    }
+
+    pub fn forward(&self) -> Option<Host> {
+        self.forward
+    }
+
+    // Forwarded services are used by this node, not provided by it
+    pub fn provided(&self) -> bool {
+        self.forward.is_none()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn server(&self) -> Option<Host> {
+        self.server
+    }
+
+    // A service without server runs on the node announcing it
+    pub fn with_server(mut self, server: Host) -> Self {
+        if self.server.is_none() {
+            self.server = Some(server);
+        }
+        self
+    }
+}
+
+// Services announced by other nodes, forgotten once their TTL runs out
+#[derive(Debug, Default)]
+pub struct ServiceRegistry {
+    entries: HashMap<Service,Instant>,
+}
+
+impl ServiceRegistry {
+    pub fn new() -> Self {
+        ServiceRegistry { entries: HashMap::new() }
+    }
+
+    pub fn register(&mut self, service: Service, ttl: Duration, now: Instant) {
+        self.entries.insert(service, now + ttl);
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        self.entries.retain( |_, expires| *expires > now );
+    }
+
+    pub fn lookup(&self, name: &str, now: Instant) -> Vec<(Service,Duration)> {
+        self.entries.iter()
+            .filter( |(service, expires)| service.name() == name && **expires > now )
+            .map( |(service, expires)| (service.clone(), *expires - now) )
+            .collect()
+    }
+
+    pub fn services(&self, now: Instant) -> Vec<Service> {
+        self.entries.iter().filter( |(_, expires)| **expires > now ).map( |(service, _)| service.clone() ).collect()
     }
 }
 
@@ -25,5 +98,26 @@ pub fn test_serde_service() -> serde_json::Result<()> {
     assert_eq!(s,ss);
     assert_eq!(ss,sss);
 
+    let forwarded = Service::new("ssh".to_string(), 22, Some( Host::new("10.0.0.2:3333") )).with_forward( Host::new("127.0.0.1:2222") );
+    assert_eq!(forwarded,serde_json::from_str( &serde_json::to_string(&forwarded)? )?);
+    assert!(!forwarded.provided());
+
     Ok(())
-}
\ No newline at end of file
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_service_registry_ttl() {
+    let mut registry = ServiceRegistry::new();
+    let web = Service::new("web".to_string(), 80, Some( Host::new("127.0.0.1:1111") ));
+    let now = Instant::now();
+
+    registry.register( web.clone(), Duration::from_secs(10), now );
+    registry.register( Service::new("ssh".to_string(), 22, None).with_server( Host::new("127.0.0.1:2222") ), Duration::from_secs(1), now );
+    assert_eq!(registry.lookup("web", now),vec![ (web.clone(), Duration::from_secs(10)) ]);
+    assert_eq!(registry.lookup("ssh", now)[0].0.server(),Some( Host::new("127.0.0.1:2222") ));
+
+    tokio::time::advance( Duration::from_secs(2) ).await;
+    registry.expire( Instant::now() );
+    assert!(registry.lookup("ssh", Instant::now()).is_empty());
+    assert_eq!(registry.services( Instant::now() ),vec![web]);
+}
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use rand_core::{OsRng,RngCore};
+use ssh_key::public::PublicKey;
+use tokio::time::{Duration,Instant};
+
+use crate::network::host::Host;
+use crate::network::peer::PeerId;
+use crate::message::header::Header;
+
+// A HELLO left unanswered is sent again after HANDSHAKE_RETRY, HANDSHAKE_TRIES times,
+// then only every HANDSHAKE_BACKOFF
+pub const HANDSHAKE_RETRY: Duration = Duration::from_secs(1);
+pub const HANDSHAKE_TRIES: u32 = 4;
+pub const HANDSHAKE_BACKOFF: Duration = Duration::from_secs(30);
+// Once we said BYE, the session is forgotten after that
+pub const CLOSING_LINGER: Duration = Duration::from_secs(2);
+
+// No session at all: the peer is unknown
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum SessionState {
+    // We said HELLO, no answer yet
+    Handshaking,
+    Established,
+    // The failure detector lost track of the peer, it still has to refute or be evicted
+    Suspect,
+    // We said BYE
+    Closing,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Session {
+    state: SessionState,
+    since: Instant,
+    // The identity the peer proved, and the key it proved it with
+    id: Option<PeerId>,
+    key: Option<PublicKey>,
+    // Handshake progress: HELLOs sent and not answered yet, and when the last one left
+    greetings: u32,
+    greeted: Option<Instant>,
+    // The nonce we want signed, the one the peer wants signed, and the last one we signed
+    nonce: u64,
+    challenge: Option<u64>,
+    proved: Option<u64>,
+    received: u64,
+    rejected: u64,
+}
+
+impl Session {
+    fn new(state: SessionState, now: Instant) -> Session {
+        Session { state, since: now, id: None, key: None, greetings: 0, greeted: None, nonce: OsRng.next_u64(), challenge: None, proved: None, received: 0, rejected: 0 }
+    }
+
+    fn enter(&mut self, state: SessionState, now: Instant) {
+        if self.state != state {
+            self.state = state;
+            self.since = now;
+        }
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    pub fn since(&self) -> Instant {
+        self.since
+    }
+
+    pub fn id(&self) -> Option<PeerId> {
+        self.id
+    }
+
+    pub fn key(&self) -> Option<&PublicKey> {
+        self.key.as_ref()
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+}
+
+// What a header needs from the session it arrives on. Liveness, path MTU and handshake traffic
+// goes anywhere, the DHT, lease answers and services work without a session as long as we aren't
+// leaving, lease requests and overlay traffic only flow on established sessions
+pub fn admits(state: Option<SessionState>, header: Header) -> bool {
+    match header {
+        Header::HELLO | Header::BYE | Header::PING | Header::PONG | Header::PING_REQ | Header::PING_ACK | Header::GOSSIP
+        | Header::PROBE | Header::PROBE_ACK | Header::MULTIPLE | Header::FRAGMENT | Header::UNKNOWN => true,
+
+        Header::FIND_NODE | Header::FIND_VALUE | Header::NODES | Header::VALUE | Header::STORE
+        | Header::LEASE_OFFER | Header::LEASE_ACK | Header::LEASE_CONFIRM
+        | Header::SERVICE_ANNOUNCE | Header::SERVICE_QUERY => state != Some(SessionState::Closing),
+
+        Header::LEASE_REQUEST | Header::RELAY | Header::ROUTE | Header::IPPACKET
+        | Header::STREAM_OPEN | Header::STREAM_DATA | Header::STREAM_ACK | Header::STREAM_CLOSE
+        | Header::APPLICATION(_) =>
+            matches!(state, Some(SessionState::Established) | Some(SessionState::Suspect)),
+    }
+}
+
+#[derive(Debug,Default)]
+pub struct Sessions {
+    sessions: HashMap<Host,Session>,
+    // The first key an id was proved with, wherever it came from. Kept until the peer is removed
+    keys: HashMap<PeerId,PublicKey>,
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        Sessions { sessions: HashMap::new(), keys: HashMap::new() }
+    }
+
+    pub fn get(&self, peer: &Host) -> Option<Session> {
+        self.sessions.get(peer).cloned()
+    }
+
+    pub fn state(&self, peer: &Host) -> Option<SessionState> {
+        self.sessions.get(peer).map( |session| session.state )
+    }
+
+    pub fn sessions(&self) -> HashMap<Host,Session> {
+        self.sessions.clone()
+    }
+
+    // Counted against the session, refused ones are dropped by the caller
+    pub fn admit(&mut self, peer: &Host, header: Header, now: Instant) -> bool {
+        let admitted: bool = admits( self.state(peer), header );
+        if let Some(session) = self.sessions.get_mut(peer) {
+            match admitted {
+                true => { session.received += 1; },
+                false => { session.rejected += 1; },
+            }
+            // the peer answers: whatever made it suspect is over
+            if session.state == SessionState::Suspect && header == Header::PONG {
+                session.enter(SessionState::Established, now);
+            }
+        }
+        admitted
+    }
+
+    // We are about to say HELLO: a session being set up, established ones stay usable meanwhile.
+    // Returns our nonce, and the peer's one our HELLO signs
+    pub fn greet(&mut self, peer: Host, now: Instant) -> (u64, Option<u64>) {
+        let session = self.sessions.entry(peer).or_insert( Session::new(SessionState::Handshaking, now) );
+        if session.state == SessionState::Closing {
+            session.enter(SessionState::Handshaking, now);
+        }
+        session.greetings += 1;
+        session.greeted = Some(now);
+        session.proved = session.challenge;
+        (session.nonce, session.challenge)
+    }
+
+    // The peer signed our nonce: the handshake is over on our side, unless `id` was proved with
+    // another key before
+    pub fn verified(&mut self, peer: Host, id: PeerId, key: PublicKey, now: Instant) -> bool {
+        if self.keys.get(&id).is_some_and( |pinned| *pinned != key ) {
+            return false;
+        }
+        self.keys.insert( id, key.clone() );
+        let session = self.sessions.entry(peer).or_insert( Session::new(SessionState::Handshaking, now) );
+        session.enter(SessionState::Established, now);
+        session.id = Some(id);
+        session.key = Some(key);
+        session.greetings = 0;
+        session.greeted = None;
+        true
+    }
+
+    // The peer is gone, its id may be proved with another key
+    pub fn unpin(&mut self, id: &PeerId) {
+        self.keys.remove(id);
+    }
+
+    // The peer said HELLO, maybe with a nonce to sign, after `verified` when it signed ours. True
+    // when it waits for ours in return: to get its nonce signed, or to get ours when it did not
+    // sign it, no more than once per HANDSHAKE_RETRY
+    pub fn hello(&mut self, peer: Host, challenge: Option<u64>, signed: bool, now: Instant) -> bool {
+        let session = self.sessions.entry(peer).or_insert( Session::new(SessionState::Handshaking, now) );
+        if challenge.is_some() {
+            session.challenge = challenge;
+        }
+        let unproved: bool = session.challenge.is_some() && session.challenge != session.proved;
+        let unverified: bool = session.key.is_none() && !signed
+            && session.greeted.is_none_or( |greeted| now >= greeted + HANDSHAKE_RETRY );
+        unproved || unverified
+    }
+
+    // Whom to say HELLO to now: `peers` we have no session with, and handshakes left unanswered
+    pub fn due(&self, peers: &[Host], now: Instant) -> Vec<Host> {
+        peers.iter()
+            .filter( |peer| match self.sessions.get(peer) {
+                None => true,
+                Some(session) => match (session.state, session.greeted) {
+                    (SessionState::Handshaking, Some(greeted)) => {
+                        let wait: Duration = match session.greetings < HANDSHAKE_TRIES {
+                            true => HANDSHAKE_RETRY,
+                            false => HANDSHAKE_BACKOFF,
+                        };
+                        now >= greeted + wait
+                    },
+                    _ => false,
+                },
+            } )
+            .copied()
+            .collect()
+    }
+
+    pub fn suspect(&mut self, peer: &Host, now: Instant) {
+        if let Some(session) = self.sessions.get_mut(peer) {
+            if session.state == SessionState::Established {
+                session.enter(SessionState::Suspect, now);
+            }
+        }
+    }
+
+    pub fn close(&mut self, peer: &Host, now: Instant) {
+        if let Some(session) = self.sessions.get_mut(peer) {
+            session.enter(SessionState::Closing, now);
+        }
+    }
+
+    pub fn forget(&mut self, peer: &Host) {
+        self.sessions.remove(peer);
+    }
+
+    // Closing sessions are dropped once they lingered
+    pub fn expire(&mut self, now: Instant) {
+        self.sessions.retain( |_, session| session.state != SessionState::Closing || now < session.since + CLOSING_LINGER );
+    }
+}
+
+#[test]
+fn test_session_admits() {
+    for state in [ None, Some(SessionState::Handshaking), Some(SessionState::Established), Some(SessionState::Suspect), Some(SessionState::Closing) ] {
+        assert!( admits(state, Header::PING) );
+        assert!( admits(state, Header::HELLO) );
+    }
+    assert!( !admits(None, Header::IPPACKET) );
+    assert!( !admits(Some(SessionState::Handshaking), Header::STREAM_DATA) );
+    assert!( admits(Some(SessionState::Established), Header::STREAM_DATA) );
+    assert!( admits(Some(SessionState::Suspect), Header::RELAY) );
+    assert!( !admits(Some(SessionState::Closing), Header::RELAY) );
+    assert!( admits(None, Header::FIND_NODE) );
+    assert!( !admits(Some(SessionState::Closing), Header::FIND_NODE) );
+    assert!( !admits(None, Header::APPLICATION(40)) );
+    assert!( admits(Some(SessionState::Established), Header::APPLICATION(40)) );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_session_handshake() {
+    let mut sessions = Sessions::new();
+    let peer = Host::new("127.0.0.1:1111");
+    let other = Host::new("127.0.0.2:2222");
+
+    // we start it, the peer signing our nonce establishes it, without being answered again
+    assert_eq!(sessions.due( &[peer], Instant::now() ),vec![peer]);
+    sessions.greet( peer, Instant::now() );
+    assert_eq!(sessions.state(&peer),Some( SessionState::Handshaking ));
+    assert!( !sessions.admit( &peer, Header::IPPACKET, Instant::now() ) );
+    assert!( sessions.due( &[peer], Instant::now() ).is_empty() );
+    tokio::time::advance(HANDSHAKE_RETRY).await;
+    assert_eq!(sessions.due( &[peer], Instant::now() ),vec![peer]);
+    let key: PublicKey = crate::crypto::asymetric::KeyPair::random().into();
+    sessions.verified( peer, PeerId::new(1), key.clone(), Instant::now() );
+    assert!( !sessions.hello( peer, None, true, Instant::now() ) );
+    assert_eq!(sessions.state(&peer),Some( SessionState::Established ));
+    assert!( sessions.admit( &peer, Header::IPPACKET, Instant::now() ) );
+    assert_eq!(sessions.get(&peer).map( |session| (session.id(), session.received(), session.rejected()) ),Some( (Some( PeerId::new(1) ), 1, 1) ));
+    assert_eq!(sessions.get(&peer).unwrap().key(),Some(&key));
+    assert!( sessions.due( &[peer], Instant::now() ).is_empty() );
+
+    // they start it: answered once with our nonce and their own signed, established once they sign ours
+    assert!( sessions.hello( other, Some(7), false, Instant::now() ) );
+    assert_eq!(sessions.state(&other),Some( SessionState::Handshaking ));
+    let nonce: u64 = sessions.get(&other).unwrap().nonce();
+    assert_eq!(sessions.greet( other, Instant::now() ),(nonce, Some(7)));
+    assert!( !sessions.hello( other, Some(7), false, Instant::now() ) );
+    sessions.verified( other, PeerId::new(2), key, Instant::now() );
+    assert_eq!(sessions.state(&other),Some( SessionState::Established ));
+    // a new nonce, e.g. after a restart, is signed again
+    assert!( sessions.hello( other, Some(8), false, Instant::now() ) );
+
+    // suspect until it answers a ping
+    sessions.suspect( &peer, Instant::now() );
+    assert!( sessions.admit( &peer, Header::IPPACKET, Instant::now() ) );
+    assert_eq!(sessions.state(&peer),Some( SessionState::Suspect ));
+    sessions.admit( &peer, Header::PONG, Instant::now() );
+    assert_eq!(sessions.state(&peer),Some( SessionState::Established ));
+
+    // closing, then gone
+    sessions.close( &peer, Instant::now() );
+    assert!( !sessions.admit( &peer, Header::STREAM_DATA, Instant::now() ) );
+    tokio::time::advance(CLOSING_LINGER).await;
+    sessions.expire( Instant::now() );
+    assert_eq!(sessions.state(&peer),None);
+
+    // unanswered handshakes back off
+    for _ in 0..HANDSHAKE_TRIES {
+        sessions.greet( peer, Instant::now() );
+        tokio::time::advance(HANDSHAKE_RETRY).await;
+    }
+    assert!( sessions.due( &[peer], Instant::now() ).is_empty() );
+    tokio::time::advance(HANDSHAKE_BACKOFF).await;
+    assert_eq!(sessions.due( &[peer], Instant::now() ),vec![peer]);
+}
@@ -0,0 +1,309 @@
+use std::collections::{BTreeMap,HashMap,HashSet};
+use std::sync::{Arc,Mutex};
+use tokio::sync::Notify;
+use tokio::time::{Duration,Instant,sleep_until};
+
+use crate::network::host::Host;
+use crate::network::transport::{Transport,TransportFuture};
+
+// How one direction of a link misbehaves, probabilities are in [0, 1]
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub struct LinkConfig {
+    pub latency: Duration,
+    pub jitter: Duration,
+    pub loss: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+    // Larger datagrams are dropped, like a tunnel that doesn't fragment
+    pub mtu: Option<usize>,
+}
+
+// SplitMix64: the same seed always gives the same run
+#[derive(Debug)]
+struct Prng(u64);
+
+impl Prng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ( (self.next() >> 11) as f64 / (1u64 << 53) as f64 ) < p
+    }
+
+    fn upto(&mut self, max: Duration) -> Duration {
+        match max.as_nanos() as u64 {
+            0 => Duration::ZERO,
+            max => Duration::from_nanos( self.next() % (max + 1) ),
+        }
+    }
+}
+
+// Datagrams waiting for their delivery time, in (due, sequence) order
+#[derive(Debug,Default)]
+struct Mailbox {
+    queue: BTreeMap<(Instant,u64),(Vec<u8>,Host)>,
+    notify: Arc<Notify>,
+}
+
+#[derive(Debug)]
+struct SimState {
+    prng: Prng,
+    sequence: u64,
+    default: LinkConfig,
+    links: HashMap<(Host,Host),LinkConfig>,
+    cut: HashSet<(Host,Host)>,
+    // Sends still to fail, per sender
+    refused: HashMap<Host,u32>,
+    mailboxes: HashMap<Host,Mailbox>,
+    delivered: u64,
+    dropped: u64,
+}
+
+enum Delivery {
+    Ready(Vec<u8>,Host),
+    Wait(Option<Instant>,Arc<Notify>),
+}
+
+// The whole network in one process: a switch every simulated transport is plugged in
+#[derive(Debug,Clone)]
+pub struct SimNet {
+    state: Arc<Mutex<SimState>>,
+}
+
+impl SimNet {
+    pub fn new(seed: u64) -> SimNet {
+        SimNet { state: Arc::new( Mutex::new( SimState {
+            prng: Prng(seed),
+            sequence: 0,
+            default: LinkConfig::default(),
+            links: HashMap::new(),
+            cut: HashSet::new(),
+            refused: HashMap::new(),
+            mailboxes: HashMap::new(),
+            delivered: 0,
+            dropped: 0,
+        } ) ) }
+    }
+
+    pub fn bind(&self, host: Host) -> SimTransport {
+        self.state.lock().unwrap().mailboxes.entry(host).or_default();
+        SimTransport { local: host, net: self.clone() }
+    }
+
+    // Used by every link without its own configuration
+    pub fn set_default(&self, config: LinkConfig) {
+        self.state.lock().unwrap().default = config;
+    }
+
+    // Both directions between `a` and `b`
+    pub fn set_link(&self, a: Host, b: Host, config: LinkConfig) {
+        let mut state = self.state.lock().unwrap();
+        state.links.insert( (a, b), config );
+        state.links.insert( (b, a), config );
+    }
+
+    // Nothing goes through between the two sides until `heal`
+    pub fn partition(&self, left: &[Host], right: &[Host]) {
+        let mut state = self.state.lock().unwrap();
+        for a in left.iter() {
+            for b in right.iter() {
+                state.cut.insert( (*a, *b) );
+                state.cut.insert( (*b, *a) );
+            }
+        }
+    }
+
+    pub fn heal(&self) {
+        self.state.lock().unwrap().cut.clear();
+    }
+
+    // The next `sends` datagrams of `host` fail, like on a full socket buffer
+    pub fn refuse(&self, host: Host, sends: u32) {
+        self.state.lock().unwrap().refused.insert(host, sends);
+    }
+
+    fn refused(&self, host: &Host) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.refused.get_mut(host) {
+            Some(sends) if *sends > 0 => { *sends -= 1; true },
+            _ => false,
+        }
+    }
+
+    pub fn delivered(&self) -> u64 {
+        self.state.lock().unwrap().delivered
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.state.lock().unwrap().dropped
+    }
+
+    fn send(&self, src: Host, dst: Host, bytes: &[u8]) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let config: LinkConfig = state.links.get( &(src, dst) ).copied().unwrap_or(state.default);
+
+        if state.cut.contains( &(src, dst) ) || !state.mailboxes.contains_key(&dst) || state.prng.chance(config.loss)
+            || config.mtu.is_some_and( |mtu| bytes.len() > mtu ) {
+            state.dropped += 1;
+            return;
+        }
+
+        let copies: usize = if state.prng.chance(config.duplicate) { 2 } else { 1 };
+        for _ in 0..copies {
+            let mut delay: Duration = config.latency + state.prng.upto(config.jitter);
+            // held back long enough for the following datagrams to overtake it
+            if state.prng.chance(config.reorder) {
+                delay += config.latency + config.jitter + Duration::from_millis(1);
+            }
+            state.sequence += 1;
+            let key = (now + delay, state.sequence);
+            let mailbox = state.mailboxes.get_mut(&dst).unwrap();
+            mailbox.queue.insert( key, (Vec::from(bytes), src) );
+            mailbox.notify.notify_one();
+        }
+    }
+
+    // The next datagram already due, or when to look again
+    fn take(&self, host: &Host) -> Delivery {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let mailbox = state.mailboxes.get_mut(host).unwrap();
+        let next: Option<(Instant,u64)> = mailbox.queue.keys().next().copied();
+
+        match next {
+            Some(key) if key.0 <= now => {
+                let (bytes, src) = mailbox.queue.remove(&key).unwrap();
+                state.delivered += 1;
+                Delivery::Ready(bytes, src)
+            },
+            _ => Delivery::Wait( next.map( |key| key.0 ), Arc::clone(&mailbox.notify) ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SimTransport {
+    local: Host,
+    net: SimNet,
+}
+
+impl Drop for SimTransport {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.net.state.lock() {
+            state.mailboxes.remove(&self.local);
+        }
+    }
+}
+
+impl Transport for SimTransport {
+    fn send_to<'a>(&'a self, buf: &'a [u8], dst: Host) -> TransportFuture<'a, usize> {
+        Box::pin( async move {
+            if self.net.refused(&self.local) {
+                return Err( std::io::Error::from( std::io::ErrorKind::WouldBlock ) );
+            }
+            self.net.send(self.local, dst, buf);
+            Ok( buf.len() )
+        })
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, Host)> {
+        Box::pin( async move {
+            loop {
+                match self.net.take(&self.local) {
+                    Delivery::Ready(bytes, src) => {
+                        let len = bytes.len().min( buf.len() );
+                        buf[..len].copy_from_slice( &bytes[..len] );
+                        return Ok( (len, src) );
+                    },
+                    Delivery::Wait(None, notify) => { notify.notified().await; },
+                    Delivery::Wait(Some(due), notify) => {
+                        tokio::select! {
+                            _ = notify.notified() => {},
+                            _ = sleep_until(due) => {},
+                        }
+                    },
+                }
+            }
+        })
+    }
+
+    fn local_addr(&self) -> std::io::Result<Host> {
+        Ok(self.local)
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_simulated_latency_and_order() -> std::io::Result<()> {
+    let sim = SimNet::new(1);
+    let (a, b) = ( Host::new("10.0.0.1:1"), Host::new("10.0.0.2:1") );
+    sim.set_link( a, b, LinkConfig { latency: Duration::from_millis(50), ..Default::default() } );
+    let (ta, tb) = ( sim.bind(a), sim.bind(b) );
+
+    let start = Instant::now();
+    for i in 0..10u8 {
+        ta.send_to( &[i], b ).await?;
+    }
+    let mut buf = [0; 16];
+    for i in 0..10u8 {
+        let (len, src) = tb.recv_from(&mut buf).await?;
+        assert_eq!((&buf[..len], src),(&[i][..], a));
+    }
+    assert_eq!(start.elapsed(),Duration::from_millis(50));
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_simulated_faults() -> std::io::Result<()> {
+    let run = |seed: u64| async move {
+        let sim = SimNet::new(seed);
+        let (a, b) = ( Host::new("10.0.0.1:1"), Host::new("10.0.0.2:1") );
+        sim.set_default( LinkConfig { latency: Duration::from_millis(10), jitter: Duration::from_millis(5), loss: 0.2, duplicate: 0.1, reorder: 0.2, mtu: None } );
+        let (ta, tb) = ( sim.bind(a), sim.bind(b) );
+
+        for i in 0..100u8 {
+            ta.send_to( &[i], b ).await.unwrap();
+        }
+        tokio::time::sleep( Duration::from_secs(1) ).await;
+
+        let mut received: Vec<u8> = Vec::new();
+        let mut buf = [0; 16];
+        let queued: usize = sim.state.lock().unwrap().mailboxes[&b].queue.len();
+        for _ in 0..queued {
+            tb.recv_from(&mut buf).await.unwrap();
+            received.push(buf[0]);
+        }
+        (received, sim.dropped())
+    };
+
+    // same seed, same run
+    let (received, dropped) = run(7).await;
+    assert_eq!((received.clone(), dropped),run(7).await);
+    assert!(dropped > 0 && dropped < 50);
+    assert!(received.len() as u64 > 100 - dropped);
+    assert!(received.windows(2).any( |w| w[0] > w[1] ));
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_simulated_partition() -> std::io::Result<()> {
+    let sim = SimNet::new(1);
+    let (a, b) = ( Host::new("10.0.0.1:1"), Host::new("10.0.0.2:1") );
+    let (ta, tb) = ( sim.bind(a), sim.bind(b) );
+
+    sim.partition( &[a], &[b] );
+    tb.send_to( &[1], a ).await?;
+    assert_eq!(1,sim.dropped());
+
+    sim.heal();
+    tb.send_to( &[2], a ).await?;
+    let mut buf = [0; 16];
+    assert_eq!((1, b),ta.recv_from(&mut buf).await?);
+    assert_eq!(buf[0],2);
+    Ok(())
+}
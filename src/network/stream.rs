@@ -0,0 +1,333 @@
+use std::collections::{BTreeMap,HashMap};
+use std::sync::Arc;
+use tokio::sync::{mpsc,Notify};
+use tokio::time::{Duration,Instant};
+
+use crate::network::host::Host;
+use crate::message::stream::StreamMessage;
+
+// Bytes carried by one DATA, leaves room for the other headers inside a MULTIPLE
+pub const STREAM_SEGMENT: usize = 1000;
+// Segments in flight at most, and segments queued for the local connection
+pub const STREAM_WINDOW: usize = 32;
+pub const STREAM_RTO: Duration = Duration::from_millis(200);
+// The peer is considered gone after that many retransmissions of the same segment
+const MAX_RETRANSMITS: u32 = 10;
+// Set on the stream ID of the messages sent by the accepting side
+const ACCEPTOR: u32 = 0x8000_0000;
+
+// Both ends name a stream after the ID chosen by the side which opened it
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct StreamKey {
+    peer: Host,
+    id: u32,
+    opener: bool,
+}
+
+impl StreamKey {
+    // The stream a message received from `peer` belongs to
+    pub fn received(peer: Host, stream: u32) -> StreamKey {
+        StreamKey { peer, id: stream & !ACCEPTOR, opener: stream & ACCEPTOR != 0 }
+    }
+
+    pub fn peer(&self) -> Host {
+        self.peer
+    }
+
+    pub fn opener(&self) -> bool {
+        self.opener
+    }
+
+    // The ID as written in the messages we send
+    fn wire(&self) -> u32 {
+        match self.opener {
+            true => self.id,
+            false => self.id | ACCEPTOR,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StreamSend<T> {
+    Sent(T),
+    // The window is full, wait for an ACK
+    Blocked(Arc<Notify>),
+    Gone,
+}
+
+#[derive(Debug)]
+struct Stream {
+    next_seq: u32,
+    // seq -> (segment, last sent, retransmissions)
+    unacked: BTreeMap<u32,(StreamMessage,Instant,u32)>,
+    window: usize,
+    expected: u32,
+    pending: BTreeMap<u32,StreamMessage>,
+    // Dropped once the peer closed its side
+    deliver: Option<mpsc::Sender<Vec<u8>>>,
+    space: Arc<Notify>,
+    closed: bool,
+}
+
+impl Stream {
+    fn new(expected: u32, deliver: mpsc::Sender<Vec<u8>>) -> Stream {
+        Stream { next_seq: 0, unacked: BTreeMap::new(), window: STREAM_WINDOW, expected, pending: BTreeMap::new(), deliver: Some(deliver), space: Arc::new( Notify::new() ), closed: false }
+    }
+
+    fn push(&mut self, segment: StreamMessage, now: Instant) -> StreamMessage {
+        self.unacked.insert( self.next_seq, (segment.clone(), now, 0) );
+        self.next_seq = self.next_seq.wrapping_add(1);
+        segment
+    }
+
+    // Never zero, the oldest segment keeps probing a closed window
+    fn room(&self) -> bool {
+        self.unacked.len() < self.window.clamp(1, STREAM_WINDOW)
+    }
+
+    fn ack(&self, key: &StreamKey) -> StreamMessage {
+        let window: usize = self.deliver.as_ref().map( |deliver| deliver.capacity() ).unwrap_or(0);
+        StreamMessage::Ack { stream: key.wire(), next: self.expected, window: window as u16 }
+    }
+
+    fn finished(&self) -> bool {
+        self.closed && self.deliver.is_none() && self.unacked.is_empty()
+    }
+}
+
+// Reliable, ordered byte streams on top of datagrams: go-back-N-ish, with a fixed window
+// shrunk to what the receiving side can still queue
+#[derive(Debug,Default)]
+pub struct StreamTable {
+    next_id: u32,
+    streams: HashMap<StreamKey,Stream>,
+}
+
+impl StreamTable {
+    pub fn new() -> StreamTable {
+        StreamTable { next_id: 0, streams: HashMap::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    pub fn contains(&self, key: &StreamKey) -> bool {
+        self.streams.contains_key(key)
+    }
+
+    // The OPEN takes the first sequence number, it is retransmitted until acknowledged
+    pub fn open(&mut self, peer: Host, port: u16, deliver: mpsc::Sender<Vec<u8>>, now: Instant) -> (StreamKey, StreamMessage) {
+        self.next_id = self.next_id.wrapping_add(1) & !ACCEPTOR;
+        let key = StreamKey { peer, id: self.next_id, opener: true };
+        let mut stream = Stream::new(0, deliver);
+        let open = stream.push( StreamMessage::Open { stream: key.wire(), seq: 0, port }, now );
+        self.streams.insert(key, stream);
+        (key, open)
+    }
+
+    // False if the stream already exists, the OPEN was retransmitted
+    pub fn accept(&mut self, key: StreamKey, seq: u32, deliver: mpsc::Sender<Vec<u8>>) -> bool {
+        if key.opener || self.streams.contains_key(&key) {
+            return false;
+        }
+        self.streams.insert( key, Stream::new(seq, deliver) );
+        true
+    }
+
+    pub fn write(&mut self, key: &StreamKey, bytes: &[u8], now: Instant) -> StreamSend<StreamMessage> {
+        match self.streams.get_mut(key) {
+            Some(stream) if !stream.closed => {
+                if !stream.room() {
+                    return StreamSend::Blocked( Arc::clone(&stream.space) );
+                }
+                let seq: u32 = stream.next_seq;
+                StreamSend::Sent( stream.push( StreamMessage::Data { stream: key.wire(), seq, bytes: Vec::from(bytes) }, now ) )
+            },
+            _ => StreamSend::Gone,
+        }
+    }
+
+    // Our side is done writing, the CLOSE goes after whatever is still in flight
+    pub fn shutdown(&mut self, key: &StreamKey, now: Instant) -> Option<StreamMessage> {
+        let stream = self.streams.get_mut(key)?;
+        if stream.closed {
+            return None;
+        }
+        stream.closed = true;
+        let seq: u32 = stream.next_seq;
+        let close = stream.push( StreamMessage::Close { stream: key.wire(), seq }, now );
+        Some(close)
+    }
+
+    // OPEN, DATA or CLOSE from the peer: queue it, deliver what is in order, and say how far we got
+    pub fn receive(&mut self, key: &StreamKey, segment: StreamMessage) -> Option<StreamMessage> {
+        let seq: u32 = segment.seq()?;
+        let stream = match self.streams.get_mut(key) {
+            Some(stream) => stream,
+            // the stream is over on our side, the peer only missed our ACK
+            None => {
+                return match segment {
+                    StreamMessage::Close { .. } => Some( StreamMessage::Ack { stream: key.wire(), next: seq.wrapping_add(1), window: 0 } ),
+                    _ => None,
+                };
+            },
+        };
+
+        let ahead: u32 = seq.wrapping_sub(stream.expected);
+        if (ahead as usize) < STREAM_WINDOW {
+            stream.pending.insert(seq, segment);
+        }
+        while let Some(segment) = stream.pending.remove(&stream.expected) {
+            match segment {
+                StreamMessage::Data { bytes, .. } => {
+                    if let Some(deliver) = stream.deliver.as_ref() {
+                        match deliver.try_send(bytes) {
+                            Ok(_) => {},
+                            // the local connection lags behind, the peer will send it again
+                            Err(mpsc::error::TrySendError::Full(bytes)) => {
+                                stream.pending.insert( stream.expected, StreamMessage::Data { stream: key.wire(), seq: stream.expected, bytes } );
+                                break;
+                            },
+                            // the local connection is gone, nothing to deliver to anymore
+                            Err(mpsc::error::TrySendError::Closed(_)) => {},
+                        }
+                    }
+                },
+                StreamMessage::Close { .. } => { stream.deliver = None; },
+                _ => {},
+            }
+            stream.expected = stream.expected.wrapping_add(1);
+        }
+
+        let ack: StreamMessage = stream.ack(key);
+        if stream.finished() {
+            self.streams.remove(key);
+        }
+        Some(ack)
+    }
+
+    pub fn acknowledge(&mut self, key: &StreamKey, next: u32, window: u16) {
+        if let Some(stream) = self.streams.get_mut(key) {
+            let acked: Vec<u32> = stream.unacked.keys().copied().filter( |seq| next.wrapping_sub(*seq).wrapping_sub(1) < (1 << 31) ).collect();
+            for seq in acked {
+                stream.unacked.remove(&seq);
+            }
+            stream.window = window as usize;
+            stream.space.notify_one();
+            if stream.finished() {
+                self.streams.remove(key);
+            }
+        }
+    }
+
+    // Segments not acknowledged in time, streams retransmitted too often are dropped
+    pub fn retransmit(&mut self, now: Instant) -> Vec<(Host, StreamMessage)> {
+        let mut segments: Vec<(Host, StreamMessage)> = Vec::new();
+        let mut dead: Vec<StreamKey> = Vec::new();
+        for (key, stream) in self.streams.iter_mut() {
+            if stream.unacked.values().any( |(_, sent, retransmits)| now >= *sent + STREAM_RTO && *retransmits >= MAX_RETRANSMITS ) {
+                dead.push(*key);
+                continue;
+            }
+            for (segment, sent, retransmits) in stream.unacked.values_mut() {
+                if now >= *sent + STREAM_RTO {
+                    *sent = now;
+                    *retransmits += 1;
+                    segments.push( (key.peer, segment.clone()) );
+                }
+            }
+        }
+        for key in dead.iter() {
+            self.remove(key);
+        }
+        segments
+    }
+
+    // Forget the stream, the local connection stops once it notices
+    pub fn remove(&mut self, key: &StreamKey) {
+        if let Some(stream) = self.streams.remove(key) {
+            stream.space.notify_one();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_stream_reorder_window() {
+    let now = Instant::now();
+    let (a_host, b_host) = ( Host::new("127.0.0.1:1"), Host::new("127.0.0.1:2") );
+    let (mut a, mut b) = ( StreamTable::new(), StreamTable::new() );
+    let (a_deliver, _a_delivered) = mpsc::channel(STREAM_WINDOW);
+    let (b_deliver, mut b_delivered) = mpsc::channel(2);
+
+    let (a_key, open) = a.open( b_host, 22, a_deliver, now );
+    let b_key = StreamKey::received( a_host, open.stream() );
+    assert!(b.accept( b_key, 0, b_deliver.clone() ));
+    assert!(!b.accept( b_key, 0, b_deliver ));
+    assert_eq!(b.receive(&b_key, open),Some( StreamMessage::Ack { stream: b_key.wire(), next: 1, window: 2 } ));
+    assert_eq!(StreamKey::received( b_host, b_key.wire() ),a_key);
+
+    let mut segments: Vec<StreamMessage> = Vec::new();
+    for i in 0..3u8 {
+        match a.write( &a_key, &[i], now ) {
+            StreamSend::Sent(segment) => segments.push(segment),
+            _ => panic!("the window is open"),
+        }
+    }
+
+    // out of order: nothing delivered before the gap is filled, then only what fits
+    assert_eq!(b.receive(&b_key, segments[2].clone()),Some( StreamMessage::Ack { stream: b_key.wire(), next: 1, window: 2 } ));
+    assert_eq!(b.receive(&b_key, segments[0].clone()),Some( StreamMessage::Ack { stream: b_key.wire(), next: 2, window: 1 } ));
+    assert_eq!(b.receive(&b_key, segments[1].clone()),Some( StreamMessage::Ack { stream: b_key.wire(), next: 3, window: 0 } ));
+    assert_eq!(b_delivered.recv().await,Some( vec![0] ));
+    assert_eq!(b_delivered.recv().await,Some( vec![1] ));
+
+    // a closed window only lets the segment in flight through, retransmitted until it fits
+    a.acknowledge( &a_key, 3, 0 );
+    assert!(matches!( a.write( &a_key, &[3], now ), StreamSend::Blocked(_) ));
+    assert_eq!(b.receive(&b_key, segments[2].clone()),Some( StreamMessage::Ack { stream: b_key.wire(), next: 4, window: 1 } ));
+    assert_eq!(b_delivered.recv().await,Some( vec![2] ));
+    a.acknowledge( &a_key, 4, 1 );
+    assert!(matches!( a.write( &a_key, &[3], now ), StreamSend::Sent(_) ));
+    assert!(matches!( a.write( &a_key, &[4], now ), StreamSend::Blocked(_) ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_stream_retransmit_close() {
+    let (a_host, b_host) = ( Host::new("127.0.0.1:1"), Host::new("127.0.0.1:2") );
+    let (mut a, mut b) = ( StreamTable::new(), StreamTable::new() );
+    let (a_deliver, mut a_delivered) = mpsc::channel(STREAM_WINDOW);
+    let (b_deliver, mut b_delivered) = mpsc::channel(STREAM_WINDOW);
+
+    let (a_key, open) = a.open( b_host, 22, a_deliver, Instant::now() );
+    assert!(a.retransmit( Instant::now() ).is_empty());
+    tokio::time::advance(STREAM_RTO).await;
+    assert_eq!(a.retransmit( Instant::now() ),vec![ (b_host, open.clone()) ]);
+
+    // both sides close, the streams are forgotten once everything is acknowledged
+    let b_key = StreamKey::received( a_host, open.stream() );
+    b.accept( b_key, 0, b_deliver );
+    b.receive( &b_key, open );
+    let close = a.shutdown( &a_key, Instant::now() ).unwrap();
+    assert_eq!(a.shutdown( &a_key, Instant::now() ),None);
+    if let Some(StreamMessage::Ack { next, window, .. }) = b.receive( &b_key, close.clone() ) {
+        a.acknowledge( &a_key, next, window );
+    }
+    assert_eq!(b_delivered.recv().await,None);
+    let close = b.shutdown( &b_key, Instant::now() ).unwrap();
+    if let Some(StreamMessage::Ack { next, window, .. }) = a.receive( &a_key, close.clone() ) {
+        b.acknowledge( &b_key, next, window );
+    }
+    assert_eq!(a_delivered.recv().await,None);
+    assert_eq!((a.len(), b.len()),(0, 0));
+    assert!(b.receive( &b_key, close ).is_some());
+
+    // a peer which never answers is given up on
+    let (a_deliver, _) = mpsc::channel(STREAM_WINDOW);
+    a.open( b_host, 22, a_deliver, Instant::now() );
+    for _ in 0..=MAX_RETRANSMITS {
+        tokio::time::advance(STREAM_RTO).await;
+        a.retransmit( Instant::now() );
+    }
+    assert_eq!(a.len(),0);
+}
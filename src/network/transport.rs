@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt,AsyncWriteExt};
+#[cfg(target_os = "linux")]
+use tokio::io::Interest;
+use tokio::net::{UdpSocket,TcpListener,TcpStream,UnixDatagram};
+use tokio::net::tcp::{OwnedReadHalf,OwnedWriteHalf};
+use tokio::sync::{mpsc,Mutex};
+use tokio::time::Duration;
+use socket2::{Socket,Domain,Type,Protocol};
+
+use crate::network::host::Host;
+#[cfg(target_os = "linux")]
+use crate::network::batch;
+
+pub type TransportFuture<'a, T> = Pin<Box<dyn Future<Output = std::io::Result<T>> + Send + 'a>>;
+
+// Whatever moves our datagrams: the rest of the network only sees hosts and bytes
+pub trait Transport: std::fmt::Debug + Send + Sync {
+    fn send_to<'a>(&'a self, buf: &'a [u8], dst: Host) -> TransportFuture<'a, usize>;
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, Host)>;
+    fn local_addr(&self) -> std::io::Result<Host>;
+
+    fn set_broadcast(&self, on: bool) -> std::io::Result<()> {
+        Err( std::io::Error::new( std::io::ErrorKind::Unsupported, "broadcast" ) )
+    }
+
+    // How many of `packets` were sent, from the first one: one at a time unless the transport does better
+    fn send_batch<'a>(&'a self, packets: &'a [(Vec<u8>,Host)]) -> TransportFuture<'a, usize> {
+        Box::pin( async move {
+            for (sent, (bytes, dst)) in packets.iter().enumerate() {
+                if let Err(err) = self.send_to(bytes, *dst).await {
+                    return match sent {
+                        0 => Err(err),
+                        sent => Ok(sent),
+                    };
+                }
+            }
+            Ok( packets.len() )
+        })
+    }
+
+    // At least one datagram into `bufs`, with its length and source into `meta`
+    fn recv_batch<'a>(&'a self, bufs: &'a mut [Vec<u8>], meta: &'a mut [(usize,Host)]) -> TransportFuture<'a, usize> {
+        Box::pin( async move {
+            match (bufs.first_mut(), meta.first_mut()) {
+                (Some(buf), Some(first)) => {
+                    *first = self.recv_from(buf).await?;
+                    Ok(1)
+                },
+                _ => Ok(0),
+            }
+        })
+    }
+}
+
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq,Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    Udp,
+    Tcp,
+    Unix,
+}
+
+pub async fn bind(kind: TransportKind, host: Host) -> std::io::Result<Arc<dyn Transport>> {
+    match kind {
+        TransportKind::Udp => Ok( Arc::new( UdpSocket::bind( host.sock() ).await? ) ),
+        TransportKind::Tcp => Ok( Arc::new( TcpTransport::bind(host).await? ) ),
+        TransportKind::Unix => Ok( Arc::new( UnixTransport::bind(host)? ) ),
+    }
+}
+
+// `count` UDP sockets on the same address: with SO_REUSEPORT the kernel spreads the senders
+// among them, each sender always landing on the same one. A single socket where it is not supported
+pub fn bind_shards(host: Host, count: usize) -> std::io::Result<Vec<Arc<dyn Transport>>> {
+    let mut shards: Vec<Arc<dyn Transport>> = Vec::new();
+    // port 0: the others take the port the first one got
+    let mut addr = host.sock();
+    for _ in 0..count.max(1) {
+        let socket = Socket::new( Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP) )?;
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))))]
+        socket.set_reuse_port(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind( &addr.into() )?;
+        let sock = UdpSocket::from_std( socket.into() )?;
+        addr = sock.local_addr()?;
+        shards.push( Arc::new(sock) );
+        if !cfg!(all(unix, not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin")))) {
+            break;
+        }
+    }
+    Ok(shards)
+}
+
+impl Transport for UdpSocket {
+    fn send_to<'a>(&'a self, buf: &'a [u8], dst: Host) -> TransportFuture<'a, usize> {
+        Box::pin( UdpSocket::send_to(self, buf, dst.sock()) )
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, Host)> {
+        Box::pin( async move {
+            let (len, addr) = UdpSocket::recv_from(self, buf).await?;
+            Ok( (len, Host::from(addr)) )
+        })
+    }
+
+    fn local_addr(&self) -> std::io::Result<Host> {
+        Ok( Host::from( UdpSocket::local_addr(self)? ) )
+    }
+
+    fn set_broadcast(&self, on: bool) -> std::io::Result<()> {
+        UdpSocket::set_broadcast(self, on)
+    }
+
+    // sendmmsg: up to BATCH_SIZE messages per system call, with GSO several datagrams in each
+    #[cfg(target_os = "linux")]
+    fn send_batch<'a>(&'a self, packets: &'a [(Vec<u8>,Host)]) -> TransportFuture<'a, usize> {
+        use std::os::fd::AsRawFd;
+        Box::pin( self.async_io( Interest::WRITABLE, move || batch::sendmmsg( self.as_raw_fd(), packets ) ) )
+    }
+
+    // recvmmsg: waits for the first datagram, takes the others already queued along
+    #[cfg(target_os = "linux")]
+    fn recv_batch<'a>(&'a self, bufs: &'a mut [Vec<u8>], meta: &'a mut [(usize,Host)]) -> TransportFuture<'a, usize> {
+        use std::os::fd::AsRawFd;
+        Box::pin( async move {
+            self.async_io( Interest::READABLE, || batch::recvmmsg( self.as_raw_fd(), bufs, meta ) ).await
+        })
+    }
+}
+
+// How long an accepted connection has to send its first frame
+pub const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Every frame is [u16 length][datagram]. The first frame on a connection is the listening
+// address of the side that opened it, so answers flow back on the same stream. Only its port is
+// taken, the IP is the one the connection comes from
+#[derive(Debug)]
+pub struct TcpTransport {
+    local: Host,
+    streams: Arc<Mutex<HashMap<Host,Arc<Mutex<OwnedWriteHalf>>>>>,
+    inbox_tx: mpsc::Sender<(Vec<u8>,Host)>,
+    inbox: Mutex<mpsc::Receiver<(Vec<u8>,Host)>>,
+}
+
+impl TcpTransport {
+    pub async fn bind(host: Host) -> std::io::Result<TcpTransport> {
+        let listener = TcpListener::bind( host.sock() ).await?;
+        let local = Host::from( listener.local_addr()? );
+        let (inbox_tx, inbox) = mpsc::channel(1024);
+        let streams: Arc<Mutex<HashMap<Host,Arc<Mutex<OwnedWriteHalf>>>>> = Arc::new( Mutex::new( HashMap::new() ) );
+
+        let accepted = Arc::clone(&streams);
+        let sink = inbox_tx.clone();
+        tokio::task::spawn( async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                // a connection that never announces itself must not hold up the next ones
+                let accepted = Arc::clone(&accepted);
+                let sink = sink.clone();
+                tokio::task::spawn( async move {
+                    let (mut reader, writer) = stream.into_split();
+                    let announced = match tokio::time::timeout( ANNOUNCE_TIMEOUT, read_frame(&mut reader) ).await {
+                        Ok(Ok(frame)) => String::from_utf8(frame).ok().and_then( |frame| Host::try_from( frame.as_str() ).ok() ),
+                        _ => None,
+                    };
+                    if let Some(announced) = announced {
+                        let remote = Host::from( std::net::SocketAddr::new( addr.ip(), announced.port() ) );
+                        accepted.lock().await.insert( remote, Arc::new( Mutex::new(writer) ) );
+                        pump(reader, remote, sink, accepted).await;
+                    }
+                });
+            }
+        });
+
+        Ok( TcpTransport { local, streams, inbox_tx, inbox: Mutex::new(inbox) } )
+    }
+
+    async fn stream(&self, dst: Host) -> std::io::Result<Arc<Mutex<OwnedWriteHalf>>> {
+        if let Some(writer) = self.streams.lock().await.get(&dst) {
+            return Ok( Arc::clone(writer) );
+        }
+
+        let (reader, mut writer) = TcpStream::connect( dst.sock() ).await?.into_split();
+        write_frame( &mut writer, self.local.local_addr().as_bytes() ).await?;
+        let writer = Arc::new( Mutex::new(writer) );
+        self.streams.lock().await.insert( dst, Arc::clone(&writer) );
+        tokio::task::spawn( pump(reader, dst, self.inbox_tx.clone(), Arc::clone(&self.streams)) );
+        Ok(writer)
+    }
+}
+
+async fn read_frame(reader: &mut OwnedReadHalf) -> std::io::Result<Vec<u8>> {
+    let len = reader.read_u16().await?;
+    let mut frame: Vec<u8> = vec![0; len as usize];
+    reader.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+// Larger than the length prefix allows: refused rather than cut
+async fn write_frame(writer: &mut OwnedWriteHalf, frame: &[u8]) -> std::io::Result<()> {
+    if frame.len() > u16::MAX as usize {
+        return Err( std::io::Error::from( std::io::ErrorKind::InvalidInput ) );
+    }
+    let mut bytes: Vec<u8> = Vec::with_capacity( frame.len() + 2 );
+    bytes.extend_from_slice( &(frame.len() as u16).to_be_bytes() );
+    bytes.extend_from_slice(frame);
+    writer.write_all(&bytes).await
+}
+
+// Frames from one connection into the inbox, until it closes
+async fn pump(mut reader: OwnedReadHalf, remote: Host, inbox: mpsc::Sender<(Vec<u8>,Host)>, streams: Arc<Mutex<HashMap<Host,Arc<Mutex<OwnedWriteHalf>>>>>) {
+    while let Ok(frame) = read_frame(&mut reader).await {
+        if inbox.send( (frame, remote) ).await.is_err() {
+            break;
+        }
+    }
+    streams.lock().await.remove(&remote);
+}
+
+impl Transport for TcpTransport {
+    fn send_to<'a>(&'a self, buf: &'a [u8], dst: Host) -> TransportFuture<'a, usize> {
+        Box::pin( async move {
+            let writer = self.stream(dst).await?;
+            let sent = write_frame( &mut *writer.lock().await, buf ).await;
+            if sent.is_err() {
+                self.streams.lock().await.remove(&dst);
+            }
+            sent.map( |_| buf.len() )
+        })
+    }
+
+    // Frames that don't fit in `buf` are dropped, a cut datagram would only be misread
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, Host)> {
+        Box::pin( async move {
+            let mut inbox = self.inbox.lock().await;
+            loop {
+                match inbox.recv().await {
+                    None => { return Err( std::io::Error::from( std::io::ErrorKind::BrokenPipe ) ); },
+                    Some((frame, _)) if frame.len() > buf.len() => {},
+                    Some((frame, src)) => {
+                        buf[..frame.len()].copy_from_slice(&frame);
+                        return Ok( (frame.len(), src) );
+                    },
+                }
+            }
+        })
+    }
+
+    fn local_addr(&self) -> std::io::Result<Host> {
+        Ok(self.local)
+    }
+}
+
+// Unix datagram sockets live in the temporary directory, one path per host
+pub fn unix_path(host: &Host) -> PathBuf {
+    std::env::temp_dir().join( format!("toktok-{}-{}.sock", host.ip(), host.port()) )
+}
+
+fn unix_host(path: &std::path::Path) -> Option<Host> {
+    let name = path.file_name()?.to_str()?.strip_prefix("toktok-")?.strip_suffix(".sock")?;
+    let (ip, port) = name.rsplit_once('-')?;
+    let ip: std::net::IpAddr = ip.parse().ok()?;
+    Some( Host::from( std::net::SocketAddr::new( ip, port.parse().ok()? ) ) )
+}
+
+#[derive(Debug)]
+pub struct UnixTransport {
+    local: Host,
+    sock: UnixDatagram,
+}
+
+impl UnixTransport {
+    pub fn bind(host: Host) -> std::io::Result<UnixTransport> {
+        let path = unix_path(&host);
+        // a previous run may have left its socket behind
+        let _ = std::fs::remove_file(&path);
+        Ok( UnixTransport { local: host, sock: UnixDatagram::bind(path)? } )
+    }
+}
+
+impl Drop for UnixTransport {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file( unix_path(&self.local) );
+    }
+}
+
+impl Transport for UnixTransport {
+    fn send_to<'a>(&'a self, buf: &'a [u8], dst: Host) -> TransportFuture<'a, usize> {
+        Box::pin( self.sock.send_to( buf, unix_path(&dst) ) )
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, Host)> {
+        Box::pin( async move {
+            loop {
+                let (len, addr) = self.sock.recv_from(buf).await?;
+                // datagrams from sockets outside of the overlay are dropped
+                if let Some(src) = addr.as_pathname().and_then(unix_host) {
+                    return Ok( (len, src) );
+                }
+            }
+        })
+    }
+
+    fn local_addr(&self) -> std::io::Result<Host> {
+        Ok(self.local)
+    }
+}
+
+#[test]
+fn test_unix_path_host() {
+    let host = Host::new("127.0.0.1:4242");
+    assert_eq!(Some(host),unix_host( &unix_path(&host) ));
+    let host = Host::new("[::1]:4242");
+    assert_eq!(Some(host),unix_host( &unix_path(&host) ));
+    assert_eq!(None,unix_host( std::path::Path::new("/tmp/other.sock") ));
+}
+
+#[tokio::test]
+async fn test_transports_round_trip() -> std::io::Result<()> {
+    for (kind, a, b) in [
+        (TransportKind::Udp, "127.0.0.1:4660", "127.0.0.1:4661"),
+        (TransportKind::Tcp, "127.0.0.1:4662", "127.0.0.1:4663"),
+        (TransportKind::Unix, "127.0.0.1:4664", "127.0.0.1:4665"),
+    ] {
+        let a = bind( kind, Host::new(a) ).await?;
+        let b = bind( kind, Host::new(b) ).await?;
+        let mut buf = [0; 1026];
+
+        assert_eq!(3,a.send_to( &[1,2,3], b.local_addr()? ).await?);
+        let (len, src) = b.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..len],&[1,2,3]);
+        assert_eq!(src,a.local_addr()?);
+
+        // the answer goes back to the announced source
+        b.send_to( &[4,5], src ).await?;
+        let (len, src) = a.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..len],&[4,5]);
+        assert_eq!(src,b.local_addr()?);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tcp_announced_address_and_oversize() -> std::io::Result<()> {
+    let b = TcpTransport::bind( Host::new("127.0.0.1:4731") ).await?;
+    let mut buf = [0; 1026];
+
+    // a connection staying silent holds up nobody
+    let _silent = TcpStream::connect( b.local_addr()?.sock() ).await?;
+
+    // an address announced for somebody else keeps only its port
+    let (_, mut writer) = TcpStream::connect( b.local_addr()?.sock() ).await?.into_split();
+    write_frame( &mut writer, b"10.9.9.9:4732" ).await?;
+    write_frame( &mut writer, &[0; 2000] ).await?;
+    write_frame( &mut writer, &[1,2,3] ).await?;
+
+    // the frame too large for the buffer is dropped, not cut
+    let (len, src) = b.recv_from(&mut buf).await?;
+    assert_eq!(&buf[..len],&[1,2,3]);
+    assert_eq!(src,Host::new("127.0.0.1:4732"));
+
+    assert_eq!(std::io::ErrorKind::InvalidInput,write_frame( &mut writer, &vec![0; u16::MAX as usize + 1] ).await.unwrap_err().kind());
+    Ok(())
+}
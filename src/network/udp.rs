@@ -6,6 +6,7 @@ use std::collections::VecDeque;
 
 use crate::message::tlv::TLV;
 use crate::network::host::Host;
+use crate::network::peer::PeerId;
 use crate::message::header::Header;
 
 #[derive(Debug,Clone)]
@@ -13,11 +14,22 @@ pub struct Datagram {
     src: Option<Host>,
     data: TLV,
     dst: Option<Host>,
+    peer: Option<PeerId>,
 }
 
 impl Datagram {
     pub fn new(src: Option<Host>, data: TLV, dst: Option<Host>) -> Datagram {
-        Datagram { src, data, dst }
+        Datagram { src, data, dst, peer: None }
+    }
+
+    // Address the datagram to a peer ID, the network picks the next hop when sending
+    pub fn with_peer(mut self, peer: PeerId) -> Datagram {
+        self.peer = Some(peer);
+        self
+    }
+
+    pub fn peer(&self) -> Option<PeerId> {
+        self.peer
     }
 
     pub fn src(&self) -> Option<Host> { 
@@ -46,7 +58,7 @@ impl Datagram {
 
     pub fn from_bytes(src: Option<Host>, dg_bytes: Vec<u8>, dst: Option<Host>) -> Option<Datagram> { 
         if let Some(data) = TLV::from_bytes( VecDeque::from(dg_bytes) ) {
-            Some(Datagram { src, data, dst })
+            Some(Datagram { src, data, dst, peer: None })
         }
         else { None }
     }
@@ -69,12 +81,12 @@ impl Datagram {
 
 impl From<TLV> for Datagram {
     fn from(data: TLV) -> Self {
-        Datagram { src: None, data, dst: None }
+        Datagram { src: None, data, dst: None, peer: None }
     }
 }
 
 impl From<Header> for Datagram {
     fn from(header: Header) -> Self {
-        Datagram { src: None, data: TLV::new(header,None).unwrap() , dst: None }
+        Datagram { src: None, data: TLV::new(header,None).unwrap() , dst: None, peer: None }
     }
 }
\ No newline at end of file
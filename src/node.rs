@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+use std::sync::{Arc,Mutex};
+use tokio::time::Duration;
+
+use crate::crypto::asymetric::KeyPair;
+
+use crate::network::udp::Datagram;
+use crate::network::network::Network;
+use crate::network::packet_io::PacketIo;
+use crate::network::host::RESOLVE_PERIOD;
+use crate::network::middleware::{Layer,Pipeline};
+use crate::network::session::SessionState;
+
+use crate::message::signal::{Signal,SignalType};
+use crate::message::header::Header;
+
+use crate::memory::sqlite::SqliteCore;
+use crate::memory::shared_fifo::SharedFifo;
+
+use crate::workers::config::{Config,ConfigErr};
+use crate::workers::registry::{HandlerRegistry,MessageHandler};
+use crate::workers::supervise::{supervise,report,WorkerFactory,WorkerState,WorkerStatus,WorkerStatuses};
+use crate::workers::{heartbeat::heartbeater,receive::receiver,emit::emitter,dispatch::dispatcher,lease::leaser,forward::{forwarder,retransmitter},dns::dns_responder,resolve::resolver,advertise::{advertiser,ADVERTISE_PERIOD},tunnel::tunneler,trace::tracer};
+
+// What a node runs, all of them unless told otherwise. Some only start when they have
+// something to do: the DNS responder, forwarders, the tunneler and the tracer
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum Worker {
+    Receiver,
+    Dispatcher,
+    Emitter,
+    Heartbeater,
+    Leaser,
+    Retransmitter,
+    Resolver,
+    Advertiser,
+    DnsResponder,
+    Forwarder,
+    Tunneler,
+    Tracer,
+}
+
+pub const WORKERS: [Worker; 12] = [
+    Worker::Receiver, Worker::Dispatcher, Worker::Emitter, Worker::Heartbeater, Worker::Leaser, Worker::Retransmitter,
+    Worker::Resolver, Worker::Advertiser, Worker::DnsResponder, Worker::Forwarder, Worker::Tunneler, Worker::Tracer,
+];
+
+// The tracer keeps seconds between two looks at its queue
+const TRACER_GRACETIME: u64 = 5;
+// How long one-shot commands wait for a peer to answer our HELLO, and how often they look
+pub const JOIN_TIMEOUT: Duration = Duration::from_secs(2);
+const JOIN_POLL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NodeErr {
+    NoConfig,
+    Config(ConfigErr),
+    Storage,
+}
+
+pub struct Node;
+
+impl Node {
+    pub fn builder() -> NodeBuilder {
+        NodeBuilder { config: None, key: None, storage: None, network: None, packet_io: None, workers: WORKERS.to_vec(), handlers: HandlerRegistry::with_defaults(), middleware: Pipeline::new() }
+    }
+}
+
+pub struct NodeBuilder {
+    config: Option<Config>,
+    key: Option<KeyPair>,
+    // SQLite file the tracer writes to
+    storage: Option<String>,
+    // Instead of the one the configuration binds, e.g. on a simulated network
+    network: Option<Network>,
+    packet_io: Option<Arc<dyn PacketIo>>,
+    workers: Vec<Worker>,
+    handlers: HandlerRegistry,
+    middleware: Pipeline,
+}
+
+impl NodeBuilder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    // The configuration must be signed with it
+    pub fn key(mut self, key: KeyPair) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn storage(mut self, filename: &str) -> Self {
+        self.storage = Some( filename.to_string() );
+        self
+    }
+
+    pub fn network(mut self, net: Network) -> Self {
+        self.network = Some(net);
+        self
+    }
+
+    pub fn packet_io(mut self, io: Arc<dyn PacketIo>) -> Self {
+        self.packet_io = Some(io);
+        self
+    }
+
+    pub fn workers(mut self, workers: &[Worker]) -> Self {
+        self.workers = workers.to_vec();
+        self
+    }
+
+    // On top of the defaults, replacing the one already there for `header` e.g. PING
+    pub fn handler(mut self, header: Header, handler: Arc<dyn MessageHandler>) -> Self {
+        self.handlers.register(header, handler);
+        self
+    }
+
+    // Layers wrap each other in the order they are added, see Pipeline
+    pub fn layer(mut self, layer: Arc<dyn Layer>) -> Self {
+        self.middleware = self.middleware.layer(layer);
+        self
+    }
+
+    pub async fn start(self) -> Result<NodeHandle,NodeErr> {
+        let mut config: Config = match self.config {
+            None => { return Err( NodeErr::NoConfig ); },
+            Some(config) => config,
+        };
+        if let Some(key) = self.key.as_ref() {
+            config.verify(key).map_err(NodeErr::Config)?;
+        }
+        if let Some(storage) = self.storage.as_ref() {
+            if SqliteCore::init(storage).is_none() {
+                return Err( NodeErr::Storage );
+            }
+        }
+        let net: Network = match self.network {
+            Some(net) => net,
+            None => config.into_network().await.map_err(NodeErr::Config)?,
+        };
+        let mut net: Network = net.with_handlers(self.handlers).with_middleware(self.middleware);
+        // a private key also signs our handshakes
+        if let Some(key) = self.key {
+            net = net.with_key(key);
+        }
+        for client in config.clients().iter().chain( net.server().as_ref() ) {
+            net.insert(client);
+        }
+
+        let (income, outcome) = config.queues();
+        let backbone: Signal<()> = Signal::new(SignalType::broadcast);
+        let statuses: WorkerStatuses = Arc::new( Mutex::new( HashMap::new() ) );
+        let mut handle = NodeHandle { net: net.clone(), income: income.clone(), outcome: outcome.clone(), backbone, statuses, supervisors: Vec::new() };
+        let runs = |worker: Worker| self.workers.contains(&worker);
+
+        if runs(Worker::Receiver) {
+            let queues = match runs(Worker::Dispatcher) {
+                true => config.ingress_queues(&income),
+                false => vec![ income.clone() ],
+            };
+            for (index, shard) in net.receivers().into_iter().enumerate() {
+                let queue = queues[index % queues.len()].clone();
+                handle.spawn( format!("receiver/{}", index), Box::new( move |backbone| Box::pin( receiver( shard.clone(), queue.clone(), backbone ) ) ) );
+            }
+            if runs(Worker::Dispatcher) {
+                for (index, queue) in queues.into_iter().enumerate() {
+                    let (net, outcome) = (net.clone(), outcome.clone());
+                    handle.spawn( format!("dispatcher/{}", index), Box::new( move |backbone| Box::pin( dispatcher( net.clone(), queue.clone(), outcome.clone(), backbone ) ) ) );
+                }
+            }
+        }
+        else if runs(Worker::Dispatcher) {
+            let (net, income, outcome) = (net.clone(), income.clone(), outcome.clone());
+            handle.spawn( "dispatcher/0".to_string(), Box::new( move |backbone| Box::pin( dispatcher( net.clone(), income.clone(), outcome.clone(), backbone ) ) ) );
+        }
+
+        if runs(Worker::Emitter) {
+            let (net, outcome) = (net.clone(), outcome.clone());
+            handle.spawn( "emitter".to_string(), Box::new( move |backbone| Box::pin( emitter( net.clone(), outcome.clone(), backbone ) ) ) );
+        }
+        if runs(Worker::Heartbeater) {
+            let net = net.clone();
+            handle.spawn( "heartbeater".to_string(), Box::new( move |backbone| Box::pin( heartbeater( net.clone(), backbone ) ) ) );
+        }
+        if runs(Worker::Leaser) {
+            let net = net.clone();
+            handle.spawn( "leaser".to_string(), Box::new( move |backbone| Box::pin( leaser( net.clone(), backbone ) ) ) );
+        }
+        if runs(Worker::Retransmitter) {
+            let (net, outcome) = (net.clone(), outcome.clone());
+            handle.spawn( "retransmitter".to_string(), Box::new( move |backbone| Box::pin( retransmitter( net.clone(), outcome.clone(), backbone ) ) ) );
+        }
+        if runs(Worker::Resolver) {
+            let net = net.clone();
+            handle.spawn( "resolver".to_string(), Box::new( move |backbone| Box::pin( resolver( net.clone(), backbone, RESOLVE_PERIOD ) ) ) );
+        }
+        if runs(Worker::Advertiser) {
+            let net = net.clone();
+            handle.spawn( "advertiser".to_string(), Box::new( move |backbone| Box::pin( advertiser( net.clone(), backbone, ADVERTISE_PERIOD ) ) ) );
+        }
+        if runs(Worker::DnsResponder) && net.dns().is_some() {
+            let net = net.clone();
+            handle.spawn( "dns_responder".to_string(), Box::new( move |backbone| Box::pin( dns_responder( net.clone(), backbone ) ) ) );
+        }
+        if runs(Worker::Forwarder) {
+            for service in net.local_services().into_iter().filter( |service| service.forward().is_some() ) {
+                let (net, outcome) = (net.clone(), outcome.clone());
+                handle.spawn( format!("forwarder/{}", service.name()), Box::new( move |backbone| Box::pin( forwarder( net.clone(), service.clone(), outcome.clone(), backbone ) ) ) );
+            }
+        }
+        if let (true, Some(io)) = (runs(Worker::Tunneler), self.packet_io) {
+            let (net, outcome) = (net.clone(), outcome.clone());
+            handle.spawn( "tunneler".to_string(), Box::new( move |backbone| Box::pin( tunneler( net.clone(), Arc::clone(&io), outcome.clone(), backbone ) ) ) );
+        }
+        if let (true, Some(storage)) = (runs(Worker::Tracer), self.storage) {
+            let outcome = outcome.clone();
+            // nothing feeds it yet, see the dispatcher. It never looks at the backbone: stopping aborts it
+            handle.spawn( "tracer".to_string(), Box::new( move |_| {
+                let (storage, outcome) = (storage.clone(), outcome.clone());
+                Box::pin( async move {
+                    match SqliteCore::init(&storage) {
+                        None => Err( std::io::Error::other("No db file found !") ),
+                        Some(co) => tracer( Signal::new(SignalType::mpsc), co, outcome, TRACER_GRACETIME ).await,
+                    }
+                })
+            }) );
+        }
+
+        handle.join().await;
+        Ok(handle)
+    }
+}
+
+// A running node: its network, its queues and how its workers are doing
+pub struct NodeHandle {
+    net: Network,
+    income: SharedFifo<Datagram,()>,
+    outcome: SharedFifo<Datagram,()>,
+    backbone: Signal<()>,
+    statuses: WorkerStatuses,
+    supervisors: Vec<tokio::task::JoinHandle<std::io::Result<()>>>,
+}
+
+impl NodeHandle {
+    fn spawn(&mut self, name: String, factory: WorkerFactory) {
+        report(&self.statuses, &name, WorkerState::Running, None);
+        self.supervisors.push( tokio::task::spawn( supervise( name, factory, self.backbone.subscribe(), Arc::clone(&self.statuses) ) ) );
+    }
+
+    pub fn network(&self) -> Network {
+        self.net.clone()
+    }
+
+    // Whether a session with some peer got established within `timeout`
+    pub async fn established(&self, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if self.net.sessions().values().any( |session| session.state() == SessionState::Established ) {
+                return true;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(JOIN_POLL).await;
+        }
+    }
+
+    // HELLO to the known clients, the server among them, and to whoever listens on the gateway's
+    // broadcast address. Unanswered greetings are sent again by the heartbeater
+    pub async fn join(&self) {
+        self.net.send_many( self.net.greetings() ).await;
+        self.net.broadcast( self.net.hello() ).await.ok();
+    }
+
+    pub fn income(&self) -> SharedFifo<Datagram,()> {
+        self.income.clone()
+    }
+
+    pub fn outcome(&self) -> SharedFifo<Datagram,()> {
+        self.outcome.clone()
+    }
+
+    // Per worker, e.g. "receiver/0" or "forwarder/<service>"
+    pub fn status(&self) -> HashMap<String,WorkerStatus> {
+        self.statuses.lock().unwrap().clone()
+    }
+
+    // Peers are told BYE when there is an emitter to send it, the workers are waited for
+    pub async fn shutdown(mut self) -> std::io::Result<()> {
+        if self.statuses.lock().unwrap().contains_key("emitter") {
+            for bye in self.net.farewell() {
+                self.outcome.push(bye);
+            }
+        }
+        self.backbone.send(()).await.ok();
+        for supervisor in self.supervisors.drain(..) {
+            supervisor.await.ok();
+        }
+        Ok(())
+    }
+}
+
+// for test only: a node running `workers` on an in-process network, fed `i_max` copies of `dg` by a client
+// not running any. Both networks and the node's queues are returned once it is shut down
+#[cfg(test)]
+pub async fn simulated_server_and_client(dg: Datagram, i_max: usize, workers: &[Worker]) -> std::io::Result<(Network,Network,SharedFifo<Datagram,()>,SharedFifo<Datagram,()>)> {
+    use crate::network::host::Host;
+
+    let sim = crate::network::simulated::SimNet::new(0);
+    let bind = |host: &str| Arc::new( sim.bind( Host::new(host) ) );
+    let server = Network::new( bind("127.0.0.1:3333"), None, Host::new("127.255.255.255:3333"), None );
+    let client = Network::new( bind("127.0.0.1:3334"), Some( bind("127.0.0.1:3335") ), Host::new("127.255.255.255:3333"), Some( Host::new("127.0.0.1:3333") ) );
+    let config: Config = serde_json::from_str(r#"{"server":null,"gateway":"127.255.255.255:3333","rx":"127.0.0.1:3333","tx":null,"clients":null,"services":null,"signature":null}"#).unwrap();
+
+    let node = match Node::builder().config(config).network( server.clone() ).workers(workers).start().await {
+        Err(err) => { return Err( std::io::Error::other( format!("{:?}", err) ) ); },
+        Ok(node) => node,
+    };
+    let (income, outcome) = (node.income(), node.outcome());
+    for _ in 0..i_max {
+        client.send_to( dg.clone(), Some( server.local_addr() ) ).await.ok();
+    }
+    tokio::time::sleep( tokio::time::Duration::from_secs(1) ).await;
+    node.shutdown().await?;
+    Ok((client, server, income, outcome))
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_node_lifecycle() -> std::io::Result<()> {
+    use crate::message::header::Header;
+
+    // the whole node answers a HELLO, then stops every worker
+    let (_, server, income, outcome) = simulated_server_and_client( Datagram::from(Header::HELLO), 1, &WORKERS ).await?;
+    assert!(income.pop().is_none());
+    assert!(outcome.pop().is_none());
+    // said HELLO to us, then we said BYE
+    let session = server.session( &crate::network::host::Host::new("127.0.0.1:3335") ).unwrap();
+    assert_eq!(session.state(),crate::network::session::SessionState::Closing);
+
+    let config: Config = serde_json::from_str(r#"{"server":null,"gateway":"127.255.255.255:4713","rx":"127.0.0.1:4713","tx":null,"clients":null,"services":null,"signature":null}"#).unwrap();
+    assert_eq!(Node::builder().start().await.err(),Some(NodeErr::NoConfig));
+    assert_eq!(Node::builder().config( config.clone() ).storage("/nonexistent/toktok.db").start().await.err(),Some(NodeErr::Storage));
+
+    let sim = crate::network::simulated::SimNet::new(0);
+    let net = Network::new( Arc::new( sim.bind( crate::network::host::Host::new("127.0.0.1:4713") ) ), None, crate::network::host::Host::new("127.255.255.255:4713"), None );
+    let node = Node::builder().config(config).network(net).workers( &[Worker::Receiver, Worker::Emitter, Worker::DnsResponder] ).start().await.unwrap();
+    let status = node.status();
+    let mut names: Vec<&String> = status.keys().collect();
+    names.sort();
+    assert_eq!(names,vec!["emitter", "receiver/0"]);
+    assert!(status.values().all( |status| status.state() == WorkerState::Running ));
+    node.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_node_middleware() -> std::io::Result<()> {
+    use crate::network::host::Host;
+    use crate::network::middleware::{MetricsLayer,RateLimitLayer};
+    use crate::network::pacing::RateLimit;
+    use crate::message::header::Header;
+    use crate::message::tlv::TLV;
+
+    let sim = crate::network::simulated::SimNet::new(0);
+    let server = Network::new( Arc::new( sim.bind( Host::new("127.0.0.1:4717") ) ), None, Host::new("127.255.255.255:4717"), None );
+    let client = Network::new( Arc::new( sim.bind( Host::new("127.0.0.1:4718") ) ), None, Host::new("127.255.255.255:4717"), None );
+    let config: Config = serde_json::from_str(r#"{"server":null,"gateway":"127.255.255.255:4717","rx":"127.0.0.1:4717","tx":null,"clients":null,"services":null,"signature":null}"#).unwrap();
+
+    // counted first, then 5 empty PINGs of 2 bytes in all get through
+    let metrics = Arc::new( MetricsLayer::new() );
+    let limit = Arc::new( RateLimitLayer::new( RateLimit::new(0, 10) ) );
+    let node = Node::builder().config(config).network(server).workers( &[Worker::Receiver, Worker::Emitter] )
+        .layer( metrics.clone() )
+        .layer( limit.clone() )
+        .start().await.unwrap();
+
+    let ping = Datagram::new( None, TLV::new( Header::PING, None ).unwrap(), None );
+    for _ in 0..8 {
+        client.send_to( ping.clone(), Some( node.network().local_addr() ) ).await.ok();
+    }
+    node.outcome().push_notice( Datagram::new( None, TLV::new( Header::PONG, None ).unwrap(), Some( client.local_addr() ) ), () ).await.ok();
+    tokio::time::sleep( tokio::time::Duration::from_secs(1) ).await;
+
+    let income = node.income();
+    node.shutdown().await?;
+    let mut received: usize = 0;
+    while income.pop().is_some() {
+        received += 1;
+    }
+    assert_eq!(received,5);
+    assert_eq!(limit.dropped(),3);
+    assert_eq!(metrics.inbound_counts()[&Header::PING],8);
+    assert_eq!(metrics.outbound_counts()[&Header::PONG],1);
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_node_join() -> std::io::Result<()> {
+    use crate::network::host::Host;
+    use crate::network::session::SessionState;
+
+    // `a` knows `b` from its configuration, `b` knows nobody
+    let sim = crate::network::simulated::SimNet::new(0);
+    let (a, b) = ( Host::new("127.0.0.1:4721"), Host::new("127.0.0.2:4722") );
+    let config = |rx: Host, clients: &str| serde_json::from_str::<Config>( &format!(r#"{{"server":null,"gateway":"127.255.255.255:4721","rx":"{}","tx":null,"clients":{},"services":null,"signature":null}}"#, rx.local_addr(), clients) ).unwrap();
+    let workers = [Worker::Receiver, Worker::Dispatcher, Worker::Emitter];
+
+    let node_b = Node::builder().config( config(b, "null") ).network( Network::new( Arc::new( sim.bind(b) ), None, Host::new("127.255.255.255:4721"), None ) ).workers(&workers).start().await.unwrap();
+    let node_a = Node::builder().config( config(a, &format!(r#"{{"127.0.0.2":"{}"}}"#, b.local_addr())) ).network( Network::new( Arc::new( sim.bind(a) ), None, Host::new("127.255.255.255:4721"), None ) ).workers(&workers).start().await.unwrap();
+    assert!(node_a.network().contains(&b));
+    tokio::time::sleep( tokio::time::Duration::from_secs(1) ).await;
+
+    assert_eq!(node_a.network().session(&b).map( |session| session.state() ),Some( SessionState::Established ));
+    assert_eq!(node_b.network().session(&a).map( |session| session.state() ),Some( SessionState::Established ));
+    assert_eq!(node_b.network().session(&a).and_then( |session| session.id() ),Some( node_a.network().id() ));
+    node_a.shutdown().await?;
+    node_b.shutdown().await?;
+    Ok(())
+}
@@ -5,3 +5,12 @@ pub mod emit;
 pub mod handle;
 pub mod trace;
 pub mod config;
+pub mod advertise;
+pub mod tunnel;
+pub mod lease;
+pub mod forward;
+pub mod dns;
+pub mod resolve;
+pub mod supervise;
+pub mod registry;
+pub mod pool;
@@ -0,0 +1,41 @@
+use tokio::time::{Duration,sleep};
+
+use crate::network::udp::Datagram;
+use crate::network::network::Network;
+use crate::network::routing::ROUTE_EXPIRY;
+
+use crate::message::signal::Signal;
+
+// Well within ROUTE_EXPIRY, a lost advertisement or two doesn't expire our routes
+pub const ADVERTISE_PERIOD: Duration = Duration::from_secs(5);
+
+// Periodically tell our neighbours every destination we can reach, and the services we run
+pub async fn advertiser(net: Network, mut backbone: Signal<()>, period: Duration) -> Result<(), std::io::Error> {
+    loop {
+        // If received any data => stop the thread
+        match backbone.try_recv() {
+            Err(_) => { break; },
+            Ok(data) => { 
+                if data.is_some() {
+                    break;
+                } 
+            }
+        }
+
+        net.expire_routes(ROUTE_EXPIRY);
+        for tlv in net.advertisement().to_tlvs() {
+            net.multicast( Datagram::from(tlv) ).await;
+        }
+        net.expire_services();
+        for dg in net.announcements(None) {
+            net.multicast(dg).await;
+        }
+        tokio::select! {
+            _ = backbone.recv() => { break; },
+            _ = sleep(period) => {},
+        }
+    }
+
+    backbone.close();
+    Ok(())
+}
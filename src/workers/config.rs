@@ -1,6 +1,7 @@
 use std::fs;
 use std::net::IpAddr;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use crate::crypto::asymetric::KeyPair;
 
@@ -9,17 +10,64 @@ use signature::{Verifier,Signer};
 
 use serde::{Deserialize, Serialize};
 
-use crate::network::{host::Host,network::Network,service::Service};
+use crate::network::{host::{Host,HostName},network::Network,service::Service,pacing::RateLimit,transport::{self,Transport,TransportKind},lease::{AddressPool,Cidr,LEASE_DURATION}};
+use crate::network::dns::valid_name;
+use crate::memory::sqlite::LeaseStore;
+use crate::memory::shared_fifo::{SharedFifo,QueueLimit,DropPolicy};
+use crate::network::udp::Datagram;
+use crate::message::signal::SignalType;
+
+// Queue limits when the configuration has none: a flooding host only fills its share of the
+// ingress queue, the emitter keeps the freshest datagrams
+const INGRESS_QUEUE: QueueLimit = QueueLimit::new(4096, DropPolicy::FairShare);
+const EGRESS_QUEUE: QueueLimit = QueueLimit::new(4096, DropPolicy::DropOldest);
+
+// SO_REUSEPORT sockets bound on `rx`, each drained by its own receiver. `sharded` gives each one
+// its own ingress queue, and dispatcher, instead of a queue shared by all
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,PartialEq,Eq)]
+pub struct RxShards {
+    pub sockets: usize,
+    #[serde(default)]
+    pub sharded: bool,
+}
 
 #[derive(Debug,Clone,Serialize, Deserialize, PartialEq, Eq)]
 pub struct Config {
-    server: Option<Host>,
-    gateway: Host,
+    server: Option<HostName>,
+    gateway: HostName,
     rx: Host,
     tx: Option<Host>,
     clients: Option<HashMap<IpAddr,Host>>,
     services: Option<Vec<Service>>,
     signature: Option<Vec<u8>>,
+    // Left out when unset, configurations signed before they existed stay valid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rate_limit: Option<RateLimit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer_rate_limits: Option<HashMap<Host,RateLimit>>,
+    // UDP unless told otherwise
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rx_transport: Option<TransportKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tx_transport: Option<TransportKind>,
+    // Overlay addresses handed out by this node, leases kept in `lease_file`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address_pool: Option<Cidr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lease_file: Option<String>,
+    // Our node name, and where to answer DNS queries for <name>.toktok
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns: Option<Host>,
+    // Received datagrams waiting for the dispatcher, and datagrams waiting for the emitter
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ingress_queue: Option<QueueLimit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    egress_queue: Option<QueueLimit>,
+    // UDP only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rx_shards: Option<RxShards>,
 }
 
 
@@ -33,6 +81,8 @@ pub enum ConfigErr {
     BindingTxError,
     UnableToReadSignature,
     InvalidSignature,
+    InvalidName,
+    UnresolvedHost,
 }
 
 impl Config {
@@ -69,23 +119,92 @@ impl Config {
     }
 
     pub async fn into_network(&self) -> Result<Network,ConfigErr> {
-        let sock = match tokio::net::UdpSocket::bind( self.rx.sock() ).await {
-            Err(_) => { return Err( ConfigErr::BindingRxError ); },
-            Ok(sock) => std::sync::Arc::new(sock),
+        let kind: TransportKind = self.rx_transport.unwrap_or(TransportKind::Udp);
+        let mut shards: Vec<Arc<dyn Transport>> = match (kind, self.rx_shards) {
+            (TransportKind::Udp, Some(shards)) if shards.sockets > 1 => match transport::bind_shards( self.rx, shards.sockets ) {
+                Err(_) => { return Err( ConfigErr::BindingRxError ); },
+                Ok(shards) => shards,
+            },
+            _ => match transport::bind( kind, self.rx ).await {
+                Err(_) => { return Err( ConfigErr::BindingRxError ); },
+                Ok(sock) => vec![sock],
+            },
         };
+        let sock = shards.remove(0);
         
         let sock_tx = match self.tx {
             None => None,
             Some(host) => {
-                match tokio::net::UdpSocket::bind( host.sock() ).await {
+                match transport::bind( self.tx_transport.unwrap_or(TransportKind::Udp), host ).await {
                     Err(_) => { return Err( ConfigErr::BindingTxError ); },
-                    Ok(sock_tx) => Some(std::sync::Arc::new(sock_tx)),
+                    Ok(sock_tx) => Some(sock_tx),
                 } 
             },
         };
         
 
-        Ok( Network::new(sock,sock_tx,self.gateway,self.server) )
+        let peer_rate_limits: HashMap<Host,RateLimit> = self.peer_rate_limits.clone().unwrap_or_default();
+        // The names are kept, the network resolves them again later
+        let mut gateway: HostName = self.gateway.clone();
+        let mut server: Option<HostName> = self.server.clone();
+        if gateway.resolve().await.is_err() {
+            return Err( ConfigErr::UnresolvedHost );
+        }
+        if let Some(server) = server.as_mut() {
+            if server.resolve().await.is_err() {
+                return Err( ConfigErr::UnresolvedHost );
+            }
+        }
+
+        let mut net = Network::with_transport( sock, sock_tx, gateway.host().unwrap(), server.as_ref().and_then( |server| server.host() ) )
+            .with_hostnames( Some(gateway), server )
+            .with_limits(self.rate_limit,peer_rate_limits)
+            .with_services( self.services.clone().unwrap_or_default() )
+            .with_shards(shards);
+        if let Some(cidr) = self.address_pool {
+            let mut pool = AddressPool::new(cidr, LEASE_DURATION);
+            if let Some(store) = self.lease_file.as_ref().and_then(LeaseStore::open) {
+                pool = pool.with_store(store);
+            }
+            net = net.with_pool(pool);
+        }
+        if let Some(name) = self.name.as_ref() {
+            if !valid_name(name) {
+                return Err( ConfigErr::InvalidName );
+            }
+            net = net.with_name(name);
+        }
+        if let Some(listen) = self.dns {
+            net = net.with_dns(listen);
+        }
+        Ok(net)
+    }
+
+    // The peers to greet when the node starts
+    pub fn clients(&self) -> Vec<Host> {
+        self.clients.as_ref().map( |clients| clients.values().copied().collect() ).unwrap_or_default()
+    }
+
+    // The ingress queue shares its room among the senders, the egress queue among the destinations
+    pub fn queues(&self) -> (SharedFifo<Datagram,()>, SharedFifo<Datagram,()>) {
+        let outcome = SharedFifo::bounded( SignalType::notify, self.egress_queue.unwrap_or(EGRESS_QUEUE), |dg: &Datagram| dg.dst().map( |dst| dst.ip() ) );
+        (self.ingress(), outcome)
+    }
+
+    // The queue of each receiver, in the order of `Network::receivers`: the same one for all unless sharded
+    pub fn ingress_queues(&self, income: &SharedFifo<Datagram,()>) -> Vec<SharedFifo<Datagram,()>> {
+        match self.rx_shards {
+            Some(shards) if shards.sharded && shards.sockets > 1 => {
+                let mut queues: Vec<SharedFifo<Datagram,()>> = vec![ income.clone() ];
+                queues.extend( (1..shards.sockets).map( |_| self.ingress() ) );
+                queues
+            },
+            _ => vec![ income.clone() ],
+        }
+    }
+
+    fn ingress(&self) -> SharedFifo<Datagram,()> {
+        SharedFifo::bounded( SignalType::notify, self.ingress_queue.unwrap_or(INGRESS_QUEUE), |dg: &Datagram| dg.src().map( |src| src.ip() ) )
     }
 
     pub fn verify(&mut self,key: &KeyPair) -> Result<(),ConfigErr> {
@@ -135,13 +254,24 @@ impl Config {
 fn test_serde() -> serde_json::Result<()> {
 
     let c = Config {
-        server: Some( Host::new( "127.0.0.1:1111" ) ),
-        gateway: Host::new( "127.0.0.1:22222" ),
+        server: Some( HostName::from( Host::new( "127.0.0.1:1111" ) ) ),
+        gateway: HostName::from( Host::new( "127.0.0.1:22222" ) ),
         rx: Host::new( "127.0.0.1:3333" ),
         tx: Some(Host::new( "127.0.0.1:4444" )),
         clients: None,
         services: None,
         signature: None,
+        rate_limit: None,
+        peer_rate_limits: None,
+        rx_transport: None,
+        tx_transport: None,
+        address_pool: None,
+        lease_file: None,
+        name: None,
+        dns: None,
+        ingress_queue: None,
+        egress_queue: None,
+        rx_shards: None,
     };
 
     // Serialize it to a JSON string.
@@ -155,23 +285,45 @@ fn test_serde() -> serde_json::Result<()> {
 #[test]
 fn test_sign_verify_from_into() {
     let c = Config {
-        server: Some( Host::new( "127.0.0.1:3333" ) ),
-        gateway: Host::new( "127.255.255.255:3333" ),
+        server: Some( HostName::from( Host::new( "127.0.0.1:3333" ) ) ),
+        gateway: HostName::from( Host::new( "127.255.255.255:3333" ) ),
         rx: Host::new( "127.0.0.1:3334" ),
         tx: Some(Host::new( "127.0.0.1:3335" )),
         clients: None,
         services: None,
         signature: None,
+        rate_limit: None,
+        peer_rate_limits: None,
+        rx_transport: None,
+        tx_transport: None,
+        address_pool: None,
+        lease_file: None,
+        name: None,
+        dns: None,
+        ingress_queue: None,
+        egress_queue: None,
+        rx_shards: None,
     };
 
     let s = Config {
         server: None,
-        gateway: Host::new( "127.255.255.255:3333" ),
+        gateway: HostName::from( Host::new( "127.255.255.255:3333" ) ),
         rx: Host::new( "127.0.0.1:3333" ),
         tx: None,
         clients: None,
         services: None,
         signature: None,
+        rate_limit: None,
+        peer_rate_limits: None,
+        rx_transport: None,
+        tx_transport: None,
+        address_pool: None,
+        lease_file: None,
+        name: None,
+        dns: None,
+        ingress_queue: None,
+        egress_queue: None,
+        rx_shards: None,
     };
 
     let key = crate::crypto::openssh::from(
@@ -210,4 +362,116 @@ fn test_sign_verify_from_into() {
 "services":[
     "lama":"1111"
 ]
-*/
\ No newline at end of file
+*/
+#[test]
+fn test_serde_rate_limits() -> serde_json::Result<()> {
+    // older configurations know nothing about limits
+    let old = r#"{"server":null,"gateway":"127.255.255.255:3333","rx":"127.0.0.1:3333","tx":null,"clients":null,"services":null,"signature":null}"#;
+    let c: Config = serde_json::from_str(old)?;
+    assert_eq!(c.rate_limit,None);
+    assert_eq!(old,serde_json::to_string(&c)?);
+
+    let mut peers = HashMap::new();
+    peers.insert( Host::new("127.0.0.1:1111"), RateLimit::new(1000, 2000) );
+    let limited = Config {
+        rate_limit: Some( RateLimit::new(100_000, 10_000) ),
+        peer_rate_limits: Some(peers),
+        ..c
+    };
+    let j = serde_json::to_string(&limited)?;
+    assert!(j.contains(r#""127.0.0.1:1111":{"rate":1000,"burst":2000}"#));
+    assert_eq!(limited,serde_json::from_str(&j)?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_into_network_transports() {
+    let c: Config = serde_json::from_str(r#"{"server":null,"gateway":"127.255.255.255:4666","rx":"127.0.0.1:4666","tx":"127.0.0.1:4667","clients":null,"services":null,"signature":null,"rx_transport":"tcp","tx_transport":"unix"}"#).unwrap();
+    assert_eq!(c.rx_transport,Some(TransportKind::Tcp));
+    let net = c.into_network().await.unwrap();
+    assert_eq!(net.local_addr(),Host::new("127.0.0.1:4666"));
+    assert!( std::path::Path::new( &transport::unix_path( &Host::new("127.0.0.1:4667") ) ).exists() );
+}
+
+#[tokio::test]
+async fn test_into_network_name() {
+    let named = |name: &str| serde_json::from_str::<Config>( &format!(r#"{{"server":null,"gateway":"127.255.255.255:4668","rx":"127.0.0.1:4668","tx":null,"clients":null,"services":null,"signature":null,"name":"{}","dns":"127.0.0.1:4669"}}"#, name) ).unwrap();
+    assert_eq!(named("not.a.label").into_network().await.err(),Some( ConfigErr::InvalidName ));
+    let net = named("Alpha").into_network().await.unwrap();
+    assert_eq!(net.name(),Some( "alpha".to_string() ));
+    assert_eq!(net.dns(),Some( Host::new("127.0.0.1:4669") ));
+}
+
+#[tokio::test]
+async fn test_into_network_hostnames() {
+    let c: Config = serde_json::from_str(r#"{"server":"localhost:4691","gateway":"localhost:4690","rx":"127.0.0.1:4692","tx":null,"clients":null,"services":null,"signature":null}"#).unwrap();
+    assert_eq!("localhost:4690",c.gateway.to_string());
+    let net = c.into_network().await.unwrap();
+    assert_eq!(net.gateway().port(),4690);
+    assert!(net.server().unwrap().ip().is_loopback());
+    assert_eq!(Ok(false),net.re_resolve().await);
+
+    let c: Config = serde_json::from_str(r#"{"server":null,"gateway":"no-such-host.invalid:4690","rx":"127.0.0.1:4693","tx":null,"clients":null,"services":null,"signature":null}"#).unwrap();
+    assert_eq!(c.into_network().await.err(),Some( ConfigErr::UnresolvedHost ));
+    assert!(serde_json::from_str::<Config>(r#"{"server":null,"gateway":"localhost","rx":"127.0.0.1:4693","tx":null,"clients":null,"services":null,"signature":null}"#).is_err());
+}
+
+#[test]
+fn test_config_queues() {
+    let c: Config = serde_json::from_str(r#"{"server":null,"gateway":"127.255.255.255:3333","rx":"127.0.0.1:3333","tx":null,"clients":null,"services":null,"signature":null,"ingress_queue":{"capacity":2,"policy":"drop_newest"}}"#).unwrap();
+    assert_eq!(c.ingress_queue,Some( QueueLimit::new(2, DropPolicy::DropNewest) ));
+    let (mut income, outcome) = c.queues();
+    for _ in 0..3 {
+        income.push( Datagram::new( Some( Host::new("127.0.0.1:1111") ), crate::message::tlv::TLV::new(crate::message::header::Header::PING, None).unwrap(), None ) );
+    }
+    assert_eq!((income.len(), income.dropped()),(2, 1));
+    assert_eq!(income.dropped_from( Some( Host::new("127.0.0.1:1111").ip() ) ),1);
+    assert_eq!(outcome.dropped(),0);
+}
+
+#[tokio::test]
+async fn test_into_network_rx_shards() -> std::io::Result<()> {
+    use crate::message::signal::Signal;
+    use crate::workers::receive::receiver;
+
+    let c: Config = serde_json::from_str(r#"{"server":null,"gateway":"127.255.255.255:4710","rx":"127.0.0.1:4710","tx":null,"clients":null,"services":null,"signature":null,"rx_shards":{"sockets":4,"sharded":true}}"#).unwrap();
+    let net = c.into_network().await.unwrap();
+    let receivers = net.receivers();
+    assert_eq!(receivers.len(),4);
+    assert!(receivers.iter().all( |shard| shard.local_addr() == Host::new("127.0.0.1:4710") ));
+
+    let (income, _) = c.queues();
+    let queues = c.ingress_queues(&income);
+    assert_eq!(queues.len(),4);
+    let mut backbone: Signal<()> = Signal::new(SignalType::broadcast);
+    for (shard, queue) in receivers.into_iter().zip( queues.iter() ) {
+        tokio::task::spawn( receiver( shard, queue.clone(), backbone.subscribe() ) );
+    }
+
+    // every sender sticks to one socket, hence to one queue
+    let ping = crate::message::tlv::TLV::new(crate::message::header::Header::PING, None).unwrap();
+    for _ in 0..16 {
+        let sender = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        for _ in 0..3 {
+            sender.send_to( &Vec::from( ping.to_bytes() ), "127.0.0.1:4710" ).await?;
+        }
+    }
+    tokio::time::sleep( std::time::Duration::from_millis(200) ).await;
+    backbone.send(()).await.ok();
+
+    let mut total: usize = 0;
+    let mut seen: HashMap<Host,usize> = HashMap::new();
+    for (index, queue) in queues.iter().enumerate() {
+        while let Some(dg) = queue.pop() {
+            total += 1;
+            assert_eq!(*seen.entry( dg.src().unwrap() ).or_insert(index),index);
+        }
+    }
+    assert_eq!((total, seen.len()),(48, 16));
+
+    // the queue is shared unless asked otherwise
+    let c: Config = serde_json::from_str(r#"{"server":null,"gateway":"127.255.255.255:4711","rx":"127.0.0.1:4711","tx":null,"clients":null,"services":null,"signature":null,"rx_shards":{"sockets":2}}"#).unwrap();
+    assert_eq!(c.into_network().await.unwrap().receivers().len(),2);
+    assert_eq!(c.ingress_queues(&income).len(),1);
+    Ok(())
+}
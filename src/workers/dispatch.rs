@@ -5,7 +5,25 @@ use crate::message::signal::Signal;
 
 use crate::memory::shared_fifo::SharedFifo;
 
+use crate::workers::pool::{WorkerPool,POOL_WORKERS,WORKER_QUEUE};
+
+// The datagrams popped from `income` go to the workers, in the order they came. One a full worker
+// gives back is tried again once the workers had a turn, then dropped there: the dispatcher doesn't
+// wait for any peer
+async fn dispatch(income: &SharedFifo<Datagram,()>, pool: &mut WorkerPool) {
+    while let Some(dg) = income.pop() {
+        if let Some(dg) = pool.dispatch(dg) {
+            tokio::task::yield_now().await;
+            if let Some(dg) = pool.dispatch(dg) {
+                income.discard(&dg);
+            }
+        }
+    }
+}
+
 pub async fn dispatcher(net: Network, mut income: SharedFifo<Datagram,()>, mut outcome: SharedFifo<Datagram,()>,/*mut tracing: Signal<Datagram>,*/ mut backbone: Signal<()>) -> std::io::Result<()> {    
+    let mut pool = WorkerPool::new(net, outcome.clone(), POOL_WORKERS, WORKER_QUEUE);
+
     loop {
         // If received any data => stop the thread
         match backbone.try_recv() {
@@ -22,22 +40,18 @@ pub async fn dispatcher(net: Network, mut income: SharedFifo<Datagram,()>, mut o
                 break;
             },
             Ok(future) => {
-                future.await;
-                let mut maybe_dg = income.pop();
-                while maybe_dg.is_some() {
-                    tokio::task::spawn( 
-                        crate::workers::handle::handler(
-                            net.clone(),
-                            maybe_dg.unwrap(),
-                            /*tracing.subscribe(),*/
-                            outcome.clone()
-                        )
-                    );
-                    maybe_dg = income.pop();
+                tokio::select! {
+                    _ = backbone.recv() => { break; },
+                    _ = future => {},
                 }
+                dispatch(&income, &mut pool).await;
             }
         }
     }
+
+    // Drain: what was already received is still handled, its answers are flushed by the emitter
+    dispatch(&income, &mut pool).await;
+    pool.close().await;
     
     income.close();
     //tracing.close();
@@ -46,18 +60,17 @@ pub async fn dispatcher(net: Network, mut income: SharedFifo<Datagram,()>, mut o
 }
 
 
-#[tokio::test]
+#[tokio::test(start_paused = true)]
 async fn test_dispatcher_hello() -> std::io::Result<()>{
     
     let dg = Datagram::from(crate::message::header::Header::HELLO);
     let i_max = 100;
 
-    match crate::server_and_client(dg, i_max,1).await {
+    match crate::node::simulated_server_and_client(dg, i_max, &[crate::node::Worker::Receiver, crate::node::Worker::Dispatcher]).await {
         Err(_) => { assert!(false); },
         Ok((_, server, income, outcome)) => {
-            for _ in 0..i_max {
-                assert_eq!(crate::message::header::Header::HELLO,outcome.pop().unwrap().data().header());
-            }
+            // a flood is answered once per HANDSHAKE_RETRY, none of them signed our nonce
+            assert_eq!(crate::message::header::Header::HELLO,outcome.pop().unwrap().data().header());
         
             assert!(income.pop().is_none());
             assert!(outcome.pop().is_none());
@@ -69,13 +82,13 @@ async fn test_dispatcher_hello() -> std::io::Result<()>{
     Ok(())
 }
 
-#[tokio::test]
+#[tokio::test(start_paused = true)]
 async fn test_dispatcher_ping() -> std::io::Result<()>{
     
     let dg = Datagram::from(crate::message::header::Header::PING);
     let i_max = 100;
 
-    let (_, _, income, outcome) = crate::server_and_client(dg, i_max,1).await.unwrap();
+    let (_, _, income, outcome) = crate::node::simulated_server_and_client(dg, i_max, &[crate::node::Worker::Receiver, crate::node::Worker::Dispatcher]).await.unwrap();
 
     for _ in 0..i_max {
         assert_eq!(crate::message::header::Header::PONG,outcome.pop().unwrap().data().header());
@@ -86,15 +99,55 @@ async fn test_dispatcher_ping() -> std::io::Result<()>{
     Ok(())
 }
 
-#[tokio::test]
+#[tokio::test(start_paused = true)]
 async fn test_dispatcher_unknown() -> std::io::Result<()>{
     
     let dg = Datagram::from(crate::message::header::Header::UNKNOWN);
     let i_max = 100;
 
-    let (_, _, income, outcome) = crate::server_and_client(dg, i_max,1).await.unwrap();
+    let (_, _, income, outcome) = crate::node::simulated_server_and_client(dg, i_max, &[crate::node::Worker::Receiver, crate::node::Worker::Dispatcher]).await.unwrap();
 
     assert!(income.pop().is_none());
     assert!(outcome.pop().is_none());
     Ok(())
-}
\ No newline at end of file
+}
+#[tokio::test(start_paused = true)]
+async fn test_dispatch_full_worker_drops() -> std::io::Result<()>{
+    use crate::network::host::Host;
+    use crate::message::header::Header;
+    use crate::message::signal::SignalType;
+    use crate::memory::shared_fifo::{QueueLimit,DropPolicy};
+    use crate::workers::registry::{HandlerRegistry,MessageHandler,Context,HandlerFuture};
+
+    // a handler that takes its time
+    struct Slow;
+    impl MessageHandler for Slow {
+        fn handle<'a>(&'a self, _ctx: &'a mut Context, _dg: Datagram) -> HandlerFuture<'a> {
+            Box::pin( async move {
+                tokio::time::sleep( tokio::time::Duration::from_secs(1) ).await;
+                Ok(())
+            })
+        }
+    }
+
+    let sock = std::sync::Arc::new( crate::network::simulated::SimNet::new(0).bind( Host::new("127.0.0.1:4740") ) );
+    let mut registry = HandlerRegistry::new();
+    registry.register( Header::STORE, std::sync::Arc::new(Slow) );
+    let net = Network::new(sock, None, Host::new("127.255.255.255:4740"), None).with_handlers(registry);
+    let mut income: SharedFifo<Datagram,()> = SharedFifo::bounded( SignalType::notify, QueueLimit::new(64, DropPolicy::FairShare), |dg: &Datagram| dg.src().map( |src| src.ip() ) );
+    let flood = Host::new("127.0.0.1:1111");
+    for _ in 0..10 {
+        income.push( Datagram::new( Some(flood), crate::message::tlv::TLV::new(Header::STORE, None).unwrap(), None ) );
+    }
+
+    // one worker holding two: one more fits once it started on the first, the rest is dropped
+    // rather than waited for
+    let mut pool = WorkerPool::new(net.clone(), SharedFifo::new(SignalType::notify), 1, 2);
+    dispatch(&income, &mut pool).await;
+    assert!(income.is_empty());
+    assert_eq!(pool.depth(),3);
+    assert_eq!(income.dropped_from( Some( flood.ip() ) ),7);
+    pool.close().await;
+    assert_eq!(net.dispatch_depth(),0);
+    Ok(())
+}
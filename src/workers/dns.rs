@@ -0,0 +1,98 @@
+use tokio::net::UdpSocket;
+
+use crate::network::network::Network;
+
+use crate::message::signal::Signal;
+
+// A DNS server for <name>.toktok, so that ordinary tools reach the peers by name
+pub async fn dns_responder(net: Network, mut backbone: Signal<()>) -> std::io::Result<()> {
+    let listen = match net.dns() {
+        None => { backbone.close(); return Ok(()); },
+        Some(listen) => listen,
+    };
+    let sock = UdpSocket::bind( listen.sock() ).await?;
+    let mut buf: [u8; 512] = [0; 512];
+
+    loop {
+        // If received any data => stop the thread
+        match backbone.try_recv() {
+            Err(_) => { break; },
+            Ok(data) => { 
+                if data.is_some() {
+                    break;
+                } 
+            }
+        }
+
+        tokio::select! {
+            received = sock.recv_from(&mut buf) => {
+                if let Ok((len, src)) = received {
+                    // malformed queries get no answer, like most servers do
+                    if let Some(answer) = net.dns_answer(&buf[..len]) {
+                        sock.send_to(&answer, src).await.ok();
+                    }
+                }
+            },
+            _ = backbone.recv() => { break; },
+        }
+    }
+
+    backbone.close();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dns_resolves_peer_names() -> std::io::Result<()> {
+    use std::sync::Arc;
+    use std::net::IpAddr;
+    use tokio::time::{Duration,sleep};
+    use crate::network::host::Host;
+    use crate::network::simulated::SimNet;
+    use crate::network::dns::dns_query;
+    use crate::message::signal::SignalType;
+
+    let sim = SimNet::new(0);
+    let mut net = Network::new( Arc::new( sim.bind( Host::new("10.4.0.1:1") ) ), None, Host::new("10.4.255.255:1"), None )
+        .with_name("Local").with_dns( Host::new("127.0.0.1:4680") );
+    let peer_id = crate::network::peer::PeerId::new(2);
+    net.member_alive( peer_id, &Host::new("10.4.0.2:1") );
+    assert!(net.name_peer( peer_id, "Beta" ));
+    assert!(!net.name_peer( peer_id, "not a label" ));
+    net.assign_overlay( "fd00::2".parse().unwrap(), peer_id );
+
+    let backbone: Signal<()> = Signal::new(SignalType::broadcast);
+    tokio::task::spawn( dns_responder( net.clone(), backbone.subscribe() ) );
+    sleep( Duration::from_millis(20) ).await;
+
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let mut buf: [u8; 512] = [0; 512];
+    let ask = |qname: &'static str, qtype: u16| {
+        let client = &client;
+        async move {
+            client.send_to( &dns_query(7, qname, qtype), "127.0.0.1:4680" ).await.unwrap();
+            let len = tokio::time::timeout( Duration::from_secs(1), client.recv(&mut buf) ).await.unwrap().unwrap();
+            Vec::from(&buf[..len])
+        }
+    };
+
+    // the endpoint for A, the overlay address for AAAA
+    let a = ask("beta.toktok", 1).await;
+    assert_eq!((a[3], a[7]),(0, 1));
+    assert_eq!(&a[(a.len()-4)..],&[10, 4, 0, 2]);
+    let aaaa = ask("BETA.toktok", 28).await;
+    assert_eq!(&aaaa[(aaaa.len()-16)..],&"fd00::2".parse::<std::net::Ipv6Addr>().unwrap().octets());
+
+    assert_eq!(ask("nobody.toktok", 1).await[3],3);
+    assert_eq!(ask("example.com", 1).await[3],5);
+    assert_eq!(net.resolve_name("local"),Some( vec![ IpAddr::from([10, 4, 0, 1]) ] ));
+
+    // the name stays with the first peer until it is gone
+    let other = crate::network::peer::PeerId::new(3);
+    net.member_alive( other, &Host::new("10.4.0.3:1") );
+    assert!(!net.name_peer( other, "BETA" ));
+    assert_eq!(net.peer_name(&peer_id),Some( "beta".to_string() ));
+    net.evict( &Host::new("10.4.0.2:1") );
+    assert_eq!(net.peer_name(&peer_id),None);
+    assert!(net.name_peer( other, "beta" ));
+    Ok(())
+}
@@ -1,3 +1,7 @@
+use std::cmp::{Ordering,Reverse};
+use std::collections::BinaryHeap;
+use tokio::time::{Duration,Instant,sleep_until};
+
 use crate::network::udp::Datagram;
 use crate::network::network::Network;
 
@@ -5,7 +9,91 @@ use crate::message::signal::Signal;
 
 use crate::memory::shared_fifo::SharedFifo;
 
+// Once stopped, `outcome` is flushed until it stays empty for DRAIN_GRACE, at most for DRAIN_TIMEOUT
+pub const DRAIN_GRACE: Duration = Duration::from_millis(50);
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+// Transient send failures are tried again after SEND_BACKOFF, doubled each time, SEND_RETRIES times at most
+pub const SEND_BACKOFF: Duration = Duration::from_millis(10);
+pub const SEND_RETRIES: u32 = 4;
+// Datagrams held back at most, past that `outcome` is left to fill up and apply its own drop policy
+pub const PACED_LIMIT: usize = 1024;
+
+struct Held {
+    due: Instant,
+    order: u64,
+    dg: Datagram,
+    tries: u32,
+}
+
+impl PartialEq for Held {
+    fn eq(&self, other: &Self) -> bool {
+        (self.due, self.order) == (other.due, other.order)
+    }
+}
+
+impl Eq for Held {}
+
+impl PartialOrd for Held {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some( self.cmp(other) )
+    }
+}
+
+impl Ord for Held {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.due, self.order).cmp( &(other.due, other.order) )
+    }
+}
+
+// Datagrams held back by the pacer or waiting to be tried again: earliest due first, equal dues in
+// the order they came
+#[derive(Default)]
+struct Paced {
+    held: BinaryHeap<Reverse<Held>>,
+    order: u64,
+}
+
+impl Paced {
+    fn hold(&mut self, due: Instant, dg: Datagram, tries: u32) {
+        self.order += 1;
+        self.held.push( Reverse( Held { due, order: self.order, dg, tries } ) );
+    }
+
+    fn first(&self) -> Option<Instant> {
+        self.held.peek().map( |Reverse(held)| held.due )
+    }
+
+    // The first one if due by `now`
+    fn pop_due(&mut self, now: Instant) -> Option<(Datagram,u32)> {
+        match self.first() {
+            Some(due) if due <= now => self.held.pop().map( |Reverse(held)| (held.dg, held.tries) ),
+            _ => None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.held.len()
+    }
+}
+
+// Sent together, the ones failing for a transient reason are held back for another try
+async fn emit(net: &Network, ready: Vec<(Datagram,u32)>, paced: &mut Paced) {
+    if ready.is_empty() {
+        return;
+    }
+    let results = net.send_outbound( ready.iter().map( |(dg, _)| dg.clone() ).collect() ).await;
+    for ((dg, tries), result) in ready.into_iter().zip(results) {
+        if let Err(err) = result {
+            if err.transient() && tries < SEND_RETRIES {
+                paced.hold( Instant::now() + SEND_BACKOFF * 2u32.pow(tries), dg, tries + 1 );
+            }
+        }
+    }
+}
+
 pub async fn emitter(net: Network, mut outcome: SharedFifo<Datagram,()>, mut backbone: Signal<()>) -> std::io::Result<()> {  
+    let mut paced: Paced = Paced::default();
+
     loop {
         // If received any data => stop the thread
         match backbone.try_recv() {
@@ -22,33 +110,325 @@ pub async fn emitter(net: Network, mut outcome: SharedFifo<Datagram,()>, mut bac
                 break;
             },
             Ok(future) => {
-                future.await;
-                let mut dg: Datagram;
-                let mut maybe_dg = outcome.pop();
-                while maybe_dg.is_some() {
-                    dg = maybe_dg.unwrap();
-                    //    
-                    net.send_to(dg,None).await;
-                    //
-                    maybe_dg = outcome.pop();
+                match paced.first() {
+                    None => {
+                        tokio::select! {
+                            _ = backbone.recv() => { break; },
+                            _ = future => {},
+                        }
+                    },
+                    Some(due) => {
+                        tokio::select! {
+                            _ = backbone.recv() => { break; },
+                            _ = future => {},
+                            _ = sleep_until(due) => {},
+                        }
+                    },
+                }
+
+                let now = Instant::now();
+                let mut ready: Vec<(Datagram,u32)> = Vec::new();
+                while let Some(due) = paced.pop_due(now) {
+                    ready.push(due);
+                }
+
+                // Small datagrams for the same peer leave together, up to its path MTU. Nothing is
+                // taken while the pacer holds back too much
+                let mut popped: Vec<Datagram> = Vec::new();
+                while paced.len() + popped.len() < PACED_LIMIT {
+                    match outcome.pop() {
+                        None => { break; },
+                        Some(dg) => { popped.extend( net.outbound(dg) ); },
+                    }
                 }
+
+                for dg in net.batch(popped) {
+                    let wait = net.pace(&dg);
+                    if wait.is_zero() {
+                        ready.push( (dg, 0) );
+                    }
+                    else {
+                        paced.hold(now + wait, dg, 0);
+                    }
+                }
+                emit(&net, ready, &mut paced).await;
+            }
+        }
+    }
+
+    // Drain: pacing no longer holds anything back, late answers from the handlers are still sent
+    net.send_outbound( paced.held.into_sorted_vec().into_iter().rev().map( |Reverse(held)| held.dg ).collect() ).await;
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    loop {
+        let mut maybe_dg = outcome.pop();
+        if maybe_dg.is_none() {
+            let quiet = Instant::now() + DRAIN_GRACE;
+            if quiet > deadline {
+                break;
+            }
+            sleep_until(quiet).await;
+            maybe_dg = outcome.pop();
+            if maybe_dg.is_none() {
+                break;
             }
         }
+        let mut popped: Vec<Datagram> = Vec::new();
+        while let Some(dg) = maybe_dg {
+            popped.extend( net.outbound(dg) );
+            maybe_dg = outcome.pop();
+        }
+        net.send_outbound( net.batch(popped) ).await;
     }
     
     outcome.close();
     Ok( backbone.close() )
 }
 
-#[tokio::test]
+#[tokio::test(start_paused = true)]
 async fn test_emitter() -> std::io::Result<()>{
 
     let dg = Datagram::from(crate::message::header::Header::HELLO);
     let i_max = 100;
 
-    let (_, _, income, outcome) = crate::server_and_client(dg, i_max,2).await.unwrap();
+    let (_, _, income, outcome) = crate::node::simulated_server_and_client(dg, i_max, &[crate::node::Worker::Receiver, crate::node::Worker::Dispatcher, crate::node::Worker::Emitter]).await.unwrap();
 
     assert!(income.pop().is_none());
     assert!(outcome.pop().is_none());
     Ok(())
-}
\ No newline at end of file
+}
+#[tokio::test(start_paused = true)]
+async fn test_emitter_paced() -> std::io::Result<()>{
+    use std::collections::HashMap;
+    use crate::network::host::Host;
+    use crate::network::pacing::RateLimit;
+    use crate::message::tlv::TLV;
+    use crate::message::header::Header;
+    use crate::message::signal::SignalType;
+    use crate::network::transport::Transport;
+
+    let sim = crate::network::simulated::SimNet::new(0);
+    let dst = Host::new("127.0.0.1:4657");
+    let peer = sim.bind(dst);
+    let mut limits = HashMap::new();
+    limits.insert( dst, RateLimit::new(10_000, 1002) );
+
+    let sock = std::sync::Arc::new( sim.bind( Host::new("127.0.0.1:4656") ) );
+    let net = Network::new(sock, None, Host::new("127.255.255.255:4656"), None).with_limits(None, limits);
+    let mut outcome: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
+    let mut backbone: Signal<()> = Signal::new(SignalType::broadcast);
+    tokio::task::spawn( emitter(net, outcome.clone(), backbone.subscribe()) );
+
+    let dg = Datagram::new( None, TLV::new( Header::UNKNOWN, Some( vec![0; 1000] ) ).unwrap(), Some(dst) );
+    for _ in 0..3 {
+        outcome.push(dg.clone());
+    }
+    outcome.send(()).await.ok();
+
+    // 1002 bytes of burst, then 10 kB/s: about 100ms between datagrams
+    let mut buf = [0; 1026];
+    peer.recv_from(&mut buf).await?;
+    let start = Instant::now();
+    peer.recv_from(&mut buf).await?;
+    peer.recv_from(&mut buf).await?;
+    assert!(start.elapsed() >= tokio::time::Duration::from_millis(200));
+    assert!(start.elapsed() < tokio::time::Duration::from_millis(210));
+
+    backbone.send(()).await.ok();
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_shutdown_drains_outcome() -> std::io::Result<()> {
+    use crate::network::host::Host;
+    use crate::message::header::Header;
+    use crate::message::signal::SignalType;
+    use crate::network::transport::Transport;
+
+    let sim = crate::network::simulated::SimNet::new(0);
+    let peer = Host::new("127.0.0.1:4696");
+    let peer_sock = sim.bind(peer);
+    let net = Network::new( std::sync::Arc::new( sim.bind( Host::new("127.0.0.1:4695") ) ), None, Host::new("127.255.255.255:4695"), None );
+    let mut income: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
+    let mut outcome: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
+    let mut backbone: Signal<()> = Signal::new(SignalType::broadcast);
+    let workers = vec![
+        tokio::task::spawn( crate::workers::receive::receiver(net.clone(), income.clone(), backbone.subscribe()) ),
+        tokio::task::spawn( crate::workers::dispatch::dispatcher(net.clone(), income.clone(), outcome.clone(), backbone.subscribe()) ),
+        tokio::task::spawn( emitter(net.clone(), outcome.clone(), backbone.subscribe()) ),
+    ];
+    tokio::task::yield_now().await;
+
+    // queued without waking anybody: only the drain gets them out
+    income.push( Datagram::new( Some(peer), crate::message::tlv::TLV::new(Header::PING, None).unwrap(), None ) );
+    outcome.push( Datagram::new( None, crate::message::gossip::bye_to_tlv( net.id(), 0 ).unwrap(), Some(peer) ) );
+    backbone.send(()).await.ok();
+
+    // nothing is sent to the receiver, blocked workers still stop
+    for worker in workers {
+        tokio::time::timeout( DRAIN_TIMEOUT, worker ).await.unwrap().unwrap()?;
+    }
+
+    // both may leave in the same MULTIPLE
+    let mut buf = [0; 64];
+    let mut headers: Vec<Header> = Vec::new();
+    while headers.len() < 2 {
+        let (len, _) = peer_sock.recv_from(&mut buf).await?;
+        let tlv = Datagram::from_bytes( None, Vec::from( &buf[..len] ), None ).unwrap().data();
+        headers.extend( tlv.split().unwrap().iter().map( |part| part.header() ) );
+    }
+    headers.sort_by_key( |header| header.to_byte() );
+    assert_eq!(headers,vec![ Header::PONG, Header::BYE ]);
+    assert!(outcome.pop().is_none());
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_emitter_path_mtu() -> std::io::Result<()> {
+    use crate::network::host::Host;
+    use crate::network::peer::PeerId;
+    use crate::network::simulated::{SimNet,LinkConfig};
+    use crate::message::packet::ipv4_packet;
+    use crate::message::tlv::TLV;
+    use crate::message::header::Header;
+    use crate::message::signal::SignalType;
+
+    let sim = SimNet::new(0);
+    let (a_addr, b_addr) = ( Host::new("10.5.0.1:1"), Host::new("10.5.0.2:1") );
+    sim.set_link( a_addr, b_addr, LinkConfig { latency: tokio::time::Duration::from_millis(5), mtu: Some(700), ..Default::default() } );
+    let backbone: Signal<()> = Signal::new(SignalType::broadcast);
+    let mut nodes: Vec<(Network, SharedFifo<Datagram,()>)> = Vec::new();
+    for host in [a_addr, b_addr] {
+        let net = Network::new( std::sync::Arc::new( sim.bind(host) ), None, Host::new("10.5.255.255:1"), None );
+        let income: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
+        let outcome: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
+        tokio::task::spawn( crate::workers::receive::receiver(net.clone(), income.clone(), backbone.subscribe()) );
+        tokio::task::spawn( crate::workers::dispatch::dispatcher(net.clone(), income, outcome.clone(), backbone.subscribe()) );
+        tokio::task::spawn( emitter(net.clone(), outcome.clone(), backbone.subscribe()) );
+        tokio::task::spawn( crate::workers::heartbeat::heartbeater(net.clone(), backbone.subscribe()) );
+        nodes.push( (net, outcome) );
+    }
+    let (mut a, mut a_outcome) = nodes.remove(0);
+    let (mut b, _) = nodes.remove(0);
+    a.member_alive( PeerId::from(&b_addr), &b_addr );
+    b.member_alive( PeerId::from(&a_addr), &a_addr );
+    let mut delivered = b.attach_tunnel();
+
+    assert_eq!(a.mtu(&b_addr),1026);
+    tokio::time::sleep( tokio::time::Duration::from_secs(30) ).await;
+    assert_eq!(a.mtu(&b_addr),700);
+    assert_eq!(b.mtu(&a_addr),700);
+
+    // too large for the path: fragmented, small ones share a datagram
+    let (a_ip, b_ip) = ( "172.18.0.1".parse().unwrap(), "172.18.0.2".parse().unwrap() );
+    b.assign_overlay( std::net::IpAddr::V4(a_ip), a.id() );
+    let payload: Vec<u8> = (0..980).map( |i| i as u8 ).collect();
+    let packet: Vec<u8> = ipv4_packet( a_ip, b_ip, &payload );
+    a_outcome.push( Datagram::new( None, TLV::new( Header::IPPACKET, Some( packet.clone() ) ).unwrap(), Some(b_addr) ) );
+    for _ in 0..3 {
+        a_outcome.push( Datagram::new( None, TLV::new( Header::IPPACKET, Some( ipv4_packet( a_ip, b_ip, &[1; 180] ) ) ).unwrap(), Some(b_addr) ) );
+    }
+    let dropped = sim.dropped();
+    a_outcome.send(()).await.ok();
+
+    let mut received: Vec<Vec<u8>> = Vec::new();
+    for _ in 0..4 {
+        received.push( tokio::time::timeout( tokio::time::Duration::from_secs(1), delivered.recv() ).await.unwrap().unwrap() );
+    }
+    assert!(received.contains(&packet));
+    assert_eq!(sim.dropped(),dropped);
+
+    // 2 * (2 + 300) bytes fit in one datagram of 700, 2 * (2 + 400) don't
+    let batched = a.batch( vec![ Datagram::new( None, TLV::new( Header::PING, Some( vec![0; 300] ) ).unwrap(), Some(b_addr) ); 2 ] );
+    assert_eq!(batched.len(),1);
+    assert_eq!(batched[0].header(),Header::MULTIPLE);
+    let apart = a.batch( vec![ Datagram::new( None, TLV::new( Header::PING, Some( vec![0; 400] ) ).unwrap(), Some(b_addr) ); 2 ] );
+    assert_eq!(apart.len(),2);
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_emitter_requeues_transient_failures() -> std::io::Result<()> {
+    use crate::network::host::Host;
+    use crate::network::network::SendErr;
+    use crate::message::header::Header;
+    use crate::message::signal::SignalType;
+    use crate::network::transport::Transport;
+
+    let sim = crate::network::simulated::SimNet::new(0);
+    let (local, dst) = ( Host::new("127.0.0.1:4697"), Host::new("127.0.0.1:4698") );
+    let peer = sim.bind(dst);
+    let net = Network::new( std::sync::Arc::new( sim.bind(local) ), None, Host::new("127.255.255.255:4697"), None );
+
+    // errors are reported, and counted against the destination
+    assert_eq!(Err(SendErr::NoDestination),net.send_to( Datagram::from(Header::PING).with_peer( crate::network::peer::PeerId::new(9) ), None ).await);
+    sim.refuse(local, 1);
+    assert_eq!(Err(SendErr::RateLimited),net.send_to( Datagram::from(Header::PING), Some(dst) ).await);
+    assert_eq!(net.send_failures(&dst),1);
+
+    let mut outcome: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
+    let mut backbone: Signal<()> = Signal::new(SignalType::broadcast);
+    tokio::task::spawn( emitter(net.clone(), outcome.clone(), backbone.subscribe()) );
+
+    // 3 failures: sent on the 4th try, after 10 + 20 + 40ms
+    sim.refuse(local, 3);
+    let start = Instant::now();
+    outcome.push_notice( Datagram::new( None, crate::message::tlv::TLV::new(Header::PONG, None).unwrap(), Some(dst) ), () ).await.ok();
+    let mut buf = [0; 64];
+    peer.recv_from(&mut buf).await?;
+    assert!(start.elapsed() >= SEND_BACKOFF * 7);
+    assert!(start.elapsed() < SEND_BACKOFF * 8);
+    assert_eq!(net.send_failures(&dst),4);
+
+    // given up after SEND_RETRIES
+    sim.refuse(local, SEND_RETRIES + 1);
+    outcome.push_notice( Datagram::new( None, crate::message::tlv::TLV::new(Header::PONG, None).unwrap(), Some(dst) ), () ).await.ok();
+    assert!(tokio::time::timeout( SEND_BACKOFF * 64, peer.recv_from(&mut buf) ).await.is_err());
+    assert_eq!(net.send_failures(&dst),4 + SEND_RETRIES as u64 + 1);
+
+    backbone.send(()).await.ok();
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_emitter_paced_limit() -> std::io::Result<()> {
+    use std::collections::HashMap;
+    use crate::network::host::Host;
+    use crate::network::pacing::RateLimit;
+    use crate::memory::shared_fifo::{QueueLimit,DropPolicy};
+    use crate::message::tlv::TLV;
+    use crate::message::header::Header;
+    use crate::message::signal::SignalType;
+
+    let sim = crate::network::simulated::SimNet::new(0);
+    let dst = Host::new("127.0.0.1:4736");
+    let _peer = sim.bind(dst);
+    let mut limits = HashMap::new();
+    limits.insert( dst, RateLimit::new(1, 10) );
+    let net = Network::new( std::sync::Arc::new( sim.bind( Host::new("127.0.0.1:4735") ) ), None, Host::new("127.255.255.255:4735"), None ).with_limits(None, limits);
+    let mut outcome: SharedFifo<Datagram,()> = SharedFifo::bounded( SignalType::notify, QueueLimit::new(16, DropPolicy::DropOldest), |dg: &Datagram| dg.dst().map( |dst| dst.ip() ) );
+    let mut backbone: Signal<()> = Signal::new(SignalType::broadcast);
+    tokio::task::spawn( emitter(net, outcome.clone(), backbone.subscribe()) );
+
+    // a byte per second: everything is held back until the pacer is full, then `outcome` drops
+    let dg = Datagram::new( None, TLV::new( Header::UNKNOWN, Some( vec![0; 1000] ) ).unwrap(), Some(dst) );
+    for _ in 0..(PACED_LIMIT / 16) {
+        for _ in 0..16 {
+            outcome.push( dg.clone() );
+        }
+        outcome.send(()).await.ok();
+        tokio::time::sleep( Duration::from_millis(1) ).await;
+    }
+    assert_eq!(outcome.dropped(),0);
+    for _ in 0..4 {
+        for _ in 0..16 {
+            outcome.push( dg.clone() );
+        }
+        outcome.send(()).await.ok();
+        tokio::time::sleep( Duration::from_millis(1) ).await;
+    }
+    assert_eq!(outcome.len(),16);
+    assert_eq!(outcome.dropped(),3 * 16);
+
+    backbone.send(()).await.ok();
+    Ok(())
+}
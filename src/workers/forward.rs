@@ -0,0 +1,194 @@
+use std::net::{Ipv4Addr,SocketAddr};
+use tokio::io::{AsyncReadExt,AsyncWriteExt};
+use tokio::net::{TcpListener,TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+use crate::network::host::Host;
+use crate::network::udp::Datagram;
+use crate::network::network::Network;
+use crate::network::service::Service;
+use crate::network::stream::{StreamKey,StreamSend,STREAM_SEGMENT,STREAM_RTO};
+
+use crate::message::signal::Signal;
+
+use crate::memory::shared_fifo::SharedFifo;
+
+// Listen on the forward address of `service`, every connection accepted is tunneled to its server
+pub async fn forwarder(net: Network, service: Service, outcome: SharedFifo<Datagram,()>, mut backbone: Signal<()>) -> std::io::Result<()> {
+    let (listen, server) = match (service.forward(), service.server()) {
+        (Some(listen), Some(server)) => (listen, server),
+        _ => { backbone.close(); return Ok(()); },
+    };
+    let listener = TcpListener::bind( listen.sock() ).await?;
+
+    loop {
+        // If received any data => stop the thread
+        match backbone.try_recv() {
+            Err(_) => { break; },
+            Ok(data) => { 
+                if data.is_some() {
+                    break;
+                } 
+            }
+        }
+
+        tokio::select! {
+            accepted = listener.accept() => {
+                if let Ok((tcp, _)) = accepted {
+                    let (key, open, delivered) = net.stream_open( server, service.port() );
+                    outcome.clone().push_notice(open,()).await.ok();
+                    tokio::task::spawn( pump( net.clone(), key, tcp, delivered, outcome.clone() ) );
+                }
+            },
+            _ = backbone.recv() => { break; },
+        }
+    }
+
+    backbone.close();
+    Ok(())
+}
+
+// Accepting side: connect to the local port the OPEN asked for, or close the stream right away
+pub async fn connect(net: Network, key: StreamKey, port: u16, delivered: mpsc::Receiver<Vec<u8>>, mut outcome: SharedFifo<Datagram,()>) -> std::io::Result<()> {
+    let local = Host::from( SocketAddr::new( Ipv4Addr::LOCALHOST.into(), port ) );
+    match TcpStream::connect( local.sock() ).await {
+        Ok(tcp) => pump(net, key, tcp, delivered, outcome).await,
+        Err(err) => {
+            if let Some(close) = net.stream_shutdown(&key) {
+                outcome.push_notice(close,()).await.ok();
+            }
+            Err(err)
+        },
+    }
+}
+
+// Resend what was not acknowledged in time, for every stream
+pub async fn retransmitter(net: Network, mut outcome: SharedFifo<Datagram,()>, mut backbone: Signal<()>) -> std::io::Result<()> {
+    loop {
+        // If received any data => stop the thread
+        match backbone.try_recv() {
+            Err(_) => { break; },
+            Ok(data) => { 
+                if data.is_some() {
+                    break;
+                } 
+            }
+        }
+
+        for dg in net.stream_retransmit() {
+            outcome.push_notice(dg,()).await.ok();
+        }
+        tokio::select! {
+            _ = backbone.recv() => { break; },
+            _ = sleep(STREAM_RTO / 2) => {},
+        }
+    }
+
+    backbone.close();
+    Ok(())
+}
+
+// Bytes read from the connection go out as DATA while the window allows it, DATA received is
+// written back. Our CLOSE is sent once the connection stops sending, the peer's shuts its writing half
+async fn pump(net: Network, key: StreamKey, tcp: TcpStream, mut delivered: mpsc::Receiver<Vec<u8>>, outcome: SharedFifo<Datagram,()>) -> std::io::Result<()> {
+    let (mut reader, mut writer) = tcp.into_split();
+
+    let writing = async move {
+        while let Some(bytes) = delivered.recv().await {
+            if writer.write_all(&bytes).await.is_err() {
+                break;
+            }
+        }
+        writer.shutdown().await.ok();
+    };
+
+    let mut sending = outcome.clone();
+    let reading = async move {
+        let mut buf: [u8; STREAM_SEGMENT] = [0; STREAM_SEGMENT];
+        'read: loop {
+            let len: usize = match reader.read(&mut buf).await {
+                Err(_) | Ok(0) => { break; },
+                Ok(len) => len,
+            };
+            loop {
+                match net.stream_write( &key, &buf[..len] ) {
+                    StreamSend::Sent(dg) => {
+                        sending.push_notice(dg,()).await.ok();
+                        break;
+                    },
+                    StreamSend::Blocked(space) => { space.notified().await; },
+                    StreamSend::Gone => { break 'read; },
+                }
+            }
+        }
+        if let Some(close) = net.stream_shutdown(&key) {
+            sending.push_notice(close,()).await.ok();
+        }
+    };
+
+    tokio::join!(reading, writing);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_forward_echo_over_lossy_overlay() -> std::io::Result<()> {
+    use std::sync::Arc;
+    use crate::network::simulated::{SimNet,LinkConfig};
+    use crate::message::signal::SignalType;
+
+    // the service: a TCP echo server on the provider's side
+    let echo = TcpListener::bind("127.0.0.1:4671").await?;
+    tokio::task::spawn( async move {
+        while let Ok((mut tcp, _)) = echo.accept().await {
+            tokio::task::spawn( async move {
+                let (mut reader, mut writer) = tcp.split();
+                tokio::io::copy(&mut reader, &mut writer).await.ok();
+            });
+        }
+    });
+
+    let sim = SimNet::new(3);
+    sim.set_default( LinkConfig { latency: tokio::time::Duration::from_millis(5), loss: 0.1, reorder: 0.1, ..Default::default() } );
+    let (provider_host, user_host) = ( Host::new("10.3.0.1:1"), Host::new("10.3.0.2:1") );
+    let services = [
+        vec![ Service::new("echo".to_string(), 4671, None) ],
+        vec![ Service::new("echo".to_string(), 4671, Some(provider_host)).with_forward( Host::new("127.0.0.1:4670") ) ],
+    ];
+    let backbone: Signal<()> = Signal::new(SignalType::broadcast);
+    let mut nodes: Vec<Network> = Vec::new();
+    for (host, services) in [provider_host, user_host].into_iter().zip(services) {
+        let net = Network::new( Arc::new( sim.bind(host) ), None, Host::new("10.3.255.255:1"), None ).with_services(services.clone());
+        let income: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
+        let outcome: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
+
+        tokio::task::spawn( crate::workers::receive::receiver(net.clone(), income.clone(), backbone.subscribe()) );
+        tokio::task::spawn( crate::workers::dispatch::dispatcher(net.clone(), income, outcome.clone(), backbone.subscribe()) );
+        tokio::task::spawn( crate::workers::emit::emitter(net.clone(), outcome.clone(), backbone.subscribe()) );
+        tokio::task::spawn( retransmitter(net.clone(), outcome.clone(), backbone.subscribe()) );
+        for service in services {
+            tokio::task::spawn( forwarder(net.clone(), service, outcome.clone(), backbone.subscribe()) );
+        }
+        nodes.push(net);
+    }
+    // streams need a session, the HELLOs may be lost too
+    let established = |net: &Network, peer: &Host| net.session(peer).map( |session| session.state() ) == Some( crate::network::session::SessionState::Established );
+    while !established(&nodes[0], &user_host) || !established(&nodes[1], &provider_host) {
+        nodes[1].send_to( nodes[1].greet(provider_host), None ).await.ok();
+        sleep( tokio::time::Duration::from_millis(50) ).await;
+    }
+
+    // more than the window, in order and complete despite the losses
+    let sent: Vec<u8> = (0..40_000u32).map( |i| (i % 251) as u8 ).collect();
+    let mut tcp = TcpStream::connect("127.0.0.1:4670").await?;
+    let (mut reader, mut writer) = tcp.split();
+    let (_, received) = tokio::join!(
+        async { writer.write_all(&sent).await.ok(); writer.shutdown().await.ok(); },
+        async { let mut received: Vec<u8> = Vec::new(); reader.read_to_end(&mut received).await.map( |_| received ) },
+    );
+    assert_eq!(received?,sent);
+
+    sleep( STREAM_RTO * 4 ).await;
+    assert_eq!((nodes[0].streams(), nodes[1].streams()),(0, 0));
+    Ok(())
+}
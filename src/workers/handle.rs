@@ -3,39 +3,734 @@
 use crate::network::host::Host;
 use crate::network::udp::Datagram;
 use crate::network::network::Network;
+use crate::network::session::admits;
+use crate::network::peer::PeerId;
 
 use crate::message::tlv::TLV;
 use crate::message::signal::Signal;
 use crate::message::header::Header;
+use crate::message::hello::Hello;
+use crate::message::relay::Relay;
+use crate::message::route::Advertisement;
+use crate::message::dht::DhtMessage;
+use crate::message::gossip::{gossip_from_bytes,probe_from_bytes,probe_to_bytes,bye_from_bytes,MemberState};
+use crate::message::lease::LeaseMessage;
+use crate::message::service::ServiceMessage;
+use crate::message::stream::StreamMessage;
+use crate::message::pmtu::PmtuMessage;
+use crate::message::packet::ip_source;
 
 use crate::memory::shared_fifo::SharedFifo;
 
-pub async fn handler(mut net: Network, mut dg: Datagram, /*mut tracing: Signal<Datagram>,*/ mut outcome: SharedFifo<Datagram,()>) -> std::io::Result<()> {
+use crate::workers::registry::Context;
+
+pub async fn handler(net: Network, dg: Datagram, /*mut tracing: Signal<Datagram>,*/ outcome: SharedFifo<Datagram,()>) -> std::io::Result<()> {
+    let peer: Host = dg.src().unwrap();
+    // Checked against our session with the peer first, e.g. no overlay traffic before the handshake
+    if !net.admit( &peer, dg.header() ) {
+        return Ok(());
+    }
+    handle(net, dg, outcome).await
+}
+
+async fn handle(mut net: Network, dg: Datagram, mut outcome: SharedFifo<Datagram,()>) -> std::io::Result<()> {
     let peer: Host = dg.src().unwrap();
+    // Registered handlers come first, see HandlerRegistry
+    if let Some(registered) = net.message_handler( dg.header() ) {
+        let mut ctx = Context::new(net, peer, outcome);
+        return registered.handle(&mut ctx, dg).await;
+    }
     match dg.header() {
-        Header::PING => { 
-            dg.swap();
-            dg.set_header(Header::PONG);
-            outcome.push_notice(dg,()).await.ok();
+        // Handled by default, see PingHandler and HelloHandler
+        Header::PING | Header::HELLO | Header::APPLICATION(_) => {},
+
+        // Answer to one of our probes, maybe also to a probe made on behalf of other members
+        Header::PONG => {
+            net.pong( &peer, &dg.data().payload() );
+            let target = net.peer_id(&peer);
+            for requester in net.ack(&peer) {
+                if let Some(target) = target {
+                    let ack = TLV::new( Header::PING_ACK, Some( probe_to_bytes(target, peer) ) ).unwrap();
+                    outcome.push_notice( Datagram::new( None, ack, Some(requester) ), () ).await.ok();
+                }
+            }
+        },
+
+        Header::PING_REQ => {
+            // The target's host as our membership knows it, not whatever the request says
+            let target: Option<PeerId> = probe_from_bytes( &dg.data().payload() ).map( |(target, _)| target );
+            let member = target.and_then( |target| net.member(&target).filter( |member| member.state() != MemberState::Dead ).map( |member| (target, member.host()) ) );
+            if let Some((target, host)) = member {
+                net.indirect_probe(target, peer);
+                outcome.push_notice( net.ping(host), () ).await.ok();
+            }
+        },
+
+        Header::PING_ACK => {
+            if let Some((target, _)) = probe_from_bytes( &dg.data().payload() ) {
+                net.ack_id(&target);
+            }
+        },
+
+        Header::GOSSIP => {
+            if let Some(updates) = gossip_from_bytes( &dg.data().payload() ) {
+                net.apply_gossip(updates);
+            }
+        },
+
+        Header::BYE => {
+            // Only a peer can say it leaves, as proved by its session
+            if let Some((id, incarnation)) = bye_from_bytes( &dg.data().payload() ) {
+                if net.session(&peer).and_then( |session| session.id() ) == Some(id) {
+                    net.leave(id, incarnation, &peer);
+                }
+            }
+        },
+
+        // Piggybacked traffic, every part is handled on its own
+        Header::MULTIPLE => {
+            if let Some(parts) = dg.data().split() {
+                for part in parts {
+                    if part.header() == Header::MULTIPLE {
+                        continue;
+                    }
+                    let part = Datagram::new( dg.src(), part, dg.dst() );
+                    Box::pin( handler(net.clone(), part, outcome.clone()) ).await?;
+                }
+            }
+        },
+
+        // Too large for the path on its way, handled once every piece arrived
+        Header::FRAGMENT => {
+            if let Some(fragment) = PmtuMessage::from_tlv( &dg.data() ) {
+                if let Some(whole) = net.reassemble(peer, fragment) {
+                    if whole.header() != Header::FRAGMENT {
+                        Box::pin( handler(net.clone(), Datagram::new( dg.src(), whole, dg.dst() ), outcome.clone()) ).await?;
+                    }
+                }
+            }
+        },
+
+        // Path MTU discovery: the probe made it in one piece
+        Header::PROBE => {
+            if let Some(PmtuMessage::Probe { size }) = PmtuMessage::from_tlv( &dg.data() ) {
+                outcome.push_notice( Datagram::new( None, PmtuMessage::Ack { size }.to_tlv().unwrap(), Some(peer) ), () ).await.ok();
+            }
+        },
+
+        Header::PROBE_ACK => {
+            if let Some(PmtuMessage::Ack { size }) = PmtuMessage::from_tlv( &dg.data() ) {
+                net.pmtu_acked(&peer, size);
+            }
         },
         
-        // Sounds like a (re?)newcomer
-        Header::HELLO => {
-            net.insert(&peer);
-            dg.swap();
-            dg.set_header(Header::HELLO);
-            outcome.push_notice(dg,()).await.ok();
+        Header::RELAY => {
+            if let Some(relay) = Relay::from_bytes( &dg.data().payload() ) {
+                relay_handler(net, peer, relay, outcome).await?;
+            }
+        },
+
+        // Advertisements are only trusted from the neighbour they claim to come from
+        // Only taken from the peer the origin is bound to, anyone else could claim to be it and
+        // draw its traffic
+        Header::ROUTE => {
+            if let Some(adv) = Advertisement::from_bytes( &dg.data().payload() ) {
+                if let Some(origin) = adv.origin() {
+                    if net.bound(&origin, &peer) {
+                        net.update_routes(origin, &adv);
+                    }
+                }
+            }
+        },
+
+        Header::FIND_NODE | Header::FIND_VALUE | Header::NODES | Header::VALUE | Header::STORE => {
+            if let Some(message) = DhtMessage::from_tlv( &dg.data() ) {
+                net.dht_observe( message.sender(), &peer );
+                if let Some(answer) = net.dht_answer(message, &peer) {
+                    if let Some(tlv) = answer.to_tlv() {
+                        outcome.push_notice( Datagram::new( None, tlv, Some(peer) ), () ).await.ok();
+                    }
+                }
+            }
+        },
+
+        // Overlay traffic for the local system, only from an address the sender holds
+        Header::IPPACKET => {
+            let packet: Vec<u8> = dg.data().payload();
+            let sender: Option<PeerId> = net.session(&peer).and_then( |session| session.id() );
+            if sender.is_some() && ip_source(&packet).and_then( |ip| net.overlay_peer(&ip) ) == sender {
+                net.deliver_packet(packet);
+            }
+        },
+
+        // Only for the id the peer's session proved, a lease is not taken on behalf of others
+        Header::LEASE_REQUEST => {
+            let proved: Option<PeerId> = net.session(&peer).and_then( |session| session.id() );
+            let request = LeaseMessage::from_tlv( &dg.data() ).filter( |request| matches!( request, LeaseMessage::Request { id, .. } if Some(*id) == proved ) );
+            if let Some(answer) = request.and_then( |request| net.lease_answer(request) ) {
+                if let Some(tlv) = answer.to_tlv() {
+                    outcome.push_notice( Datagram::new( None, tlv, Some(peer) ), () ).await.ok();
+                }
+            }
+        },
+
+        // Only our server hands out addresses
+        Header::LEASE_OFFER | Header::LEASE_ACK if net.server() == Some(peer) => {
+            match LeaseMessage::from_tlv( &dg.data() ) {
+                Some(LeaseMessage::Offer { ip, .. }) => {
+                    let request = LeaseMessage::Request { id: net.id(), requested: Some(ip) };
+                    if let Some(tlv) = request.to_tlv() {
+                        outcome.push_notice( Datagram::new( None, tlv, Some(peer) ), () ).await.ok();
+                    }
+                },
+                Some(LeaseMessage::Ack { ip, prefix, duration }) => { net.lease_acquired(ip, prefix, duration); },
+                _ => {},
+            }
+        },
+        Header::LEASE_OFFER | Header::LEASE_ACK => {},
+
+        // Asked by a client about an address claimed in a HELLO, or the answer of our server
+        Header::LEASE_CONFIRM => {
+            if let Some(LeaseMessage::Confirm { id, ip }) = LeaseMessage::from_tlv( &dg.data() ) {
+                if net.lease_held(&id, &ip) {
+                    outcome.push_notice( Datagram::new( None, dg.data(), Some(peer) ), () ).await.ok();
+                }
+                else if net.server() == Some(peer) {
+                    net.assign_overlay(ip, id);
+                }
+            }
+        },
+
+        Header::SERVICE_ANNOUNCE => {
+            if let Some(ServiceMessage::Announce { ttl, service }) = ServiceMessage::from_tlv( &dg.data() ) {
+                net.register_service(service, ttl, &peer);
+            }
+        },
+
+        Header::SERVICE_QUERY => {
+            if let Some(ServiceMessage::Query { name }) = ServiceMessage::from_tlv( &dg.data() ) {
+                for answer in net.announcements( Some(&name) ) {
+                    outcome.push_notice( Datagram::new( None, answer.data(), Some(peer) ), () ).await.ok();
+                }
+            }
+        },
+
+        // A connection to one of our services, forwarded by the peer
+        Header::STREAM_OPEN => {
+            if let Some(open) = StreamMessage::from_tlv( &dg.data() ) {
+                if let (StreamMessage::Open { port, .. }, Some((key, delivered))) = (&open, net.stream_accept(peer, &open)) {
+                    match net.forwardable(*port) {
+                        true => { tokio::task::spawn( crate::workers::forward::connect( net.clone(), key, *port, delivered, outcome.clone() ) ); },
+                        false => {
+                            if let Some(close) = net.stream_shutdown(&key) {
+                                outcome.push_notice(close,()).await.ok();
+                            }
+                        },
+                    }
+                }
+                if let Some(ack) = net.stream_receive(peer, open) {
+                    outcome.push_notice(ack,()).await.ok();
+                }
+            }
+        },
+
+        Header::STREAM_DATA | Header::STREAM_CLOSE => {
+            if let Some(ack) = StreamMessage::from_tlv( &dg.data() ).and_then( |segment| net.stream_receive(peer, segment) ) {
+                outcome.push_notice(ack,()).await.ok();
+            }
+        },
+
+        Header::STREAM_ACK => {
+            if let Some(ack) = StreamMessage::from_tlv( &dg.data() ) {
+                net.stream_acknowledge(peer, &ack);
+            }
         },
 
         Header::UNKNOWN => {
             /*let dg = Datagram::new( Some(peer), dg.data(), Some(net.local_addr()) );
             tracing.send(dg).await.ok();*/
         }
-        _ => { 
-            
-        },
     };
 
     Ok(())
-}    
- 
\ No newline at end of file
+}
+
+// Deliver the inner TLV if we are the destination, otherwise pass it on to the next hop
+async fn relay_handler(net: Network, peer: Host, mut relay: Relay, mut outcome: SharedFifo<Datagram,()>) -> std::io::Result<()> {
+    if relay.dst() == net.id() {
+        // Nested envelopes are not allowed, it would only serve to bypass the hop limit
+        match relay.inner() {
+            Some(inner) if inner.header() != Header::RELAY => {
+                // Checked against the origin's own session, the one of the relaying peer says
+                // nothing about it: without one, only what needs no session gets through
+                let origin: Option<Host> = net.peer( &relay.src() ).filter( |host| net.session(host).and_then( |session| session.id() ) == Some( relay.src() ) );
+                let admitted: bool = match origin {
+                    Some(origin) => net.admit( &origin, inner.header() ),
+                    None => admits( None, inner.header() ),
+                };
+                if !admitted {
+                    return Ok(());
+                }
+                let from: Host = origin.unwrap_or(peer);
+                let dg = Datagram::new( Some(from), inner, Some( net.local_addr() ) );
+                let replies: SharedFifo<Datagram,()> = SharedFifo::new(crate::message::signal::SignalType::notify);
+                Box::pin( handle(net.clone(), dg, replies.clone()) ).await?;
+
+                // The origin went through a relay, so its answers do too: wrapped for its id and sent
+                // along its route, or back to the relay it came from. Sent to its host the origin may
+                // not get them, sent bare the relay would take them for itself
+                let back: Host = match net.next_hop( &relay.src() ) {
+                    Some(hop) if net.peer( &relay.src() ) != Some(hop) => hop,
+                    _ => peer,
+                };
+                let answers: Vec<Datagram> = std::iter::from_fn( || replies.pop() ).collect();
+                for reply in answers.into_iter().rev() {
+                    if reply.dst() != Some(from) {
+                        outcome.push_notice(reply, ()).await.ok();
+                        continue;
+                    }
+                    if let Some(tlv) = Relay::new( net.id(), relay.src(), &reply.data() ).to_tlv() {
+                        outcome.push_notice( Datagram::new( None, tlv, Some(back) ), () ).await.ok();
+                    }
+                }
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+    else {
+        let len: usize = relay.inner_len();
+        let next: Option<Host> = net.next_hop( &relay.dst() );
+
+        match next {
+            Some(next) if next != peer && relay.forward( net.id() ) => {
+                match relay.to_tlv() {
+                    None => { net.account_relay(relay.src(), len, false); },
+                    Some(tlv) => {
+                        net.account_relay(relay.src(), len, true);
+                        outcome.push_notice( Datagram::new( None, tlv, Some(next) ), () ).await.ok();
+                    },
+                }
+            },
+            _ => { net.account_relay(relay.src(), len, false); },
+        }
+        Ok(())
+    }
+}
+
+// For test: `peer` goes through the handshake with `net`, introducing itself with `hello` and a fresh
+// key. What `net` answers to the signed HELLO is left in `outcome`
+pub async fn handshake(net: &Network, peer: Host, hello: Hello, outcome: &SharedFifo<Datagram,()>) -> std::io::Result<()> {
+    use signature::Signer;
+    use crate::message::hello::proof_message;
+
+    let key = crate::crypto::asymetric::KeyPair::random();
+    let hello = hello.with_challenge( key.public_bytes().unwrap(), 1 );
+    let said = |hello: &Hello| Datagram::new( Some(peer), TLV::new( Header::HELLO, Some( hello.to_bytes() ) ).unwrap(), None );
+
+    handler( net.clone(), said(&hello), outcome.clone() ).await?;
+    let nonce: u64 = std::iter::from_fn( || outcome.pop() )
+        .find( |dg| dg.header() == Header::HELLO && dg.dst() == Some(peer) )
+        .and_then( |dg| Hello::from_bytes( &dg.data().payload() ) )
+        .and_then( |answer| answer.nonce() )
+        .unwrap();
+    let signature = key.try_sign( &proof_message( nonce, hello.id() ) ).unwrap();
+    handler( net.clone(), said( &hello.with_proof( nonce, signature.as_bytes().to_vec() ) ), outcome.clone() ).await
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handler_relay_forward() -> std::io::Result<()> {
+    let sock = std::sync::Arc::new( crate::network::simulated::SimNet::new(0).bind( Host::new("127.0.0.1:4646") ) );
+    let mut net = Network::new(sock, None, Host::new("127.255.255.255:4646"), None);
+    let outcome: SharedFifo<Datagram,()> = SharedFifo::new(crate::message::signal::SignalType::notify);
+
+    let src = crate::network::peer::PeerId::new(1);
+    let dst = crate::network::peer::PeerId::new(3);
+    let from = Host::new("127.0.0.1:1111");
+    let next = Host::new("127.0.0.3:3333");
+    net.insert_peer(dst, &next);
+    // relays are only taken from established sessions
+    handshake( &net, from, Hello::new( crate::network::peer::PeerId::new(2) ), &outcome ).await?;
+    assert!(outcome.pop().is_none());
+
+    let inner = TLV::new(Header::PING, Some(vec![1,2,3])).unwrap();
+    let relay = Relay::new(src, dst, &inner).to_tlv().unwrap();
+    handler( net.clone(), Datagram::new( Some(from), relay.clone(), None ), outcome.clone() ).await?;
+
+    let forwarded = outcome.pop().unwrap();
+    assert_eq!(forwarded.dst(),Some(next));
+    let forwarded = Relay::from_bytes( &forwarded.data().payload() ).unwrap();
+    assert_eq!(forwarded.path(),vec![net.id()]);
+    assert_eq!(forwarded.inner(),Some(inner));
+    assert_eq!(net.relay_stats(&src).unwrap().forwarded(),1);
+
+    // unknown destination: dropped and accounted
+    let lost = Relay::new(src, crate::network::peer::PeerId::new(4), &TLV::new(Header::PING, None).unwrap()).to_tlv().unwrap();
+    handler( net.clone(), Datagram::new( Some(from), lost, None ), outcome.clone() ).await?;
+    assert!(outcome.pop().is_none());
+    assert_eq!(net.relay_stats(&src).unwrap().dropped(),1);
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handler_relay_deliver() -> std::io::Result<()> {
+    let sock = std::sync::Arc::new( crate::network::simulated::SimNet::new(0).bind( Host::new("127.0.0.1:4647") ) );
+    let net = Network::new(sock, None, Host::new("127.255.255.255:4647"), None);
+    let outcome: SharedFifo<Datagram,()> = SharedFifo::new(crate::message::signal::SignalType::notify);
+
+    let from = Host::new("127.0.0.1:1111");
+    let inner = TLV::new(Header::PING, None).unwrap();
+    let relay = Relay::new(crate::network::peer::PeerId::new(1), net.id(), &inner).to_tlv().unwrap();
+    handler( net.clone(), Datagram::new( Some(from), relay.clone(), None ), outcome.clone() ).await?;
+    assert!(outcome.pop().is_none());
+
+    handshake( &net, from, Hello::new( crate::network::peer::PeerId::new(2) ), &outcome ).await?;
+    handler( net.clone(), Datagram::new( Some(from), relay, None ), outcome.clone() ).await?;
+
+    // the answer goes back through the relay, addressed to the origin
+    let answer = outcome.pop().unwrap();
+    assert_eq!(answer.dst(),Some(from));
+    let answer = Relay::from_bytes( &answer.data().payload() ).unwrap();
+    assert_eq!((answer.src(), answer.dst()),(net.id(), crate::network::peer::PeerId::new(1)));
+    assert_eq!(answer.inner().unwrap().header(),Header::PONG);
+    assert!(net.relay_usage().is_empty());
+
+    // overlay traffic needs the origin's own session, the relaying peer's one is not enough
+    let mut tunnel = net.attach_tunnel();
+    let origin = Host::new("127.0.0.3:3333");
+    let ip = crate::message::packet::ipv4_packet( "10.9.0.3".parse().unwrap(), "10.9.0.1".parse().unwrap(), b"relayed" );
+    net.assign_overlay( "10.9.0.3".parse().unwrap(), crate::network::peer::PeerId::new(3) );
+    let packet = Relay::new( crate::network::peer::PeerId::new(3), net.id(), &TLV::new( Header::IPPACKET, Some( ip.clone() ) ).unwrap() ).to_tlv().unwrap();
+    handler( net.clone(), Datagram::new( Some(from), packet.clone(), None ), outcome.clone() ).await?;
+    assert!(tunnel.try_recv().is_err());
+    handshake( &net, origin, Hello::new( crate::network::peer::PeerId::new(3) ), &outcome ).await?;
+    handler( net.clone(), Datagram::new( Some(from), packet, None ), outcome.clone() ).await?;
+    assert_eq!(tunnel.try_recv().ok(),Some(ip));
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handler_relay_reply_reaches_origin() -> std::io::Result<()> {
+    let sim = crate::network::simulated::SimNet::new(0);
+    let node = |port: u16| Network::new( std::sync::Arc::new( sim.bind( Host::new( &format!("127.0.0.1:{}", port) ) ) ), None, Host::new("127.255.255.255:4737"), None );
+    // origin reaches target only through relay
+    let (origin, relay, target) = ( node(4737), node(4738), node(4739) );
+    let (a, r, t) = ( origin.local_addr(), relay.local_addr(), target.local_addr() );
+    let (to_origin, to_relay, to_target): (SharedFifo<Datagram,()>,SharedFifo<Datagram,()>,SharedFifo<Datagram,()>) = (
+        SharedFifo::new(crate::message::signal::SignalType::notify),
+        SharedFifo::new(crate::message::signal::SignalType::notify),
+        SharedFifo::new(crate::message::signal::SignalType::notify),
+    );
+    handshake( &relay, a, Hello::new( origin.id() ), &to_relay ).await?;
+    handshake( &relay, t, Hello::new( target.id() ), &to_relay ).await?;
+    handshake( &target, r, Hello::new( relay.id() ), &to_target ).await?;
+    handshake( &origin, r, Hello::new( relay.id() ), &to_origin ).await?;
+    handshake( &origin, t, Hello::new( target.id() ), &to_origin ).await?;
+    for outcome in [&to_origin, &to_relay, &to_target] {
+        while outcome.pop().is_some() {}
+    }
+
+    // a relayed PING, forwarded to target
+    let ping = Relay::new( origin.id(), target.id(), &origin.ping(t).data() ).to_tlv().unwrap();
+    handler( relay.clone(), Datagram::new( Some(a), ping, None ), to_relay.clone() ).await?;
+    let forwarded = to_relay.pop().unwrap();
+    assert_eq!(forwarded.dst(),Some(t));
+
+    // target doesn't know origin: the PONG goes back to the relay, for origin
+    handler( target.clone(), Datagram::new( Some(r), forwarded.data(), None ), to_target.clone() ).await?;
+    let pong = to_target.pop().unwrap();
+    assert_eq!((pong.header(), pong.dst()),(Header::RELAY, Some(r)));
+    assert!(to_target.pop().is_none());
+
+    handler( relay.clone(), Datagram::new( Some(t), pong.data(), None ), to_relay.clone() ).await?;
+    let pong = to_relay.pop().unwrap();
+    assert_eq!((pong.header(), pong.dst()),(Header::RELAY, Some(a)));
+    assert!(to_relay.pop().is_none());
+
+    // and origin takes it as the answer to its ping
+    handler( origin.clone(), Datagram::new( Some(r), pong.data(), None ), to_origin.clone() ).await?;
+    assert_eq!(origin.link_stats(&t).map( |stats| stats.received() ),Some(1));
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handler_swim_indirect_probe() -> std::io::Result<()> {
+    let sock = std::sync::Arc::new( crate::network::simulated::SimNet::new(0).bind( Host::new("127.0.0.1:4650") ) );
+    let mut net = Network::new(sock, None, Host::new("127.255.255.255:4650"), None);
+    let outcome: SharedFifo<Datagram,()> = SharedFifo::new(crate::message::signal::SignalType::notify);
+
+    let requester = Host::new("127.0.0.1:1111");
+    let target = Host::new("127.0.0.2:2222");
+    let target_id = crate::network::peer::PeerId::new(2);
+    net.member_alive(target_id, &target);
+
+    // asked to probe target: we ping it where we know it is, not where the request says
+    let request = TLV::new( Header::PING_REQ, Some( probe_to_bytes(target_id, Host::new("10.66.0.1:53")) ) ).unwrap();
+    handler( net.clone(), Datagram::new( Some(requester), request, None ), outcome.clone() ).await?;
+    let ping = outcome.pop().unwrap();
+    assert_eq!(ping.header(),Header::PING);
+    assert_eq!(ping.dst(),Some(target));
+
+    // target answers: the requester gets the ack
+    handler( net.clone(), Datagram::new( Some(target), TLV::new(Header::PONG, None).unwrap(), None ), outcome.clone() ).await?;
+    let ack = outcome.pop().unwrap();
+    assert_eq!(ack.header(),Header::PING_ACK);
+    assert_eq!(ack.dst(),Some(requester));
+    assert_eq!(Some( (target_id, target) ),probe_from_bytes( &ack.data().payload() ));
+
+    // nobody we know: nothing is sent
+    let request = TLV::new( Header::PING_REQ, Some( probe_to_bytes(crate::network::peer::PeerId::new(3), Host::new("10.66.0.1:53")) ) ).unwrap();
+    handler( net.clone(), Datagram::new( Some(requester), request, None ), outcome.clone() ).await?;
+    assert!(outcome.pop().is_none());
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handler_swim_gossip_evicts() -> std::io::Result<()> {
+    use crate::message::gossip::{MemberUpdate,MemberState,gossip_to_tlv};
+
+    let sock = std::sync::Arc::new( crate::network::simulated::SimNet::new(0).bind( Host::new("127.0.0.1:4651") ) );
+    let mut net = Network::new(sock, None, Host::new("127.255.255.255:4651"), None);
+    let outcome: SharedFifo<Datagram,()> = SharedFifo::new(crate::message::signal::SignalType::notify);
+
+    let from = Host::new("127.0.0.1:1111");
+    let dead = Host::new("127.0.0.3:3333");
+    let dead_id = crate::network::peer::PeerId::new(3);
+    let joining = Host::new("127.0.0.4:4444");
+    let client = Host::new("127.0.0.5:5555");
+    net.member_alive(dead_id, &dead);
+    net.insert(&client);
+    assert!(net.contains(&dead));
+
+    // piggybacked on a PING: joins and deaths are applied, the PING is still answered
+    let gossip = gossip_to_tlv( &[
+        MemberUpdate::new( dead_id, MemberState::Dead, 0, client ),
+        MemberUpdate::new( crate::network::peer::PeerId::new(4), MemberState::Alive, 0, joining ),
+        MemberUpdate::new( crate::network::peer::PeerId::new(5), MemberState::Dead, 0, client ),
+    ] ).unwrap();
+    let multiple = TLV::merge( TLV::new(Header::PING, None).unwrap(), gossip ).unwrap();
+    handler( net.clone(), Datagram::new( Some(from), multiple, None ), outcome.clone() ).await?;
+
+    // the dead member is evicted where we know it, not where the gossip says
+    assert!(!net.contains(&dead));
+    assert!(net.contains(&client));
+    assert_eq!(net.members()[&dead_id].host(),dead);
+    // the newcomer is greeted, its id only bound once its session proved it
+    assert!(net.contains(&joining));
+    assert_eq!(None,net.peer( &crate::network::peer::PeerId::new(4) ));
+    assert_eq!(outcome.pop().unwrap().header(),Header::PONG);
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handler_ping_rtt() -> std::io::Result<()> {
+    let sock = std::sync::Arc::new( crate::network::simulated::SimNet::new(0).bind( Host::new("127.0.0.1:4654") ) );
+    let net = Network::new(sock, None, Host::new("127.255.255.255:4654"), None);
+    let outcome: SharedFifo<Datagram,()> = SharedFifo::new(crate::message::signal::SignalType::notify);
+
+    let peer = Host::new("127.0.0.1:1111");
+    let silent = Host::new("127.0.0.1:2222");
+    let ping = net.ping(peer);
+    net.ping(silent);
+
+    // the peer echoes our PING payload in its PONG
+    handler( net.clone(), Datagram::new( Some(net.local_addr()), ping.data(), None ), outcome.clone() ).await?;
+    let pong = outcome.pop().unwrap();
+    assert_eq!(pong.header(),Header::PONG);
+    assert_eq!(pong.data().payload(),ping.data().payload());
+
+    handler( net.clone(), Datagram::new( Some(peer), pong.data(), None ), outcome.clone() ).await?;
+    let stats = net.link_stats(&peer).unwrap();
+    assert_eq!(stats.received(),1);
+    assert!(stats.srtt().is_some());
+    assert_eq!(net.link_stats(&silent).unwrap().srtt(),None);
+    assert_eq!(net.fastest( &[silent, peer] ),Some(peer));
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handler_service_discovery() -> std::io::Result<()> {
+    use crate::network::service::{Service,SERVICE_TTL};
+    let sim = crate::network::simulated::SimNet::new(0);
+    let provider = Network::new( std::sync::Arc::new( sim.bind( Host::new("127.0.0.1:4655") ) ), None, Host::new("127.255.255.255:4655"), None )
+        .with_services( vec![ Service::new("web".to_string(), 8080, None), Service::new("db".to_string(), 5432, Some( Host::new("10.0.0.9:5432") )) ] );
+    let seeker = Network::new( std::sync::Arc::new( sim.bind( Host::new("127.0.0.2:4655") ) ), None, Host::new("127.255.255.255:4655"), None );
+    let outcome: SharedFifo<Datagram,()> = SharedFifo::new(crate::message::signal::SignalType::notify);
+
+    // only the services with the queried name are announced back
+    let query = ServiceMessage::Query { name: "web".to_string() }.to_tlv().unwrap();
+    handler( provider.clone(), Datagram::new( Some(seeker.local_addr()), query, None ), outcome.clone() ).await?;
+    let announce = outcome.pop().unwrap();
+    assert!(outcome.pop().is_none());
+    assert_eq!(announce.dst(),Some(seeker.local_addr()));
+
+    // a service without server runs on the announcer
+    handler( seeker.clone(), Datagram::new( Some(provider.local_addr()), announce.data(), None ), outcome.clone() ).await?;
+    assert_eq!(seeker.lookup_service("web"),vec![ Service::new("web".to_string(), 8080, Some( Host::new("127.0.0.1:8080") )) ]);
+    assert!(seeker.lookup_service("db").is_empty());
+
+    tokio::time::advance( SERVICE_TTL ).await;
+    seeker.expire_services();
+    assert!(seeker.known_services().is_empty());
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handler_bye() -> std::io::Result<()> {
+    use crate::message::gossip::{MemberState,bye_to_tlv};
+
+    let sock = std::sync::Arc::new( crate::network::simulated::SimNet::new(0).bind( Host::new("127.0.0.1:4694") ) );
+    let net = Network::new(sock, None, Host::new("127.255.255.255:4694"), None);
+    let outcome: SharedFifo<Datagram,()> = SharedFifo::new(crate::message::signal::SignalType::notify);
+
+    let leaving = Host::new("127.0.0.2:2222");
+    let leaving_id = crate::network::peer::PeerId::new(2);
+    handshake( &net, leaving, Hello::new(leaving_id), &outcome ).await?;
+    assert_eq!(net.farewell().len(),1);
+    assert_eq!(net.farewell()[0].dst(),Some(leaving));
+
+    // nobody else can say it for the peer
+    handler( net.clone(), Datagram::new( Some( Host::new("127.0.0.3:3333") ), bye_to_tlv(leaving_id, 0).unwrap(), None ), outcome.clone() ).await?;
+    assert_eq!(net.peer(&leaving_id),Some(leaving));
+    assert_eq!(net.members()[&leaving_id].state(),MemberState::Alive);
+
+    handler( net.clone(), Datagram::new( Some(leaving), bye_to_tlv(leaving_id, 0).unwrap(), None ), outcome.clone() ).await?;
+    assert!(!net.contains(&leaving));
+    assert_eq!(None,net.peer(&leaving_id));
+    assert_eq!(net.members()[&leaving_id].state(),MemberState::Dead);
+    assert!(outcome.pop().is_none());
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handler_session_handshake() -> std::io::Result<()> {
+    use signature::Signer;
+    use crate::crypto::asymetric::KeyPair;
+    use crate::message::hello::proof_message;
+    use crate::network::peer::PeerId;
+    use crate::network::session::SessionState;
+
+    let sock = std::sync::Arc::new( crate::network::simulated::SimNet::new(0).bind( Host::new("127.0.0.1:4712") ) );
+    let mut net = Network::new(sock, None, Host::new("127.255.255.255:4712"), None);
+    let outcome: SharedFifo<Datagram,()> = SharedFifo::new(crate::message::signal::SignalType::notify);
+    let (peer, spoofer) = (Host::new("127.0.0.2:2222"), Host::new("127.0.0.3:3333"));
+    let key = KeyPair::random();
+    let said = |from: Host, hello: Hello| Datagram::new( Some(from), TLV::new( Header::HELLO, Some( hello.to_bytes() ) ).unwrap(), None );
+    let data = Datagram::new( Some(peer), TLV::new( Header::IPPACKET, Some( vec![0; 20] ) ).unwrap(), None );
+
+    // we greet a configured client with our nonce, its data is refused until it signed it
+    net.insert(&peer);
+    let greetings = net.greetings();
+    assert_eq!(greetings.iter().map( |hello| hello.dst() ).collect::<Vec<_>>(),vec![ Some(peer) ]);
+    let nonce: u64 = Hello::from_bytes( &greetings[0].data().payload() ).and_then( |hello| hello.nonce() ).unwrap();
+    assert_eq!(net.session(&peer).map( |session| session.state() ),Some( SessionState::Handshaking ));
+    handler( net.clone(), data.clone(), outcome.clone() ).await?;
+    assert_eq!(net.session(&peer).unwrap().rejected(),1);
+
+    // an answer without the signature, or signed for another id, establishes nothing
+    let challenge = |nonce: u64| Hello::new( PeerId::new(2) ).with_challenge( key.public_bytes().unwrap(), nonce );
+    let signed = |nonce: u64, id: u64| key.try_sign( &proof_message( nonce, PeerId::new(id) ) ).unwrap().as_bytes().to_vec();
+    handler( net.clone(), said( peer, challenge(7) ), outcome.clone() ).await?;
+    handler( net.clone(), said( peer, challenge(7).with_proof( nonce, signed(nonce, 3) ) ), outcome.clone() ).await?;
+    assert_eq!(net.session(&peer).map( |session| session.state() ),Some( SessionState::Handshaking ));
+    while outcome.pop().is_some() {}
+
+    // the signed answer establishes the session with the peer's key, and is not answered back
+    handler( net.clone(), said( peer, challenge(7).with_proof( nonce, signed(nonce, 2) ) ), outcome.clone() ).await?;
+    assert!(outcome.pop().is_none());
+    assert_eq!(net.session(&peer).map( |session| (session.state(), session.id()) ),Some( (SessionState::Established, Some( PeerId::new(2) )) ));
+    assert_eq!(net.session(&peer).unwrap().key(),Some( &key.clone().into() ));
+    assert!(net.greetings().is_empty());
+
+    // replaying it from elsewhere does not sign that session's nonce
+    handler( net.clone(), said( spoofer, challenge(7).with_proof( nonce, signed(nonce, 2) ) ), outcome.clone() ).await?;
+    assert_ne!(net.session(&spoofer).map( |session| session.state() ),Some( SessionState::Established ));
+    while outcome.pop().is_some() {}
+
+    // a peer greeting us again with a new nonce, e.g. after a restart, gets it signed
+    handler( net.clone(), said( peer, challenge(8) ), outcome.clone() ).await?;
+    let answer = Hello::from_bytes( &outcome.pop().unwrap().data().payload() ).unwrap();
+    assert_eq!(answer.proof().map( |(echo, _)| echo ),Some(8));
+
+    // nothing but control traffic once we said BYE
+    net.farewell();
+    handler( net.clone(), data, outcome.clone() ).await?;
+    assert_eq!(net.session(&peer).map( |session| (session.state(), session.rejected()) ),Some( (SessionState::Closing, 2) ));
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handler_route_bound_origin() -> std::io::Result<()> {
+    use crate::message::route::{Advertisement,RouteEntry};
+    use crate::network::peer::PeerId;
+
+    let sock = std::sync::Arc::new( crate::network::simulated::SimNet::new(0).bind( Host::new("127.0.0.1:4727") ) );
+    let net = Network::new(sock, None, Host::new("127.255.255.255:4727"), None);
+    let outcome: SharedFifo<Datagram,()> = SharedFifo::new(crate::message::signal::SignalType::notify);
+    let peer = Host::new("127.0.0.2:2222");
+    let route = |origin: u64| Datagram::new( Some(peer), TLV::new( Header::ROUTE, Some( Advertisement::new( vec![
+        RouteEntry::new( PeerId::new(origin), 0, 1 ), RouteEntry::new( PeerId::new(9), 1, 1 ),
+    ] ).to_bytes() ) ).unwrap(), None );
+
+    handshake( &net, peer, Hello::new( PeerId::new(2) ), &outcome ).await?;
+
+    // the peer speaking for someone else is not believed
+    handler( net.clone(), route(3), outcome.clone() ).await?;
+    assert!(net.routing_table().routes().is_empty());
+    assert_eq!(net.peer( &PeerId::new(3) ),None);
+
+    handler( net.clone(), route(2), outcome.clone() ).await?;
+    assert_eq!(net.routing_table().next_hop( &PeerId::new(9) ),Some( PeerId::new(2) ));
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handler_id_pinned_key() -> std::io::Result<()> {
+    use crate::network::peer::PeerId;
+    use crate::network::session::SessionState;
+
+    let sock = std::sync::Arc::new( crate::network::simulated::SimNet::new(0).bind( Host::new("127.0.0.1:4733") ) );
+    let mut net = Network::new(sock, None, Host::new("127.255.255.255:4733"), None);
+    let outcome: SharedFifo<Datagram,()> = SharedFifo::new(crate::message::signal::SignalType::notify);
+    let (owner, thief) = (Host::new("127.0.0.2:2222"), Host::new("127.0.0.3:3333"));
+
+    // another key claiming a proved id gets neither the session nor the binding
+    handshake( &net, owner, Hello::new( PeerId::new(42) ), &outcome ).await?;
+    handshake( &net, thief, Hello::new( PeerId::new(42) ), &outcome ).await?;
+    assert_eq!(net.peer( &PeerId::new(42) ),Some(owner));
+    assert_eq!(net.session(&thief).map( |session| (session.state(), session.id()) ),Some( (SessionState::Handshaking, None) ));
+    assert!(!net.insert_peer( PeerId::new(42), &thief ));
+    assert_eq!(net.peer( &PeerId::new(42) ),Some(owner));
+
+    // once the owner is gone, the id is free again
+    net.evict(&owner);
+    while outcome.pop().is_some() {}
+    tokio::time::advance(crate::network::session::HANDSHAKE_RETRY).await;
+    handshake( &net, thief, Hello::new( PeerId::new(42) ), &outcome ).await?;
+    assert_eq!(net.peer( &PeerId::new(42) ),Some(thief));
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handler_lease_request_proved_id() -> std::io::Result<()> {
+    use crate::network::lease::{AddressPool,Cidr};
+    use crate::network::peer::PeerId;
+
+    let sock = std::sync::Arc::new( crate::network::simulated::SimNet::new(0).bind( Host::new("127.0.0.1:4734") ) );
+    let net = Network::new(sock, None, Host::new("127.255.255.255:4734"), None)
+        .with_pool( AddressPool::new( Cidr::try_from("172.19.0.0/24").unwrap(), 60 ) );
+    let outcome: SharedFifo<Datagram,()> = SharedFifo::new(crate::message::signal::SignalType::notify);
+    let peer = Host::new("127.0.0.2:2222");
+    let request = |id: u64| Datagram::new( Some(peer), ( LeaseMessage::Request { id: PeerId::new(id), requested: None } ).to_tlv().unwrap(), None );
+
+    // nothing without a session, nor for somebody else
+    handler( net.clone(), request(2), outcome.clone() ).await?;
+    assert!(outcome.pop().is_none());
+    handshake( &net, peer, Hello::new( PeerId::new(2) ), &outcome ).await?;
+    handler( net.clone(), request(3), outcome.clone() ).await?;
+    assert!(outcome.pop().is_none());
+
+    handler( net.clone(), request(2), outcome.clone() ).await?;
+    assert_eq!(outcome.pop().unwrap().header(),Header::LEASE_OFFER);
+    Ok(())
+}
@@ -6,13 +6,15 @@ use tokio::time::{Duration,sleep};
 
 use crate::network::udp::Datagram;
 use crate::network::network::Network;
+use crate::network::membership::{SwimAction,PROBE_TIMEOUT};
 
+use crate::message::tlv::TLV;
 use crate::message::header::Header;
 use crate::message::signal::Signal;
+use crate::message::gossip::probe_to_bytes;
 
-pub async fn heartbeater(net: Network, mut backbone: Signal<()>) -> Result<(), std::io::Error> {
-    let ping: Datagram = Datagram::from( Header::PING );
-    
+// SWIM failure detector: probe one member per period, ask others when it doesn't answer
+pub async fn heartbeater(mut net: Network, mut backbone: Signal<()>) -> Result<(), std::io::Error> {
     loop {
         // If received any data => stop the thread
         match backbone.try_recv() {
@@ -24,13 +26,67 @@ pub async fn heartbeater(net: Network, mut backbone: Signal<()>) -> Result<(), s
             }
         }
 
-        net.multicast(ping.clone()).await;
-        sleep(Duration::from_millis(500)).await;
-        net.multicast(ping.clone()).await;
-        sleep(Duration::from_millis(500)).await;
-        net.multicast(ping.clone()).await;
-        sleep(Duration::from_secs(5)).await;
+        net.expire_pings();
+        for action in net.swim_tick() {
+            match action {
+                SwimAction::Probe(_, host) => {
+                    net.send_to(net.ping(host), None).await.ok();
+                },
+                SwimAction::IndirectProbe { target, host, via } => {
+                    net.suspect(&host);
+                    let request: Datagram = Datagram::from( TLV::new( Header::PING_REQ, Some( probe_to_bytes(target, host) ) ).unwrap() );
+                    for helper in via {
+                        net.send_to(request.clone(), Some(helper)).await.ok();
+                    }
+                },
+                SwimAction::Join(id, host) => { net.insert_peer(id, &host); },
+                SwimAction::Evict(_, host) => { net.evict(&host); },
+            }
+        }
+        // Handshakes with the clients we have no session with yet
+        for hello in net.greetings() {
+            net.send_to(hello, None).await.ok();
+        }
+        for probe in net.pmtu_probes() {
+            net.send_to(probe, None).await.ok();
+        }
+        tokio::select! {
+            _ = backbone.recv() => { break; },
+            _ = sleep(PROBE_TIMEOUT / 2) => {},
+        }
     }
 
     Ok( backbone.close() )
-}
\ No newline at end of file
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_heartbeat_outbound_middleware() -> std::io::Result<()> {
+    use std::sync::Arc;
+    use crate::network::host::Host;
+    use crate::network::peer::PeerId;
+    use crate::network::middleware::{MetricsLayer,Pipeline};
+    use crate::network::transport::Transport;
+    use crate::message::signal::SignalType;
+
+    let sim = crate::network::simulated::SimNet::new(0);
+    let member = Host::new("127.0.0.2:4730");
+    let member_sock = sim.bind(member);
+    let metrics = Arc::new( MetricsLayer::new() );
+    let mut net = Network::new( Arc::new( sim.bind( Host::new("127.0.0.1:4729") ) ), None, Host::new("127.255.255.255:4729"), None )
+        .with_middleware( Pipeline::new().layer( metrics.clone() ) );
+    net.member_alive( PeerId::new(2), &member );
+    let mut backbone: Signal<()> = Signal::new(SignalType::broadcast);
+    tokio::task::spawn( heartbeater(net.clone(), backbone.subscribe()) );
+
+    // the probe is sent without the emitter, and still seen by the layers
+    let mut buf = [0; 1024];
+    let mut pinged: bool = false;
+    while !pinged {
+        let (len, _) = member_sock.recv_from(&mut buf).await?;
+        let dg = Datagram::from_bytes( None, Vec::from( &buf[..len] ), None ).unwrap();
+        pinged = dg.data().split().unwrap().iter().any( |part| part.header() == Header::PING );
+    }
+    backbone.send(()).await.ok();
+    assert_eq!(metrics.outbound_counts().get(&Header::PING),Some(&1));
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use tokio::time::sleep;
+
+use crate::network::udp::Datagram;
+use crate::network::network::Network;
+use crate::network::lease::LEASE_RETRY;
+use crate::network::session::SessionState;
+
+use crate::message::signal::Signal;
+
+// Keep an overlay address: from our own pool on the server, from the server everywhere else
+pub async fn leaser(net: Network, mut backbone: Signal<()>) -> Result<(), std::io::Error> {
+    loop {
+        // If received any data => stop the thread
+        match backbone.try_recv() {
+            Err(_) => { break; },
+            Ok(data) => { 
+                if data.is_some() {
+                    break;
+                } 
+            }
+        }
+
+        net.expire_leases();
+        if net.lease_due() {
+            if net.pool() {
+                net.lease_local();
+            }
+            // the server only answers on an established session, see `admits`
+            else if let Some(server) = net.server() {
+                let established: bool = net.session(&server).is_some_and( |session| session.state() == SessionState::Established );
+                let dg: Option<Datagram> = match established {
+                    true => net.lease_request().to_tlv().map(Datagram::from),
+                    false => Some( net.greet(server) ),
+                };
+                if let Some(dg) = dg {
+                    net.send_to( dg, Some(server) ).await.ok();
+                }
+            }
+        }
+        tokio::select! {
+            _ = backbone.recv() => { break; },
+            _ = sleep(LEASE_RETRY) => {},
+        }
+    }
+
+    backbone.close();
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_leaser_allocates_and_renews() -> std::io::Result<()> {
+    use std::sync::Arc;
+    use crate::network::host::Host;
+    use crate::network::simulated::SimNet;
+    use crate::network::lease::{AddressPool,Cidr};
+    use crate::memory::shared_fifo::SharedFifo;
+    use crate::message::signal::SignalType;
+
+    let sim = SimNet::new(0);
+    let backbone: Signal<()> = Signal::new(SignalType::broadcast);
+    let server_host = Host::new("10.2.0.1:1");
+    let pool = AddressPool::new( Cidr::try_from("172.16.0.0/24").unwrap(), 10 );
+
+    let mut nodes: Vec<Network> = Vec::new();
+    for (host, server) in [ ("10.2.0.1:1", None), ("10.2.0.2:1", Some(server_host)), ("10.2.0.3:1", Some(server_host)) ] {
+        let mut net = Network::new( Arc::new( sim.bind( Host::new(host) ) ), None, Host::new("10.2.255.255:1"), server );
+        if server.is_none() {
+            net = net.with_pool( AddressPool::new( pool.cidr(), pool.duration() ) );
+        }
+        let income: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
+        let outcome: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
+        tokio::task::spawn( crate::workers::receive::receiver(net.clone(), income.clone(), backbone.subscribe()) );
+        tokio::task::spawn( crate::workers::dispatch::dispatcher(net.clone(), income, outcome.clone(), backbone.subscribe()) );
+        tokio::task::spawn( crate::workers::emit::emitter(net.clone(), outcome, backbone.subscribe()) );
+        tokio::task::spawn( leaser(net.clone(), backbone.subscribe()) );
+        nodes.push(net);
+    }
+    // the first request waits for the handshake with the server
+    tokio::time::sleep( LEASE_RETRY + tokio::time::Duration::from_millis(100) ).await;
+
+    // the server takes the first address, the clients get the next free ones
+    assert_eq!(nodes[0].overlay_ip(),Some( "172.16.0.1".parse().unwrap() ));
+    let mut ips: Vec<String> = nodes.iter().skip(1).map( |n| n.overlay_ip().unwrap().to_string() ).collect();
+    ips.sort();
+    assert_eq!(ips,vec![ "172.16.0.2", "172.16.0.3" ]);
+    assert_eq!(nodes[0].overlay_peer( &nodes[2].overlay_ip().unwrap() ),Some( nodes[2].id() ));
+
+    // renewed halfway through, the address stays the same
+    let first = nodes[1].client_lease().unwrap();
+    tokio::time::sleep( tokio::time::Duration::from_secs(6) ).await;
+    let renewed = nodes[1].client_lease().unwrap();
+    assert!(renewed.renew_at() > first.renew_at());
+    assert_eq!(renewed.ip(),first.ip());
+    Ok(())
+}
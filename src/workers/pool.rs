@@ -0,0 +1,232 @@
+use std::hash::{Hash,Hasher};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use metrohash::MetroHash64;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::network::udp::Datagram;
+use crate::network::network::Network;
+
+use crate::memory::shared_fifo::SharedFifo;
+
+// Workers of a dispatcher. The datagrams of one peer always go to the same worker, which handles
+// them one after the other: in the order they came
+pub const POOL_WORKERS: usize = 16;
+// Datagrams waiting per worker: past that, the dispatcher doesn't wait, the datagram is dropped and
+// counted with the ingress drops. A worker only holds the peers hashed to it, mostly the flooding one
+pub const WORKER_QUEUE: usize = 64;
+
+struct Worker {
+    queue: mpsc::Sender<Datagram>,
+    task: JoinHandle<()>,
+    // Given to the worker and not handled yet
+    depth: Arc<AtomicUsize>,
+}
+
+pub struct WorkerPool {
+    net: Network,
+    outcome: SharedFifo<Datagram,()>,
+    workers: Vec<Worker>,
+    capacity: usize,
+}
+
+impl WorkerPool {
+    pub fn new(net: Network, outcome: SharedFifo<Datagram,()>, workers: usize, capacity: usize) -> WorkerPool {
+        let mut pool = WorkerPool { net, outcome, workers: Vec::new(), capacity: capacity.max(1) };
+        for _ in 0..workers.max(1) {
+            let worker = pool.spawn( Arc::new( AtomicUsize::new(0) ) );
+            pool.workers.push(worker);
+        }
+        pool
+    }
+
+    fn spawn(&self, depth: Arc<AtomicUsize>) -> Worker {
+        let (queue, mut rx) = mpsc::channel::<Datagram>(self.capacity);
+        let (net, outcome) = (self.net.clone(), self.outcome.clone());
+        let handled = Arc::clone(&depth);
+        let task = tokio::task::spawn( async move {
+            while let Some(dg) = rx.recv().await {
+                crate::workers::handle::handler( net.clone(), dg, outcome.clone() ).await.ok();
+                handled.fetch_sub(1, Ordering::SeqCst);
+                net.dispatch_handled(1);
+            }
+        });
+        Worker { queue, task, depth }
+    }
+
+    fn shard(&self, dg: &Datagram) -> usize {
+        let mut hasher = MetroHash64::new();
+        dg.src().hash(&mut hasher);
+        (hasher.finish() % self.workers.len() as u64) as usize
+    }
+
+    // Never waits: the datagram is given back when the peer's worker is full
+    pub fn dispatch(&mut self, dg: Datagram) -> Option<Datagram> {
+        let index: usize = self.shard(&dg);
+        self.workers[index].depth.fetch_add(1, Ordering::SeqCst);
+        self.net.dispatch_queued(1);
+        match self.workers[index].queue.try_send(dg) {
+            Ok(()) => None,
+            Err(mpsc::error::TrySendError::Full(dg)) => {
+                self.workers[index].depth.fetch_sub(1, Ordering::SeqCst);
+                self.net.dispatch_handled(1);
+                Some(dg)
+            },
+            Err(mpsc::error::TrySendError::Closed(dg)) => {
+                // a handler panicked and took the worker down, with what was waiting for it
+                let depth = Arc::clone(&self.workers[index].depth);
+                self.net.dispatch_handled( depth.swap(1, Ordering::SeqCst) - 1 );
+                self.workers[index] = self.spawn(depth);
+                self.workers[index].queue.try_send(dg).ok();
+                None
+            },
+        }
+    }
+
+    // Datagrams given to the workers and not handled yet, per worker
+    pub fn depths(&self) -> Vec<usize> {
+        self.workers.iter().map( |worker| worker.depth.load(Ordering::SeqCst) ).collect()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depths().iter().sum()
+    }
+
+    // Everything already dispatched is handled first
+    pub async fn close(self) {
+        for worker in self.workers {
+            drop(worker.queue);
+            worker.task.await.ok();
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_pool_keeps_peer_order() -> std::io::Result<()> {
+    use std::sync::Mutex;
+    use crate::network::host::Host;
+    use crate::message::tlv::TLV;
+    use crate::message::header::Header;
+    use crate::message::signal::SignalType;
+    use crate::workers::registry::{HandlerRegistry,MessageHandler,Context,HandlerFuture};
+
+    // records who sent what, taking longer for the first datagrams of a peer
+    struct Record(Mutex<Vec<(Host,u8)>>);
+    impl MessageHandler for Record {
+        fn handle<'a>(&'a self, ctx: &'a mut Context, dg: Datagram) -> HandlerFuture<'a> {
+            Box::pin( async move {
+                let seq: u8 = dg.data().payload()[0];
+                tokio::time::sleep( tokio::time::Duration::from_millis( 10 - seq as u64 ) ).await;
+                self.0.lock().unwrap().push( (ctx.peer(), seq) );
+                Ok(())
+            })
+        }
+    }
+
+    let sock = Arc::new( crate::network::simulated::SimNet::new(0).bind( Host::new("127.0.0.1:4719") ) );
+    let record = Arc::new( Record( Mutex::new( Vec::new() ) ) );
+    let mut registry = HandlerRegistry::new();
+    registry.register( Header::STORE, record.clone() );
+    let net = Network::new(sock, None, Host::new("127.255.255.255:4719"), None).with_handlers(registry);
+    let outcome: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
+    let peers: Vec<Host> = (1..=4).map( |i| Host::new( &format!("127.0.0.{}:2222", i) ) ).collect();
+
+    let mut pool = WorkerPool::new(net.clone(), outcome, 2, 4);
+    for seq in 0..8u8 {
+        for peer in peers.iter() {
+            // given back while the worker is full, tried again once it made room
+            let mut dg = Datagram::new( Some(*peer), TLV::new( Header::STORE, Some( vec![seq] ) ).unwrap(), None );
+            while let Some(back) = pool.dispatch(dg) {
+                dg = back;
+                tokio::time::sleep( tokio::time::Duration::from_millis(1) ).await;
+            }
+        }
+    }
+    // bounded: the workers hold at most their queue and the one being handled
+    assert!(pool.depth() <= 2 * (4 + 1));
+    assert_eq!(pool.depth(),net.dispatch_depth());
+    pool.close().await;
+    assert_eq!(net.dispatch_depth(),0);
+
+    let record = record.0.lock().unwrap();
+    assert_eq!(record.len(),32);
+    for peer in peers.iter() {
+        let seqs: Vec<u8> = record.iter().filter( |(from, _)| from == peer ).map( |(_, seq)| *seq ).collect();
+        assert_eq!(seqs,(0..8).collect::<Vec<u8>>());
+    }
+    Ok(())
+}
+
+// cargo test bench_dispatch_latency -- --ignored --nocapture
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn bench_dispatch_latency() -> std::io::Result<()> {
+    use std::sync::Mutex;
+    use crate::network::host::Host;
+    use crate::message::tlv::TLV;
+    use crate::message::header::Header;
+    use crate::message::signal::SignalType;
+    use crate::workers::registry::{HandlerRegistry,MessageHandler,Context,HandlerFuture};
+
+    const DATAGRAMS: usize = 200_000;
+    const PEERS: usize = 1000;
+
+    // from being dispatched to being handled, the dispatch time rides in the payload
+    struct Latency(std::time::Instant, Mutex<Vec<u64>>);
+    impl MessageHandler for Latency {
+        fn handle<'a>(&'a self, _ctx: &'a mut Context, dg: Datagram) -> HandlerFuture<'a> {
+            Box::pin( async move {
+                let sent: u64 = u64::from_be_bytes( dg.data().payload()[..8].try_into().unwrap() );
+                let mut hasher = MetroHash64::new();
+                dg.data().payload().hash(&mut hasher);
+                std::hint::black_box( hasher.finish() );
+                self.1.lock().unwrap().push( self.0.elapsed().as_micros() as u64 - sent );
+                Ok(())
+            })
+        }
+    }
+
+    for pooled in [false, true] {
+        let sock = Arc::new( crate::network::simulated::SimNet::new(0).bind( Host::new("127.0.0.1:4720") ) );
+        let latency = Arc::new( Latency( std::time::Instant::now(), Mutex::new( Vec::with_capacity(DATAGRAMS) ) ) );
+        let mut registry = HandlerRegistry::new();
+        registry.register( Header::STORE, latency.clone() );
+        let net = Network::new(sock, None, Host::new("127.255.255.255:4720"), None).with_handlers(registry);
+        let outcome: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
+        let peers: Vec<Host> = (0..PEERS).map( |i| Host::new( &format!("10.0.{}.{}:2222", i / 250, i % 250 + 1) ) ).collect();
+
+        let mut pool = WorkerPool::new(net.clone(), outcome.clone(), POOL_WORKERS, WORKER_QUEUE);
+        let mut tasks = Vec::new();
+        let start = std::time::Instant::now();
+        let mut max_depth: usize = 0;
+        for i in 0..DATAGRAMS {
+            let mut payload: Vec<u8> = ( latency.0.elapsed().as_micros() as u64 ).to_be_bytes().to_vec();
+            payload.extend( [0u8; 56] );
+            let dg = Datagram::new( Some( peers[i % PEERS] ), TLV::new( Header::STORE, Some(payload) ).unwrap(), None );
+            match pooled {
+                false => { tasks.push( tokio::task::spawn( crate::workers::handle::handler( net.clone(), dg, outcome.clone() ) ) ); },
+                true => {
+                    let mut dg = dg;
+                    while let Some(back) = pool.dispatch(dg) {
+                        dg = back;
+                        tokio::task::yield_now().await;
+                    }
+                    max_depth = max_depth.max( pool.depth() );
+                },
+            }
+        }
+        for task in tasks {
+            task.await.ok();
+        }
+        pool.close().await;
+        let elapsed = start.elapsed();
+
+        let mut latencies = latency.1.lock().unwrap().clone();
+        latencies.sort();
+        let percentile = |p: usize| latencies[ (latencies.len() - 1) * p / 100 ];
+        println!("pooled: {:5} {} datagrams/s, latency p50 {}us p99 {}us max {}us, depth at most {}", pooled,
+            (DATAGRAMS as f64 / elapsed.as_secs_f64()) as u64, percentile(50), percentile(99), percentile(100), max_depth);
+    }
+    Ok(())
+}
@@ -17,10 +17,22 @@ pub async fn receiver(net: Network, mut income: SharedFifo<Datagram,()>, mut bac
             }
         }
         
-        if let Ok(dg) = net.recv_from().await {
-            if let Err(_) = income.push_notice(dg,()).await {
-                break;
-            };
+        // Stopping must not wait for one more datagram
+        tokio::select! {
+            _ = backbone.recv() => { break; },
+            // Everything already queued on the socket comes in one go, handlers are woken once
+            received = net.recv_batch() => {
+                if let Ok(dgs) = received {
+                    for dg in dgs {
+                        if let Some(dg) = net.inbound(dg) {
+                            income.push(dg);
+                        }
+                    }
+                    if income.send(()).await.is_err() {
+                        break;
+                    };
+                }
+            },
         }
     }
     
@@ -29,13 +41,13 @@ pub async fn receiver(net: Network, mut income: SharedFifo<Datagram,()>, mut bac
 }
 
 
-#[tokio::test]
+#[tokio::test(start_paused = true)]
 async fn test_receiver_hello() -> std::io::Result<()> {
     
     let dg = Datagram::from(crate::message::header::Header::HELLO);
     let i_max = 100;
 
-    match crate::server_and_client(dg, i_max,0).await {
+    match crate::node::simulated_server_and_client(dg, i_max, &[crate::node::Worker::Receiver]).await {
         Err(_) => { assert!(false); },
         Ok((_, _, income, outcome)) => {
 
@@ -52,13 +64,13 @@ async fn test_receiver_hello() -> std::io::Result<()> {
 }
 
 // transformed into pong by dispatcher
-#[tokio::test]
+#[tokio::test(start_paused = true)]
 async fn test_receiver_ping() -> std::io::Result<()> {
     
     let dg = Datagram::from(crate::message::header::Header::PING);
     let i_max = 100;
 
-    match crate::server_and_client(dg, i_max,0).await {
+    match crate::node::simulated_server_and_client(dg, i_max, &[crate::node::Worker::Receiver]).await {
         Err(_) => { assert!(false); },
         Ok((_, _, income, outcome)) => {
             for _ in 0..i_max {
@@ -73,13 +85,13 @@ async fn test_receiver_ping() -> std::io::Result<()> {
 }
 
 // unknown are filtered by dispatcher
-#[tokio::test]
+#[tokio::test(start_paused = true)]
 async fn test_receiver_unknown() -> std::io::Result<()> {
     
     let dg = Datagram::from(crate::message::header::Header::UNKNOWN);
     let i_max = 100;
 
-    match crate::server_and_client(dg, i_max,0).await {
+    match crate::node::simulated_server_and_client(dg, i_max, &[crate::node::Worker::Receiver]).await {
         Err(_) => { assert!(false); },
         Ok((_, _, income, outcome)) => {
             for _ in 0..i_max {
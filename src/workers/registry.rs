@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::network::host::Host;
+use crate::network::udp::Datagram;
+use crate::network::network::Network;
+use crate::message::tlv::TLV;
+use crate::message::header::Header;
+use crate::message::hello::Hello;
+use crate::message::lease::LeaseMessage;
+use crate::memory::shared_fifo::SharedFifo;
+
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = std::io::Result<()>> + Send + 'a>>;
+
+// Handles one message type, registered under its header. Only called once the datagram passed the
+// session checks
+pub trait MessageHandler: Send + Sync {
+    fn handle<'a>(&'a self, ctx: &'a mut Context, dg: Datagram) -> HandlerFuture<'a>;
+}
+
+// What a handler gets along with the datagram: who sent it, the network and a way to answer
+pub struct Context {
+    net: Network,
+    peer: Host,
+    outcome: SharedFifo<Datagram,()>,
+}
+
+impl Context {
+    pub fn new(net: Network, peer: Host, outcome: SharedFifo<Datagram,()>) -> Context {
+        Context { net, peer, outcome }
+    }
+
+    pub fn peer(&self) -> Host {
+        self.peer
+    }
+
+    pub fn network(&mut self) -> &mut Network {
+        &mut self.net
+    }
+
+    // Queued for the emitter
+    pub async fn send(&mut self, dg: Datagram) {
+        self.outcome.push_notice(dg,()).await.ok();
+    }
+
+    pub async fn reply(&mut self, tlv: TLV) {
+        let dg = Datagram::new( None, tlv, Some(self.peer) );
+        self.send(dg).await;
+    }
+}
+
+#[derive(Clone,Default)]
+pub struct HandlerRegistry {
+    handlers: HashMap<Header,Arc<dyn MessageHandler>>,
+}
+
+impl HandlerRegistry {
+    // Nothing registered: every header goes to the built-in handling
+    pub fn new() -> HandlerRegistry {
+        HandlerRegistry { handlers: HashMap::new() }
+    }
+
+    pub fn with_defaults() -> HandlerRegistry {
+        let mut registry = HandlerRegistry::new();
+        registry.register( Header::PING, Arc::new(PingHandler) );
+        registry.register( Header::HELLO, Arc::new(HelloHandler) );
+        registry
+    }
+
+    // Replaces the handler registered for `header`, if any
+    pub fn register(&mut self, header: Header, handler: Arc<dyn MessageHandler>) {
+        self.handlers.insert(header, handler);
+    }
+
+    pub fn get(&self, header: Header) -> Option<Arc<dyn MessageHandler>> {
+        self.handlers.get(&header).cloned()
+    }
+
+    pub fn headers(&self) -> Vec<Header> {
+        self.handlers.keys().copied().collect()
+    }
+}
+
+impl std::fmt::Debug for HandlerRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HandlerRegistry").field("headers", &self.headers()).finish()
+    }
+}
+
+// Answered with the same payload, it carries the probe's timestamp
+pub struct PingHandler;
+
+impl MessageHandler for PingHandler {
+    fn handle<'a>(&'a self, ctx: &'a mut Context, mut dg: Datagram) -> HandlerFuture<'a> {
+        Box::pin( async move {
+            dg.swap();
+            dg.set_header(Header::PONG);
+            ctx.send(dg).await;
+            Ok(())
+        })
+    }
+}
+
+// Sounds like a (re?)newcomer, or the answer to our own HELLO. What it says about the peer is only
+// taken once it signed our nonce, see Network::verify_hello
+pub struct HelloHandler;
+
+impl MessageHandler for HelloHandler {
+    fn handle<'a>(&'a self, ctx: &'a mut Context, dg: Datagram) -> HandlerFuture<'a> {
+        Box::pin( async move {
+            let peer: Host = ctx.peer();
+            let net = ctx.network();
+            let hello: Option<Hello> = Hello::from_bytes( &dg.data().payload() );
+            // our own, back from the gateway's broadcast address
+            if hello.as_ref().is_some_and( |hello| hello.id() == net.id() ) {
+                return Ok(());
+            }
+            let signed: bool = hello.as_ref().is_some_and( |hello| net.verify_hello(&peer, hello) );
+            let mut confirm: Option<Datagram> = None;
+            match hello.as_ref() {
+                Some(hello) if signed => {
+                    net.member_alive(hello.id(), &peer);
+                    // Only addresses we leased, or that our server vouches for: the server's own
+                    // claim, or its answer to our CONFIRM
+                    if let Some(ip) = hello.overlay() {
+                        if net.lease_held(&hello.id(), &ip) || net.server() == Some(peer) {
+                            net.assign_overlay(ip, hello.id());
+                        }
+                        else if let Some(server) = net.server() {
+                            confirm = ( LeaseMessage::Confirm { id: hello.id(), ip } ).to_tlv().map( |tlv| Datagram::new( None, tlv, Some(server) ) );
+                        }
+                    }
+                    if let Some(name) = hello.name() {
+                        net.name_peer(hello.id(), name);
+                    }
+                },
+                _ => { net.insert(&peer); },
+            }
+            // ours in return, with our nonce and the signature of theirs
+            let answer: Option<Datagram> = match net.hello_received( &peer, hello.as_ref(), signed ) {
+                true => Some( net.greet(peer) ),
+                false => None,
+            };
+            for dg in confirm.into_iter().chain(answer) {
+                ctx.send(dg).await;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_registry_application_handler() -> std::io::Result<()> {
+    use std::sync::atomic::{AtomicU32,Ordering};
+    use crate::message::signal::SignalType;
+    use crate::network::session::SessionState;
+
+    // echoes the payload back under the next application type, counting the calls
+    struct Echo(AtomicU32);
+    impl MessageHandler for Echo {
+        fn handle<'a>(&'a self, ctx: &'a mut Context, dg: Datagram) -> HandlerFuture<'a> {
+            Box::pin( async move {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                ctx.reply( TLV::new( Header::APPLICATION(33), Some( dg.data().payload() ) ).unwrap() ).await;
+                Ok(())
+            })
+        }
+    }
+
+    let sock = Arc::new( crate::network::simulated::SimNet::new(0).bind( Host::new("127.0.0.1:4714") ) );
+    let echo = Arc::new( Echo( AtomicU32::new(0) ) );
+    let mut registry = HandlerRegistry::with_defaults();
+    registry.register( Header::APPLICATION(32), echo.clone() );
+    let net = Network::new(sock, None, Host::new("127.255.255.255:4714"), None).with_handlers(registry);
+    let outcome: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
+    let peer = Host::new("127.0.0.2:2222");
+    let app = Datagram::new( Some(peer), TLV::new( Header::APPLICATION(32), Some( vec![1,2,3] ) ).unwrap(), None );
+
+    // session traffic: nothing before the handshake
+    crate::workers::handle::handler( net.clone(), app.clone(), outcome.clone() ).await?;
+    assert_eq!(echo.0.load(Ordering::SeqCst),0);
+
+    // the default HELLO handler establishes the session once the peer signed our nonce
+    crate::workers::handle::handshake( &net, peer, Hello::new( crate::network::peer::PeerId::new(2) ), &outcome ).await?;
+    assert_eq!(net.session(&peer).map( |session| session.state() ),Some( SessionState::Established ));
+
+    crate::workers::handle::handler( net.clone(), app, outcome.clone() ).await?;
+    assert_eq!(echo.0.load(Ordering::SeqCst),1);
+    let answer = outcome.pop().unwrap();
+    assert_eq!((answer.header(), answer.dst(), answer.data().payload()),(Header::APPLICATION(33), Some(peer), vec![1,2,3]));
+
+    // the default PING handler
+    let ping = Datagram::new( Some(peer), TLV::new( Header::PING, Some( vec![4] ) ).unwrap(), None );
+    crate::workers::handle::handler( net.clone(), ping, outcome.clone() ).await?;
+    let pong = outcome.pop().unwrap();
+    assert_eq!((pong.header(), pong.dst(), pong.data().payload()),(Header::PONG, Some(peer), vec![4]));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_hello_overlay_leased_only() -> std::io::Result<()> {
+    use crate::message::signal::SignalType;
+    use crate::network::lease::{AddressPool,Cidr};
+    use crate::network::peer::PeerId;
+
+    let sim = crate::network::simulated::SimNet::new(0);
+    let (server_host, peer) = (Host::new("10.3.0.1:4728"), Host::new("10.3.0.2:4728"));
+    let server = Network::new( Arc::new( sim.bind(server_host) ), None, Host::new("10.3.255.255:4728"), None )
+        .with_pool( AddressPool::new( Cidr::try_from("172.17.0.0/24").unwrap(), 60 ) );
+    let client = Network::new( Arc::new( sim.bind( Host::new("10.3.0.3:4728") ) ), None, Host::new("10.3.255.255:4728"), Some(server_host) );
+    let outcome: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
+    let (owner, thief) = (PeerId::new(2), PeerId::new(3));
+    let hello = |id: PeerId, ip: std::net::IpAddr| Hello::new(id).with_overlay(ip);
+
+    let ip = match server.lease_answer( LeaseMessage::Request { id: owner, requested: None } ) {
+        Some( LeaseMessage::Offer { ip, .. } ) => ip,
+        _ => panic!("no offer"),
+    };
+    server.lease_answer( LeaseMessage::Request { id: owner, requested: Some(ip) } );
+
+    // the server knows who it leased the address to
+    crate::workers::handle::handshake( &server, peer, hello(thief, ip), &outcome ).await?;
+    assert_eq!(server.overlay_peer(&ip),Some(owner));
+    while outcome.pop().is_some() {}
+
+    // a client asks the server before believing the claim
+    crate::workers::handle::handshake( &client, peer, hello(owner, ip), &outcome ).await?;
+    assert_eq!(client.overlay_peer(&ip),None);
+    let confirm = std::iter::from_fn( || outcome.pop() ).find( |dg| dg.header() == Header::LEASE_CONFIRM ).unwrap();
+    assert_eq!(confirm.dst(),Some(server_host));
+    let confirm = Datagram::new( Some(peer), confirm.data(), None );
+    crate::workers::handle::handler( server.clone(), confirm.clone(), outcome.clone() ).await?;
+    let confirmed = outcome.pop().unwrap();
+    assert_eq!(confirmed.dst(),Some(peer));
+
+    // only the server's answer counts
+    crate::workers::handle::handler( client.clone(), confirm, outcome.clone() ).await?;
+    assert_eq!(client.overlay_peer(&ip),None);
+    crate::workers::handle::handler( client.clone(), Datagram::new( Some(server_host), confirmed.data(), None ), outcome.clone() ).await?;
+    assert_eq!(client.overlay_peer(&ip),Some(owner));
+
+    // and nothing is confirmed for the thief
+    let claim = Datagram::new( Some(peer), ( LeaseMessage::Confirm { id: thief, ip } ).to_tlv().unwrap(), None );
+    while outcome.pop().is_some() {}
+    crate::workers::handle::handler( server.clone(), claim, outcome.clone() ).await?;
+    assert!(outcome.pop().is_none());
+    Ok(())
+}
@@ -0,0 +1,30 @@
+use tokio::time::{Duration,sleep};
+
+use crate::network::network::Network;
+
+use crate::message::signal::Signal;
+
+// Follow the gateway and the server when the address behind their name changes
+pub async fn resolver(net: Network, mut backbone: Signal<()>, period: Duration) -> Result<(), std::io::Error> {
+    loop {
+        // If received any data => stop the thread
+        match backbone.try_recv() {
+            Err(_) => { break; },
+            Ok(data) => { 
+                if data.is_some() {
+                    break;
+                } 
+            }
+        }
+
+        // unresolved for now, the last address known is still used
+        net.re_resolve().await.ok();
+        tokio::select! {
+            _ = backbone.recv() => { break; },
+            _ = sleep(period) => {},
+        }
+    }
+
+    backbone.close();
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc,Mutex};
+use tokio::time::{Duration,Instant,sleep,timeout};
+
+use crate::message::signal::Signal;
+
+// A crashed worker is started again after RESTART_BACKOFF, doubled on each crash in a row up to
+// RESTART_BACKOFF_MAX. Running for RESTART_RESET makes it a fresh start again
+pub const RESTART_BACKOFF: Duration = Duration::from_millis(100);
+pub const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(10);
+pub const RESTART_RESET: Duration = Duration::from_secs(30);
+// Once stopped, a worker that doesn't return by then is aborted
+pub const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub type WorkerFuture = Pin<Box<dyn Future<Output = std::io::Result<()>> + Send>>;
+// Builds the worker from clones of what it needs, every time it has to be (re)started
+pub type WorkerFactory = Box<dyn Fn(Signal<()>) -> WorkerFuture + Send + Sync>;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum WorkerState {
+    Running,
+    // Crashed, waiting for its backoff
+    Restarting,
+    Stopped,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct WorkerStatus {
+    state: WorkerState,
+    restarts: u32,
+    last_error: Option<String>,
+}
+
+impl WorkerStatus {
+    pub fn state(&self) -> WorkerState {
+        self.state
+    }
+
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+}
+
+pub type WorkerStatuses = Arc<Mutex<HashMap<String,WorkerStatus>>>;
+
+pub fn report(statuses: &WorkerStatuses, name: &str, state: WorkerState, error: Option<String>) {
+    let mut statuses = statuses.lock().unwrap();
+    let status = statuses.entry( name.to_string() ).or_insert( WorkerStatus { state, restarts: 0, last_error: None } );
+    if state == WorkerState::Restarting {
+        status.restarts += 1;
+    }
+    status.state = state;
+    if error.is_some() {
+        status.last_error = error;
+    }
+}
+
+// Run the worker `factory` builds until `backbone` says stop. Returning on its own, with an error
+// or by panicking, is a crash: the worker is built again after a backoff
+pub async fn supervise(name: String, factory: WorkerFactory, mut backbone: Signal<()>, statuses: WorkerStatuses) -> std::io::Result<()> {
+    let mut crashes: u32 = 0;
+    loop {
+        // If received any data => stop the thread
+        match backbone.try_recv() {
+            Err(_) => { break; },
+            Ok(data) => {
+                if data.is_some() {
+                    break;
+                }
+            }
+        }
+
+        report(&statuses, &name, WorkerState::Running, None);
+        let started = Instant::now();
+        let mut task = tokio::task::spawn( factory( backbone.subscribe() ) );
+        let error: String = tokio::select! {
+            _ = backbone.recv() => {
+                // the worker got the signal too
+                if timeout(STOP_TIMEOUT, &mut task).await.is_err() {
+                    task.abort();
+                }
+                break;
+            },
+            result = &mut task => match result {
+                Ok(Ok(())) => "returned".to_string(),
+                Ok(Err(err)) => err.to_string(),
+                Err(err) => match err.is_panic() {
+                    true => "panicked".to_string(),
+                    false => "cancelled".to_string(),
+                },
+            },
+        };
+
+        // it may simply have seen the stop signal first
+        if let Ok(Some(_)) = backbone.try_recv() {
+            break;
+        }
+        crashes = match started.elapsed() >= RESTART_RESET {
+            true => 1,
+            false => crashes + 1,
+        };
+        report(&statuses, &name, WorkerState::Restarting, Some(error));
+        let backoff: Duration = RESTART_BACKOFF.saturating_mul( 2u32.saturating_pow(crashes - 1) ).min(RESTART_BACKOFF_MAX);
+        tokio::select! {
+            _ = backbone.recv() => { break; },
+            _ = sleep(backoff) => {},
+        }
+    }
+
+    report(&statuses, &name, WorkerState::Stopped, None);
+    backbone.close();
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_supervise_restarts_with_backoff() {
+    use std::sync::atomic::{AtomicU32,Ordering};
+    use crate::message::signal::SignalType;
+
+    let statuses: WorkerStatuses = Arc::new( Mutex::new( HashMap::new() ) );
+    let mut backbone: Signal<()> = Signal::new(SignalType::broadcast);
+    let starts: Arc<AtomicU32> = Arc::new( AtomicU32::new(0) );
+
+    // fails twice, panics once, then behaves until stopped
+    let counter = Arc::clone(&starts);
+    let factory: WorkerFactory = Box::new( move |mut backbone: Signal<()>| {
+        let start = counter.fetch_add(1, Ordering::SeqCst);
+        Box::pin( async move {
+            match start {
+                0 | 1 => Err( std::io::Error::other("boom") ),
+                2 => panic!("worker bug"),
+                _ => { backbone.recv().await.ok(); Ok(()) },
+            }
+        })
+    });
+    let supervisor = tokio::task::spawn( supervise("flaky".to_string(), factory, backbone.subscribe(), Arc::clone(&statuses)) );
+
+    // 100ms, 200ms then 400ms between the starts
+    sleep( Duration::from_millis(50) ).await;
+    assert_eq!(starts.load(Ordering::SeqCst),1);
+    assert_eq!(statuses.lock().unwrap()["flaky"].state(),WorkerState::Restarting);
+    sleep( Duration::from_millis(100) ).await;
+    assert_eq!(starts.load(Ordering::SeqCst),2);
+    assert_eq!(statuses.lock().unwrap()["flaky"].last_error(),Some("boom"));
+    sleep( Duration::from_millis(200) ).await;
+    assert_eq!(starts.load(Ordering::SeqCst),3);
+    sleep( Duration::from_millis(400) ).await;
+    assert_eq!(starts.load(Ordering::SeqCst),4);
+    let status = statuses.lock().unwrap()["flaky"].clone();
+    assert_eq!((status.state(), status.restarts(), status.last_error()),(WorkerState::Running, 3, Some("panicked")));
+
+    backbone.send(()).await.ok();
+    supervisor.await.unwrap().ok();
+    assert_eq!(starts.load(Ordering::SeqCst),4);
+    assert_eq!(statuses.lock().unwrap()["flaky"].state(),WorkerState::Stopped);
+}
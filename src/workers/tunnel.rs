@@ -0,0 +1,102 @@
+use std::sync::Arc;
+
+use crate::network::udp::Datagram;
+use crate::network::network::Network;
+use crate::network::packet_io::PacketIo;
+
+use crate::message::signal::Signal;
+
+use crate::memory::shared_fifo::SharedFifo;
+
+// Bridge between the local packet source and the overlay: packets read are sent to the peer owning
+// their destination, IPPACKETs received by the handler are written back
+pub async fn tunneler(net: Network, io: Arc<dyn PacketIo>, mut outcome: SharedFifo<Datagram,()>, mut backbone: Signal<()>) -> std::io::Result<()> {
+    let mut delivered = net.attach_tunnel();
+    let mut buf: [u8; 1500] = [0; 1500];
+    // a replayed capture runs dry, delivering still goes on
+    let mut reading: bool = true;
+
+    loop {
+        // If received any data => stop the thread
+        match backbone.try_recv() {
+            Err(_) => { break; },
+            Ok(data) => { 
+                if data.is_some() {
+                    break;
+                } 
+            }
+        }
+
+        tokio::select! {
+            read = io.read(&mut buf), if reading => {
+                match read {
+                    Err(_) => { reading = false; },
+                    Ok(len) => {
+                        // oversized packets or unknown destinations are dropped
+                        if let Some(dg) = net.encapsulate(&buf[..len]) {
+                            outcome.push_notice(dg,()).await.ok();
+                        }
+                    },
+                }
+            },
+            _ = backbone.recv() => { break; },
+            packet = delivered.recv() => {
+                match packet {
+                    None => { break; },
+                    Some(packet) => { io.write(&packet).await.ok(); },
+                }
+            },
+        }
+    }
+
+    backbone.close();
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_tunnel_forwarding() -> std::io::Result<()> {
+    use std::net::{IpAddr,Ipv4Addr};
+    use crate::network::host::Host;
+    use crate::network::simulated::SimNet;
+    use crate::network::packet_io::MemoryPacketIo;
+    use crate::message::packet::ipv4_packet;
+    use crate::message::signal::SignalType;
+
+    let sim = SimNet::new(0);
+    let backbone: Signal<()> = Signal::new(SignalType::broadcast);
+    let mut nodes: Vec<(Network, crate::network::packet_io::MemoryHandle)> = Vec::new();
+    for host in ["10.1.0.1:1", "10.1.0.2:1"] {
+        let net = Network::new( Arc::new( sim.bind( Host::new(host) ) ), None, Host::new("10.1.255.255:1"), None );
+        let income: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
+        let outcome: SharedFifo<Datagram,()> = SharedFifo::new(SignalType::notify);
+        let (io, handle) = MemoryPacketIo::new();
+
+        tokio::task::spawn( crate::workers::receive::receiver(net.clone(), income.clone(), backbone.subscribe()) );
+        tokio::task::spawn( crate::workers::dispatch::dispatcher(net.clone(), income, outcome.clone(), backbone.subscribe()) );
+        tokio::task::spawn( crate::workers::emit::emitter(net.clone(), outcome.clone(), backbone.subscribe()) );
+        tokio::task::spawn( tunneler(net.clone(), Arc::new(io), outcome, backbone.subscribe()) );
+        nodes.push( (net, handle) );
+    }
+    let (mut a, a_io) = nodes.remove(0);
+    let (b, b_io) = nodes.remove(0);
+
+    a.insert_peer( b.id(), &b.local_addr() );
+    a.assign_overlay( IpAddr::V4( Ipv4Addr::new(192, 168, 7, 2) ), b.id() );
+    b.assign_overlay( IpAddr::V4( Ipv4Addr::new(192, 168, 7, 1) ), a.id() );
+    // b only takes overlay traffic once a said HELLO
+    a.send_to( a.greet( b.local_addr() ), None ).await.ok();
+    tokio::time::sleep( tokio::time::Duration::from_millis(10) ).await;
+
+    let packet = ipv4_packet( Ipv4Addr::new(192, 168, 7, 1), Ipv4Addr::new(192, 168, 7, 2), b"overlay" );
+    let stray = ipv4_packet( Ipv4Addr::new(192, 168, 7, 1), Ipv4Addr::new(192, 168, 7, 9), b"nobody" );
+    // b drops what comes from an address a does not hold
+    let spoofed = ipv4_packet( Ipv4Addr::new(192, 168, 7, 5), Ipv4Addr::new(192, 168, 7, 2), b"spoofed" );
+    a_io.inject(stray).await;
+    a_io.inject(spoofed).await;
+    a_io.inject(packet.clone()).await;
+    tokio::time::sleep( tokio::time::Duration::from_millis(100) ).await;
+
+    assert_eq!(vec![packet],b_io.written());
+    assert!(a_io.written().is_empty());
+    Ok(())
+}